@@ -0,0 +1,460 @@
+// 权限缓存
+//
+// AclCache 按 uid 保存用户直接授予的规则和通过角色获得的规则，check_user_acl 由此判断，
+// 与直接查询数据库的结果相同。缓存通过 Repositories::with_acl_cache 包装 acl 与 role 仓库生效，
+// 包装后的仓库在写入成功后使缓存失效：用户规则与角色分配的修改只影响该用户，
+// 删除资源以及角色规则的修改影响所有用户。
+
+use std::{collections::HashMap, sync::Arc};
+
+use actix::{Actor, Addr, Context, Handler, Message, MessageResult};
+use actix_web::{web, HttpResponse};
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::lib::error::ApiError;
+
+use super::{
+    change::{AclChange, ProtectedAcl},
+    sql_acl::{AclGrant, AclRepository, Operation, Resource},
+    sql_role::{Role, RoleRepository},
+};
+
+// 规则来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GrantKind {
+    Direct, // 直接授予
+    Role,   // 通过角色
+}
+
+// 命中统计
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct AclCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct AclCache {
+    grants: HashMap<(GrantKind, u64), Vec<AclGrant>>,
+    // 每次失效加一，查询数据库期间缓存失效时丢弃查询结果
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl AclCache {
+    // 查询缓存，同时返回当前版本
+    pub fn get(&mut self, kind: GrantKind, uid: u64) -> (Option<Vec<AclGrant>>, u64) {
+        let grants = self.grants.get(&(kind, uid)).cloned();
+        match grants {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        (grants, self.generation)
+    }
+
+    // 保存查询结果，版本已变化时丢弃
+    pub fn put(&mut self, kind: GrantKind, uid: u64, generation: u64, grants: Vec<AclGrant>) {
+        if generation == self.generation {
+            self.grants.insert((kind, uid), grants);
+        }
+    }
+
+    // 使缓存失效，uid 为 None 时该来源的所有用户失效
+    pub fn invalidate(&mut self, kind: GrantKind, uid: Option<u64>) {
+        self.generation += 1;
+        match uid {
+            Some(uid) => {
+                self.grants.remove(&(kind, uid));
+            }
+            None => self.grants.retain(|(cached, _), _| *cached != kind),
+        }
+    }
+
+    pub fn stats(&self) -> AclCacheStats {
+        AclCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.grants.len(),
+        }
+    }
+}
+
+impl Actor for AclCache {
+    type Context = Context<Self>;
+}
+
+#[derive(Message)]
+#[rtype(result = "(Option<Vec<AclGrant>>, u64)")]
+pub struct GetGrants {
+    pub kind: GrantKind,
+    pub uid: u64,
+}
+
+impl Handler<GetGrants> for AclCache {
+    type Result = MessageResult<GetGrants>;
+
+    fn handle(&mut self, msg: GetGrants, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.get(msg.kind, msg.uid))
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PutGrants {
+    pub kind: GrantKind,
+    pub uid: u64,
+    pub generation: u64,
+    pub grants: Vec<AclGrant>,
+}
+
+impl Handler<PutGrants> for AclCache {
+    type Result = ();
+
+    fn handle(&mut self, msg: PutGrants, _: &mut Context<Self>) {
+        self.put(msg.kind, msg.uid, msg.generation, msg.grants);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Invalidate {
+    pub kind: GrantKind,
+    pub uid: Option<u64>,
+}
+
+impl Handler<Invalidate> for AclCache {
+    type Result = ();
+
+    fn handle(&mut self, msg: Invalidate, _: &mut Context<Self>) {
+        self.invalidate(msg.kind, msg.uid);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "AclCacheStats")]
+pub struct GetStats;
+
+impl Handler<GetStats> for AclCache {
+    type Result = MessageResult<GetStats>;
+
+    fn handle(&mut self, _: GetStats, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.stats())
+    }
+}
+
+// 先查缓存，未命中时查询数据库并保存；缓存不可用时直接查询数据库
+async fn cached_grants<F>(
+    cache: &Addr<AclCache>,
+    kind: GrantKind,
+    uid: u64,
+    query: F,
+) -> Result<Vec<AclGrant>, sqlx::Error>
+where
+    F: std::future::Future<Output = Result<Vec<AclGrant>, sqlx::Error>>,
+{
+    let generation = match cache.send(GetGrants { kind, uid }).await {
+        Ok((Some(grants), _)) => return Ok(grants),
+        Ok((None, generation)) => Some(generation),
+        Err(_) => None,
+    };
+    let grants = query.await?;
+    if let Some(generation) = generation {
+        cache.do_send(PutGrants {
+            kind,
+            uid,
+            generation,
+            grants: grants.clone(),
+        });
+    }
+    Ok(grants)
+}
+
+// 写入成功后使缓存失效
+async fn invalidate(cache: &Addr<AclCache>, kind: GrantKind, uid: Option<u64>) {
+    cache.send(Invalidate { kind, uid }).await.ok();
+}
+
+/// 带缓存的权限仓库
+pub struct CachedAclRepository {
+    pub(crate) inner: Arc<dyn AclRepository>,
+    pub(crate) cache: Addr<AclCache>,
+}
+
+#[async_trait]
+impl AclRepository for CachedAclRepository {
+    async fn add_resource(&self, name: &str) -> Result<(), sqlx::Error> {
+        self.inner.add_resource(name).await
+    }
+
+    async fn add_acl(
+        &self,
+        uid: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        self.inner
+            .add_acl(uid, resource_id, operation, deny)
+            .await?;
+        invalidate(&self.cache, GrantKind::Direct, Some(uid as u64)).await;
+        Ok(())
+    }
+
+    async fn get_resource_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        self.inner.get_resource_id(name).await
+    }
+
+    async fn get_all_resource(&self) -> Result<Vec<Resource>, sqlx::Error> {
+        self.inner.get_all_resource().await
+    }
+
+    async fn add_operation(&self, resource_id: i64, name: &str) -> Result<(), sqlx::Error> {
+        self.inner.add_operation(resource_id, name).await
+    }
+
+    async fn remove_operation(&self, resource_id: i64, name: &str) -> Result<u64, sqlx::Error> {
+        self.inner.remove_operation(resource_id, name).await
+    }
+
+    async fn get_all_operation(&self) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        self.inner.get_all_operation().await
+    }
+
+    async fn query_user_acl(&self, uid: u64) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        self.inner.query_user_acl(uid).await
+    }
+
+    async fn query_user_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        cached_grants(
+            &self.cache,
+            GrantKind::Direct,
+            uid,
+            self.inner.query_user_grants(uid),
+        )
+        .await
+    }
+
+    async fn apply_acl_change(
+        &self,
+        change: &AclChange,
+        protected: &[ProtectedAcl],
+    ) -> Result<Option<u64>, sqlx::Error> {
+        let affected = self.inner.apply_acl_change(change, protected).await?;
+        if affected.is_none() {
+            return Ok(None);
+        }
+        match change {
+            AclChange::AddAcl { uid, .. } | AclChange::RemoveAcl { uid, .. } => {
+                invalidate(&self.cache, GrantKind::Direct, Some(*uid as u64)).await;
+            }
+            AclChange::AddRoleAcl { .. }
+            | AclChange::RemoveRoleAcl { .. }
+            | AclChange::RemoveRole { .. } => {
+                invalidate(&self.cache, GrantKind::Role, None).await;
+            }
+            AclChange::AssignRole { uid, .. } | AclChange::UnassignRole { uid, .. } => {
+                invalidate(&self.cache, GrantKind::Role, Some(*uid as u64)).await;
+            }
+            // 规则中保存的是资源名，角色的规则也需要失效
+            AclChange::RemoveResource { .. } => {
+                invalidate(&self.cache, GrantKind::Direct, None).await;
+                invalidate(&self.cache, GrantKind::Role, None).await;
+            }
+            AclChange::DeleteUser { uid } => {
+                invalidate(&self.cache, GrantKind::Direct, Some(*uid as u64)).await;
+                invalidate(&self.cache, GrantKind::Role, Some(*uid as u64)).await;
+            }
+        }
+        Ok(affected)
+    }
+}
+
+/// 带缓存的角色仓库
+pub struct CachedRoleRepository {
+    pub(crate) inner: Arc<dyn RoleRepository>,
+    pub(crate) cache: Addr<AclCache>,
+}
+
+#[async_trait]
+impl RoleRepository for CachedRoleRepository {
+    async fn add_role(&self, name: &str) -> Result<(), sqlx::Error> {
+        self.inner.add_role(name).await
+    }
+
+    async fn get_role_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        self.inner.get_role_id(name).await
+    }
+
+    async fn get_all_role(&self) -> Result<Vec<Role>, sqlx::Error> {
+        self.inner.get_all_role().await
+    }
+
+    async fn add_role_acl(
+        &self,
+        role_id: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        self.inner
+            .add_role_acl(role_id, resource_id, operation, deny)
+            .await?;
+        invalidate(&self.cache, GrantKind::Role, None).await;
+        Ok(())
+    }
+
+    async fn query_role_acl(&self, role_id: i64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        self.inner.query_role_acl(role_id).await
+    }
+
+    async fn assign_role(&self, uid: i64, role_id: i64) -> Result<(), sqlx::Error> {
+        self.inner.assign_role(uid, role_id).await?;
+        invalidate(&self.cache, GrantKind::Role, Some(uid as u64)).await;
+        Ok(())
+    }
+
+    async fn get_user_roles(&self, uid: i64) -> Result<Vec<Role>, sqlx::Error> {
+        self.inner.get_user_roles(uid).await
+    }
+
+    async fn query_user_role_acl(
+        &self,
+        uid: u64,
+    ) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        self.inner.query_user_role_acl(uid).await
+    }
+
+    async fn query_user_role_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        cached_grants(
+            &self.cache,
+            GrantKind::Role,
+            uid,
+            self.inner.query_user_role_grants(uid),
+        )
+        .await
+    }
+}
+
+// 缓存命中统计，路由上要求 resource 的 Check 权限
+pub async fn acl_cache_stats(cache: web::Data<Addr<AclCache>>) -> Result<HttpResponse, ApiError> {
+    let stats = cache.send(GetStats).await.unwrap_or_default();
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+// 经过缓存与直接查询数据库的判断结果一致，写入后缓存失效
+#[actix_web::test]
+async fn test_acl_cache() {
+    use crate::lib::{
+        acl::check_user_acl,
+        config::{init_base_data_acl, HttpServerConfig},
+        repository::Repositories,
+        user::web_user::RegisterUser,
+    };
+
+    let config = HttpServerConfig::default();
+    let database = Repositories::memory();
+    init_base_data_acl(&database, &config).await;
+    let cache = AclCache::default().start();
+    let cached = database.clone().with_acl_cache(cache.clone());
+    let uid = database
+        .user
+        .register_user(&RegisterUser {
+            email: "cache@example.com".to_string(),
+            password: "Password123".to_string(),
+        })
+        .await
+        .unwrap();
+    cached.acl.add_resource("server.*").await.unwrap();
+    let wildcard = cached.acl.get_resource_id("server.*").await.unwrap();
+    let server = cached.acl.get_resource_id("server").await.unwrap();
+
+    let checks = [
+        ("server", Operation::Check),
+        ("server.survival", Operation::Check),
+        ("user", Operation::Check),
+        ("missing", Operation::Add),
+    ];
+    let same = || async {
+        for (resource, operation) in &checks {
+            let from_cache = check_user_acl(
+                cached.acl.as_ref(),
+                cached.role.as_ref(),
+                uid as u64,
+                resource,
+                operation,
+            )
+            .await;
+            let from_database = check_user_acl(
+                database.acl.as_ref(),
+                database.role.as_ref(),
+                uid as u64,
+                resource,
+                operation,
+            )
+            .await;
+            assert_eq!(
+                format!("{:?}", from_cache),
+                format!("{:?}", from_database),
+                "{} {}",
+                resource,
+                operation
+            );
+        }
+    };
+
+    same().await;
+    let first = cache.send(GetStats).await.unwrap();
+    assert!(first.misses > 0);
+    same().await;
+    let second = cache.send(GetStats).await.unwrap();
+    assert!(second.hits > first.hits);
+    assert_eq!(second.misses, first.misses);
+
+    // 每次写入后都与数据库一致
+    cached
+        .acl
+        .add_acl(uid, wildcard, &Operation::Check, false)
+        .await
+        .unwrap();
+    same().await;
+    cached
+        .acl
+        .add_acl(uid, server, &Operation::Check, true)
+        .await
+        .unwrap();
+    same().await;
+    let admin = cached
+        .role
+        .get_role_id(super::sql_role::ADMIN_ROLE)
+        .await
+        .unwrap();
+    cached.role.assign_role(uid, admin).await.unwrap();
+    same().await;
+    let changes = [
+        AclChange::RemoveRoleAcl {
+            role_id: admin,
+            resource_id: server,
+            operation: "Check".to_string(),
+        },
+        AclChange::RemoveAcl {
+            uid,
+            resource_id: server,
+            operation: "Check".to_string(),
+        },
+        AclChange::UnassignRole {
+            uid,
+            role_id: admin,
+        },
+        AclChange::RemoveResource {
+            resource_id: wildcard,
+        },
+    ];
+    for change in &changes {
+        let affected = cached.acl.apply_acl_change(change, &[]).await.unwrap();
+        assert!(affected.is_some());
+        same().await;
+    }
+}
@@ -0,0 +1,620 @@
+// 撤销权限的修改
+//
+// 移除规则、添加拒绝规则、取消或删除角色、删除资源与删除用户都可能撤销权限，
+// 这些修改由 AclRepository::apply_acl_change 在一个事务中执行：先锁定受保护权限涉及的资源行，
+// 使并发的修改依次执行，再在同一个事务中查询修改前后受保护权限的持有者，
+// 失去最后一个持有者时回滚。
+
+use std::collections::{HashMap, HashSet};
+
+use sqlx::{
+    database::HasArguments, mysql::MySqlQueryResult, postgres::PgQueryResult, query::Query,
+    sqlite::SqliteQueryResult, ColumnIndex, Database, Decode, Encode, Executor, IntoArguments,
+    Pool, Row, Type,
+};
+
+use crate::lib::{
+    config::AclConfig,
+    repository::memory::{unique_violation, AclRow, MemoryState, RoleAclRow, UserRoleRow},
+};
+
+use super::{
+    resolve_grants, resource_candidates,
+    sql_acl::{AclGrant, Operation},
+};
+
+// 至少保留一个持有者的权限
+#[derive(Debug, Clone)]
+pub struct ProtectedAcl {
+    pub resource: String,
+    pub operation: Operation,
+}
+
+impl ProtectedAcl {
+    // 配置中的受保护权限
+    pub fn from_config(config: &AclConfig) -> Vec<ProtectedAcl> {
+        config
+            .protected_acl
+            .iter()
+            .flat_map(|(resource, operations)| {
+                operations.iter().map(move |operation| ProtectedAcl {
+                    resource: resource.clone(),
+                    operation: Operation::from_string(operation),
+                })
+            })
+            .collect()
+    }
+}
+
+// 对规则、角色分配、资源或用户的一次修改
+#[derive(Debug, Clone)]
+pub enum AclChange {
+    // 添加用户对资源的操作，deny 为 true 时添加拒绝规则
+    AddAcl {
+        uid: i64,
+        resource_id: i64,
+        operation: Operation,
+        deny: bool,
+    },
+    // 移除用户对资源的操作
+    RemoveAcl {
+        uid: i64,
+        resource_id: i64,
+        operation: String,
+    },
+    // 添加角色对资源的操作，deny 为 true 时添加拒绝规则
+    AddRoleAcl {
+        role_id: i64,
+        resource_id: i64,
+        operation: Operation,
+        deny: bool,
+    },
+    // 移除角色对资源的操作
+    RemoveRoleAcl {
+        role_id: i64,
+        resource_id: i64,
+        operation: String,
+    },
+    // 为用户分配角色
+    AssignRole {
+        uid: i64,
+        role_id: i64,
+    },
+    // 取消用户的角色
+    UnassignRole {
+        uid: i64,
+        role_id: i64,
+    },
+    // 删除角色，同时删除角色的操作权与分配
+    RemoveRole {
+        role_id: i64,
+    },
+    // 删除资源，同时删除资源上注册的操作与所有用户、角色对该资源的规则
+    RemoveResource {
+        resource_id: i64,
+    },
+    // 删除用户，同时删除用户的规则、角色分配、玩家、两步验证、会话、邮箱更换记录与验证码
+    DeleteUser {
+        uid: i64,
+    },
+}
+
+// 语句的参数
+#[derive(Debug, Clone)]
+enum SqlValue {
+    Int(i64),
+    Text(String),
+    Bool(bool),
+}
+
+impl AclChange {
+    // 依次执行的语句，使用 ? 占位，返回最后一条语句受影响的行数
+    fn statements(&self) -> Vec<(&'static str, Vec<SqlValue>)> {
+        use SqlValue::*;
+        match self {
+            AclChange::AddAcl {
+                uid,
+                resource_id,
+                operation,
+                deny,
+            } => vec![(
+                "INSERT INTO acl (uid, resource_id, operation, deny) VALUES (?, ?, ?, ?)",
+                vec![
+                    Int(*uid),
+                    Int(*resource_id),
+                    Text(operation.to_string()),
+                    Bool(*deny),
+                ],
+            )],
+            AclChange::RemoveAcl {
+                uid,
+                resource_id,
+                operation,
+            } => vec![(
+                "DELETE FROM acl WHERE uid = ? AND resource_id = ? AND operation = ?",
+                vec![Int(*uid), Int(*resource_id), Text(operation.clone())],
+            )],
+            AclChange::AddRoleAcl {
+                role_id,
+                resource_id,
+                operation,
+                deny,
+            } => vec![(
+                "INSERT INTO role_acl (role_id, resource_id, operation, deny) VALUES (?, ?, ?, ?)",
+                vec![
+                    Int(*role_id),
+                    Int(*resource_id),
+                    Text(operation.to_string()),
+                    Bool(*deny),
+                ],
+            )],
+            AclChange::RemoveRoleAcl {
+                role_id,
+                resource_id,
+                operation,
+            } => vec![(
+                "DELETE FROM role_acl WHERE role_id = ? AND resource_id = ? AND operation = ?",
+                vec![Int(*role_id), Int(*resource_id), Text(operation.clone())],
+            )],
+            AclChange::AssignRole { uid, role_id } => vec![(
+                "INSERT INTO user_roles (uid, role_id) VALUES (?, ?)",
+                vec![Int(*uid), Int(*role_id)],
+            )],
+            AclChange::UnassignRole { uid, role_id } => vec![(
+                "DELETE FROM user_roles WHERE uid = ? AND role_id = ?",
+                vec![Int(*uid), Int(*role_id)],
+            )],
+            AclChange::RemoveRole { role_id } => vec![
+                (
+                    "DELETE FROM user_roles WHERE role_id = ?",
+                    vec![Int(*role_id)],
+                ),
+                (
+                    "DELETE FROM role_acl WHERE role_id = ?",
+                    vec![Int(*role_id)],
+                ),
+                ("DELETE FROM roles WHERE id = ?", vec![Int(*role_id)]),
+            ],
+            AclChange::RemoveResource { resource_id } => vec![
+                (
+                    "DELETE FROM resource_operations WHERE resource_id = ?",
+                    vec![Int(*resource_id)],
+                ),
+                (
+                    "DELETE FROM acl WHERE resource_id = ?",
+                    vec![Int(*resource_id)],
+                ),
+                (
+                    "DELETE FROM role_acl WHERE resource_id = ?",
+                    vec![Int(*resource_id)],
+                ),
+                ("DELETE FROM resource WHERE id = ?", vec![Int(*resource_id)]),
+            ],
+            AclChange::DeleteUser { uid } => vec![
+                ("DELETE FROM acl WHERE uid = ?", vec![Int(*uid)]),
+                ("DELETE FROM user_roles WHERE uid = ?", vec![Int(*uid)]),
+                ("DELETE FROM java_player WHERE uid = ?", vec![Int(*uid)]),
+                ("DELETE FROM totp WHERE uid = ?", vec![Int(*uid)]),
+                ("DELETE FROM recovery_codes WHERE uid = ?", vec![Int(*uid)]),
+                ("DELETE FROM sessions WHERE uid = ?", vec![Int(*uid)]),
+                ("DELETE FROM email_changes WHERE uid = ?", vec![Int(*uid)]),
+                (
+                    "DELETE FROM verification_codes
+                    WHERE email IN (SELECT email FROM users WHERE id = ?)",
+                    vec![Int(*uid)],
+                ),
+                ("DELETE FROM users WHERE id = ?", vec![Int(*uid)]),
+            ],
+        }
+    }
+
+    // 在内存仓库中执行，出错时不修改数据
+    fn apply_memory(&self, state: &mut MemoryState) -> Result<u64, sqlx::Error> {
+        fn removed<T>(rows: &mut Vec<T>, remove: impl Fn(&T) -> bool) -> u64 {
+            let before = rows.len();
+            rows.retain(|row| !remove(row));
+            (before - rows.len()) as u64
+        }
+
+        match self {
+            AclChange::AddAcl {
+                uid,
+                resource_id,
+                operation,
+                deny,
+            } => {
+                let operation = operation.to_string();
+                if state.acl.iter().any(|row| {
+                    row.uid == *uid && row.resource_id == *resource_id && row.operation == operation
+                }) {
+                    return Err(unique_violation("acl.uid, acl.resource_id, acl.operation"));
+                }
+                state.acl.push(AclRow {
+                    uid: *uid,
+                    resource_id: *resource_id,
+                    operation,
+                    deny: *deny,
+                });
+                Ok(1)
+            }
+            AclChange::RemoveAcl {
+                uid,
+                resource_id,
+                operation,
+            } => Ok(removed(&mut state.acl, |row| {
+                row.uid == *uid && row.resource_id == *resource_id && row.operation == *operation
+            })),
+            AclChange::AddRoleAcl {
+                role_id,
+                resource_id,
+                operation,
+                deny,
+            } => {
+                let operation = operation.to_string();
+                if state.role_acl.iter().any(|row| {
+                    row.role_id == *role_id
+                        && row.resource_id == *resource_id
+                        && row.operation == operation
+                }) {
+                    return Err(unique_violation(
+                        "role_acl.role_id, role_acl.resource_id, role_acl.operation",
+                    ));
+                }
+                state.role_acl.push(RoleAclRow {
+                    role_id: *role_id,
+                    resource_id: *resource_id,
+                    operation,
+                    deny: *deny,
+                });
+                Ok(1)
+            }
+            AclChange::RemoveRoleAcl {
+                role_id,
+                resource_id,
+                operation,
+            } => Ok(removed(&mut state.role_acl, |row| {
+                row.role_id == *role_id
+                    && row.resource_id == *resource_id
+                    && row.operation == *operation
+            })),
+            AclChange::AssignRole { uid, role_id } => {
+                if state
+                    .user_roles
+                    .iter()
+                    .any(|row| row.uid == *uid && row.role_id == *role_id)
+                {
+                    return Err(unique_violation("user_roles.uid, user_roles.role_id"));
+                }
+                state.user_roles.push(UserRoleRow {
+                    uid: *uid,
+                    role_id: *role_id,
+                });
+                Ok(1)
+            }
+            AclChange::UnassignRole { uid, role_id } => Ok(removed(&mut state.user_roles, |row| {
+                row.uid == *uid && row.role_id == *role_id
+            })),
+            AclChange::RemoveRole { role_id } => {
+                removed(&mut state.user_roles, |row| row.role_id == *role_id);
+                removed(&mut state.role_acl, |row| row.role_id == *role_id);
+                Ok(removed(&mut state.roles, |row| row.id == *role_id))
+            }
+            AclChange::RemoveResource { resource_id } => {
+                removed(&mut state.resource_operations, |row| {
+                    row.resource_id == *resource_id
+                });
+                removed(&mut state.acl, |row| row.resource_id == *resource_id);
+                removed(&mut state.role_acl, |row| row.resource_id == *resource_id);
+                Ok(removed(&mut state.resources, |row| row.id == *resource_id))
+            }
+            AclChange::DeleteUser { uid } => {
+                removed(&mut state.acl, |row| row.uid == *uid);
+                removed(&mut state.user_roles, |row| row.uid == *uid);
+                removed(&mut state.players, |row| row.uid == *uid);
+                removed(&mut state.totp, |row| row.uid == *uid);
+                removed(&mut state.recovery_codes, |row| row.uid == *uid);
+                removed(&mut state.sessions, |row| row.uid == *uid);
+                removed(&mut state.email_changes, |row| row.uid == *uid);
+                let emails: HashSet<String> = state
+                    .users
+                    .iter()
+                    .filter(|row| row.id == *uid)
+                    .map(|row| row.email.clone())
+                    .collect();
+                removed(&mut state.codes, |row| emails.contains(&row.email));
+                Ok(removed(&mut state.users, |row| row.id == *uid))
+            }
+        }
+    }
+}
+
+// 受保护权限可能匹配的规则资源名，包括通配符
+fn candidate_names(protected: &[ProtectedAcl]) -> Vec<String> {
+    let names: HashSet<String> = protected
+        .iter()
+        .flat_map(|protected| resource_candidates(&protected.resource))
+        .collect();
+    let mut names: Vec<String> = names.into_iter().collect();
+    // 按相同的顺序加锁
+    names.sort();
+    names
+}
+
+// n 个参数的占位符
+fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
+// 锁定资源行，并发的修改在此等待，直到先执行的事务结束
+fn lock_sql(names: usize) -> String {
+    format!(
+        "UPDATE resource SET name = name WHERE name IN ({})",
+        placeholders(names)
+    )
+}
+
+// 现有用户在这些资源上的规则，包括直接授予与通过角色获得的，参数为两遍资源名
+fn grants_sql(names: usize) -> String {
+    format!(
+        r#"SELECT acl.uid AS uid, resource.name AS resource, acl.operation AS operation,
+            acl.deny AS deny
+        FROM acl JOIN resource ON resource.id = acl.resource_id
+        JOIN users ON users.id = acl.uid
+        WHERE resource.name IN ({0})
+        UNION ALL
+        SELECT user_roles.uid AS uid, resource.name AS resource,
+            role_acl.operation AS operation, role_acl.deny AS deny
+        FROM user_roles JOIN role_acl ON role_acl.role_id = user_roles.role_id
+        JOIN resource ON resource.id = role_acl.resource_id
+        JOIN users ON users.id = user_roles.uid
+        WHERE resource.name IN ({0})"#,
+        placeholders(names)
+    )
+}
+
+// 语句使用 ? 占位，postgres 换成 $1、$2
+fn prepare<DB: Database>(sql: &str) -> String {
+    match DB::NAME {
+        "PostgreSQL" => numbered(sql),
+        _ => sql.to_string(),
+    }
+}
+
+fn numbered(sql: &str) -> String {
+    let mut n = 0;
+    sql.chars()
+        .map(|c| match c {
+            '?' => {
+                n += 1;
+                format!("${}", n)
+            }
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+// 绑定参数
+fn bind_values<'q, DB>(
+    mut query: Query<'q, DB, <DB as HasArguments<'q>>::Arguments>,
+    values: &'q [SqlValue],
+) -> Query<'q, DB, <DB as HasArguments<'q>>::Arguments>
+where
+    DB: Database,
+    i64: Encode<'q, DB> + Type<DB>,
+    &'q str: Encode<'q, DB> + Type<DB>,
+    bool: Encode<'q, DB> + Type<DB>,
+{
+    for value in values {
+        query = match value {
+            SqlValue::Int(value) => query.bind(*value),
+            SqlValue::Text(value) => query.bind(value.as_str()),
+            SqlValue::Bool(value) => query.bind(*value),
+        };
+    }
+    query
+}
+
+// 资源名参数，查询规则时需要两遍
+fn name_values(names: &[String], times: usize) -> Vec<SqlValue> {
+    (0..times)
+        .flat_map(|_| names.iter().cloned().map(SqlValue::Text))
+        .collect()
+}
+
+// 各数据库的执行结果没有共同的 trait
+pub(crate) trait RowsAffected {
+    fn rows_affected(&self) -> u64;
+}
+
+impl RowsAffected for SqliteQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+impl RowsAffected for MySqlQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+impl RowsAffected for PgQueryResult {
+    fn rows_affected(&self) -> u64 {
+        self.rows_affected()
+    }
+}
+
+// 在数据库中执行修改，受保护的权限失去最后一个持有者时回滚并返回 None
+pub(crate) async fn sql_apply<DB>(
+    pool: &Pool<DB>,
+    change: &AclChange,
+    protected: &[ProtectedAcl],
+) -> Result<Option<u64>, sqlx::Error>
+where
+    DB: Database,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> i64: Encode<'q, DB> + Decode<'q, DB> + Type<DB>,
+    for<'q> &'q str: Encode<'q, DB> + Type<DB>,
+    for<'q> String: Decode<'q, DB> + Type<DB>,
+    for<'q> bool: Encode<'q, DB> + Decode<'q, DB> + Type<DB>,
+    for<'a> &'a str: ColumnIndex<DB::Row>,
+    DB::QueryResult: RowsAffected,
+{
+    let names = candidate_names(protected);
+    let mut tx = pool.begin().await?;
+    let before = match names.is_empty() {
+        true => None,
+        false => {
+            let (sql, values) = (
+                prepare::<DB>(&lock_sql(names.len())),
+                name_values(&names, 1),
+            );
+            bind_values(sqlx::query(&sql), &values)
+                .execute(&mut *tx)
+                .await?;
+            Some(count_holders(sql_grants(&mut tx, &names).await?, protected))
+        }
+    };
+    let mut affected = 0;
+    for (sql, values) in &change.statements() {
+        let sql = prepare::<DB>(sql);
+        let result = bind_values(sqlx::query(&sql), values)
+            .execute(&mut *tx)
+            .await?;
+        affected = result.rows_affected();
+    }
+    if let Some(before) = before {
+        let after = count_holders(sql_grants(&mut tx, &names).await?, protected);
+        if loses_last_holder(&before, &after) {
+            tx.rollback().await?;
+            return Ok(None);
+        }
+    }
+    tx.commit().await?;
+    Ok(Some(affected))
+}
+
+async fn sql_grants<DB>(
+    conn: &mut DB::Connection,
+    names: &[String],
+) -> Result<Vec<(i64, AclGrant)>, sqlx::Error>
+where
+    DB: Database,
+    for<'c> &'c mut DB::Connection: Executor<'c, Database = DB>,
+    for<'q> <DB as HasArguments<'q>>::Arguments: IntoArguments<'q, DB>,
+    for<'q> i64: Encode<'q, DB> + Decode<'q, DB> + Type<DB>,
+    for<'q> &'q str: Encode<'q, DB> + Type<DB>,
+    for<'q> String: Decode<'q, DB> + Type<DB>,
+    for<'q> bool: Encode<'q, DB> + Decode<'q, DB> + Type<DB>,
+    for<'a> &'a str: ColumnIndex<DB::Row>,
+{
+    let (sql, values) = (
+        prepare::<DB>(&grants_sql(names.len())),
+        name_values(names, 2),
+    );
+    let rows = bind_values(sqlx::query(&sql), &values)
+        .fetch_all(conn)
+        .await?;
+    rows.iter()
+        .map(|row| {
+            let grant = AclGrant {
+                resource: row.try_get("resource")?,
+                operation: row.try_get("operation")?,
+                deny: row.try_get("deny")?,
+            };
+            Ok((row.try_get("uid")?, grant))
+        })
+        .collect()
+}
+
+// 在内存仓库中执行修改，受保护的权限失去最后一个持有者时恢复并返回 None
+pub(crate) fn memory_apply(
+    state: &mut MemoryState,
+    change: &AclChange,
+    protected: &[ProtectedAcl],
+) -> Result<Option<u64>, sqlx::Error> {
+    let before = count_holders(memory_grants(state), protected);
+    let backup = state.clone();
+    let affected = change.apply_memory(state)?;
+    let after = count_holders(memory_grants(state), protected);
+    if loses_last_holder(&before, &after) {
+        *state = backup;
+        return Ok(None);
+    }
+    Ok(Some(affected))
+}
+
+// 内存仓库中现有用户的全部规则
+fn memory_grants(state: &MemoryState) -> Vec<(i64, AclGrant)> {
+    let resource_name = |id: i64| {
+        state
+            .resources
+            .iter()
+            .find(|resource| resource.id == id)
+            .map(|resource| resource.name.clone())
+    };
+    let exists = |uid: i64| state.users.iter().any(|user| user.id == uid);
+    let direct = state
+        .acl
+        .iter()
+        .filter(|row| exists(row.uid))
+        .filter_map(|row| {
+            resource_name(row.resource_id).map(|resource| {
+                let grant = AclGrant {
+                    resource,
+                    operation: row.operation.clone(),
+                    deny: row.deny,
+                };
+                (row.uid, grant)
+            })
+        });
+    let roles = state
+        .user_roles
+        .iter()
+        .filter(|user_role| exists(user_role.uid))
+        .flat_map(|user_role| {
+            state
+                .role_acl
+                .iter()
+                .filter(move |row| row.role_id == user_role.role_id)
+                .filter_map(move |row| {
+                    resource_name(row.resource_id).map(|resource| {
+                        let grant = AclGrant {
+                            resource,
+                            operation: row.operation.clone(),
+                            deny: row.deny,
+                        };
+                        (user_role.uid, grant)
+                    })
+                })
+        });
+    direct.chain(roles).collect()
+}
+
+// 各个受保护权限的持有者数，与 check_user_acl 的判断一致
+fn count_holders(grants: Vec<(i64, AclGrant)>, protected: &[ProtectedAcl]) -> Vec<usize> {
+    let mut users: HashMap<i64, Vec<AclGrant>> = HashMap::new();
+    for (uid, grant) in grants {
+        users.entry(uid).or_default().push(grant);
+    }
+    protected
+        .iter()
+        .map(|protected| {
+            users
+                .values()
+                .filter(|grants| {
+                    resolve_grants(grants, &protected.resource, &protected.operation) == Some(true)
+                })
+                .count()
+        })
+        .collect()
+}
+
+// 修改前有持有者的受保护权限，修改后是否失去了全部持有者
+fn loses_last_holder(before: &[usize], after: &[usize]) -> bool {
+    before
+        .iter()
+        .zip(after)
+        .any(|(before, after)| *before > 0 && *after == 0)
+}
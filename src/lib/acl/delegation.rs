@@ -0,0 +1,140 @@
+// 权限委派规则
+//
+// 授予者只能授予或撤销自己持有的操作，配置 require_grant 开启时还需要资源的 Grant 权限；
+// 配置 protected_acl 中的权限至少保留一个持有者，可能撤销权限的修改在事务中检查修改后的结果。
+
+use crate::lib::{config::AclConfig, error::ApiError};
+
+use super::{
+    change::{AclChange, ProtectedAcl},
+    check_user_acl,
+    sql_acl::{AclRepository, Operation},
+    sql_role::RoleRepository,
+    AclError,
+};
+
+/// 检查授予者能否授予或撤销资源上的操作
+/// # 参数
+/// * `uid` - 授予者id
+/// * `resource_name` - 授予的资源名，可以是通配符
+/// * `operation` - 授予的操作
+pub async fn check_delegation(
+    acl_repo: &dyn AclRepository,
+    role_repo: &dyn RoleRepository,
+    config: &AclConfig,
+    uid: u64,
+    resource_name: &str,
+    operation: &Operation,
+) -> Result<(), AclError> {
+    if config.require_grant {
+        check_user_acl(acl_repo, role_repo, uid, resource_name, &Operation::Grant).await?;
+    }
+    check_user_acl(acl_repo, role_repo, uid, resource_name, operation).await
+}
+
+/// 执行可能撤销权限的修改，返回受影响的行数
+///
+/// 受保护的权限在修改后失去最后一个持有者时回滚，返回冲突；修改前已经没有持有者的权限不检查。
+pub async fn apply_protected(
+    acl_repo: &dyn AclRepository,
+    config: &AclConfig,
+    change: AclChange,
+) -> Result<u64, ApiError> {
+    let protected = ProtectedAcl::from_config(config);
+    match acl_repo.apply_acl_change(&change, &protected).await? {
+        Some(affected) => Ok(affected),
+        None => Err(ApiError::Conflict("不能撤销管理权限的最后一个持有者")),
+    }
+}
+
+#[tokio::test]
+async fn test_last_holder() {
+    use crate::lib::{
+        acl::sql_role::ADMIN_ROLE,
+        config::{init_base_data_acl, HttpServerConfig},
+        repository::Repositories,
+        user::web_user::RegisterUser,
+    };
+
+    let config = HttpServerConfig::default();
+    let repositories = Repositories::memory();
+    init_base_data_acl(&repositories, &config).await;
+    let acl = repositories.acl.as_ref();
+    let role = repositories.role.as_ref();
+    let user = repositories.user.as_ref();
+    let admin = user.get_user_id(&config.register_user.email).await.unwrap();
+    let admin_role = role.get_role_id(ADMIN_ROLE).await.unwrap();
+    let acl_config = AclConfig::default();
+
+    // 只持有 Add 的用户不能委派 Remove
+    let uid = user
+        .register_user(&RegisterUser {
+            email: "delegate@example.com".to_string(),
+            password: "Password123".to_string(),
+        })
+        .await
+        .unwrap();
+    let resource_id = acl.get_resource_id("server").await.unwrap();
+    acl.add_acl(uid, resource_id, &Operation::Add, false)
+        .await
+        .unwrap();
+    let delegate = |operation: Operation, config: AclConfig| async move {
+        check_delegation(acl, role, &config, uid as u64, "server", &operation).await
+    };
+    assert!(delegate(Operation::Add, acl_config.clone()).await.is_ok());
+    assert!(delegate(Operation::Remove, acl_config.clone())
+        .await
+        .is_err());
+    let require_grant = AclConfig {
+        require_grant: true,
+        ..acl_config.clone()
+    };
+    assert!(delegate(Operation::Add, require_grant.clone())
+        .await
+        .is_err());
+    acl.add_acl(uid, resource_id, &Operation::Grant, false)
+        .await
+        .unwrap();
+    assert!(delegate(Operation::Add, require_grant).await.is_ok());
+
+    // admin 是唯一的管理员，不能取消其角色
+    let unassign = |uid: i64| AclChange::UnassignRole {
+        uid,
+        role_id: admin_role,
+    };
+    assert!(apply_protected(acl, &acl_config, unassign(admin))
+        .await
+        .is_err());
+    assert_eq!(role.get_user_roles(admin).await.unwrap().len(), 1);
+    assert_eq!(
+        apply_protected(acl, &acl_config, unassign(uid))
+            .await
+            .unwrap(),
+        0
+    );
+    // 有另一个管理员后可以取消
+    role.assign_role(uid, admin_role).await.unwrap();
+    assert_eq!(
+        apply_protected(acl, &acl_config, unassign(admin))
+            .await
+            .unwrap(),
+        1
+    );
+    // 移除角色的规则对所有成员生效
+    let role_resource = acl.get_resource_id("role").await.unwrap();
+    let remove_role_acl = AclChange::RemoveRoleAcl {
+        role_id: admin_role,
+        resource_id: role_resource,
+        operation: "Add".to_string(),
+    };
+    assert!(apply_protected(acl, &acl_config, remove_role_acl)
+        .await
+        .is_err());
+    // 删除唯一的管理员账号同样被拒绝
+    assert!(
+        apply_protected(acl, &acl_config, AclChange::DeleteUser { uid })
+            .await
+            .is_err()
+    );
+    assert!(user.get_user_email(uid).await.is_ok());
+}
@@ -0,0 +1,181 @@
+// 权限守卫
+//
+// 在路由上声明需要的资源与操作，例如
+// `web::resource("/get_all").wrap(RequireAcl::new("user", Operation::Check))`，
+// 未登录返回401，没有权限或按配置需要两步验证而未开启时返回403，处理函数不再自行检查。
+
+use std::rc::Rc;
+
+use actix_web::{
+    body::BoxBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error,
+};
+use futures_util::future::LocalBoxFuture;
+
+use crate::lib::{
+    config::HttpServerConfig,
+    error::ApiError,
+    user::{auth::AuthUser, sql_totp::TotpRepository, totp::check_totp_required},
+};
+
+use super::{
+    check_user_acl,
+    sql_acl::{AclRepository, Operation},
+    sql_role::RoleRepository,
+};
+
+/// 要求当前用户拥有资源的指定操作权
+#[derive(Clone)]
+pub struct RequireAcl {
+    resource: Rc<str>,
+    operation: Operation,
+}
+
+impl RequireAcl {
+    pub fn new(resource: &str, operation: Operation) -> Self {
+        RequireAcl {
+            resource: resource.into(),
+            operation,
+        }
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for RequireAcl
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = RequireAclMiddleware<S>;
+    type InitError = ();
+    type Future = std::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        std::future::ready(Ok(RequireAclMiddleware {
+            service: Rc::new(service),
+            acl: self.clone(),
+        }))
+    }
+}
+
+pub struct RequireAclMiddleware<S> {
+    service: Rc<S>,
+    acl: RequireAcl,
+}
+
+impl<S> Service<ServiceRequest> for RequireAclMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let acl = self.acl.clone();
+        Box::pin(async move {
+            let user = AuthUser::from_http_request(req.request()).map_err(ApiError::from)?;
+            let acl_repo = req
+                .app_data::<web::Data<dyn AclRepository>>()
+                .expect("AclRepository 未注册")
+                .clone();
+            let role_repo = req
+                .app_data::<web::Data<dyn RoleRepository>>()
+                .expect("RoleRepository 未注册")
+                .clone();
+            check_user_acl(
+                acl_repo.get_ref(),
+                role_repo.get_ref(),
+                user.uid,
+                &acl.resource,
+                &acl.operation,
+            )
+            .await
+            .map_err(ApiError::from)?;
+            // 未注册配置时(如测试中)不要求两步验证
+            if let Some(config) = req.app_data::<web::Data<HttpServerConfig>>().cloned() {
+                let totp_repo = req
+                    .app_data::<web::Data<dyn TotpRepository>>()
+                    .expect("TotpRepository 未注册")
+                    .clone();
+                check_totp_required(
+                    totp_repo.get_ref(),
+                    &config.totp_config,
+                    user.uid,
+                    &acl.resource,
+                    &acl.operation,
+                )
+                .await?;
+            }
+            service.call(req).await
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_require_acl() {
+    use actix_web::{http::StatusCode, test, App, HttpResponse};
+
+    use crate::lib::{
+        config::{init_base_data_acl, HttpServerConfig},
+        key::create_token_time_min,
+        repository::Repositories,
+        user::web_user::RegisterUser,
+    };
+
+    let config = HttpServerConfig::default();
+    let repositories = Repositories::memory();
+    init_base_data_acl(&repositories, &config).await;
+    let admin = repositories
+        .user
+        .get_user_id(&config.register_user.email)
+        .await
+        .unwrap();
+    let user = repositories
+        .user
+        .register_user(&RegisterUser {
+            email: "guard@example.com".to_string(),
+            password: "password".to_string(),
+        })
+        .await
+        .unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(|cfg| repositories.app_data(cfg))
+            .service(
+                web::resource("/users")
+                    .wrap(RequireAcl::new("user", Operation::Check))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    // 中间件返回的错误由 actix 转换为响应
+    let status = |token: Option<String>| {
+        let mut req = test::TestRequest::get().uri("/users");
+        if let Some(token) = token {
+            req = req.insert_header(("Authorization", format!("Bearer {}", token)));
+        }
+        let res = test::try_call_service(&app, req.to_request());
+        async move {
+            match res.await {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            }
+        }
+    };
+    assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        status(Some(create_token_time_min(user as u64, 5))).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        status(Some(create_token_time_min(admin as u64, 5))).await,
+        StatusCode::OK
+    );
+}
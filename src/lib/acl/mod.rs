@@ -1,10 +1,17 @@
-use serde::Serialize;
-use sql_acl::{get_acl, get_resource_id, Operation};
+use std::collections::HashMap;
 
-use super::config::ConnectionType;
+use serde::Serialize;
+use sql_acl::{AclGrant, AclRepository, Operation};
+use sql_role::RoleRepository;
 
+pub mod cache;
+pub mod change;
+pub mod delegation;
+pub mod guard;
 pub mod sql_acl;
+pub mod sql_role;
 pub mod web_acl;
+pub mod web_role;
 
 #[derive(Debug, Serialize)]
 pub enum AclError {
@@ -12,59 +19,176 @@ pub enum AclError {
     NotFound,
     // 无效的权限
     InvalidPermission,
-    // 过期验证
-    ExpiredVerification,
 }
 
-impl AclError {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for AclError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            AclError::NotFound => "Not Found".to_string(),
-            AclError::InvalidPermission => "Invalid Permission".to_string(),
-            AclError::ExpiredVerification => "Expired Verification".to_string(),
+            AclError::NotFound => write!(f, "Not Found"),
+            AclError::InvalidPermission => write!(f, "Invalid Permission"),
         }
     }
 }
 
-/// 检查权限
+/// 检查权限，用户的规则为直接授予的规则与所属角色规则的并集
+///
+/// 资源名可以是用 `.` 分隔的路径，例如 `server.survival-1.chat`，规则可以授予通配符
+/// `server.*`(匹配所有下级资源) 或 `*`。按 资源本身、`server.survival-1.*`、`server.*`、`*`
+/// 的顺序取第一个有该操作规则的层级，同一层级中拒绝优先于允许。
 /// # 参数
-/// * `conn` - 数据库连接
-/// * `token` - token
-/// * `resource_id` - 资源id
-/// * `acl` - 权限
+/// * `acl_repo` - 权限数据仓库
+/// * `role_repo` - 角色数据仓库
+/// * `uid` - 用户id
+/// * `resource_name` - 资源名
+/// * `operation` - 操作
 /// # 返回
-/// * `bool` - 是否有权限
-pub async fn check_acl(
-    conn: ConnectionType,
-    token: &str,
+/// * `Result<(), AclError>` - 无权限时返回错误
+pub async fn check_user_acl(
+    acl_repo: &dyn AclRepository,
+    role_repo: &dyn RoleRepository,
+    uid: u64,
     resource_name: &str,
-    operation_str: &str,
+    operation: &Operation,
 ) -> Result<(), AclError> {
-    match crate::lib::key::gettoken_to_user_no_time(token) {
-        Ok(user) => {
-            let operation = Operation::from_string(operation_str);
+    let mut grants = match acl_repo.query_user_grants(uid).await {
+        Ok(grants) => grants,
+        Err(_) => return Err(AclError::NotFound),
+    };
+    match role_repo.query_user_role_grants(uid).await {
+        Ok(role_grants) => grants.extend(role_grants),
+        Err(_) => return Err(AclError::NotFound),
+    }
+    match resolve_grants(&grants, resource_name, operation) {
+        Some(true) => Ok(()),
+        Some(false) => Err(AclError::InvalidPermission),
+        // 没有匹配的规则时区分资源是否存在
+        None => match acl_repo.get_resource_id(resource_name).await {
+            Ok(_) => Err(AclError::InvalidPermission),
+            Err(_) => Err(AclError::NotFound),
+        },
+    }
+}
 
-            let resource_id = match get_resource_id(conn, resource_name).await {
-                Ok(id) => id,
-                Err(_) => {
-                    return Err(AclError::NotFound);
-                }
-            };
-            match get_acl(resource_id.1, user.claims.uid, resource_id.0).await {
-                Ok(operations) => {
-                    if Operation::get_operation(operations, &operation) {
-                        return Ok(());
-                    } else {
-                        return Err(AclError::InvalidPermission);
-                    }
-                }
-                Err(_) => {
-                    return Err(AclError::NotFound);
-                }
+/// 按从具体到宽泛的顺序列出可以匹配资源的规则名
+pub fn resource_candidates(resource_name: &str) -> Vec<String> {
+    let mut candidates = vec![resource_name.to_string()];
+    let mut segments: Vec<&str> = resource_name.split('.').collect();
+    if segments.last() == Some(&"*") {
+        segments.pop();
+    }
+    while segments.pop().is_some() {
+        let candidate = match segments.is_empty() {
+            true => "*".to_string(),
+            false => format!("{}.*", segments.join(".")),
+        };
+        if candidate != resource_name {
+            candidates.push(candidate);
+        }
+    }
+    candidates
+}
+
+/// 规则是否匹配资源
+pub fn resource_matches(pattern: &str, resource_name: &str) -> bool {
+    resource_candidates(resource_name)
+        .iter()
+        .any(|candidate| candidate == pattern)
+}
+
+/// 按最具体的规则判断，返回 None 表示没有匹配的规则
+pub fn resolve_grants(
+    grants: &[AclGrant],
+    resource_name: &str,
+    operation: &Operation,
+) -> Option<bool> {
+    let operation = operation.to_string();
+    for candidate in resource_candidates(resource_name) {
+        let mut allowed = None;
+        for grant in grants
+            .iter()
+            .filter(|grant| grant.resource == candidate && grant.operation == operation)
+        {
+            if grant.deny {
+                return Some(false);
             }
+            allowed = Some(true);
+        }
+        if allowed.is_some() {
+            return allowed;
         }
-        Err(_) => {
-            return Err(AclError::ExpiredVerification);
+    }
+    None
+}
+
+/// 查询用户对资源的操作，包括通过角色拥有的
+pub async fn query_effective_acl(
+    acl_repo: &dyn AclRepository,
+    role_repo: &dyn RoleRepository,
+    uid: u64,
+) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+    let mut user_resources = acl_repo.query_user_acl(uid).await?;
+    for (resource, operations) in role_repo.query_user_role_acl(uid).await? {
+        let entry = user_resources.entry(resource).or_default();
+        for operation in operations {
+            if !entry.contains(&operation) {
+                entry.push(operation);
+            }
         }
     }
+    Ok(user_resources)
+}
+
+#[test]
+fn test_resolve_grants() {
+    let grant = |resource: &str, operation: &str, deny| AclGrant {
+        resource: resource.to_string(),
+        operation: operation.to_string(),
+        deny,
+    };
+    assert_eq!(
+        resource_candidates("server.survival-1.chat"),
+        [
+            "server.survival-1.chat",
+            "server.survival-1.*",
+            "server.*",
+            "*"
+        ]
+    );
+    assert_eq!(resource_candidates("server.*"), ["server.*", "*"]);
+    assert_eq!(resource_candidates("user"), ["user", "*"]);
+    assert!(resource_matches("server.*", "server.survival-1"));
+    assert!(!resource_matches("server.*", "server"));
+
+    let grants = [
+        grant("user", "Check", false),
+        grant("server.*", "Check", false),
+        grant("server.*", "Update", false),
+        grant("server.survival-1.*", "Update", true),
+        grant("server.survival-1.chat", "Update", false),
+        grant("server.creative", "Check", true),
+        grant("server.creative", "Check", false),
+    ];
+    let resolve = |resource, operation| resolve_grants(&grants, resource, &operation);
+    // 平铺的资源名仍然只匹配自身
+    assert_eq!(resolve("user", Operation::Check), Some(true));
+    assert_eq!(resolve("user", Operation::Add), None);
+    assert_eq!(resolve("server", Operation::Check), None);
+    // 通配符匹配所有下级资源
+    assert_eq!(resolve("server.survival-1", Operation::Check), Some(true));
+    assert_eq!(
+        resolve("server.survival-1.chat", Operation::Check),
+        Some(true)
+    );
+    // 最具体的规则优先
+    assert_eq!(resolve("server.lobby", Operation::Update), Some(true));
+    assert_eq!(
+        resolve("server.survival-1.mute", Operation::Update),
+        Some(false)
+    );
+    assert_eq!(
+        resolve("server.survival-1.chat", Operation::Update),
+        Some(true)
+    );
+    // 同一层级拒绝优先
+    assert_eq!(resolve("server.creative", Operation::Check), Some(false));
 }
@@ -1,46 +1,72 @@
 use std::collections::HashMap;
 
-use serde::Serialize;
+use async_trait::async_trait;
+use serde::{Serialize, Serializer};
+use sqlx::Row;
 
-use crate::lib::{
-    config::{get_conn, ConnectionType, HttpServerConfig},
-    user::sql_user::get_user_id,
+use crate::lib::repository::{
+    memory::{unique_violation, AclRow, ResourceOperationRow, ResourceRow},
+    MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
 };
 
-// 操作
-#[derive(Debug, PartialEq, Clone, Serialize)]
+use super::change::{self, AclChange, ProtectedAcl};
+
+// 操作，内置的四种对所有资源有效，其他操作需要先在资源上注册
+#[derive(Debug, PartialEq, Clone)]
 pub enum Operation {
     Add,
     Remove,
     Update,
     Check,
-    None,
+    Grant,          // 授予与撤销他人权限，配置 require_grant 开启时需要
+    Custom(String), // 例如服务器资源上的 kick、mute
 }
 
-impl Operation {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Operation::Add => String::from("Add"),
-            Operation::Remove => String::from("Remove"),
-            Operation::Update => String::from("Update"),
-            Operation::Check => String::from("Check"),
-            Operation::None => String::from("Null"),
+            Operation::Add => write!(f, "Add"),
+            Operation::Remove => write!(f, "Remove"),
+            Operation::Update => write!(f, "Update"),
+            Operation::Check => write!(f, "Check"),
+            Operation::Grant => write!(f, "Grant"),
+            Operation::Custom(name) => write!(f, "{}", name),
         }
     }
+}
+
+// 序列化为操作名
+impl Serialize for Operation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
+impl Operation {
     pub fn from_string(operation: &str) -> Self {
         match operation {
             "Add" => Operation::Add,
             "Remove" => Operation::Remove,
             "Update" => Operation::Update,
             "Check" => Operation::Check,
-            _ => Operation::None,
+            "Grant" => Operation::Grant,
+            _ => Operation::Custom(operation.to_string()),
         }
     }
 
-    // 从Vec<Operation> 获取指定的操作
-    pub fn get_operation(operations: Vec<Operation>, operation: &Operation) -> bool {
-        operations.contains(operation)
+    // 内置操作
+    pub fn builtin() -> [Operation; 5] {
+        [
+            Operation::Add,
+            Operation::Remove,
+            Operation::Update,
+            Operation::Check,
+            Operation::Grant,
+        ]
+    }
+
+    pub fn is_builtin(&self) -> bool {
+        !matches!(self, Operation::Custom(_))
     }
 
     pub fn default() -> String {
@@ -51,8 +77,8 @@ impl Operation {
 // 资源
 #[derive(Clone, Debug, Serialize)]
 pub struct Resource {
-    id: i64, // 资源id
-    name: String,
+    pub id: i64, // 资源id
+    pub name: String,
 }
 
 impl Resource {
@@ -61,432 +87,558 @@ impl Resource {
     }
 }
 
-pub async fn create_acl_table(conn: ConnectionType) -> Result<ConnectionType, sqlx::Error> {
-    let sql = r#"
-        CREATE TABLE IF NOT EXISTS acl (
-        id INTEGER PRIMARY KEY AUTOINCREMENT,
-        uid INTEGER NOT NULL,
-        resource_id INTEGER NOT NULL,
-        operation TEXT NOT NULL,
-        UNIQUE(uid, resource_id, operation)
-    );
-     CREATE TABLE IF NOT EXISTS resource (
-        id INTEGER PRIMARY KEY AUTOINCREMENT,
-        name TEXT NOT NULL UNIQUE
-    );
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(&sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Sqlite(conn))
-        }
+// 用户或角色对资源的一条授权，resource 可以是通配符如 server.*
+#[derive(Clone, Debug, Serialize, PartialEq, sqlx::FromRow)]
+pub struct AclGrant {
+    pub resource: String,
+    pub operation: String,
+    pub deny: bool, // 拒绝
+}
 
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(&sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Mysql(conn))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(&sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Postgres(conn))
-        }
+// 权限数据仓库
+#[async_trait]
+pub trait AclRepository: Send + Sync {
+    // 添加资源
+    async fn add_resource(&self, name: &str) -> Result<(), sqlx::Error>;
+
+    // 添加用户对资源的操作，deny 为 true 时添加拒绝规则
+    async fn add_acl(
+        &self,
+        uid: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error>;
+
+    // 获取资源id
+    async fn get_resource_id(&self, name: &str) -> Result<i64, sqlx::Error>;
+
+    // 获取所有资源
+    async fn get_all_resource(&self) -> Result<Vec<Resource>, sqlx::Error>;
+
+    // 在资源上注册操作
+    async fn add_operation(&self, resource_id: i64, name: &str) -> Result<(), sqlx::Error>;
+
+    // 取消注册资源上的操作，返回受影响的行数
+    async fn remove_operation(&self, resource_id: i64, name: &str) -> Result<u64, sqlx::Error>;
+
+    // 获取所有资源上注册的操作，资源名 -> 操作
+    async fn get_all_operation(&self) -> Result<HashMap<String, Vec<String>>, sqlx::Error>;
+
+    // 查询用户对资源允许的操作
+    async fn query_user_acl(&self, uid: u64) -> Result<HashMap<String, Vec<String>>, sqlx::Error>;
+
+    // 查询直接授予用户的全部规则，包括拒绝
+    async fn query_user_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error>;
+
+    // 在一个事务中执行修改，受保护的权限失去最后一个持有者时回滚并返回 None，否则返回受影响的行数
+    async fn apply_acl_change(
+        &self,
+        change: &AclChange,
+        protected: &[ProtectedAcl],
+    ) -> Result<Option<u64>, sqlx::Error>;
+}
+
+// 按资源名分组
+pub(crate) fn group_user_acl(rows: Vec<(String, String)>) -> HashMap<String, Vec<String>> {
+    let mut user_resources: HashMap<String, Vec<String>> = HashMap::new();
+    for (resource_name, operation) in rows {
+        user_resources
+            .entry(resource_name)
+            .or_default()
+            .push(operation);
     }
+    user_resources
 }
 
-// 添加资源
-pub async fn add_resource(conn: ConnectionType, name: &str) -> Result<ConnectionType, sqlx::Error> {
-    let sql = r#"INSERT INTO resource (name) VALUES ($1)"#;
+#[async_trait]
+impl AclRepository for SqliteRepository {
+    async fn add_resource(&self, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO resource (name) VALUES (?)"#;
+        sqlx::query(sql).bind(name).execute(&self.pool).await?;
+        Ok(())
+    }
 
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql).bind(name).execute(&mut conn).await?;
-            Ok(ConnectionType::Sqlite(conn))
-        }
+    async fn add_acl(
+        &self,
+        uid: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO acl (uid, resource_id, operation, deny) VALUES (?, ?, ?, ?)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(resource_id)
+            .bind(operation.to_string())
+            .bind(deny)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
 
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql).bind(name).execute(&mut conn).await?;
-            Ok(ConnectionType::Mysql(conn))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql).bind(name).execute(&mut conn).await?;
-            Ok(ConnectionType::Postgres(conn))
-        }
+    async fn get_resource_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM resource WHERE name = ?"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        row.try_get(0)
     }
-}
 
-// 删除资源
-pub async fn remove_resource(
-    conn: ConnectionType,
-    name: &str,
-) -> Result<ConnectionType, sqlx::Error> {
-    let sql = r#"DELETE FROM resource WHERE name = $1"#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql).bind(name).execute(&mut conn).await?;
-            Ok(ConnectionType::Sqlite(conn))
-        }
+    async fn get_all_resource(&self) -> Result<Vec<Resource>, sqlx::Error> {
+        let sql = r#"SELECT id, name FROM resource"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Resource {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
 
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql).bind(name).execute(&mut conn).await?;
-            Ok(ConnectionType::Mysql(conn))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql).bind(name).execute(&mut conn).await?;
-            Ok(ConnectionType::Postgres(conn))
-        }
+    async fn add_operation(&self, resource_id: i64, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO resource_operations (resource_id, name) VALUES (?, ?)"#;
+        sqlx::query(sql)
+            .bind(resource_id)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
-}
-// 添加用户对资源的操作
-pub async fn add_acl(
-    conn: ConnectionType,
-    uid: i64,
-    resource_id: i64,
-    operation: &Operation,
-) -> Result<ConnectionType, sqlx::Error> {
-    let sql = r#"INSERT INTO acl (uid, resource_id, operation) VALUES ($1, $2, $3)"#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql)
-                .bind(uid)
-                .bind(resource_id)
-                .bind(operation.to_string())
-                .execute(&mut conn)
-                .await.err();
-            Ok(ConnectionType::Sqlite(conn))
-        }
 
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql)
-                .bind(uid)
-                .bind(resource_id)
-                .bind(operation.to_string())
-                .execute(&mut conn)
-                .await.err();
-            Ok(ConnectionType::Mysql(conn))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql)
-                .bind(uid)
-                .bind(resource_id)
-                .bind(operation.to_string())
-                .execute(&mut conn)
-                .await.err();
-            Ok(ConnectionType::Postgres(conn))
-        }
+    async fn remove_operation(&self, resource_id: i64, name: &str) -> Result<u64, sqlx::Error> {
+        let sql = r#"DELETE FROM resource_operations WHERE resource_id = ? AND name = ?"#;
+        let result = sqlx::query(sql)
+            .bind(resource_id)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
     }
-}
 
-// 移除用户对资源的操作
-pub async fn remove_acl(
-    conn: ConnectionType,
-    uid: i64,
-    resourceid: i64,
-    operation: &str,
-) -> Result<ConnectionType, sqlx::Error> {
-    let sql = r#"DELETE FROM acl WHERE uid = $1 AND resourceid = $2 AND operation = $3"#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql)
-                .bind(uid)
-                .bind(resourceid)
-                .bind(operation)
-                .execute(&mut conn)
-                .await?;
-            Ok(ConnectionType::Sqlite(conn))
-        }
+    async fn get_all_operation(&self) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource_name, resource_operations.name AS operation
+            FROM resource_operations
+            JOIN resource ON resource.id = resource_operations.resource_id"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
+    }
 
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql)
-                .bind(uid)
-                .bind(resourceid)
-                .bind(operation)
-                .execute(&mut conn)
-                .await?;
-            Ok(ConnectionType::Mysql(conn))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql)
-                .bind(uid)
-                .bind(resourceid)
-                .bind(operation)
-                .execute(&mut conn)
-                .await?;
-            Ok(ConnectionType::Postgres(conn))
-        }
+    async fn query_user_acl(&self, uid: u64) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource_name, acl.operation AS operation
+            FROM acl JOIN resource ON resource.id = acl.resource_id
+            WHERE acl.uid = ? AND NOT acl.deny"#;
+        let rows = sqlx::query(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
     }
-}
 
-// 移除用户对资源的操作-all
-pub async fn remove_acl_by_resource_id(
-    conn: ConnectionType,
-    resource_id: i64,
-) -> Result<ConnectionType, sqlx::Error> {
-    let sql = r#"DELETE FROM acl WHERE resource_id = $1"#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql)
-                .bind(resource_id)
-                .execute(&mut conn)
-                .await?;
-            Ok(ConnectionType::Sqlite(conn))
-        }
+    async fn query_user_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, acl.operation AS operation, acl.deny AS deny
+            FROM acl JOIN resource ON resource.id = acl.resource_id
+            WHERE acl.uid = ?"#;
+        sqlx::query_as(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
 
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql)
-                .bind(resource_id)
-                .execute(&mut conn)
-                .await?;
-            Ok(ConnectionType::Mysql(conn))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql)
-                .bind(resource_id)
-                .execute(&mut conn)
-                .await?;
-            Ok(ConnectionType::Postgres(conn))
-        }
+    async fn apply_acl_change(
+        &self,
+        change: &AclChange,
+        protected: &[ProtectedAcl],
+    ) -> Result<Option<u64>, sqlx::Error> {
+        change::sql_apply(&self.pool, change, protected).await
     }
 }
-// 获取用户对资源的操作
-pub async fn get_acl(
-    conn: ConnectionType,
-    uid: u64,
-    resource_id: i64,
-) -> Result<Vec<Operation>, sqlx::Error> {
-    let sql = r#"SELECT operation FROM acl WHERE uid = $1 AND resource_id = $2"#;
-    use sqlx::Row;
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let uid = uid as i64;
-            let row_operation: Vec<sqlx::sqlite::SqliteRow> = sqlx::query(sql)
-                .bind(uid)
-                .bind(resource_id)
-                .fetch_all(&mut conn)
-                .await?;
-
-            let operations = row_operation
-                .iter()
-                .map(|row| {
-                    let operation: String = row.try_get(0).unwrap();
-                    Operation::from_string(&operation)
-                })
-                .collect::<Vec<Operation>>();
 
-            Ok(operations)
-        }
+#[async_trait]
+impl AclRepository for MySqlRepository {
+    async fn add_resource(&self, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO resource (name) VALUES (?)"#;
+        sqlx::query(sql).bind(name).execute(&self.pool).await?;
+        Ok(())
+    }
 
-        ConnectionType::Mysql(mut conn) => {
-            let row_operation = sqlx::query(sql)
-                .bind(uid)
-                .bind(resource_id)
-                .fetch_all(&mut conn)
-                .await?;
-            let operations = row_operation
-                .iter()
-                .map(|row| {
-                    let operation: String = row.try_get(0).unwrap();
-                    Operation::from_string(&operation)
-                })
-                .collect::<Vec<Operation>>();
+    async fn add_acl(
+        &self,
+        uid: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO acl (uid, resource_id, operation, deny) VALUES (?, ?, ?, ?)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(resource_id)
+            .bind(operation.to_string())
+            .bind(deny)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
 
-            Ok(operations)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let uid = uid as i64;
-            let row_operation = sqlx::query(sql)
-                .bind(uid)
-                .bind(resource_id)
-                .fetch_all(&mut conn)
-                .await?;
-            let operations = row_operation
-                .iter()
-                .map(|row| {
-                    let operation: String = row.try_get(0).unwrap();
-                    Operation::from_string(&operation)
+    async fn get_resource_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM resource WHERE name = ?"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        row.try_get(0)
+    }
+
+    async fn get_all_resource(&self) -> Result<Vec<Resource>, sqlx::Error> {
+        let sql = r#"SELECT id, name FROM resource"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Resource {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
                 })
-                .collect::<Vec<Operation>>();
+            })
+            .collect()
+    }
 
-            Ok(operations)
-        }
+    async fn add_operation(&self, resource_id: i64, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO resource_operations (resource_id, name) VALUES (?, ?)"#;
+        sqlx::query(sql)
+            .bind(resource_id)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
-}
 
-// 获取资源id
-pub async fn get_resource_id(
-    conn: ConnectionType,
-    name: &str,
-) -> Result<(i64, ConnectionType), sqlx::Error> {
-    let sql = r#"SELECT id FROM resource WHERE name = $1"#;
-    use sqlx::Row;
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let id = sqlx::query(sql).bind(name).fetch_one(&mut conn).await?;
-            Ok((id.get(0), ConnectionType::Sqlite(conn)))
-        }
+    async fn remove_operation(&self, resource_id: i64, name: &str) -> Result<u64, sqlx::Error> {
+        let sql = r#"DELETE FROM resource_operations WHERE resource_id = ? AND name = ?"#;
+        let result = sqlx::query(sql)
+            .bind(resource_id)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
 
-        ConnectionType::Mysql(mut conn) => {
-            let id = sqlx::query(sql).bind(name).fetch_one(&mut conn).await?;
-            Ok((id.get(0), ConnectionType::Mysql(conn)))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let id = sqlx::query(sql).bind(name).fetch_one(&mut conn).await?;
-            Ok((id.get(0), ConnectionType::Postgres(conn)))
-        }
+    async fn get_all_operation(&self) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource_name, resource_operations.name AS operation
+            FROM resource_operations
+            JOIN resource ON resource.id = resource_operations.resource_id"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_acl(&self, uid: u64) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource_name, acl.operation AS operation
+            FROM acl JOIN resource ON resource.id = acl.resource_id
+            WHERE acl.uid = ? AND NOT acl.deny"#;
+        let rows = sqlx::query(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, acl.operation AS operation, acl.deny AS deny
+            FROM acl JOIN resource ON resource.id = acl.resource_id
+            WHERE acl.uid = ?"#;
+        sqlx::query_as(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn apply_acl_change(
+        &self,
+        change: &AclChange,
+        protected: &[ProtectedAcl],
+    ) -> Result<Option<u64>, sqlx::Error> {
+        change::sql_apply(&self.pool, change, protected).await
     }
 }
 
-// 获取所有资源
-pub async fn get_all_resource(conn: ConnectionType) -> Result<Vec<Resource>, sqlx::Error> {
-    let sql = r#"SELECT id, name FROM resource"#;
-    use sqlx::Row;
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let rows = sqlx::query(sql).fetch_all(&mut conn).await?;
-            let resources = rows
-                .iter()
-                .map(|row| {
-                    let id: i64 = row.try_get(0).unwrap();
-                    let name: String = row.try_get(1).unwrap();
-                    Resource { id, name }
-                })
-                .collect::<Vec<Resource>>();
-            Ok(resources)
-        }
+#[async_trait]
+impl AclRepository for PostgresRepository {
+    async fn add_resource(&self, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO resource (name) VALUES ($1)"#;
+        sqlx::query(sql).bind(name).execute(&self.pool).await?;
+        Ok(())
+    }
 
-        ConnectionType::Mysql(mut conn) => {
-            let rows = sqlx::query(sql).fetch_all(&mut conn).await?;
-            let resources = rows
-                .iter()
-                .map(|row| {
-                    let id: i64 = row.try_get(0).unwrap();
-                    let name: String = row.try_get(1).unwrap();
-                    Resource { id, name }
-                })
-                .collect::<Vec<Resource>>();
-            Ok(resources)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let rows = sqlx::query(sql).fetch_all(&mut conn).await?;
-            let resources = rows
-                .iter()
-                .map(|row| {
-                    let id: i64 = row.try_get(0).unwrap();
-                    let name: String = row.try_get(1).unwrap();
-                    Resource { id, name }
+    async fn add_acl(
+        &self,
+        uid: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO acl (uid, resource_id, operation, deny) VALUES ($1, $2, $3, $4)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(resource_id)
+            .bind(operation.to_string())
+            .bind(deny)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_resource_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM resource WHERE name = $1"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        row.try_get(0)
+    }
+
+    async fn get_all_resource(&self) -> Result<Vec<Resource>, sqlx::Error> {
+        let sql = r#"SELECT id, name FROM resource"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Resource {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
                 })
-                .collect::<Vec<Resource>>();
-            Ok(resources)
-        }
+            })
+            .collect()
+    }
+
+    async fn add_operation(&self, resource_id: i64, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO resource_operations (resource_id, name) VALUES ($1, $2)"#;
+        sqlx::query(sql)
+            .bind(resource_id)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_operation(&self, resource_id: i64, name: &str) -> Result<u64, sqlx::Error> {
+        let sql = r#"DELETE FROM resource_operations WHERE resource_id = $1 AND name = $2"#;
+        let result = sqlx::query(sql)
+            .bind(resource_id)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
     }
-}
 
-// 初始化对资源操作权
-pub async fn init_user_acl(
-    config: &HttpServerConfig,
-    uid: i64,
-    resource: &str,
-) -> Result<(), sqlx::Error> {
-    let conn = get_conn(&config).await.unwrap();
-    let resource_id = crate::lib::acl::sql_acl::get_resource_id(conn, resource)
-        .await
-        .unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Add)
-        .await
-        .err();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Remove)
-        .await
-        .err();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Check)
-        .await
-        .err();
-    Ok(())
+    async fn get_all_operation(&self) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource_name, resource_operations.name AS operation
+            FROM resource_operations
+            JOIN resource ON resource.id = resource_operations.resource_id"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_acl(&self, uid: u64) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource_name, acl.operation AS operation
+            FROM acl JOIN resource ON resource.id = acl.resource_id
+            WHERE acl.uid = $1 AND NOT acl.deny"#;
+        let rows = sqlx::query(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, acl.operation AS operation, acl.deny AS deny
+            FROM acl JOIN resource ON resource.id = acl.resource_id
+            WHERE acl.uid = $1"#;
+        sqlx::query_as(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn apply_acl_change(
+        &self,
+        change: &AclChange,
+        protected: &[ProtectedAcl],
+    ) -> Result<Option<u64>, sqlx::Error> {
+        change::sql_apply(&self.pool, change, protected).await
+    }
 }
 
-// 查询用户对资源的操作
-pub async fn query_user_acl(
-    conn: ConnectionType,
-    uid: u64,
-) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
-    let sql = r#"SELECT
-    resource.name AS resource_name,
-    acl.operation AS operation
-FROM
-    acl
-JOIN
-    resource ON resource.id = acl.resource_id
-WHERE
-    acl.uid = $1"#;
-    use sqlx::Row;
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let uid = uid as i64;
-            let row_operation = sqlx::query(sql).bind(uid).fetch_all(&mut conn).await?;
-            let mut user_resources: HashMap<String, Vec<String>> = HashMap::new();
-            for row in row_operation {
-                let resource_name: String = row.try_get("resource_name")?;
-                let operation: String = row.try_get("operation")?;
-                if user_resources.contains_key(&resource_name) {
-                    user_resources
-                        .get_mut(&resource_name)
-                        .unwrap()
-                        .push(operation);
-                } else {
-                    user_resources.insert(resource_name, vec![operation]);
-                }
-            }
-            Ok(user_resources)
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let uid = uid as i64;
-            let row_operation = sqlx::query(sql).bind(uid).fetch_all(&mut conn).await?;
-            let mut user_resources: HashMap<String, Vec<String>> = HashMap::new();
-            for row in row_operation {
-                let resource_name: String = row.try_get("resource_name")?;
-                let operation: String = row.try_get("operation")?;
-                if user_resources.contains_key(&resource_name) {
-                    user_resources
-                        .get_mut(&resource_name)
-                        .unwrap()
-                        .push(operation);
-                } else {
-                    user_resources.insert(resource_name, vec![operation]);
-                }
-            }
-            Ok(user_resources)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let uid = uid as i64;
-            let row_operation = sqlx::query(sql).bind(uid).fetch_all(&mut conn).await?;
-            let mut user_resources: HashMap<String, Vec<String>> = HashMap::new();
-            for row in row_operation {
-                let resource_name: String = row.try_get("resource_name")?;
-                let operation: String = row.try_get("operation")?;
-                if user_resources.contains_key(&resource_name) {
-                    user_resources
-                        .get_mut(&resource_name)
-                        .unwrap()
-                        .push(operation);
-                } else {
-                    user_resources.insert(resource_name, vec![operation]);
-                }
-            }
-            Ok(user_resources)
-        }
+#[async_trait]
+impl AclRepository for MemoryRepository {
+    async fn add_resource(&self, name: &str) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.resources.iter().any(|row| row.name == name) {
+            return Err(unique_violation("resource.name"));
+        }
+        let id = state.next_id();
+        state.resources.push(ResourceRow {
+            id,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn add_acl(
+        &self,
+        uid: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let operation = operation.to_string();
+        if state.acl.iter().any(|row| {
+            row.uid == uid && row.resource_id == resource_id && row.operation == operation
+        }) {
+            return Err(unique_violation("acl.uid, acl.resource_id, acl.operation"));
+        }
+        state.acl.push(AclRow {
+            uid,
+            resource_id,
+            operation,
+            deny,
+        });
+        Ok(())
+    }
+
+    async fn get_resource_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .resources
+            .iter()
+            .find(|row| row.name == name)
+            .map(|row| row.id)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn get_all_resource(&self) -> Result<Vec<Resource>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .resources
+            .iter()
+            .map(|row| Resource {
+                id: row.id,
+                name: row.name.clone(),
+            })
+            .collect())
+    }
+
+    async fn add_operation(&self, resource_id: i64, name: &str) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .resource_operations
+            .iter()
+            .any(|row| row.resource_id == resource_id && row.name == name)
+        {
+            return Err(unique_violation(
+                "resource_operations.resource_id, resource_operations.name",
+            ));
+        }
+        state.resource_operations.push(ResourceOperationRow {
+            resource_id,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn remove_operation(&self, resource_id: i64, name: &str) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.resource_operations.len();
+        state
+            .resource_operations
+            .retain(|row| !(row.resource_id == resource_id && row.name == name));
+        Ok((before - state.resource_operations.len()) as u64)
+    }
+
+    async fn get_all_operation(&self) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        let rows = state
+            .resource_operations
+            .iter()
+            .filter_map(|row| {
+                state
+                    .resources
+                    .iter()
+                    .find(|resource| resource.id == row.resource_id)
+                    .map(|resource| (resource.name.clone(), row.name.clone()))
+            })
+            .collect();
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_acl(&self, uid: u64) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        let rows = state
+            .acl
+            .iter()
+            .filter(|row| row.uid == uid as i64 && !row.deny)
+            .filter_map(|row| {
+                state
+                    .resources
+                    .iter()
+                    .find(|resource| resource.id == row.resource_id)
+                    .map(|resource| (resource.name.clone(), row.operation.clone()))
+            })
+            .collect();
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .acl
+            .iter()
+            .filter(|row| row.uid == uid as i64)
+            .filter_map(|row| {
+                state
+                    .resources
+                    .iter()
+                    .find(|resource| resource.id == row.resource_id)
+                    .map(|resource| AclGrant {
+                        resource: resource.name.clone(),
+                        operation: row.operation.clone(),
+                        deny: row.deny,
+                    })
+            })
+            .collect())
+    }
+
+    async fn apply_acl_change(
+        &self,
+        change: &AclChange,
+        protected: &[ProtectedAcl],
+    ) -> Result<Option<u64>, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        change::memory_apply(&mut state, change, protected)
     }
 }
 
 #[tokio::test]
 async fn test_acl() {
-    use crate::HttpServerConfig;
-    let config = HttpServerConfig::default();
-    let conn = get_conn(&config).await.unwrap();
-    let user_resource = query_user_acl(conn, 1).await.unwrap();
+    use crate::lib::config::{memory_pool, HttpServerConfig};
+    let pool = memory_pool().await;
+    let repositories = crate::lib::config::init_db(&pool, &HttpServerConfig::default()).await;
+    let user_resource = repositories.acl.query_user_acl(1).await.unwrap();
     println!("{:#?}", user_resource);
 }
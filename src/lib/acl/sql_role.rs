@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::lib::repository::{
+    memory::{unique_violation, ResourceRow, RoleAclRow, RoleRow, UserRoleRow},
+    MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+};
+
+use super::sql_acl::{group_user_acl, AclGrant, Operation};
+
+// 初始化时创建的管理员角色
+pub const ADMIN_ROLE: &str = "admin";
+
+// 角色
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Role {
+    pub id: i64,
+    pub name: String,
+}
+
+// 角色数据仓库
+//
+// 角色持有对资源的操作权，分配给用户后用户拥有角色的全部操作权
+#[async_trait]
+pub trait RoleRepository: Send + Sync {
+    // 添加角色
+    async fn add_role(&self, name: &str) -> Result<(), sqlx::Error>;
+
+    // 获取角色id
+    async fn get_role_id(&self, name: &str) -> Result<i64, sqlx::Error>;
+
+    // 获取所有角色
+    async fn get_all_role(&self) -> Result<Vec<Role>, sqlx::Error>;
+
+    // 添加角色对资源的操作，deny 为 true 时添加拒绝规则
+    async fn add_role_acl(
+        &self,
+        role_id: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error>;
+
+    // 查询角色的全部规则，包括拒绝
+    async fn query_role_acl(&self, role_id: i64) -> Result<Vec<AclGrant>, sqlx::Error>;
+
+    // 为用户分配角色
+    async fn assign_role(&self, uid: i64, role_id: i64) -> Result<(), sqlx::Error>;
+
+    // 获取用户的角色
+    async fn get_user_roles(&self, uid: i64) -> Result<Vec<Role>, sqlx::Error>;
+
+    // 查询用户通过角色允许的对资源的操作
+    async fn query_user_role_acl(
+        &self,
+        uid: u64,
+    ) -> Result<HashMap<String, Vec<String>>, sqlx::Error>;
+
+    // 查询用户通过角色获得的全部规则，包括拒绝
+    async fn query_user_role_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error>;
+}
+
+#[async_trait]
+impl RoleRepository for SqliteRepository {
+    async fn add_role(&self, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO roles (name) VALUES (?)"#;
+        sqlx::query(sql).bind(name).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn get_role_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM roles WHERE name = ?"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        row.try_get(0)
+    }
+
+    async fn get_all_role(&self) -> Result<Vec<Role>, sqlx::Error> {
+        let sql = r#"SELECT id, name FROM roles ORDER BY id"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Role {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn add_role_acl(
+        &self,
+        role_id: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO role_acl (role_id, resource_id, operation, deny)
+            VALUES (?, ?, ?, ?)"#;
+        sqlx::query(sql)
+            .bind(role_id)
+            .bind(resource_id)
+            .bind(operation.to_string())
+            .bind(deny)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn query_role_acl(&self, role_id: i64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, role_acl.operation AS operation,
+            role_acl.deny AS deny
+            FROM role_acl JOIN resource ON resource.id = role_acl.resource_id
+            WHERE role_acl.role_id = ?"#;
+        sqlx::query_as(sql)
+            .bind(role_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn assign_role(&self, uid: i64, role_id: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO user_roles (uid, role_id) VALUES (?, ?)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(role_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_roles(&self, uid: i64) -> Result<Vec<Role>, sqlx::Error> {
+        let sql = r#"SELECT roles.id, roles.name
+            FROM roles JOIN user_roles ON user_roles.role_id = roles.id
+            WHERE user_roles.uid = ? ORDER BY roles.id"#;
+        let rows = sqlx::query(sql).bind(uid).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Role {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn query_user_role_acl(
+        &self,
+        uid: u64,
+    ) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT DISTINCT resource.name AS resource_name, role_acl.operation AS operation
+            FROM role_acl JOIN user_roles ON user_roles.role_id = role_acl.role_id
+            JOIN resource ON resource.id = role_acl.resource_id
+            WHERE user_roles.uid = ? AND NOT role_acl.deny"#;
+        let rows = sqlx::query(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_role_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, role_acl.operation AS operation,
+            role_acl.deny AS deny
+            FROM role_acl JOIN user_roles ON user_roles.role_id = role_acl.role_id
+            JOIN resource ON resource.id = role_acl.resource_id
+            WHERE user_roles.uid = ?"#;
+        sqlx::query_as(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+#[async_trait]
+impl RoleRepository for MySqlRepository {
+    async fn add_role(&self, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO roles (name) VALUES (?)"#;
+        sqlx::query(sql).bind(name).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn get_role_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM roles WHERE name = ?"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        row.try_get(0)
+    }
+
+    async fn get_all_role(&self) -> Result<Vec<Role>, sqlx::Error> {
+        let sql = r#"SELECT id, name FROM roles ORDER BY id"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Role {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn add_role_acl(
+        &self,
+        role_id: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO role_acl (role_id, resource_id, operation, deny)
+            VALUES (?, ?, ?, ?)"#;
+        sqlx::query(sql)
+            .bind(role_id)
+            .bind(resource_id)
+            .bind(operation.to_string())
+            .bind(deny)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn query_role_acl(&self, role_id: i64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, role_acl.operation AS operation,
+            role_acl.deny AS deny
+            FROM role_acl JOIN resource ON resource.id = role_acl.resource_id
+            WHERE role_acl.role_id = ?"#;
+        sqlx::query_as(sql)
+            .bind(role_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn assign_role(&self, uid: i64, role_id: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO user_roles (uid, role_id) VALUES (?, ?)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(role_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_roles(&self, uid: i64) -> Result<Vec<Role>, sqlx::Error> {
+        let sql = r#"SELECT roles.id, roles.name
+            FROM roles JOIN user_roles ON user_roles.role_id = roles.id
+            WHERE user_roles.uid = ? ORDER BY roles.id"#;
+        let rows = sqlx::query(sql).bind(uid).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Role {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn query_user_role_acl(
+        &self,
+        uid: u64,
+    ) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT DISTINCT resource.name AS resource_name, role_acl.operation AS operation
+            FROM role_acl JOIN user_roles ON user_roles.role_id = role_acl.role_id
+            JOIN resource ON resource.id = role_acl.resource_id
+            WHERE user_roles.uid = ? AND NOT role_acl.deny"#;
+        let rows = sqlx::query(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_role_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, role_acl.operation AS operation,
+            role_acl.deny AS deny
+            FROM role_acl JOIN user_roles ON user_roles.role_id = role_acl.role_id
+            JOIN resource ON resource.id = role_acl.resource_id
+            WHERE user_roles.uid = ?"#;
+        sqlx::query_as(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+#[async_trait]
+impl RoleRepository for PostgresRepository {
+    async fn add_role(&self, name: &str) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO roles (name) VALUES ($1)"#;
+        sqlx::query(sql).bind(name).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn get_role_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM roles WHERE name = $1"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        row.try_get(0)
+    }
+
+    async fn get_all_role(&self) -> Result<Vec<Role>, sqlx::Error> {
+        let sql = r#"SELECT id, name FROM roles ORDER BY id"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Role {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn add_role_acl(
+        &self,
+        role_id: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO role_acl (role_id, resource_id, operation, deny)
+            VALUES ($1, $2, $3, $4)"#;
+        sqlx::query(sql)
+            .bind(role_id)
+            .bind(resource_id)
+            .bind(operation.to_string())
+            .bind(deny)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn query_role_acl(&self, role_id: i64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, role_acl.operation AS operation,
+            role_acl.deny AS deny
+            FROM role_acl JOIN resource ON resource.id = role_acl.resource_id
+            WHERE role_acl.role_id = $1"#;
+        sqlx::query_as(sql)
+            .bind(role_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn assign_role(&self, uid: i64, role_id: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"INSERT INTO user_roles (uid, role_id) VALUES ($1, $2)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(role_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_roles(&self, uid: i64) -> Result<Vec<Role>, sqlx::Error> {
+        let sql = r#"SELECT roles.id, roles.name
+            FROM roles JOIN user_roles ON user_roles.role_id = roles.id
+            WHERE user_roles.uid = $1 ORDER BY roles.id"#;
+        let rows = sqlx::query(sql).bind(uid).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Role {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn query_user_role_acl(
+        &self,
+        uid: u64,
+    ) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let sql = r#"SELECT DISTINCT resource.name AS resource_name, role_acl.operation AS operation
+            FROM role_acl JOIN user_roles ON user_roles.role_id = role_acl.role_id
+            JOIN resource ON resource.id = role_acl.resource_id
+            WHERE user_roles.uid = $1 AND NOT role_acl.deny"#;
+        let rows = sqlx::query(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        let rows = rows
+            .iter()
+            .map(|row| Ok((row.try_get("resource_name")?, row.try_get("operation")?)))
+            .collect::<Result<Vec<(String, String)>, sqlx::Error>>()?;
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_role_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let sql = r#"SELECT resource.name AS resource, role_acl.operation AS operation,
+            role_acl.deny AS deny
+            FROM role_acl JOIN user_roles ON user_roles.role_id = role_acl.role_id
+            JOIN resource ON resource.id = role_acl.resource_id
+            WHERE user_roles.uid = $1"#;
+        sqlx::query_as(sql)
+            .bind(uid as i64)
+            .fetch_all(&self.pool)
+            .await
+    }
+}
+
+fn role_grant(resources: &[ResourceRow], row: &RoleAclRow) -> Option<AclGrant> {
+    resources
+        .iter()
+        .find(|resource| resource.id == row.resource_id)
+        .map(|resource| AclGrant {
+            resource: resource.name.clone(),
+            operation: row.operation.clone(),
+            deny: row.deny,
+        })
+}
+
+#[async_trait]
+impl RoleRepository for MemoryRepository {
+    async fn add_role(&self, name: &str) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.roles.iter().any(|row| row.name == name) {
+            return Err(unique_violation("roles.name"));
+        }
+        let id = state.next_id();
+        state.roles.push(RoleRow {
+            id,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn get_role_id(&self, name: &str) -> Result<i64, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .roles
+            .iter()
+            .find(|row| row.name == name)
+            .map(|row| row.id)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn get_all_role(&self) -> Result<Vec<Role>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .roles
+            .iter()
+            .map(|row| Role {
+                id: row.id,
+                name: row.name.clone(),
+            })
+            .collect())
+    }
+
+    async fn add_role_acl(
+        &self,
+        role_id: i64,
+        resource_id: i64,
+        operation: &Operation,
+        deny: bool,
+    ) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let operation = operation.to_string();
+        if state.role_acl.iter().any(|row| {
+            row.role_id == role_id && row.resource_id == resource_id && row.operation == operation
+        }) {
+            return Err(unique_violation(
+                "role_acl.role_id, role_acl.resource_id, role_acl.operation",
+            ));
+        }
+        state.role_acl.push(RoleAclRow {
+            role_id,
+            resource_id,
+            operation,
+            deny,
+        });
+        Ok(())
+    }
+
+    async fn query_role_acl(&self, role_id: i64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .role_acl
+            .iter()
+            .filter(|row| row.role_id == role_id)
+            .filter_map(|row| role_grant(&state.resources, row))
+            .collect())
+    }
+
+    async fn assign_role(&self, uid: i64, role_id: i64) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .user_roles
+            .iter()
+            .any(|row| row.uid == uid && row.role_id == role_id)
+        {
+            return Err(unique_violation("user_roles.uid, user_roles.role_id"));
+        }
+        state.user_roles.push(UserRoleRow { uid, role_id });
+        Ok(())
+    }
+
+    async fn get_user_roles(&self, uid: i64) -> Result<Vec<Role>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .roles
+            .iter()
+            .filter(|role| {
+                state
+                    .user_roles
+                    .iter()
+                    .any(|row| row.uid == uid && row.role_id == role.id)
+            })
+            .map(|row| Role {
+                id: row.id,
+                name: row.name.clone(),
+            })
+            .collect())
+    }
+
+    async fn query_user_role_acl(
+        &self,
+        uid: u64,
+    ) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        let mut rows: Vec<(String, String)> = Vec::new();
+        for row in state.role_acl.iter().filter(|row| {
+            !row.deny
+                && state.user_roles.iter().any(|user_role| {
+                    user_role.uid == uid as i64 && user_role.role_id == row.role_id
+                })
+        }) {
+            let Some(resource) = state
+                .resources
+                .iter()
+                .find(|resource| resource.id == row.resource_id)
+            else {
+                continue;
+            };
+            let pair = (resource.name.clone(), row.operation.clone());
+            if !rows.contains(&pair) {
+                rows.push(pair);
+            }
+        }
+        Ok(group_user_acl(rows))
+    }
+
+    async fn query_user_role_grants(&self, uid: u64) -> Result<Vec<AclGrant>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .role_acl
+            .iter()
+            .filter(|row| {
+                state.user_roles.iter().any(|user_role| {
+                    user_role.uid == uid as i64 && user_role.role_id == row.role_id
+                })
+            })
+            .filter_map(|row| role_grant(&state.resources, row))
+            .collect())
+    }
+}
@@ -4,284 +4,482 @@
 // 移除用户对资源的操作->Result<ConnectionType, sqlx::Error>
 // 获取用户对资源的操作->Vec<Operation>
 // 获取资源id->i64
+// 注册资源上的自定义操作->Result<(), sqlx::Error>
 
 use std::collections::HashMap;
 
-use actix_web::{http::header::AUTHORIZATION, web, HttpRequest, HttpResponse};
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
 
 use crate::lib::{
-    acl::sql_acl::remove_resource,
-    config::{get_conn, HttpServerConfig, ResponseMessage},
-    user::sql_user::get_user_id,
+    config::{HttpServerConfig, ResponseMessage},
+    error::{is_unique_violation, ApiError},
+    user::{
+        auth::AuthUser, sql_totp::TotpRepository, sql_user::UserRepository,
+        totp::check_totp_required,
+    },
+    validate::{ValidJson, Validate, Validator},
 };
 
 use super::{
-    check_acl,
-    sql_acl::{
-        add_acl, add_resource, get_all_resource, get_resource_id, query_user_acl, remove_acl,
-        remove_acl_by_resource_id, Operation, Resource,
-    },
+    change::AclChange,
+    check_user_acl,
+    delegation::{apply_protected, check_delegation},
+    query_effective_acl, resource_candidates,
+    sql_acl::{AclRepository, Operation},
+    sql_role::RoleRepository,
 };
 
-// 获取所有资源
-pub async fn acl_get_all_resource(
-    config: web::Data<HttpServerConfig>,
-    req: HttpRequest,
-) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    match check_acl(
-        conn,
-        token,
-        &Resource::default(),
-        &crate::lib::acl::Operation::Check.to_string(),
-    )
-    .await
-    {
-        Ok(_) => {
-            let conn = get_conn(&config).await.unwrap();
-            let resources = get_all_resource(conn).await.unwrap();
-            HttpResponse::Ok().json(resources)
-        }
-        Err(_err) => match _err {
-            super::AclError::NotFound => HttpResponse::Ok().json(ResponseMessage {
-                code: 404,
-                message: "Not Found",
-            }),
-            super::AclError::InvalidPermission => HttpResponse::Ok().json(ResponseMessage {
-                code: 403,
-                message: "Invalid Permission",
-            }),
-            super::AclError::ExpiredVerification => HttpResponse::Ok().json(ResponseMessage {
-                code: 401,
-                message: "Expired Verification",
-            }),
-        },
+// 资源
+#[derive(Debug, serde::Deserialize)]
+pub struct ResourceRequest {
+    pub resource: String,
+}
+
+impl Validate for ResourceRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.resource("resource", &self.resource);
     }
 }
 
-// 添加资源
-pub async fn acl_add_resource(
-    config: web::Data<HttpServerConfig>,
-    req: HttpRequest,
-    resource_query: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let resource = &Resource::default();
-    let name = &resource_query.get(resource).unwrap();
-
-    let conn = get_conn(&config).await.unwrap();
-    match check_acl(
-        conn,
-        token,
-        resource,
-        &crate::lib::acl::Operation::Add.to_string(),
-    )
-    .await
-    {
-        Ok(_) => {
-            let conn = get_conn(&config).await.unwrap();
-            match add_resource(conn, name).await {
-                Ok(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 200,
-                    message: "Success",
-                }),
-                Err(_err) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "请勿重复添加",
-                }),
-            }
-        }
-        Err(_err) => HttpResponse::Ok().json(_err),
+// 用户对资源的操作，resource 可以是通配符如 server.*
+#[derive(Debug, serde::Deserialize)]
+pub struct UserOperationRequest {
+    pub resource: String,
+    pub email: String,
+    pub operation: String,
+    #[serde(default)]
+    pub deny: bool, // 添加拒绝规则
+}
+
+impl Validate for UserOperationRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.resource("resource", &self.resource)
+            .required("email", &self.email, 254)
+            .operation("operation", &self.operation);
+    }
+}
+
+// 资源上的操作
+#[derive(Debug, serde::Deserialize)]
+pub struct ResourceOperationRequest {
+    pub resource: String,
+    pub operation: String,
+}
+
+impl Validate for ResourceOperationRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.resource("resource", &self.resource)
+            .operation("operation", &self.operation);
     }
 }
 
-// 删除资源
+// 所有可用的操作
+#[derive(Serialize)]
+struct OperationList {
+    builtin: Vec<Operation>,                 // 对所有资源有效
+    resources: HashMap<String, Vec<String>>, // 资源名 -> 注册的操作
+}
+
+// 授予前检查操作：内置操作，或注册在该资源(或其通配上级，如 server.*)上的操作
+pub(crate) async fn grantable_operation(
+    acl_repo: &dyn AclRepository,
+    resource: &str,
+    operation: &str,
+) -> Result<Operation, ApiError> {
+    let operation = Operation::from_string(operation);
+    if operation.is_builtin() {
+        return Ok(operation);
+    }
+    let registered = acl_repo.get_all_operation().await?;
+    let name = operation.to_string();
+    let found = resource_candidates(resource).iter().any(|candidate| {
+        registered
+            .get(candidate)
+            .is_some_and(|operations| operations.contains(&name))
+    });
+    if found {
+        Ok(operation)
+    } else {
+        Err(ApiError::BadRequest(format!(
+            "资源 {} 上未注册操作 {}",
+            resource, name
+        )))
+    }
+}
+
+// 获取所有资源，路由上要求 resource 的 Check 权限
+pub async fn acl_get_all_resource(
+    acl_repo: web::Data<dyn AclRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = acl_repo.get_all_resource().await?;
+    Ok(HttpResponse::Ok().json(resources))
+}
+
+// 添加资源，路由上要求 resource 的 Add 权限
+pub async fn acl_add_resource(
+    acl_repo: web::Data<dyn AclRepository>,
+    body: ValidJson<ResourceRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let name = body.resource.as_str();
+    acl_repo.add_resource(name).await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "Success",
+    }))
+}
+
+// 删除资源，路由上要求 resource 的 Remove 权限
 pub async fn acl_delete_resource(
+    acl_repo: web::Data<dyn AclRepository>,
     config: web::Data<HttpServerConfig>,
-    req: HttpRequest,
-    resource_query: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let resource = &Resource::default();
-
-    let name = &resource_query.get(resource).unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    let remove_resource_id = match get_resource_id(conn, name).await {
-        Ok(id) => id,
-        Err(_) => {
-            return HttpResponse::Ok().json(ResponseMessage {
-                code: 404,
-                message: "Not Found",
-            });
-        }
-    };
-
-    let conn = get_conn(&config).await.unwrap();
-    match check_acl(
-        conn,
-        token,
-        resource,
-        &crate::lib::acl::Operation::Remove.to_string(),
+    body: ValidJson<ResourceRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let name = body.resource.as_str();
+    let remove_resource_id = acl_repo
+        .get_resource_id(name)
+        .await
+        .map_err(|_| ApiError::NotFound("资源不存在"))?;
+    apply_protected(
+        acl_repo.get_ref(),
+        &config.acl_config,
+        AclChange::RemoveResource {
+            resource_id: remove_resource_id,
+        },
     )
-    .await
-    {
-        Ok(_) => {
-            let conn = get_conn(&config).await.unwrap();
-            remove_resource(conn, name).await.err();
-            let conn = get_conn(&config).await.unwrap();
-            match remove_acl_by_resource_id(conn, remove_resource_id.0).await {
-                Ok(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 200,
-                    message: "Success",
-                }),
-                Err(_err) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "删除失败",
-                }),
-            }
-        }
-        Err(_err) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: &_err.to_string(),
-        }),
-    }
+    .await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "Success",
+    }))
 }
 
-// 添加用户对资源的操作
+// 添加用户对资源的操作，需要拥有该资源的 Add 权限，并且自己持有要授予的操作
 pub async fn acl_add_user_operation(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    user_repo: web::Data<dyn UserRepository>,
+    totp_repo: web::Data<dyn TotpRepository>,
     config: web::Data<HttpServerConfig>,
-    req: HttpRequest,
-    resource_query: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let name = resource_query.get("resource").unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    let name_resource_id = match get_resource_id(conn, name).await {
-        Ok(id) => id,
-        Err(_) => {
-            return HttpResponse::Ok().json(ResponseMessage {
-                code: 404,
-                message: "Not Found",
-            });
-        }
-    };
-
-    let email = resource_query.get("email").unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    let uid = get_user_id(conn, email).await.unwrap();
+    user: AuthUser,
+    body: ValidJson<UserOperationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let name = body.resource.as_str();
+    let email = body.email.as_str();
+    let operation = body.operation.as_str();
 
-    let operation = resource_query.get("operation").unwrap();
+    // 资源由请求参数决定，无法在路由上声明
+    check_user_acl(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        user.uid,
+        name,
+        &Operation::Add,
+    )
+    .await?;
+    check_totp_required(
+        totp_repo.get_ref(),
+        &config.totp_config,
+        user.uid,
+        name,
+        &Operation::Add,
+    )
+    .await?;
 
-    let conn = get_conn(&config).await.unwrap();
-    match check_acl(
-        conn,
-        token,
+    let name_resource_id = acl_repo
+        .get_resource_id(name)
+        .await
+        .map_err(|_| ApiError::NotFound("资源不存在"))?;
+    let operation = grantable_operation(acl_repo.get_ref(), name, operation).await?;
+    check_delegation(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        &config.acl_config,
+        user.uid,
         name,
-        &crate::lib::acl::Operation::Add.to_string(),
+        &operation,
     )
-    .await
-    {
-        Ok(_) => {
-            match add_acl(
-                name_resource_id.1,
-                uid,
-                name_resource_id.0,
-                &Operation::from_string(operation),
-            )
-            .await
-            {
-                Ok(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 200,
-                    message: "Success",
-                }),
-                Err(_err) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "请勿重复添加",
-                }),
-            }
-        }
-        Err(_err) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: &_err.to_string(),
-        }),
-    }
+    .await?;
+    let uid = user_repo
+        .get_user_id(email)
+        .await
+        .map_err(|_| ApiError::NotFound("用户不存在"))?;
+    // 拒绝规则会撤销该用户的权限
+    apply_protected(
+        acl_repo.get_ref(),
+        &config.acl_config,
+        AclChange::AddAcl {
+            uid,
+            resource_id: name_resource_id,
+            operation,
+            deny: body.deny,
+        },
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "Success",
+    }))
 }
 
-// 移除用户对资源的操作
+// 移除用户对资源的操作，需要拥有该资源的 Remove 权限，并且自己持有要撤销的操作
 pub async fn acl_remove_user_operation(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    user_repo: web::Data<dyn UserRepository>,
+    totp_repo: web::Data<dyn TotpRepository>,
     config: web::Data<HttpServerConfig>,
-    req: HttpRequest,
-    resource_query: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let name = resource_query.get("resource").unwrap();
-
-    let email = resource_query.get("email").unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    let uid = get_user_id(conn, email).await.unwrap();
-
-    let operation = resource_query.get("operation").unwrap();
-
-    let conn = get_conn(&config).await.unwrap();
-    match check_acl(
-        conn,
-        token,
+    user: AuthUser,
+    body: ValidJson<UserOperationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let name = body.resource.as_str();
+    let email = body.email.as_str();
+    let operation = body.operation.as_str();
+
+    check_user_acl(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        user.uid,
         name,
-        &crate::lib::acl::Operation::Remove.to_string(),
+        &Operation::Remove,
     )
-    .await
-    {
-        Ok(_) => {
-            let conn = get_conn(&config).await.unwrap();
-            let name_resource_id = get_resource_id(conn, name).await.unwrap();
-            match remove_acl(name_resource_id.1, uid, name_resource_id.0, operation).await {
-                Ok(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 200,
-                    message: "Success",
-                }),
-                Err(_err) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "删除失败",
-                }),
-            }
-        }
-        Err(_err) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: &_err.to_string(),
-        }),
-    }
+    .await?;
+    check_totp_required(
+        totp_repo.get_ref(),
+        &config.totp_config,
+        user.uid,
+        name,
+        &Operation::Remove,
+    )
+    .await?;
+
+    let name_resource_id = acl_repo
+        .get_resource_id(name)
+        .await
+        .map_err(|_| ApiError::NotFound("资源不存在"))?;
+    check_delegation(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        &config.acl_config,
+        user.uid,
+        name,
+        &Operation::from_string(operation),
+    )
+    .await?;
+    let uid = user_repo
+        .get_user_id(email)
+        .await
+        .map_err(|_| ApiError::NotFound("用户不存在"))?;
+    apply_protected(
+        acl_repo.get_ref(),
+        &config.acl_config,
+        AclChange::RemoveAcl {
+            uid,
+            resource_id: name_resource_id,
+            operation: operation.to_string(),
+        },
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "Success",
+    }))
 }
 
-// 查询指定用户对资源的操作
+// 查询当前用户对资源的操作(包括角色的)，路由上要求 resource 的 Check 权限
 pub async fn acl_get_user_operation(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let user_resources =
+        query_effective_acl(acl_repo.get_ref(), role_repo.get_ref(), user.uid).await?;
+    Ok(HttpResponse::Ok().json(user_resources))
+}
+
+// 获取内置操作及所有资源上注册的操作，路由上要求 operation 的 Check 权限
+pub async fn acl_get_all_operation(
+    acl_repo: web::Data<dyn AclRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let resources = acl_repo.get_all_operation().await?;
+    Ok(HttpResponse::Ok().json(OperationList {
+        builtin: Operation::builtin().to_vec(),
+        resources,
+    }))
+}
+
+// 在资源上注册操作，注册者同时获得该操作，之后才能授予他人；路由上要求 operation 的 Add 权限
+pub async fn acl_add_operation(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
     config: web::Data<HttpServerConfig>,
-    req: HttpRequest,
-) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let resource = &Resource::default();
-
-    let conn = get_conn(&config).await.unwrap();
-    match check_acl(
-        conn,
-        token,
-        resource,
-        &crate::lib::acl::Operation::Check.to_string(),
+    user: AuthUser,
+    body: ValidJson<ResourceOperationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if Operation::from_string(&body.operation).is_builtin() {
+        return Err(ApiError::Conflict("内置操作无需注册"));
+    }
+    let resource_id = acl_repo
+        .get_resource_id(&body.resource)
+        .await
+        .map_err(|_| ApiError::NotFound("资源不存在"))?;
+    // 注册者获得的操作视为一次授予，需要能够在该资源上委派 Add
+    check_delegation(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        &config.acl_config,
+        user.uid,
+        &body.resource,
+        &Operation::Add,
     )
-    .await
+    .await?;
+    match acl_repo.add_operation(resource_id, &body.operation).await {
+        Ok(_) => {}
+        Err(err) if is_unique_violation(&err) => return Err(ApiError::Conflict("操作已注册")),
+        Err(err) => return Err(err.into()),
+    }
+    let operation = Operation::from_string(&body.operation);
+    match acl_repo
+        .add_acl(user.uid as i64, resource_id, &operation, false)
+        .await
     {
-        Ok(_) => {
-            let uid = crate::lib::key::gettoken_to_user_no_time(token)
-                .unwrap()
-                .claims
-                .uid;
-            let conn = get_conn(&config).await.unwrap();
-            let user_resources = query_user_acl(conn, uid).await.unwrap();
-            HttpResponse::Ok().json(user_resources)
-        }
-        Err(_err) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: &_err.to_string(),
-        }),
+        Ok(_) => {}
+        Err(err) if is_unique_violation(&err) => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "Success",
+    }))
+}
+
+// 取消注册资源上的操作，之后不能再授予，已有的授予不受影响；路由上要求 operation 的 Remove 权限
+pub async fn acl_delete_operation(
+    acl_repo: web::Data<dyn AclRepository>,
+    body: ValidJson<ResourceOperationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let resource_id = acl_repo
+        .get_resource_id(&body.resource)
+        .await
+        .map_err(|_| ApiError::NotFound("资源不存在"))?;
+    if acl_repo
+        .remove_operation(resource_id, &body.operation)
+        .await?
+        == 0
+    {
+        return Err(ApiError::NotFound("操作未注册"));
+    }
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "Success",
+    }))
+}
+
+#[actix_web::test]
+async fn test_custom_operation() {
+    use actix_web::{http::StatusCode, test, App};
+
+    use crate::lib::{
+        acl::guard::RequireAcl, config::init_base_data_acl, key::create_token_time_min,
+        repository::Repositories,
+    };
+
+    let config = HttpServerConfig::default();
+    let repositories = Repositories::memory();
+    init_base_data_acl(&repositories, &config).await;
+    let admin = repositories
+        .user
+        .get_user_id(&config.register_user.email)
+        .await
+        .unwrap();
+    for resource in ["server.*", "server.survival", "game"] {
+        repositories.acl.add_resource(resource).await.unwrap();
     }
+    let wildcard = repositories.acl.get_resource_id("server.*").await.unwrap();
+    repositories
+        .acl
+        .add_acl(admin, wildcard, &Operation::Add, false)
+        .await
+        .unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(|cfg| repositories.app_data(cfg))
+            .app_data(web::Data::new(config.clone()))
+            .service(
+                web::resource("/operation/get_all")
+                    .wrap(RequireAcl::new("operation", Operation::Check))
+                    .route(web::get().to(acl_get_all_operation)),
+            )
+            .service(
+                web::resource("/operation/add")
+                    .wrap(RequireAcl::new("operation", Operation::Add))
+                    .route(web::post().to(acl_add_operation)),
+            )
+            .route("/user/add", web::post().to(acl_add_user_operation)),
+    )
+    .await;
+
+    let bearer = format!("Bearer {}", create_token_time_min(admin as u64, 5));
+    let post = |uri: &'static str, body: serde_json::Value| {
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", bearer.clone()))
+            .set_json(body)
+            .to_request();
+        let res = test::try_call_service(&app, req);
+        async move {
+            match res.await {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            }
+        }
+    };
+
+    // 未注册的操作不能授予
+    let email = config.register_user.email.clone();
+    let grant =
+        serde_json::json!({ "resource": "server.survival", "email": email, "operation": "kick" });
+    assert_eq!(
+        post("/user/add", grant.clone()).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // 内置操作无需注册，注册在 server.* 上的操作对 server.survival 也有效
+    let register = |resource: &str, operation: &str| serde_json::json!({ "resource": resource, "operation": operation });
+    assert_eq!(
+        post("/operation/add", register("server", "Check")).await,
+        StatusCode::CONFLICT
+    );
+    assert_eq!(
+        post("/operation/add", register("server.*", "kick")).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        post("/operation/add", register("server.*", "kick")).await,
+        StatusCode::CONFLICT
+    );
+    assert_eq!(
+        post("/operation/add", register("missing", "kick")).await,
+        StatusCode::NOT_FOUND
+    );
+    // 没有资源的 Add 权限时不能注册，否则注册者可以借此获得任意资源上的操作
+    assert_eq!(
+        post("/operation/add", register("game", "kick")).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(post("/user/add", grant).await, StatusCode::OK);
+
+    let req = test::TestRequest::get()
+        .uri("/operation/get_all")
+        .insert_header(("Authorization", bearer.clone()))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "builtin": ["Add", "Remove", "Update", "Check", "Grant"],
+            "resources": { "server.*": ["kick"] },
+        })
+    );
 }
@@ -0,0 +1,480 @@
+// 角色管理，路由上要求 role 资源的权限
+//
+// 角色持有对资源的操作权，分配给用户后，check_user_acl 按直接授予与角色授予的并集判断。
+
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+use crate::lib::{
+    config::{HttpServerConfig, ResponseMessage},
+    error::{is_unique_violation, ApiError},
+    user::{auth::AuthUser, sql_user::UserRepository},
+    validate::{ValidJson, ValidQuery, Validate, Validator},
+};
+
+use super::{
+    change::AclChange,
+    delegation::{apply_protected, check_delegation},
+    sql_acl::{AclGrant, AclRepository, Operation},
+    sql_role::RoleRepository,
+    web_acl::grantable_operation,
+};
+
+// 角色
+#[derive(Debug, serde::Deserialize)]
+pub struct RoleRequest {
+    pub role: String,
+}
+
+impl Validate for RoleRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("role", &self.role, 64);
+    }
+}
+
+// 角色对资源的操作，resource 可以是通配符如 server.*
+#[derive(Debug, serde::Deserialize)]
+pub struct RoleOperationRequest {
+    pub role: String,
+    pub resource: String,
+    pub operation: String,
+    #[serde(default)]
+    pub deny: bool, // 添加拒绝规则
+}
+
+impl Validate for RoleOperationRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("role", &self.role, 64)
+            .resource("resource", &self.resource)
+            .operation("operation", &self.operation);
+    }
+}
+
+// 用户的角色
+#[derive(Debug, serde::Deserialize)]
+pub struct RoleUserRequest {
+    pub role: String,
+    pub email: String,
+}
+
+impl Validate for RoleUserRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("role", &self.role, 64)
+            .required("email", &self.email, 254);
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct UserRolesQuery {
+    pub email: String,
+}
+
+impl Validate for UserRolesQuery {
+    fn validate(&self, v: &mut Validator) {
+        v.required("email", &self.email, 254);
+    }
+}
+
+// 角色及其规则
+#[derive(Serialize)]
+struct RoleInfo {
+    id: i64,
+    name: String,
+    acl: Vec<AclGrant>,
+}
+
+fn success() -> HttpResponse {
+    HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "Success",
+    })
+}
+
+async fn role_id(role_repo: &dyn RoleRepository, name: &str) -> Result<i64, ApiError> {
+    match role_repo.get_role_id(name).await {
+        Ok(id) => Ok(id),
+        Err(sqlx::Error::RowNotFound) => Err(ApiError::NotFound("角色不存在")),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// 分配、取消或删除角色相当于授予或撤销角色的全部规则，需要持有这些操作；拒绝规则不需要
+async fn check_role_delegation(
+    acl_repo: &dyn AclRepository,
+    role_repo: &dyn RoleRepository,
+    config: &HttpServerConfig,
+    uid: u64,
+    grants: &[AclGrant],
+) -> Result<(), ApiError> {
+    for grant in grants.iter().filter(|grant| !grant.deny) {
+        check_delegation(
+            acl_repo,
+            role_repo,
+            &config.acl_config,
+            uid,
+            &grant.resource,
+            &Operation::from_string(&grant.operation),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// 获取所有角色及其权限，路由上要求 role 的 Check 权限
+pub async fn role_get_all(
+    role_repo: web::Data<dyn RoleRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let mut roles = Vec::new();
+    for role in role_repo.get_all_role().await? {
+        let acl = role_repo.query_role_acl(role.id).await?;
+        roles.push(RoleInfo {
+            id: role.id,
+            name: role.name,
+            acl,
+        });
+    }
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+// 添加角色，路由上要求 role 的 Add 权限
+pub async fn role_add(
+    role_repo: web::Data<dyn RoleRepository>,
+    body: ValidJson<RoleRequest>,
+) -> Result<HttpResponse, ApiError> {
+    match role_repo.add_role(&body.role).await {
+        Ok(_) => Ok(success()),
+        Err(err) if is_unique_violation(&err) => Err(ApiError::Conflict("角色已存在")),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// 删除角色，拥有该角色的用户同时失去角色的权限，需要持有角色的全部操作，路由上要求 role 的 Remove 权限
+pub async fn role_delete(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    config: web::Data<HttpServerConfig>,
+    user: AuthUser,
+    body: ValidJson<RoleRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let role_id = role_id(role_repo.get_ref(), &body.role).await?;
+    let role_grants = role_repo.query_role_acl(role_id).await?;
+    check_role_delegation(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        &config,
+        user.uid,
+        &role_grants,
+    )
+    .await?;
+    apply_protected(
+        acl_repo.get_ref(),
+        &config.acl_config,
+        AclChange::RemoveRole { role_id },
+    )
+    .await?;
+    Ok(success())
+}
+
+// 添加角色对资源的操作，需要自己持有该操作，路由上要求 role 的 Update 权限
+pub async fn role_add_operation(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    config: web::Data<HttpServerConfig>,
+    user: AuthUser,
+    body: ValidJson<RoleOperationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let role_id = role_id(role_repo.get_ref(), &body.role).await?;
+    let resource_id = acl_repo
+        .get_resource_id(&body.resource)
+        .await
+        .map_err(|_| ApiError::NotFound("资源不存在"))?;
+    let operation =
+        grantable_operation(acl_repo.get_ref(), &body.resource, &body.operation).await?;
+    check_delegation(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        &config.acl_config,
+        user.uid,
+        &body.resource,
+        &operation,
+    )
+    .await?;
+    // 拒绝规则会撤销角色成员的权限
+    let change = AclChange::AddRoleAcl {
+        role_id,
+        resource_id,
+        operation,
+        deny: body.deny,
+    };
+    match apply_protected(acl_repo.get_ref(), &config.acl_config, change).await {
+        Ok(_) => Ok(success()),
+        Err(ApiError::Database(err)) if is_unique_violation(&err) => {
+            Err(ApiError::Conflict("角色已拥有该操作"))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// 移除角色对资源的操作，需要自己持有该操作，路由上要求 role 的 Update 权限
+pub async fn role_remove_operation(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    config: web::Data<HttpServerConfig>,
+    user: AuthUser,
+    body: ValidJson<RoleOperationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let role_id = role_id(role_repo.get_ref(), &body.role).await?;
+    let resource_id = acl_repo
+        .get_resource_id(&body.resource)
+        .await
+        .map_err(|_| ApiError::NotFound("资源不存在"))?;
+    check_delegation(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        &config.acl_config,
+        user.uid,
+        &body.resource,
+        &Operation::from_string(&body.operation),
+    )
+    .await?;
+    apply_protected(
+        acl_repo.get_ref(),
+        &config.acl_config,
+        AclChange::RemoveRoleAcl {
+            role_id,
+            resource_id,
+            operation: body.operation.clone(),
+        },
+    )
+    .await?;
+    Ok(success())
+}
+
+// 为用户分配角色，需要持有角色的全部操作，路由上要求 role 的 Update 权限
+pub async fn role_assign_user(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    user_repo: web::Data<dyn UserRepository>,
+    config: web::Data<HttpServerConfig>,
+    user: AuthUser,
+    body: ValidJson<RoleUserRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let role_id = role_id(role_repo.get_ref(), &body.role).await?;
+    let uid = user_repo
+        .get_user_id(&body.email)
+        .await
+        .map_err(|_| ApiError::NotFound("用户不存在"))?;
+    let role_grants = role_repo.query_role_acl(role_id).await?;
+    check_role_delegation(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        &config,
+        user.uid,
+        &role_grants,
+    )
+    .await?;
+    // 角色中的拒绝规则会撤销该用户的权限
+    let change = AclChange::AssignRole { uid, role_id };
+    match apply_protected(acl_repo.get_ref(), &config.acl_config, change).await {
+        Ok(_) => Ok(success()),
+        Err(ApiError::Database(err)) if is_unique_violation(&err) => {
+            Err(ApiError::Conflict("用户已拥有该角色"))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// 取消用户的角色，需要持有角色的全部操作，路由上要求 role 的 Update 权限
+pub async fn role_unassign_user(
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    user_repo: web::Data<dyn UserRepository>,
+    config: web::Data<HttpServerConfig>,
+    user: AuthUser,
+    body: ValidJson<RoleUserRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let role_id = role_id(role_repo.get_ref(), &body.role).await?;
+    let uid = user_repo
+        .get_user_id(&body.email)
+        .await
+        .map_err(|_| ApiError::NotFound("用户不存在"))?;
+    let role_grants = role_repo.query_role_acl(role_id).await?;
+    check_role_delegation(
+        acl_repo.get_ref(),
+        role_repo.get_ref(),
+        &config,
+        user.uid,
+        &role_grants,
+    )
+    .await?;
+    apply_protected(
+        acl_repo.get_ref(),
+        &config.acl_config,
+        AclChange::UnassignRole { uid, role_id },
+    )
+    .await?;
+    Ok(success())
+}
+
+// 查询用户的角色，路由上要求 role 的 Check 权限
+pub async fn role_get_user(
+    role_repo: web::Data<dyn RoleRepository>,
+    user_repo: web::Data<dyn UserRepository>,
+    query: ValidQuery<UserRolesQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let uid = user_repo
+        .get_user_id(&query.email)
+        .await
+        .map_err(|_| ApiError::NotFound("用户不存在"))?;
+    let roles = role_repo.get_user_roles(uid).await?;
+    Ok(HttpResponse::Ok().json(roles))
+}
+
+#[actix_web::test]
+async fn test_role() {
+    use actix_web::{http::StatusCode, test, App};
+
+    use crate::lib::{
+        acl::guard::RequireAcl, config::init_base_data_acl, key::create_token_time_min,
+        repository::Repositories, user::web_user::RegisterUser,
+    };
+
+    let config = HttpServerConfig::default();
+    let repositories = Repositories::memory();
+    init_base_data_acl(&repositories, &config).await;
+    let admin = repositories
+        .user
+        .get_user_id(&config.register_user.email)
+        .await
+        .unwrap();
+    let email = "moderator@example.com";
+    let uid = repositories
+        .user
+        .register_user(&RegisterUser {
+            email: email.to_string(),
+            password: "Password123".to_string(),
+        })
+        .await
+        .unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .configure(|cfg| repositories.app_data(cfg))
+            .app_data(web::Data::new(config.clone()))
+            .service(
+                web::resource("/role/add")
+                    .wrap(RequireAcl::new("role", Operation::Add))
+                    .route(web::post().to(role_add)),
+            )
+            .service(
+                web::resource("/role/delete")
+                    .wrap(RequireAcl::new("role", Operation::Remove))
+                    .route(web::post().to(role_delete)),
+            )
+            .service(
+                web::resource("/role/operation/add")
+                    .wrap(RequireAcl::new("role", Operation::Update))
+                    .route(web::post().to(role_add_operation)),
+            )
+            .service(
+                web::resource("/role/user/add")
+                    .wrap(RequireAcl::new("role", Operation::Update))
+                    .route(web::post().to(role_assign_user)),
+            )
+            .service(
+                web::resource("/role/user/delete")
+                    .wrap(RequireAcl::new("role", Operation::Update))
+                    .route(web::post().to(role_unassign_user)),
+            )
+            .service(
+                web::resource("/users")
+                    .wrap(RequireAcl::new("user", Operation::Check))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    let call = |uid: i64, uri: &'static str, body: Option<serde_json::Value>| {
+        let bearer = format!("Bearer {}", create_token_time_min(uid as u64, 5));
+        let req = match body {
+            Some(body) => test::TestRequest::post().uri(uri).set_json(body),
+            None => test::TestRequest::get().uri(uri),
+        };
+        let res = test::try_call_service(
+            &app,
+            req.insert_header(("Authorization", bearer)).to_request(),
+        );
+        async move {
+            match res.await {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            }
+        }
+    };
+
+    // admin 通过角色拥有全部权限
+    assert_eq!(call(uid, "/users", None).await, StatusCode::FORBIDDEN);
+    assert_eq!(call(admin, "/users", None).await, StatusCode::OK);
+    let role = serde_json::json!({ "role": "moderator" });
+    assert_eq!(
+        call(uid, "/role/add", Some(role.clone())).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        call(admin, "/role/add", Some(role.clone())).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        call(admin, "/role/add", Some(role)).await,
+        StatusCode::CONFLICT
+    );
+    let grant =
+        serde_json::json!({ "role": "moderator", "resource": "user", "operation": "Check" });
+    assert_eq!(
+        call(admin, "/role/operation/add", Some(grant)).await,
+        StatusCode::OK
+    );
+
+    // 分配角色后拥有角色的权限，取消后失去
+    let assign = serde_json::json!({ "role": "moderator", "email": email });
+    assert_eq!(
+        call(admin, "/role/user/add", Some(assign.clone())).await,
+        StatusCode::OK
+    );
+    assert_eq!(call(uid, "/users", None).await, StatusCode::OK);
+    assert_eq!(
+        call(admin, "/role/user/delete", Some(assign)).await,
+        StatusCode::OK
+    );
+    assert_eq!(call(uid, "/users", None).await, StatusCode::FORBIDDEN);
+
+    // 删除角色需要持有角色的全部操作，拒绝规则除外
+    let muted = serde_json::json!({ "role": "muted" });
+    assert_eq!(
+        call(admin, "/role/add", Some(muted.clone())).await,
+        StatusCode::OK
+    );
+    let deny = serde_json::json!({
+        "role": "muted", "resource": "user", "operation": "Check", "deny": true
+    });
+    assert_eq!(
+        call(admin, "/role/operation/add", Some(deny)).await,
+        StatusCode::OK
+    );
+    let role_resource = repositories.acl.get_resource_id("role").await.unwrap();
+    repositories
+        .acl
+        .add_acl(uid, role_resource, &Operation::Remove, false)
+        .await
+        .unwrap();
+    let moderator = serde_json::json!({ "role": "moderator" });
+    assert_eq!(
+        call(uid, "/role/delete", Some(moderator.clone())).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(call(uid, "/role/delete", Some(muted)).await, StatusCode::OK);
+    assert_eq!(
+        call(admin, "/role/delete", Some(moderator)).await,
+        StatusCode::OK
+    );
+}
@@ -0,0 +1,316 @@
+// 数据加密
+//
+// 使用 AES-256-GCM，每条消息生成随机 nonce。密文格式：
+// 格式版本(1字节) | 密钥id(4字节，大端) | nonce(12字节) | 密文和tag
+// 格式版本与密钥id同时作为附加数据参与认证。解密时按密钥id选择密钥，
+// 轮换后旧密钥继续用于解密，reencrypt 可以把旧数据换成当前密钥加密，
+// 两步验证密钥在下一次验证通过时换成当前密钥。
+// 密钥保存在 encryption_config.key_file 中，轮换不会删除旧密钥。
+// 设置环境变量 MCU_ENCRYPTION_KEY(32字节密钥的base64)时不读取密钥文件，
+// 轮换时可以把旧值放到 MCU_ENCRYPTION_PREVIOUS_KEY 中继续解密。
+
+use std::{fs, sync::OnceLock};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::Utc;
+use log::info;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{config::EncryptionConfig, keyring::write_key_file};
+
+const KEY_ENV: &str = "MCU_ENCRYPTION_KEY";
+const PREVIOUS_KEY_ENV: &str = "MCU_ENCRYPTION_PREVIOUS_KEY";
+
+// 密文格式版本，1 为 AES-256-GCM
+const FORMAT_V1: u8 = 1;
+// 格式版本 + 密钥id
+const AAD_LEN: usize = 1 + 4;
+const HEADER_LEN: usize = AAD_LEN + NONCE_LEN;
+
+#[derive(Debug, PartialEq)]
+pub enum CipherError {
+    InvalidFormat,   // 不是可识别的密文
+    UnknownKey(u32), // 密钥已不存在
+    Decrypt,         // 密文被篡改或密钥错误
+}
+
+impl std::fmt::Display for CipherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CipherError::InvalidFormat => write!(f, "Invalid Ciphertext Format"),
+            CipherError::UnknownKey(id) => write!(f, "Unknown Encryption Key {}", id),
+            CipherError::Decrypt => write!(f, "Decryption Failed"),
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+// 密钥文件
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    active: u32, // 当前用于加密的密钥id
+    keys: Vec<StoredKey>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    id: u32,
+    created_at: i64,
+    key: String, // base64
+}
+
+impl StoredKey {
+    fn generate(id: u32) -> Self {
+        let mut key = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        StoredKey {
+            id,
+            created_at: Utc::now().timestamp(),
+            key: STANDARD.encode(key),
+        }
+    }
+}
+
+pub struct Cipher {
+    active: u32,
+    keys: Vec<(u32, LessSafeKey)>,
+}
+
+fn aead_key(key: &[u8]) -> Result<LessSafeKey, Box<dyn std::error::Error>> {
+    let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| "加密密钥长度必须为32字节")?;
+    Ok(LessSafeKey::new(key))
+}
+
+impl Cipher {
+    fn from_stored(file: &KeyFile) -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = file
+            .keys
+            .iter()
+            .map(|stored| Ok((stored.id, aead_key(&STANDARD.decode(&stored.key)?)?)))
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+        if !keys.iter().any(|(id, _)| *id == file.active) {
+            return Err(format!("加密密钥 {} 不存在", file.active).into());
+        }
+        Ok(Cipher {
+            active: file.active,
+            keys,
+        })
+    }
+
+    // 由环境变量提供的密钥，id 取密钥 sha256 的前4字节
+    fn from_secrets(
+        secret: &str,
+        previous: Option<&str>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let load = |secret: &str| -> Result<(u32, LessSafeKey), Box<dyn std::error::Error>> {
+            let key = STANDARD.decode(secret)?;
+            let digest = Sha256::digest(&key);
+            let id = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+            Ok((id, aead_key(&key)?))
+        };
+        let (active, key) = load(secret)?;
+        let mut keys = vec![(active, key)];
+        if let Some(previous) = previous.filter(|previous| !previous.is_empty()) {
+            keys.push(load(previous)?);
+        }
+        Ok(Cipher { active, keys })
+    }
+
+    // 进程内的临时密钥，重启后无法解密
+    fn ephemeral() -> Self {
+        Cipher::from_stored(&KeyFile {
+            active: 1,
+            keys: vec![StoredKey::generate(1)],
+        })
+        .unwrap()
+    }
+
+    /// 加载密钥，密钥文件不存在时生成
+    pub fn load(config: &EncryptionConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Ok(secret) = std::env::var(KEY_ENV) {
+            info!("使用环境变量 {} 提供的加密密钥", KEY_ENV);
+            let previous = std::env::var(PREVIOUS_KEY_ENV).ok();
+            return Cipher::from_secrets(&secret, previous.as_deref());
+        }
+
+        let file = match fs::read_to_string(&config.key_file) {
+            Ok(contents) => serde_json::from_str::<KeyFile>(&contents)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let file = KeyFile {
+                    active: 1,
+                    keys: vec![StoredKey::generate(1)],
+                };
+                write_key_file(&config.key_file, &file)?;
+                info!("已生成加密密钥: {}", config.key_file);
+                file
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Cipher::from_stored(&file)
+    }
+
+    /// 轮换密钥：生成新的加密密钥，旧密钥保留用于解密，返回新的密钥id
+    pub fn rotate(config: &EncryptionConfig) -> Result<u32, Box<dyn std::error::Error>> {
+        let mut keys = match fs::read_to_string(&config.key_file) {
+            Ok(contents) => serde_json::from_str::<KeyFile>(&contents)?.keys,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let id = keys.iter().map(|key| key.id).max().unwrap_or(0) + 1;
+        keys.push(StoredKey::generate(id));
+        write_key_file(&config.key_file, &KeyFile { active: id, keys })?;
+        Ok(id)
+    }
+
+    fn key(&self, id: u32) -> Option<&LessSafeKey> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| *key_id == id)
+            .map(|(_, key)| key)
+    }
+
+    /// 使用当前密钥加密
+    pub fn encrypt(&self, plain: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+
+        let mut data = Vec::with_capacity(HEADER_LEN + plain.len() + AES_256_GCM.tag_len());
+        data.push(FORMAT_V1);
+        data.extend_from_slice(&self.active.to_be_bytes());
+        data.extend_from_slice(&nonce);
+        let mut in_out = plain.to_vec();
+        self.key(self.active)
+            .unwrap()
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&data[..AAD_LEN]),
+                &mut in_out,
+            )
+            .expect("加密数据过长");
+        data.extend_from_slice(&in_out);
+        data
+    }
+
+    /// 按密文中的密钥id解密
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        let id = key_id(data)?;
+        let key = self.key(id).ok_or(CipherError::UnknownKey(id))?;
+        let nonce = Nonce::try_assume_unique_for_key(&data[AAD_LEN..HEADER_LEN])
+            .map_err(|_| CipherError::InvalidFormat)?;
+        let mut in_out = data[HEADER_LEN..].to_vec();
+        let plain = key
+            .open_in_place(nonce, Aad::from(&data[..AAD_LEN]), &mut in_out)
+            .map_err(|_| CipherError::Decrypt)?;
+        Ok(plain.to_vec())
+    }
+
+    /// 加密为可以保存在文本字段中的字符串
+    pub fn encrypt_to_string(&self, plain: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(self.encrypt(plain))
+    }
+
+    pub fn decrypt_str(&self, data: &str) -> Result<Vec<u8>, CipherError> {
+        let data = URL_SAFE_NO_PAD
+            .decode(data)
+            .map_err(|_| CipherError::InvalidFormat)?;
+        self.decrypt(&data)
+    }
+
+    /// 密文是否由旧密钥加密，供轮换密钥后迁移旧数据使用
+    pub fn needs_reencrypt(&self, data: &[u8]) -> bool {
+        key_id(data).is_ok_and(|id| id != self.active)
+    }
+
+    /// 使用当前密钥重新加密旧密文
+    pub fn reencrypt(&self, data: &[u8]) -> Result<Vec<u8>, CipherError> {
+        Ok(self.encrypt(&self.decrypt(data)?))
+    }
+
+    /// 字符串形式的旧密文换成当前密钥加密，已是当前密钥时返回 None
+    pub fn reencrypt_str(&self, data: &str) -> Result<Option<String>, CipherError> {
+        let data = URL_SAFE_NO_PAD
+            .decode(data)
+            .map_err(|_| CipherError::InvalidFormat)?;
+        if !self.needs_reencrypt(&data) {
+            return Ok(None);
+        }
+        Ok(Some(URL_SAFE_NO_PAD.encode(self.reencrypt(&data)?)))
+    }
+}
+
+// 读取密文头中的密钥id
+fn key_id(data: &[u8]) -> Result<u32, CipherError> {
+    if data.len() < HEADER_LEN + AES_256_GCM.tag_len() || data[0] != FORMAT_V1 {
+        return Err(CipherError::InvalidFormat);
+    }
+    Ok(u32::from_be_bytes([data[1], data[2], data[3], data[4]]))
+}
+
+static CIPHER: OnceLock<Cipher> = OnceLock::new();
+
+/// 启动时加载加密密钥
+pub fn init_cipher(config: &EncryptionConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let cipher = Cipher::load(config)?;
+    CIPHER.set(cipher).map_err(|_| "加密密钥已初始化".into())
+}
+
+/// 获取加密密钥，未初始化时(如测试中)使用进程内的临时密钥
+pub fn cipher() -> &'static Cipher {
+    CIPHER.get_or_init(Cipher::ephemeral)
+}
+
+#[tokio::test]
+async fn test_cipher() {
+    let key_file =
+        std::env::temp_dir().join(format!("mcu_encryption_keys_{}.json", std::process::id()));
+    let config = EncryptionConfig {
+        key_file: key_file.to_string_lossy().to_string(),
+    };
+
+    // 每次加密使用不同的 nonce，篡改后无法解密
+    let cipher = Cipher::load(&config).unwrap();
+    let first = cipher.encrypt(b"payload");
+    assert_ne!(first, cipher.encrypt(b"payload"));
+    assert_eq!(cipher.decrypt(&first).unwrap(), b"payload");
+    let mut tampered = first.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert_eq!(cipher.decrypt(&tampered), Err(CipherError::Decrypt));
+    assert_eq!(cipher.decrypt(b"short"), Err(CipherError::InvalidFormat));
+    let text = cipher.encrypt_to_string(b"text");
+    assert_eq!(cipher.decrypt_str(&text).unwrap(), b"text");
+
+    // 轮换后旧密文仍可解密，并可换成新密钥加密
+    assert_eq!(Cipher::rotate(&config).unwrap(), 2);
+    let rotated = Cipher::load(&config).unwrap();
+    assert_eq!(rotated.decrypt(&first).unwrap(), b"payload");
+    assert!(rotated.needs_reencrypt(&first));
+    let second = rotated.reencrypt(&first).unwrap();
+    assert!(!rotated.needs_reencrypt(&second));
+    assert_eq!(
+        cipher.decrypt(&second),
+        Err(CipherError::UnknownKey(rotated.active))
+    );
+    let moved = rotated.reencrypt_str(&text).unwrap().unwrap();
+    assert_eq!(rotated.decrypt_str(&moved).unwrap(), b"text");
+    assert_eq!(rotated.reencrypt_str(&moved), Ok(None));
+    fs::remove_file(&key_file).unwrap();
+
+    // 环境变量提供的密钥
+    let old_key = STANDARD.encode([1u8; 32]);
+    let new_key = STANDARD.encode([2u8; 32]);
+    let old = Cipher::from_secrets(&old_key, None).unwrap();
+    let new = Cipher::from_secrets(&new_key, Some(&old_key)).unwrap();
+    assert_eq!(new.decrypt(&old.encrypt(b"env")).unwrap(), b"env");
+    assert!(Cipher::from_secrets(&new_key, None)
+        .unwrap()
+        .decrypt(&old.encrypt(b"env"))
+        .is_err());
+    assert!(Cipher::from_secrets("c2hvcnQ=", None).is_err());
+}
@@ -1,21 +1,26 @@
 use std::{
+    collections::HashMap,
     fs::File,
     io::{Read, Write},
+    str::FromStr,
+    time::Duration,
 };
 
 use actix::Actor;
 use serde::{Deserialize, Serialize};
 use sqlx::{
-    migrate::MigrateDatabase, Connection, MySqlConnection, PgConnection, Sqlite, SqliteConnection,
+    pool::PoolOptions, sqlite::SqliteConnectOptions, Database, MySql, MySqlPool, PgPool, Postgres,
+    Sqlite, SqlitePool,
 };
 
 use super::{
-    acl::sql_acl::{create_acl_table, Operation, Resource},
-    java::player::sql_player::create_player_table,
-    user::{
-        sql_user::{change_password, create_user_table, get_user_id, register_user},
-        web_user::RegisterUser,
+    acl::{
+        sql_acl::{Operation, Resource},
+        sql_role::ADMIN_ROLE,
     },
+    migrations::run_migrations,
+    repository::Repositories,
+    user::web_user::RegisterUser,
 };
 
 // 请求时消息
@@ -30,19 +35,318 @@ pub struct HttpServerConfig {
     pub name: String,
     pub v4port: u16,
     pub v6port: u16,
+    #[serde(default)]
+    pub public_url: String, // 对外访问的地址，用于邮件中的链接；为空时使用 http://localhost:v4port
     pub sql_mode: SqlMode,
     pub sql_url: String,
     pub email_config: EmailConfig,
     pub register_user: RegisterUser,
+    #[serde(default)]
+    pub pool_config: PoolConfig,
+    #[serde(default)]
+    pub jwt_config: JwtConfig,
+    #[serde(default)]
+    pub encryption_config: EncryptionConfig,
+    #[serde(default)]
+    pub totp_config: TotpConfig,
+    #[serde(default)]
+    pub rate_limit_config: RateLimitConfig,
+    #[serde(default)]
+    pub code_config: CodeConfig,
+    #[serde(default)]
+    pub acl_config: AclConfig,
 }
 
-#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
 pub struct EmailConfig {
     pub mine_email: String,     // 发件人邮箱
     pub smtp_server: String,    // smtp服务器
     pub email_password: String, // 请使用授权码，而不是真实密码
+    pub transport: MailTransportKind,
+    pub mail_dir: String,       // transport 为 file 时邮件的保存目录
+    pub template_dir: String,   // 邮件模板目录，见 mail::template
+    pub default_locale: String, // 请求未指定语言时使用的模板语言
+    pub change_cancel_ttl: u64, // 更换邮箱后旧邮箱中撤销链接的有效期(秒)
+    pub outbox: OutboxConfig,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        EmailConfig {
+            mine_email: String::new(),
+            smtp_server: String::new(),
+            email_password: String::new(),
+            transport: MailTransportKind::smtp,
+            mail_dir: "mails".to_string(),
+            template_dir: "templates/mail".to_string(),
+            default_locale: "zh-CN".to_string(),
+            change_cancel_ttl: 259200,
+            outbox: OutboxConfig::default(),
+        }
+    }
+}
+
+// 发件箱配置，第n次失败后等待 retry_delay * 2^(n-1) 秒重试，最长 max_retry_delay
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct OutboxConfig {
+    pub max_attempts: i64,    // 最多发送次数，之后标记为发送失败
+    pub retry_delay: u64,     // 第一次重试的等待时间(秒)
+    pub max_retry_delay: u64, // 最长等待时间(秒)
+    pub poll_interval: u64,   // 检查待发送邮件的间隔(秒)
+    pub batch_size: i64,      // 每次取出的邮件数
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            max_attempts: 8,
+            retry_delay: 30,
+            max_retry_delay: 3600,
+            poll_interval: 5,
+            batch_size: 20,
+        }
+    }
+}
+
+// 邮件发送方式，见 mail 模块
+#[allow(non_camel_case_types)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub enum MailTransportKind {
+    smtp,
+    file,
+    log,
+    memory,
+}
+
+// 邮箱验证码配置，错误次数限制见 rate_limit_config.code_max_attempts
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct CodeConfig {
+    pub ttl: u64,            // 有效期(秒)
+    pub store: CodeStore,    // 保存位置
+    pub sweep_interval: u64, // 清理过期验证码的间隔(秒)
+}
+
+impl Default for CodeConfig {
+    fn default() -> Self {
+        CodeConfig {
+            ttl: 300,
+            store: CodeStore::memory,
+            sweep_interval: 60,
+        }
+    }
+}
+
+// 验证码保存位置，memory 重启后丢失，database 保存在 verification_codes 表
+#[allow(non_camel_case_types)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub enum CodeStore {
+    memory,
+    database,
+}
+
+// 数据库连接池配置
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct PoolConfig {
+    pub max_connections: u32, // 最大连接数
+    pub min_connections: u32, // 最少保持的空闲连接数
+    pub acquire_timeout: u64, // 获取连接的超时时间(秒)
+    pub idle_timeout: u64,    // 空闲连接的回收时间(秒)，0为不回收
+    pub max_lifetime: u64,    // 连接的最长存活时间(秒)，0为不限制
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_connections: 10,
+            min_connections: 1,
+            acquire_timeout: 30,
+            idle_timeout: 600,
+            max_lifetime: 1800,
+        }
+    }
+}
+
+impl PoolConfig {
+    fn options<DB: Database>(&self) -> PoolOptions<DB> {
+        let seconds = |s: u64| (s > 0).then(|| Duration::from_secs(s));
+        PoolOptions::<DB>::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(Duration::from_secs(self.acquire_timeout))
+            .idle_timeout(seconds(self.idle_timeout))
+            .max_lifetime(seconds(self.max_lifetime))
+    }
+}
+
+// token签名配置
+//
+// 密钥不写入config.yml，首次启动时生成并保存到key_file；
+// 也可以通过环境变量 MCU_JWT_SECRET 提供 HS256 密钥
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm, // 新生成密钥使用的算法
+    pub key_file: String,        // 密钥文件路径
+    pub keep_keys: usize,        // 轮换后保留的密钥数量，旧密钥只用于验证
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            algorithm: JwtAlgorithm::HS256,
+            key_file: "jwt_keys.json".to_string(),
+            keep_keys: 3,
+        }
+    }
+}
+
+// 数据加密密钥配置
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub key_file: String, // 密钥文件路径，轮换后旧密钥仍保留在文件中
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        EncryptionConfig {
+            key_file: "encryption_keys.json".to_string(),
+        }
+    }
+}
+
+// 两步验证配置
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct TotpConfig {
+    // 资源名 -> 操作，拥有这些权限的用户必须开启两步验证后才能使用，例如 acl: [Add, Remove]
+    // 资源名可以是通配符，例如 server.*: [Remove]
+    pub required_acl: HashMap<String, Vec<String>>,
+}
+
+// 权限授予配置
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct AclConfig {
+    // 授予或撤销资源上的操作时，是否还需要持有该资源的 Grant 权限
+    pub require_grant: bool,
+    // 资源名 -> 操作，这些权限至少保留一个持有者，不能撤销最后一个持有者的权限或删除该用户
+    pub protected_acl: HashMap<String, Vec<String>>,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        let operations: Vec<String> = Operation::builtin()
+            .iter()
+            .map(|operation| operation.to_string())
+            .collect();
+        AclConfig {
+            require_grant: false,
+            protected_acl: HashMap::from([
+                (Resource::default(), operations.clone()),
+                ("role".to_string(), operations),
+            ]),
+        }
+    }
+}
+
+// 请求频率限制与登录失败锁定配置
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // 使用 X-Forwarded-For/Forwarded 中的客户端ip，只在反向代理之后开启，否则可以伪造
+    pub trust_proxy_headers: bool,
+    pub get_code: RateLimitRule,     // 获取邮箱验证码
+    pub change_email: RateLimitRule, // 更换邮箱，验证码发送到新邮箱
+    pub verify_code: RateLimitRule,  // 提交邮箱验证码的注册、重置密码、注销账号、确认更换邮箱
+    pub login: RateLimitRule,        // 账号登录
+    pub login_totp: RateLimitRule,   // 登录第二步
+    pub player_login: RateLimitRule, // 玩家快捷登录，请求来自游戏服务器，按ip的限制应放宽
+    pub lockout: LockoutConfig,
+    pub code_max_attempts: u32, // 验证码允许的错误次数，超过后作废
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            trust_proxy_headers: false,
+            get_code: RateLimitRule {
+                window: 60,
+                per_ip: 5,
+                per_identifier: 1,
+            },
+            change_email: RateLimitRule {
+                window: 60,
+                per_ip: 5,
+                per_identifier: 1,
+            },
+            verify_code: RateLimitRule {
+                window: 60,
+                per_ip: 10,
+                per_identifier: 5,
+            },
+            login: RateLimitRule {
+                window: 60,
+                per_ip: 20,
+                per_identifier: 10,
+            },
+            login_totp: RateLimitRule {
+                window: 60,
+                per_ip: 10,
+                per_identifier: 0,
+            },
+            player_login: RateLimitRule {
+                window: 60,
+                per_ip: 600,
+                per_identifier: 10,
+            },
+            lockout: LockoutConfig::default(),
+            code_max_attempts: 5,
+        }
+    }
+}
+
+// 一个时间窗口内允许的请求数，0为不限制
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct RateLimitRule {
+    pub window: u64,         // 时间窗口(秒)
+    pub per_ip: u32,         // 每个ip
+    pub per_identifier: u32, // 每个邮箱/玩家名
+}
+
+// 连续登录失败 max_failures 次后锁定，再次达到时锁定时间翻倍，最长 max_lock_seconds
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub max_failures: u32,     // 允许连续失败的次数
+    pub lock_seconds: u64,     // 第一次锁定的时间(秒)
+    pub max_lock_seconds: u64, // 最长锁定时间(秒)，超过这段时间没有失败时清零
 }
 
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            max_failures: 5,
+            lock_seconds: 900,
+            max_lock_seconds: 86400,
+        }
+    }
+}
+
+// HS256 为对称密钥；RS256、EdDSA 可以只把公钥交给游戏服务器插件验证token
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    EdDSA,
+}
+
+#[allow(non_camel_case_types)]
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub enum SqlMode {
     sqlite,
@@ -51,10 +355,12 @@ pub enum SqlMode {
 }
 
 impl HttpServerConfig {
-    pub fn setpassword(&mut self, password: String) {
-        self.register_user.password = password;
-        let file_path = "config.yml";
-        let _ = write_config_to_yml(&self, file_path);
+    // 对外访问的地址，不以 / 结尾
+    pub fn public_url(&self) -> String {
+        match self.public_url.trim_end_matches('/') {
+            "" => format!("http://localhost:{}", self.v4port),
+            url => url.to_string(),
+        }
     }
 }
 
@@ -70,6 +376,7 @@ impl Default for HttpServerConfig {
             name: "联合公社".to_string(), // 服务器名称
             v4port: 2024,
             v6port: 2024,
+            public_url: String::new(),
             sql_url: "sqlite://sqlite.db".to_string(),
             sql_mode: SqlMode::sqlite,
             email_config: EmailConfig::default(),
@@ -77,8 +384,15 @@ impl Default for HttpServerConfig {
                 email: "admin".to_string(),
                 password,
             },
+            pool_config: PoolConfig::default(),
+            jwt_config: JwtConfig::default(),
+            encryption_config: EncryptionConfig::default(),
+            totp_config: TotpConfig::default(),
+            rate_limit_config: RateLimitConfig::default(),
+            code_config: CodeConfig::default(),
+            acl_config: AclConfig::default(),
         };
-        match read_yml(&file_path) {
+        match read_yml(file_path) {
             Ok(config) => config,
             Err(_err) => {
                 let _ = write_config_to_yml(&config, file_path);
@@ -111,156 +425,111 @@ pub fn read_yml(file_path: &str) -> Result<HttpServerConfig, Box<dyn std::error:
     Ok(config)
 }
 
-#[derive(Debug)]
-pub enum ConnectionType {
-    Sqlite(SqliteConnection),
-    Mysql(MySqlConnection),
-    Postgres(PgConnection),
+/// 数据库连接池
+///
+/// 启动时创建一次，通过 `web::Data` 共享给所有处理函数
+#[derive(Debug, Clone)]
+pub enum DbPool {
+    Sqlite(SqlitePool),
+    Mysql(MySqlPool),
+    Postgres(PgPool),
 }
 
-pub async fn get_conn(config: &HttpServerConfig) -> Result<ConnectionType, sqlx::Error> {
+// 创建数据库连接池
+pub async fn create_pool(config: &HttpServerConfig) -> Result<DbPool, sqlx::Error> {
+    let pool_config = &config.pool_config;
     match config.sql_mode {
         SqlMode::sqlite => {
-            if config.sql_mode == SqlMode::sqlite {
-                if !Sqlite::database_exists(&config.sql_url)
-                    .await
-                    .unwrap_or(false)
-                {
-                    match Sqlite::create_database(&config.sql_url).await {
-                        Ok(_) => println!("Create db success"),
-                        Err(error) => panic!("error: {}", error),
-                    }
-                }
-                let conn: SqliteConnection = SqliteConnection::connect(&config.sql_url)
-                    .await
-                    .unwrap_or_else(|_| panic!("sqlite connect error"));
-                Ok(ConnectionType::Sqlite(conn))
-            } else {
-                Err(sqlx::Error::RowNotFound)
-            }
+            // 数据库文件不存在时自动创建
+            let options = SqliteConnectOptions::from_str(&config.sql_url)?.create_if_missing(true);
+            let pool = pool_config
+                .options::<Sqlite>()
+                .connect_with(options)
+                .await?;
+            Ok(DbPool::Sqlite(pool))
         }
         SqlMode::mysql => {
-            let conn = MySqlConnection::connect(&config.sql_url).await?;
-            Ok(ConnectionType::Mysql(conn))
+            let pool = pool_config
+                .options::<MySql>()
+                .connect(&config.sql_url)
+                .await?;
+            Ok(DbPool::Mysql(pool))
         }
         SqlMode::postgres => {
-            let conn = PgConnection::connect(&config.sql_url).await?;
-            Ok(ConnectionType::Postgres(conn))
+            let pool = pool_config
+                .options::<Postgres>()
+                .connect(&config.sql_url)
+                .await?;
+            Ok(DbPool::Postgres(pool))
         }
     }
 }
 
-pub(crate) async fn init_db(config: &HttpServerConfig) -> ConnectionType {
-    let conn: ConnectionType = get_conn(config).await.unwrap();
-    let conn = create_user_table(conn).await.unwrap();
-    let conn = create_player_table(conn).await.unwrap();
-    let conn = create_acl_table(conn).await.unwrap();
-    init_base_data_acl(config).await;
-    conn
-}
-
-// 初始化基本数据
-pub async fn init_base_data_acl(config: &HttpServerConfig) {
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_resource(conn, "resource")
-        .await
-        .err();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_resource(conn, "operation")
-        .await
-        .err();
-
-    // 获取资源id
-    let conn = get_conn(&config).await.unwrap();
-    let resource_id = crate::lib::acl::sql_acl::get_resource_id(conn, &Resource::default())
+// 测试用的内存数据库，只保留一个连接，避免连接回收后数据丢失
+#[cfg(test)]
+pub async fn memory_pool() -> DbPool {
+    let pool_config = PoolConfig {
+        max_connections: 1,
+        idle_timeout: 0,
+        max_lifetime: 0,
+        ..Default::default()
+    };
+    let pool = pool_config
+        .options::<Sqlite>()
+        .connect("sqlite::memory:")
         .await
         .unwrap();
+    DbPool::Sqlite(pool)
+}
 
-    let conn = get_conn(&config).await.unwrap();
-    let operation_id = crate::lib::acl::sql_acl::get_resource_id(conn, &Operation::default())
+pub(crate) async fn init_db(pool: &DbPool, config: &HttpServerConfig) -> Repositories {
+    run_migrations(pool)
         .await
-        .unwrap();
+        .unwrap_or_else(|err| panic!("数据库迁移失败: {}", err));
+    let repositories = Repositories::new(pool);
+    init_base_data_acl(&repositories, config).await;
+    repositories
+}
 
-    let conn = get_conn(&config).await.unwrap();
-    let uid = match register_user(conn, &config.register_user).await {
-        Ok(uid) => uid.try_into().unwrap(),
+// 初始化基本数据
+pub async fn init_base_data_acl(repositories: &Repositories, config: &HttpServerConfig) {
+    let user_repo = &repositories.user;
+    let acl_repo = &repositories.acl;
+    let uid = match user_repo.register_user(&config.register_user).await {
+        Ok(uid) => uid,
         Err(_) => {
             // 修改密码
-            let conn = get_conn(&config).await.unwrap();
-            change_password(conn, &config.register_user).await.unwrap();
-            let conn = get_conn(&config).await.unwrap();
-            get_user_id(conn, &config.register_user.email)
+            user_repo
+                .change_password(&config.register_user)
+                .await
+                .unwrap();
+            user_repo
+                .get_user_id(&config.register_user.email)
                 .await
-                .unwrap()
-                .try_into()
                 .unwrap()
         }
     };
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Add)
-        .await
-        .unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Remove)
-        .await
-        .err();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Update)
-        .await
-        .err();
-
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Check)
-        .await
-        .err();
-
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, operation_id.0, &Operation::Add)
-        .await
-        .err();
-    let conn = get_conn(&config).await.unwrap();
-
-    crate::lib::acl::sql_acl::add_acl(conn, uid, operation_id.0, &Operation::Remove)
-        .await
-        .err();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, operation_id.0, &Operation::Update)
-        .await
-        .err();
-
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, operation_id.0, &Operation::Check)
-        .await
-        .err();
-
-    /*******************用户 */
-    let user = "user";
-    let conn: ConnectionType = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_resource(conn, user)
-        .await
-        .err();
 
-    // 获取资源id
-    let conn = get_conn(&config).await.unwrap();
-    let resource_id = crate::lib::acl::sql_acl::get_resource_id(conn, user)
-        .await
-        .unwrap();
-
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Add)
-        .await
-        .unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Remove)
-        .await
-        .err();
-
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Update)
-        .await
-        .err();
-    let conn = get_conn(&config).await.unwrap();
-    crate::lib::acl::sql_acl::add_acl(conn, uid, resource_id.0, &Operation::Check)
-        .await
-        .err();
+    // admin 角色拥有 resource、operation、role、user、server、mail 六个资源的全部操作权
+    let role_repo = &repositories.role;
+    role_repo.add_role(ADMIN_ROLE).await.ok();
+    let role_id = role_repo.get_role_id(ADMIN_ROLE).await.unwrap();
+    for resource in [
+        Resource::default(),
+        Operation::default(),
+        "role".to_string(),
+        "user".to_string(),
+        "server".to_string(),
+        "mail".to_string(),
+    ] {
+        acl_repo.add_resource(&resource).await.ok();
+        let resource_id = acl_repo.get_resource_id(&resource).await.unwrap();
+        for operation in Operation::builtin() {
+            role_repo
+                .add_role_acl(role_id, resource_id, &operation, false)
+                .await
+                .ok();
+        }
+    }
+    role_repo.assign_role(uid, role_id).await.ok();
 }
@@ -0,0 +1,287 @@
+// 接口错误
+//
+// 处理函数返回 Result<HttpResponse, ApiError>，错误统一转换为
+// {"code": HTTP状态码, "error": 错误码, "message": 提示信息}，
+// 参数校验失败时另有 fields 列出每个无效字段。
+// error 是稳定的英文错误码，前端据此判断错误类型；message 只用于展示，可能调整。
+
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse, ResponseError,
+};
+use log::error;
+use serde::Serialize;
+
+use super::{
+    acl::AclError,
+    mail::MailError,
+    user::{auth::AuthError, session::SessionError},
+    validate::FieldError,
+};
+
+#[derive(Debug)]
+pub enum ApiError {
+    // 请求参数缺失或格式错误
+    BadRequest(String),
+    // 参数校验未通过
+    Validation(Vec<FieldError>),
+    // 验证码错误或已过期
+    InvalidCode,
+    // 账号或密码错误
+    InvalidCredentials,
+    // 未登录或token无效
+    Auth(AuthError),
+    // 刷新token无效或已被使用
+    InvalidRefreshToken,
+    // 两步验证码或恢复码错误
+    InvalidTotpCode,
+    // 需要先开启两步验证
+    TotpRequired,
+    // 游戏服务器的API密钥缺失或无效
+    InvalidApiKey,
+    // 权限检查未通过
+    Acl(AclError),
+    // 不允许的操作，例如删除admin
+    Forbidden(&'static str),
+    // 数据不存在
+    NotFound(&'static str),
+    // 数据已存在
+    Conflict(&'static str),
+    // 失败次数过多，需要等待的秒数
+    TooManyAttempts(u64),
+    // 请求过于频繁，需要等待的秒数
+    RateLimited(u64),
+    // 邮件发送失败
+    Mail(String),
+    // 数据库错误
+    Database(sqlx::Error),
+    // 其他内部错误
+    Internal(String),
+}
+
+// 错误的响应内容
+#[derive(Debug, Serialize)]
+pub struct ErrorMessage<'a> {
+    pub code: u16,
+    pub error: &'a str,
+    pub message: &'a str,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub fields: &'a [FieldError],
+}
+
+impl ApiError {
+    /// 稳定的错误码
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::InvalidCode => "invalid_code",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::Auth(AuthError::Missing) => "missing_token",
+            ApiError::Auth(AuthError::Invalid) => "invalid_token",
+            ApiError::InvalidRefreshToken => "invalid_refresh_token",
+            ApiError::InvalidTotpCode => "invalid_totp_code",
+            ApiError::TotpRequired => "totp_required",
+            ApiError::InvalidApiKey => "invalid_api_key",
+            ApiError::Acl(AclError::NotFound) => "resource_not_found",
+            ApiError::Acl(AclError::InvalidPermission) => "permission_denied",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::TooManyAttempts(_) => "too_many_attempts",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Mail(_) => "mail_failed",
+            ApiError::Database(sqlx::Error::RowNotFound) => "not_found",
+            ApiError::Database(err) if is_unique_violation(err) => "conflict",
+            ApiError::Database(_) => "database_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::BadRequest(message) => write!(f, "{}", message),
+            ApiError::Validation(_) => write!(f, "参数校验未通过"),
+            ApiError::InvalidCode => write!(f, "验证码错误"),
+            ApiError::InvalidCredentials => write!(f, "账号或密码错误"),
+            ApiError::Auth(err) => write!(f, "{}", err),
+            ApiError::InvalidRefreshToken => write!(f, "刷新token无效，请重新登录"),
+            ApiError::InvalidTotpCode => write!(f, "两步验证码错误"),
+            ApiError::TotpRequired => write!(f, "该操作需要先开启两步验证"),
+            ApiError::InvalidApiKey => write!(f, "API密钥无效"),
+            ApiError::Acl(AclError::NotFound) => write!(f, "资源不存在"),
+            ApiError::Acl(AclError::InvalidPermission) => write!(f, "权限不足"),
+            ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message) => write!(f, "{}", message),
+            ApiError::TooManyAttempts(_) => write!(f, "失败次数过多，请稍后再试"),
+            ApiError::RateLimited(_) => write!(f, "请求过于频繁，请稍后再试"),
+            ApiError::Mail(_) => write!(f, "邮件发送失败"),
+            ApiError::Database(sqlx::Error::RowNotFound) => write!(f, "数据不存在"),
+            ApiError::Database(err) if is_unique_violation(err) => write!(f, "请勿重复添加"),
+            // 不向客户端暴露内部错误的细节
+            ApiError::Database(_) | ApiError::Internal(_) => write!(f, "服务器内部错误"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::Validation(_) | ApiError::InvalidCode => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::InvalidCredentials
+            | ApiError::Auth(_)
+            | ApiError::InvalidRefreshToken
+            | ApiError::InvalidTotpCode
+            | ApiError::InvalidApiKey => StatusCode::UNAUTHORIZED,
+            ApiError::Acl(AclError::InvalidPermission)
+            | ApiError::TotpRequired
+            | ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Acl(AclError::NotFound) | ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::TooManyAttempts(_) | ApiError::RateLimited(_) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ApiError::Mail(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Database(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND,
+            ApiError::Database(err) if is_unique_violation(err) => StatusCode::CONFLICT,
+            ApiError::Database(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        match self {
+            ApiError::Mail(err) => error!("邮件发送失败: {}", err),
+            ApiError::Database(err) if status == StatusCode::INTERNAL_SERVER_ERROR => {
+                error!("数据库错误: {}", err)
+            }
+            ApiError::Internal(err) => error!("内部错误: {}", err),
+            _ => {}
+        }
+        let mut response = HttpResponse::build(status);
+        if let ApiError::TooManyAttempts(seconds) | ApiError::RateLimited(seconds) = self {
+            response.insert_header((RETRY_AFTER, seconds.to_string()));
+        }
+        response.json(ErrorMessage {
+            code: status.as_u16(),
+            error: self.error_code(),
+            message: &self.to_string(),
+            fields: match self {
+                ApiError::Validation(fields) => fields,
+                _ => &[],
+            },
+        })
+    }
+}
+
+/// 是否为唯一约束冲突
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(err) => err.is_unique_violation(),
+        // 内存仓库模拟的唯一约束错误
+        sqlx::Error::Protocol(message) => message.starts_with("UNIQUE constraint failed"),
+        _ => false,
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        ApiError::Database(err)
+    }
+}
+
+impl From<AclError> for ApiError {
+    fn from(err: AclError) -> Self {
+        ApiError::Acl(err)
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(err: AuthError) -> Self {
+        ApiError::Auth(err)
+    }
+}
+
+impl From<SessionError> for ApiError {
+    fn from(err: SessionError) -> Self {
+        match err {
+            SessionError::Invalid | SessionError::Reused => ApiError::InvalidRefreshToken,
+            SessionError::Database(err) => ApiError::Database(err),
+        }
+    }
+}
+
+impl From<MailError> for ApiError {
+    fn from(err: MailError) -> Self {
+        ApiError::Mail(err.0)
+    }
+}
+
+impl From<actix::MailboxError> for ApiError {
+    fn from(err: actix::MailboxError) -> Self {
+        ApiError::Internal(err.to_string())
+    }
+}
+
+#[tokio::test]
+async fn test_api_error() {
+    use actix_web::body::to_bytes;
+
+    let cases = [
+        (
+            ApiError::BadRequest("缺少参数 email".to_string()),
+            400,
+            "bad_request",
+        ),
+        (ApiError::Auth(AuthError::Missing), 401, "missing_token"),
+        (
+            ApiError::Acl(AclError::InvalidPermission),
+            403,
+            "permission_denied",
+        ),
+        (
+            ApiError::Database(sqlx::Error::RowNotFound),
+            404,
+            "not_found",
+        ),
+        (
+            ApiError::Database(crate::lib::repository::memory::unique_violation(
+                "users.email",
+            )),
+            409,
+            "conflict",
+        ),
+        (ApiError::Mail("timeout".to_string()), 502, "mail_failed"),
+    ];
+    for (err, status, code) in cases {
+        let response = err.error_response();
+        assert_eq!(response.status().as_u16(), status);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], status);
+        assert_eq!(body["error"], code);
+    }
+
+    // 校验失败时列出每个无效字段
+    let response = ApiError::Validation(vec![FieldError {
+        field: "email",
+        message: "邮箱格式错误",
+    }])
+    .error_response();
+    assert_eq!(response.status().as_u16(), 400);
+    let body = to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"], "validation_failed");
+    assert_eq!(body["fields"][0]["field"], "email");
+
+    let response = ApiError::TooManyAttempts(30).error_response();
+    assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "30");
+    let response = ApiError::RateLimited(5).error_response();
+    assert_eq!(response.status().as_u16(), 429);
+    assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "5");
+}
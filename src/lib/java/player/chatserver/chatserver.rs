@@ -28,9 +28,9 @@ impl ChatServer {
 
 impl ChatServer {
     fn send_message(&self, message: &str, skip_id: usize) {
-        for (id, _) in &self.sessions {
+        for id in self.sessions.keys() {
             if *id != skip_id {
-                if let Some(addr) = self.sessions.get(&id) {
+                if let Some(addr) = self.sessions.get(id) {
                     addr.do_send(Message(message.to_owned()));
                 }
             }
@@ -1,2 +1,3 @@
+#[allow(clippy::module_inception)]
 pub mod chatserver;
 pub mod session;
\ No newline at end of file
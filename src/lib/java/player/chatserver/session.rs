@@ -1,34 +1,27 @@
-use std::collections::HashMap;
-
 use actix::prelude::*;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 use log::info;
 
-
-use crate::lib::java::player::onlineplayer::{PlayerManager, PlayersRemoveByServer};
+use crate::lib::java::{
+    player::onlineplayer::{PlayerManager, PlayersRemoveByServer},
+    server::AuthServer,
+};
 
 use super::chatserver;
 
-pub fn chatserver_config(cfg: &mut web::ServiceConfig) {
-    
-}
-
+// 游戏服务器连接，服务器名由API密钥决定
 pub async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<chatserver::ChatServer>>,
     players: web::Data<Addr<PlayerManager>>,
-    q_path: web::Query<HashMap<String, String>>,
+    server: AuthServer,
 ) -> Result<HttpResponse, Error> {
-    let name = &q_path.get("server_name").unwrap();
-    if name.is_empty() {
-        return Ok(HttpResponse::BadRequest().finish());
-    }
     ws::start(
         WsSession {
             id: 0,
-            name: name.to_string(),
+            name: server.name,
             addr: srv.get_ref().clone(),
             playermanager: players.get_ref().clone(),
         },
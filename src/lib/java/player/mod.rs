@@ -1,10 +1,13 @@
-use std::collections::HashMap;
-
 use actix::Addr;
 use actix_web::{web, HttpResponse};
 use onlineplayer::{PlayerManager, PlayerUpdata, PlayersGet};
 
-use crate::lib::config::{get_conn, HttpServerConfig, ResponseMessage};
+use crate::lib::{
+    config::ResponseMessage,
+    error::ApiError,
+    java::server::AuthServer,
+    validate::{ValidJson, Validate, Validator},
+};
 
 pub mod chatserver;
 pub mod onlineplayer;
@@ -13,13 +16,26 @@ pub mod web_player;
 
 // 消息
 
+// 玩家加入/离开服务器，服务器由API密钥决定
+#[derive(Debug, serde::Deserialize)]
+pub struct OnlinePlayerRequest {
+    pub name: String,
+}
+
+impl Validate for OnlinePlayerRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.player_name("name", &self.name);
+    }
+}
+
 // 玩家加入
 pub async fn player_join(
     players: web::Data<Addr<PlayerManager>>,
-    quer_user: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let server = quer_user.get("server").unwrap();
-    let realname = quer_user.get("name").unwrap();
+    server: AuthServer,
+    body: ValidJson<OnlinePlayerRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let server = &server.name;
+    let realname = &body.name;
     if players
         .send(PlayerUpdata {
             r#type: "join".to_owned(),
@@ -28,28 +44,25 @@ pub async fn player_join(
                 server: server.to_string(),
             },
         })
-        .await
-        .unwrap()
+        .await?
     {
-        return HttpResponse::Ok().json(ResponseMessage {
+        Ok(HttpResponse::Ok().json(ResponseMessage {
             code: 200,
             message: "加入成功",
-        });
+        }))
     } else {
-        return HttpResponse::Ok().json(ResponseMessage {
-            code: 300,
-            message: "非法加入",
-        });
+        Err(ApiError::Conflict("非法加入"))
     }
 }
 
 // 玩家离开
 pub async fn player_leave(
     players: web::Data<Addr<PlayerManager>>,
-    quer_user: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let server = quer_user.get("server").unwrap();
-    let realname = quer_user.get("name").unwrap();
+    server: AuthServer,
+    body: ValidJson<OnlinePlayerRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let server = &server.name;
+    let realname = &body.name;
     let is_bool = players
         .send(PlayerUpdata {
             r#type: "leave".to_owned(),
@@ -58,25 +71,23 @@ pub async fn player_leave(
                 server: server.to_string(),
             },
         })
-        .await
-        .unwrap();
+        .await?;
     if is_bool {
-        HttpResponse::Ok().json(ResponseMessage {
+        Ok(HttpResponse::Ok().json(ResponseMessage {
             code: 200,
             message: "离开成功",
-        })
+        }))
     } else {
-        HttpResponse::Ok().json(ResponseMessage {
-            code: 201,
-            message: "玩家未在该服务器",
-        })
+        Err(ApiError::NotFound("玩家未在该服务器"))
     }
 }
 
 // 获取所有玩家
-pub async fn get_players(players: web::Data<Addr<PlayerManager>>) -> HttpResponse {
-    let players = players.send(PlayersGet {}).await.unwrap();
-    HttpResponse::Ok().body(players)
+pub async fn get_players(
+    players: web::Data<Addr<PlayerManager>>,
+) -> Result<HttpResponse, ApiError> {
+    let players = players.send(PlayersGet {}).await?;
+    Ok(HttpResponse::Ok().body(players))
 }
 
 // 获取所有服务端以及玩家
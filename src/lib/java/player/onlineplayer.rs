@@ -1,22 +1,8 @@
 use std::collections::HashMap;
 
 use actix::{Actor, Context, Handler, Message};
-use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-/// 广播数据
-#[derive(Serialize, Deserialize, Clone)]
-pub struct BroadcastData {
-    /// 消息
-    data: String,
-
-    /// 请求者名字
-    name: String,
-
-    /// 服务器
-    server: String,
-}
-
 pub struct OnlinePlayer {
     pub realname: String,
     pub server: String,
@@ -42,22 +28,12 @@ impl PlayerManager {
 impl PlayerManager {
     /// 添加玩家
     pub fn add_player(&mut self, player: OnlinePlayer) -> bool {
-        if self.players.contains_key(&player.server) {
-            let mut server_players = self.players.get(&player.server).unwrap().clone();
-            if !server_players.contains(&player.realname) {
-                server_players.push(player.realname);
-                if let Some(players) = self.players.get_mut(&player.server) {
-                    *players = server_players.to_vec();
-                    return true;
-                }
-            }
-        } else {
-            let mut server_players = Vec::new();
-            server_players.push(player.realname);
-            self.players.insert(player.server, server_players);
-            return true;
+        let server_players = self.players.entry(player.server).or_default();
+        if server_players.contains(&player.realname) {
+            return false;
         }
-        false
+        server_players.push(player.realname);
+        true
     }
     /// 移除某个玩家
     pub fn remove_player(&mut self, player: OnlinePlayer) -> bool {
@@ -1,341 +1,482 @@
-use crate::lib::config::ConnectionType;
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use sqlx::Row;
 
-// 创建玩家账号绑定表
-pub async fn create_player_table(conn: ConnectionType) -> Result<ConnectionType, sqlx::Error> {
-    let sql = r#"
-        CREATE TABLE IF NOT EXISTS java_player (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            uid INTEGER NOT NULL,
-            name TEXT NOT NULL UNIQUE,
-            password TEXT NOT NULL UNIQUE,
-            player_id TEXT NOT NULL
-        );
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Sqlite(conn))
-        }
+use crate::lib::{
+    key::{generate_md5_key, hash_password, verify_password, PasswordMatch},
+    repository::{
+        memory::{unique_violation, PlayerRow},
+        MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+    },
+};
 
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Mysql(conn))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Postgres(conn))
-        }
-    }
+// 离线玩家的player_id
+pub const OFFLINE_PLAYER_ID: &str = "离线玩家";
+
+// 离线玩家的uuid，与服务端离线模式的算法一致：md5("OfflinePlayer:" + name) 作为 v3 uuid
+pub fn offline_uuid(name: &str) -> String {
+    let mut bytes = generate_md5_key(&format!("OfflinePlayer:{}", name));
+    bytes[6] = (bytes[6] & 0x0f) | 0x30;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
-// 添加绑定玩家账号
-pub async fn sql_add_player(
-    conn: ConnectionType,
-    uid: i64,
-    name: &str,
-    password: &str,
-    player_id: &str,
-) -> Result<u64, sqlx::Error> {
-    let sql = r#"
-        INSERT INTO java_player (uid, name,password,player_id) VALUES (?, ?,?, ?);
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&uid)
-                .bind(&name)
-                .bind(&password)
-                .bind(&player_id)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.last_insert_rowid().try_into().unwrap())
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&uid)
-                .bind(&name)
-                .bind(&password)
-                .bind(&player_id)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.last_insert_id())
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&uid)
-                .bind(&name)
-                .bind(&password)
-                .bind(&player_id)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.rows_affected())
-        }
-    }
+// 查询指定通uid下的player，不包含快捷密码
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Player {
+    pub name: String,
+    pub player_id: String,
 }
 
-// 获取玩家账号
-pub async fn sql_get_player(conn: ConnectionType, password: &str) -> Result<String, sqlx::Error> {
-    let sql: &str = r#"
-        SELECT player_id FROM java_player WHERE password = ?;
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let player = sqlx::query(sql)
-                .bind(&password)
-                .fetch_one(&mut conn)
-                .await?;
-            let player_id: String = player.try_get(0)?;
-
-            Ok(player_id)
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let player = sqlx::query(sql)
-                .bind(&password)
-                .fetch_one(&mut conn)
-                .await?;
-            let player_id: String = player.try_get(0)?;
-            Ok(player_id)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let player = sqlx::query(sql)
-                .bind(&password)
-                .fetch_one(&mut conn)
-                .await?;
-            let player_id: String = player.try_get(0)?;
-            Ok(player_id)
-        }
-    }
+// 玩家的登录凭据
+#[derive(Debug)]
+pub struct PlayerCredential {
+    pub uid: i64,
+    pub password: String, // argon2id 哈希，旧数据可能为明文
+    pub player_id: String,
 }
 
-// 获取玩家是否为正版
-pub async fn sql_get_player_is_official(
-    conn: ConnectionType,
-    name: &str,
-) -> Result<bool, sqlx::Error> {
-    let sql: &str = r#"
-        SELECT player_id FROM java_player WHERE name = ?;
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let player = sqlx::query(sql).bind(&name).fetch_one(&mut conn).await?;
-            let player_id: String = player.try_get(0)?;
-            if player_id == "离线玩家" {
-                return Ok(false);
-            }
-            Ok(true)
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let player = sqlx::query(sql).bind(&name).fetch_one(&mut conn).await?;
-            let player_id: String = player.try_get(0)?;
-            if player_id == "离线玩家" {
-                return Ok(false);
-            }
-            Ok(true)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let player = sqlx::query(sql).bind(&name).fetch_one(&mut conn).await?;
-            let player_id: String = player.try_get(0)?;
-            if player_id == "离线玩家" {
-                return Ok(false);
+// 玩家数据仓库
+//
+// 快捷密码只保存 argon2id 哈希，哈希与校验在 add_player/verify_player/update_player_password 中完成
+#[async_trait]
+pub trait PlayerRepository: Send + Sync {
+    // 写入绑定玩家账号，返回新记录的id
+    async fn insert_player(
+        &self,
+        uid: i64,
+        name: &str,
+        password_hash: &str,
+        player_id: &str,
+    ) -> Result<i64, sqlx::Error>;
+
+    // 获取玩家的登录凭据
+    async fn get_player_credential(&self, name: &str) -> Result<PlayerCredential, sqlx::Error>;
+
+    // 更新保存的快捷密码
+    async fn update_player_password_hash(
+        &self,
+        name: &str,
+        password_hash: &str,
+        uid: i64,
+    ) -> Result<(), sqlx::Error>;
+
+    // 获取玩家是否为正版
+    async fn get_player_is_official(&self, name: &str) -> Result<bool, sqlx::Error>;
+
+    // 查询指定uid下的player
+    async fn query_user(&self, uid: i64) -> Result<Vec<Player>, sqlx::Error>;
+
+    // 删除玩家账号
+    async fn delete_player(&self, name: &str, uid: i64) -> Result<(), sqlx::Error>;
+
+    // 添加绑定玩家账号，返回新记录的id
+    async fn add_player(
+        &self,
+        uid: i64,
+        name: &str,
+        password: &str,
+        player_id: &str,
+    ) -> Result<i64, sqlx::Error> {
+        let password_hash = hash_password(password).await;
+        self.insert_player(uid, name, &password_hash, player_id)
+            .await
+    }
+
+    // 校验快捷密码；旧的明文密码在校验成功后升级为哈希
+    async fn verify_player(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> Result<PlayerCredential, sqlx::Error> {
+        let credential = self.get_player_credential(name).await?;
+        match verify_password(password, &credential.password).await {
+            PasswordMatch::Valid => Ok(credential),
+            PasswordMatch::Plaintext => {
+                let password_hash = hash_password(password).await;
+                self.update_player_password_hash(name, &password_hash, credential.uid)
+                    .await?;
+                Ok(PlayerCredential {
+                    password: password_hash,
+                    ..credential
+                })
             }
-            Ok(true)
+            PasswordMatch::Invalid => Err(sqlx::Error::RowNotFound),
         }
     }
-}
 
-// 查询指定通uid下的player
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Player {
-    name: String,
-    password: String,
+    // 修改玩家密码-修改快捷密码
+    async fn update_player_password(
+        &self,
+        name: &str,
+        password: &str,
+        uid: i64,
+    ) -> Result<(), sqlx::Error> {
+        let password_hash = hash_password(password).await;
+        self.update_player_password_hash(name, &password_hash, uid)
+            .await
+    }
 }
-pub async fn query_user(conn: ConnectionType, uid: i64) -> Result<Vec<Player>, sqlx::Error> {
-    let sql: &str = r#"
-        SELECT name,password FROM java_player WHERE uid = ?;
-    "#;
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let player = sqlx::query(sql).bind(&uid).fetch_all(&mut conn).await?;
-            let mut player_list = Vec::new();
-            for row in player {
-                let player = Player {
-                    name: row.try_get(0)?,
-                    password: row.try_get(1)?,
-                };
-                player_list.push(player);
-            }
-            Ok(player_list)
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let player = sqlx::query(sql).bind(&uid).fetch_all(&mut conn).await?;
-            let mut player_list = Vec::new();
-            for row in player {
-                let player = Player {
-                    name: row.try_get(0)?,
-                    password: row.try_get(1)?,
-                };
-                player_list.push(player);
-            }
-            Ok(player_list)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let player = sqlx::query(sql).bind(&uid).fetch_all(&mut conn).await?;
-            let mut player_list = Vec::new();
-            for row in player {
-                let player = Player {
+
+#[async_trait]
+impl PlayerRepository for SqliteRepository {
+    async fn insert_player(
+        &self,
+        uid: i64,
+        name: &str,
+        password_hash: &str,
+        player_id: &str,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO java_player (uid, name, password, player_id) VALUES (?, ?, ?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(uid)
+            .bind(name)
+            .bind(password_hash)
+            .bind(player_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn get_player_credential(&self, name: &str) -> Result<PlayerCredential, sqlx::Error> {
+        let sql = r#"SELECT uid, password, player_id FROM java_player WHERE name = ?"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        Ok(PlayerCredential {
+            uid: row.try_get(0)?,
+            password: row.try_get(1)?,
+            player_id: row.try_get(2)?,
+        })
+    }
+
+    async fn update_player_password_hash(
+        &self,
+        name: &str,
+        password_hash: &str,
+        uid: i64,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"UPDATE java_player SET password = ? WHERE name = ? AND uid = ?"#;
+        sqlx::query(sql)
+            .bind(password_hash)
+            .bind(name)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_player_is_official(&self, name: &str) -> Result<bool, sqlx::Error> {
+        let sql = r#"SELECT player_id FROM java_player WHERE name = ?"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        let player_id: String = row.try_get(0)?;
+        Ok(player_id != OFFLINE_PLAYER_ID)
+    }
+
+    async fn query_user(&self, uid: i64) -> Result<Vec<Player>, sqlx::Error> {
+        let sql = r#"SELECT name, player_id FROM java_player WHERE uid = ?"#;
+        let rows = sqlx::query(sql).bind(uid).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Player {
                     name: row.try_get(0)?,
-                    password: row.try_get(1)?,
-                };
-                player_list.push(player);
-            }
-            Ok(player_list)
-        }
+                    player_id: row.try_get(1)?,
+                })
+            })
+            .collect()
     }
-}
 
-// 修改玩家密码-修改快捷密码
-pub async fn sql_update_player_password(
-    conn: ConnectionType,
-    name: &str,
-    password: &str,
-    uid: i64,
-) -> Result<(), sqlx::Error> {
-    let sql: &str = r#"
-        UPDATE java_player SET password = ? WHERE name = ? and uid = ?;
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql)
-                .bind(&password)
-                .bind(&name)
-                .bind(&uid)
-                .execute(&mut conn)
-                .await?;
-            Ok(())
-        }
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql)
-                .bind(&password)
-                .bind(&name)
-                .bind(&uid)
-                .execute(&mut conn)
-                .await?;
-            Ok(())
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql)
-                .bind(&password)
-                .bind(&name)
-                .bind(&uid)
-                .execute(&mut conn)
-                .await?;
-            Ok(())
-        }
+    async fn delete_player(&self, name: &str, uid: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"DELETE FROM java_player WHERE name = ? AND uid = ?"#;
+        sqlx::query(sql)
+            .bind(name)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
 }
 
-// 删除玩家账号
-pub async fn sql_delete_player(
-    conn: ConnectionType,
-    name: &str,
-    uid: i64,
-) -> Result<(), sqlx::Error> {
-    let sql: &str = r#"
-        DELETE FROM java_player WHERE name = ? and uid = ?;
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql)
-                .bind(&name)
-                .bind(&uid)
-                .execute(&mut conn)
-                .await?;
-            Ok(())
-        }
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql)
-                .bind(&name)
-                .bind(&uid)
-                .execute(&mut conn)
-                .await?;
-            Ok(())
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql)
-                .bind(&name)
-                .bind(&uid)
-                .execute(&mut conn)
-                .await?;
-            Ok(())
-        }
+#[async_trait]
+impl PlayerRepository for MySqlRepository {
+    async fn insert_player(
+        &self,
+        uid: i64,
+        name: &str,
+        password_hash: &str,
+        player_id: &str,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO java_player (uid, name, password, player_id) VALUES (?, ?, ?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(uid)
+            .bind(name)
+            .bind(password_hash)
+            .bind(player_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_id() as i64)
+    }
+
+    async fn get_player_credential(&self, name: &str) -> Result<PlayerCredential, sqlx::Error> {
+        let sql = r#"SELECT uid, password, player_id FROM java_player WHERE name = ?"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        Ok(PlayerCredential {
+            uid: row.try_get(0)?,
+            password: row.try_get(1)?,
+            player_id: row.try_get(2)?,
+        })
     }
-}
 
+    async fn update_player_password_hash(
+        &self,
+        name: &str,
+        password_hash: &str,
+        uid: i64,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"UPDATE java_player SET password = ? WHERE name = ? AND uid = ?"#;
+        sqlx::query(sql)
+            .bind(password_hash)
+            .bind(name)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
 
-//admin- 获取所有玩家账号
-pub async fn sql_get_all_player(conn: ConnectionType) -> Result<Vec<Player>, sqlx::Error> {
-    let sql: &str = r#"
-        SELECT name,password FROM java_player;
-    "#;
+    async fn get_player_is_official(&self, name: &str) -> Result<bool, sqlx::Error> {
+        let sql = r#"SELECT player_id FROM java_player WHERE name = ?"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        let player_id: String = row.try_get(0)?;
+        Ok(player_id != OFFLINE_PLAYER_ID)
+    }
 
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let player = sqlx::query(sql).fetch_all(&mut conn).await?;
-            let mut player_list = Vec::new();
-            for row in player {
-                let player = Player {
+    async fn query_user(&self, uid: i64) -> Result<Vec<Player>, sqlx::Error> {
+        let sql = r#"SELECT name, player_id FROM java_player WHERE uid = ?"#;
+        let rows = sqlx::query(sql).bind(uid).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Player {
                     name: row.try_get(0)?,
-                    password: row.try_get(1)?,
-                };
-                player_list.push(player);
-            }
-            Ok(player_list)
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let player = sqlx::query(sql).fetch_all(&mut conn).await?;
-            let mut player_list = Vec::new();
-            for row in player {
-                let player = Player {
+                    player_id: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_player(&self, name: &str, uid: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"DELETE FROM java_player WHERE name = ? AND uid = ?"#;
+        sqlx::query(sql)
+            .bind(name)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PlayerRepository for PostgresRepository {
+    async fn insert_player(
+        &self,
+        uid: i64,
+        name: &str,
+        password_hash: &str,
+        player_id: &str,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO java_player (uid, name, password, player_id)
+            VALUES ($1, $2, $3, $4) RETURNING id"#;
+        let row = sqlx::query(sql)
+            .bind(uid)
+            .bind(name)
+            .bind(password_hash)
+            .bind(player_id)
+            .fetch_one(&self.pool)
+            .await?;
+        row.try_get("id")
+    }
+
+    async fn get_player_credential(&self, name: &str) -> Result<PlayerCredential, sqlx::Error> {
+        let sql = r#"SELECT uid, password, player_id FROM java_player WHERE name = $1"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        Ok(PlayerCredential {
+            uid: row.try_get(0)?,
+            password: row.try_get(1)?,
+            player_id: row.try_get(2)?,
+        })
+    }
+
+    async fn update_player_password_hash(
+        &self,
+        name: &str,
+        password_hash: &str,
+        uid: i64,
+    ) -> Result<(), sqlx::Error> {
+        let sql = r#"UPDATE java_player SET password = $1 WHERE name = $2 AND uid = $3"#;
+        sqlx::query(sql)
+            .bind(password_hash)
+            .bind(name)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_player_is_official(&self, name: &str) -> Result<bool, sqlx::Error> {
+        let sql = r#"SELECT player_id FROM java_player WHERE name = $1"#;
+        let row = sqlx::query(sql).bind(name).fetch_one(&self.pool).await?;
+        let player_id: String = row.try_get(0)?;
+        Ok(player_id != OFFLINE_PLAYER_ID)
+    }
+
+    async fn query_user(&self, uid: i64) -> Result<Vec<Player>, sqlx::Error> {
+        let sql = r#"SELECT name, player_id FROM java_player WHERE uid = $1"#;
+        let rows = sqlx::query(sql).bind(uid).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(Player {
                     name: row.try_get(0)?,
-                    password: row.try_get(1)?,
-                };
-                player_list.push(player);
-            }
-            Ok(player_list)
+                    player_id: row.try_get(1)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_player(&self, name: &str, uid: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"DELETE FROM java_player WHERE name = $1 AND uid = $2"#;
+        sqlx::query(sql)
+            .bind(name)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PlayerRepository for MemoryRepository {
+    async fn insert_player(
+        &self,
+        uid: i64,
+        name: &str,
+        password_hash: &str,
+        player_id: &str,
+    ) -> Result<i64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.players.iter().any(|row| row.name == name) {
+            return Err(unique_violation("java_player.name"));
         }
-        ConnectionType::Postgres(mut conn) => {
-            let player = sqlx::query(sql).fetch_all(&mut conn).await?;
-            let mut player_list = Vec::new();
-            for row in player {
-                let player = Player {
-                    name: row.try_get(0)?,
-                    password: row.try_get(1)?,
-                };
-                player_list.push(player);
-            }
-            Ok(player_list)
+        let id = state.next_id();
+        state.players.push(PlayerRow {
+            uid,
+            name: name.to_string(),
+            password: password_hash.to_string(),
+            player_id: player_id.to_string(),
+        });
+        Ok(id)
+    }
+
+    async fn get_player_credential(&self, name: &str) -> Result<PlayerCredential, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .players
+            .iter()
+            .find(|row| row.name == name)
+            .map(|row| PlayerCredential {
+                uid: row.uid,
+                password: row.password.clone(),
+                player_id: row.player_id.clone(),
+            })
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn update_player_password_hash(
+        &self,
+        name: &str,
+        password_hash: &str,
+        uid: i64,
+    ) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        for row in state
+            .players
+            .iter_mut()
+            .filter(|row| row.name == name && row.uid == uid)
+        {
+            row.password = password_hash.to_string();
         }
+        Ok(())
+    }
+
+    async fn get_player_is_official(&self, name: &str) -> Result<bool, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .players
+            .iter()
+            .find(|row| row.name == name)
+            .map(|row| row.player_id != OFFLINE_PLAYER_ID)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn query_user(&self, uid: i64) -> Result<Vec<Player>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .players
+            .iter()
+            .filter(|row| row.uid == uid)
+            .map(|row| Player {
+                name: row.name.clone(),
+                player_id: row.player_id.clone(),
+            })
+            .collect())
+    }
+
+    async fn delete_player(&self, name: &str, uid: i64) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .players
+            .retain(|row| !(row.name == name && row.uid == uid));
+        Ok(())
     }
 }
 
+#[tokio::test]
+async fn test_offline_uuid() {
+    assert_eq!(offline_uuid("Notch"), "b50ad385829d3141a2167e7d7539ba7f");
+}
 
 #[tokio::test]
 async fn test_sql_player() {
-    use crate::lib::config::get_conn;
+    use crate::lib::config::memory_pool;
     use crate::lib::config::HttpServerConfig;
 
-    let config = HttpServerConfig::default();
-
-    let conn: ConnectionType = get_conn(&config).await.unwrap();
+    let pool = memory_pool().await;
+    let repositories = crate::lib::config::init_db(&pool, &HttpServerConfig::default()).await;
     let uid = 1;
-    let player_list = query_user(conn, uid).await.unwrap();
+    let player_list = repositories.player.query_user(uid).await.unwrap();
     println!("{:?}", player_list);
+
+    // 旧的明文快捷密码在校验后升级为哈希，且不同玩家可以使用相同的快捷密码
+    repositories
+        .player
+        .insert_player(uid, "plain", "secret", OFFLINE_PLAYER_ID)
+        .await
+        .unwrap();
+    repositories
+        .player
+        .add_player(uid, "hashed", "secret", OFFLINE_PLAYER_ID)
+        .await
+        .unwrap();
+    repositories
+        .player
+        .verify_player("plain", "secret")
+        .await
+        .unwrap();
+    let credential = repositories
+        .player
+        .get_player_credential("plain")
+        .await
+        .unwrap();
+    assert!(credential.password.starts_with("$argon2id$"));
+    assert!(repositories
+        .player
+        .verify_player("hashed", "wrong")
+        .await
+        .is_err());
 }
@@ -1,266 +1,210 @@
-use std::collections::HashMap;
-
-use actix_web::{http::header::AUTHORIZATION, web, HttpRequest, HttpResponse};
-use log::info;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
 use reqwest::Client;
-use serde_json::json;
+use serde::Serialize;
 
 use crate::lib::{
-    config::{get_conn, HttpServerConfig, ResponseMessage},
-    key::gettoken_to_user_no_time,
+    config::ResponseMessage,
+    error::{is_unique_violation, ApiError},
+    java::server::AuthServer,
+    ratelimit::lockout::{player_key, LoginGuard, LoginSucceeded, ReserveAttempt},
+    user::auth::AuthUser,
+    validate::{ValidJson, ValidQuery, Validate, Validator},
 };
 
-use super::sql_player;
+use super::sql_player::{offline_uuid, PlayerRepository, OFFLINE_PLAYER_ID};
 
-// 添加绑定玩家账号
-pub async fn add_bind_player(
-    config: web::Data<HttpServerConfig>,
-    quer_player: web::Query<HashMap<String, String>>,
-    req: HttpRequest,
-) -> HttpResponse {
-    let player_name = quer_player.get("player_name").unwrap();
-    let player_password = quer_player.get("password").unwrap();
-    if player_name.len() <= 1 {
-        return HttpResponse::Ok().json(ResponseMessage {
-            code: 200,
-            message: "玩家名字过短",
-        });
+// 玩家名
+#[derive(Debug, serde::Deserialize)]
+pub struct PlayerName {
+    pub player_name: String,
+}
+
+impl Validate for PlayerName {
+    fn validate(&self, v: &mut Validator) {
+        v.player_name("player_name", &self.player_name);
     }
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    match gettoken_to_user_no_time(token) {
-        Ok(user) => {
-            let uid = user.claims.uid;
-            // 业务逻辑
-            let url = format!(
-                "https://api.mojang.com/users/profiles/minecraft/{}",
-                player_name
-            );
-            let client = Client::new();
-            let response_result = client.get(url).send().await.unwrap();
-            let response = response_result.text().await.unwrap();
+}
 
-            #[derive(serde::Deserialize)]
-            struct Player {
-                id: String,
-                name: String,
-            }
+// 绑定玩家、修改快捷密码
+#[derive(Debug, serde::Deserialize)]
+pub struct PlayerPassword {
+    pub player_name: String,
+    pub password: String,
+}
 
-            match serde_json::from_str::<Player>(&response) {
-                Ok(player) => {
-                    let conn = get_conn(&config).await.unwrap();
-                    match sql_player::sql_add_player(
-                        conn,
-                        uid.try_into().unwrap(),
-                        &player.name,
-                        player_password,
-                        &player.id,
-                    )
-                    .await
-                    {
-                        Ok(_) => {
-                            return HttpResponse::Ok().json(ResponseMessage {
-                                code: 200,
-                                message: "正版绑定成功",
-                            });
-                        }
-                        Err(_) => {
-                            return HttpResponse::Ok().json(ResponseMessage {
-                                code: 200,
-                                message: "正版账号->绑定失败,请检查密码或token是否合规,密码必须是唯一且只有自己知道",
-                            });
-                        }
-                    }
-                }
-                Err(_) => {
-                    let conn = get_conn(&config).await.unwrap();
+impl Validate for PlayerPassword {
+    fn validate(&self, v: &mut Validator) {
+        v.player_name("player_name", &self.player_name)
+            .player_password("password", &self.password);
+    }
+}
 
-                    match sql_player::sql_add_player(
-                        conn,
-                        uid.try_into().unwrap(),
-                        player_name,
-                        player_password,
-                        "离线玩家",
-                    )
-                    .await
-                    {
-                        Ok(_) => {
-                            return HttpResponse::Ok().json(ResponseMessage {
-                                code: 200,
-                                message: "离线绑定成功",
-                            });
-                        }
-                        Err(_) => {
-                            return HttpResponse::Ok().json(ResponseMessage {
-                                code: 200,
-                                message: "离线账号->绑定失败,请检查密码或token是否合规,密码必须是唯一且只有自己知道",
-                            });
-                        }
-                    }
-                }
-            }
-        }
-        Err(_) => {
-            return HttpResponse::Unauthorized().json(ResponseMessage {
-                code: 401,
-                message: "token已过期",
-            });
-        }
+// 快捷登录，不检查密码长度，兼容规则加入前设置的密码
+#[derive(Debug, serde::Deserialize)]
+pub struct PlayerCredentials {
+    pub player_name: String,
+    pub password: String,
+}
+
+impl Validate for PlayerCredentials {
+    fn validate(&self, v: &mut Validator) {
+        v.player_name("player_name", &self.player_name)
+            .required("password", &self.password, 128);
+    }
+}
+
+// 添加绑定玩家账号
+pub async fn add_bind_player(
+    player_repo: web::Data<dyn PlayerRepository>,
+    body: ValidJson<PlayerPassword>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let player_name = body.player_name.as_str();
+    let player_password = body.password.as_str();
+    let uid = user.uid as i64;
+    // 业务逻辑
+    let url = format!(
+        "https://api.mojang.com/users/profiles/minecraft/{}",
+        player_name
+    );
+    let client = Client::new();
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    let response = match response {
+        Ok(response) => response.text().await.unwrap_or_default(),
+        // 查询不到时按离线玩家处理
+        Err(err) if err.status().is_some() => String::new(),
+        Err(err) => return Err(ApiError::Internal(format!("查询正版玩家失败: {}", err))),
+    };
+
+    #[derive(serde::Deserialize)]
+    struct Player {
+        id: String,
+        name: String,
+    }
+
+    let (name, player_id, message) = match serde_json::from_str::<Player>(&response) {
+        Ok(player) => (player.name, player.id, "正版绑定成功"),
+        Err(_) => (
+            player_name.to_string(),
+            OFFLINE_PLAYER_ID.to_string(),
+            "离线绑定成功",
+        ),
+    };
+    match player_repo
+        .add_player(uid, &name, player_password, &player_id)
+        .await
+    {
+        Ok(_) => Ok(HttpResponse::Ok().json(ResponseMessage { code: 200, message })),
+        Err(err) if is_unique_violation(&err) => Err(ApiError::Conflict("该玩家已被绑定")),
+        Err(err) => Err(err.into()),
     }
 }
 
-// 便捷密码登录
+// 便捷密码登录的返回
+#[derive(Debug, Serialize)]
+pub struct PlayerLogin {
+    pub uid: i64,
+    pub premium: bool, // 是否为正版
+    pub uuid: String,
+}
+
+// 便捷密码登录，供游戏服务器使用，需要服务器的API密钥
 pub async fn login(
-    config: web::Data<HttpServerConfig>,
-    quer_user: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let password = quer_user.get("password").unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    let player = sql_player::sql_get_player(conn, password).await;
+    player_repo: web::Data<dyn PlayerRepository>,
+    login_guard: web::Data<Addr<LoginGuard>>,
+    body: ValidJson<PlayerCredentials>,
+    _server: AuthServer,
+) -> Result<HttpResponse, ApiError> {
+    let player_name = body.player_name.as_str();
+    let password = body.password.as_str();
+
+    // 连续失败过多时锁定
+    let key = player_key(player_name);
+    let locked = login_guard.send(ReserveAttempt { key: key.clone() }).await?;
+    if let Some(seconds) = locked {
+        return Err(ApiError::TooManyAttempts(seconds));
+    }
+
+    let player = player_repo.verify_player(player_name, password).await;
+    if player.is_ok() {
+        login_guard.do_send(LoginSucceeded { key });
+    }
     match player {
-        Ok(_) => {
-            return HttpResponse::Ok().json(ResponseMessage {
-                code: 200,
-                message: "登录成功",
-            });
-        }
-        Err(_err) => {
-            return HttpResponse::Created().json(ResponseMessage {
-                code: 201,
-                message: "登录失败",
-            })
+        Ok(credential) => {
+            let premium = credential.player_id != OFFLINE_PLAYER_ID;
+            let uuid = if premium {
+                credential.player_id
+            } else {
+                offline_uuid(player_name)
+            };
+            Ok(HttpResponse::Ok().json(PlayerLogin {
+                uid: credential.uid,
+                premium,
+                uuid,
+            }))
         }
+        Err(sqlx::Error::RowNotFound) => Err(ApiError::InvalidCredentials),
+        Err(err) => Err(err.into()),
     }
 }
 
-// 检查玩家是否为绑定玩家
-pub async fn check_player(
-    config: web::Data<HttpServerConfig>,
-    quer_user: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let player_name = quer_user.get("player_name").unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    let player = sql_player::sql_get_player_is_official(conn, player_name)
-        .await
-        .unwrap();
+// 检查玩家的返回
+#[derive(Debug, Serialize)]
+pub struct PlayerPremium {
+    pub premium: bool, // 是否为正版
+}
 
-    if player {
-        return HttpResponse::Ok().json(ResponseMessage {
-            code: 200,
-            message: "正版玩家",
-        });
-    } else {
-        return HttpResponse::Created().json(ResponseMessage {
-            code: 201,
-            message: "离线玩家",
-        });
-    }
+// 检查玩家是否为正版玩家
+pub async fn check_player(
+    player_repo: web::Data<dyn PlayerRepository>,
+    query: ValidQuery<PlayerName>,
+) -> Result<HttpResponse, ApiError> {
+    let premium = player_repo
+        .get_player_is_official(&query.player_name)
+        .await?;
+    Ok(HttpResponse::Ok().json(PlayerPremium { premium }))
 }
 
 // 查询uid拥有的java账户
-pub async fn query_player(config: web::Data<HttpServerConfig>, req: HttpRequest) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    // 验证token
-    match gettoken_to_user_no_time(token) {
-        Ok(user) => {
-            let uid = user.claims.uid;
-            let conn = get_conn(&config).await.unwrap();
-            match sql_player::query_user(conn, uid.try_into().unwrap()).await {
-                Ok(player_list) => {
-                    return HttpResponse::Ok().json(player_list);
-                }
-                Err(_) => {
-                    return HttpResponse::Ok().json(ResponseMessage {
-                        code: 404,
-                        message: "查询失败",
-                    });
-                }
-            }
-        }
-        Err(_) => {
-            return HttpResponse::Unauthorized().json(ResponseMessage {
-                code: 401,
-                message: "token已过期",
-            });
-        }
-    }
+pub async fn query_player(
+    player_repo: web::Data<dyn PlayerRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let player_list = player_repo.query_user(user.uid as i64).await?;
+    Ok(HttpResponse::Ok().json(player_list))
 }
 
 // 修改玩家快捷密码
 pub async fn update_player(
-    config: web::Data<HttpServerConfig>,
-    quer_player: web::Query<HashMap<String, String>>,
-    req: HttpRequest,
-) -> HttpResponse {
-    let player_name = quer_player.get("player_name").unwrap();
-    let player_password = quer_player.get("password").unwrap();
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    match gettoken_to_user_no_time(token) {
-        Ok(user) => {
-            let uid = user.claims.uid;
-            let conn = get_conn(&config).await.unwrap();
-            match sql_player::sql_update_player_password(
-                conn,
-                player_name,
-                player_password,
-                uid.try_into().unwrap(),
-            )
-            .await
-            {
-                Ok(_) => {
-                    return HttpResponse::Ok().json(ResponseMessage {
-                        code: 200,
-                        message: "修改成功",
-                    });
-                }
-                Err(_) => {
-                    return HttpResponse::Ok().json(ResponseMessage {
-                        code: 200,
-                        message: "修改失败",
-                    });
-                }
-            }
-        }
-        Err(_) => {
-            return HttpResponse::Unauthorized().json(ResponseMessage {
-                code: 401,
-                message: "token已过期",
-            });
-        }
-    }
+    player_repo: web::Data<dyn PlayerRepository>,
+    body: ValidJson<PlayerPassword>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    player_repo
+        .update_player_password(&body.player_name, &body.password, user.uid as i64)
+        .await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "修改成功",
+    }))
 }
 
 // 删除绑定玩家
 pub async fn delete_player(
-    config: web::Data<HttpServerConfig>,
-    quer_player: web::Query<HashMap<String, String>>,
-    req: HttpRequest,
-) -> HttpResponse {
-    let player_name = quer_player.get("player_name").unwrap();
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    match gettoken_to_user_no_time(token) {
-        Ok(user) => {
-            let uid = user.claims.uid;
-            let conn = get_conn(&config).await.unwrap();
-            match sql_player::sql_delete_player(conn, player_name, uid.try_into().unwrap()).await {
-                Ok(_) => {
-                    return HttpResponse::Ok().json(ResponseMessage {
-                        code: 200,
-                        message: "删除成功",
-                    });
-                }
-                Err(_) => {
-                    return HttpResponse::Ok().json(ResponseMessage {
-                        code: 200,
-                        message: "删除失败",
-                    });
-                }
-            }
-        }
-        Err(_) => {
-            return HttpResponse::Unauthorized().json(ResponseMessage {
-                code: 401,
-                message: "token已过期",
-            });
-        }
-    }
+    player_repo: web::Data<dyn PlayerRepository>,
+    body: ValidJson<PlayerName>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    player_repo
+        .delete_player(&body.player_name, user.uid as i64)
+        .await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "删除成功",
+    }))
 }
@@ -0,0 +1,145 @@
+// 游戏服务器
+//
+// 每个游戏服务器在注册时获得一个API密钥，调用玩家加入/离开接口和连接 /ws 时
+// 通过请求头 X-Api-Key 提交，服务器身份由密钥决定。
+// 无法设置请求头的 WebSocket 客户端可以改用查询参数 api_key。
+// 数据库中只保存密钥的sha256，密钥只在注册和更换时返回一次。
+
+use std::collections::HashMap;
+
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::future::LocalBoxFuture;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::lib::error::ApiError;
+
+use sql_server::GameServerRepository;
+
+pub mod sql_server;
+pub mod web_server;
+
+// API密钥的请求头
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+// 密钥前缀，便于在配置文件和日志中辨认
+const API_KEY_PREFIX: &str = "mcu_";
+// 列表中展示的密钥长度
+const KEY_PREFIX_LEN: usize = 8;
+
+/// 新生成的API密钥
+pub struct ApiKey {
+    pub key: String,
+    pub hash: String,
+    pub prefix: String,
+}
+
+impl ApiKey {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let key = format!("{}{}", API_KEY_PREFIX, URL_SAFE_NO_PAD.encode(bytes));
+        ApiKey {
+            hash: hash_api_key(&key),
+            prefix: key[..KEY_PREFIX_LEN].to_string(),
+            key,
+        }
+    }
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+// 从请求头或查询参数读取API密钥
+fn api_key(req: &HttpRequest) -> Option<String> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER) {
+        return key.to_str().ok().map(|key| key.trim().to_string());
+    }
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()?
+        .get("api_key")
+        .cloned()
+}
+
+/// 已通过API密钥验证的游戏服务器
+#[derive(Debug, Clone)]
+pub struct AuthServer {
+    pub name: String,
+}
+
+impl FromRequest for AuthServer {
+    type Error = ApiError;
+    type Future = LocalBoxFuture<'static, Result<AuthServer, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let key = api_key(req);
+        let server_repo = req
+            .app_data::<web::Data<dyn GameServerRepository>>()
+            .expect("GameServerRepository 未注册")
+            .clone();
+        Box::pin(async move {
+            let key = key
+                .filter(|key| !key.is_empty())
+                .ok_or(ApiError::InvalidApiKey)?;
+            match server_repo.find_server_by_key(&hash_api_key(&key)).await {
+                Ok(server) => Ok(AuthServer { name: server.name }),
+                Err(sqlx::Error::RowNotFound) => Err(ApiError::InvalidApiKey),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_auth_server() {
+    use actix_web::test::TestRequest;
+    use chrono::Utc;
+
+    use crate::lib::repository::Repositories;
+
+    let repositories = Repositories::memory();
+    let server_repo = repositories.server.clone();
+    let now = Utc::now().timestamp();
+    let key = ApiKey::generate();
+    assert!(key.key.starts_with(API_KEY_PREFIX));
+    server_repo
+        .add_server("lobby", &key.hash, &key.prefix, now)
+        .await
+        .unwrap();
+
+    let auth = |header: Option<&str>, query: &str| {
+        let mut req = TestRequest::default()
+            .uri(&format!("/ws{}", query))
+            .app_data(web::Data::from(server_repo.clone()));
+        if let Some(header) = header {
+            req = req.insert_header((API_KEY_HEADER, header.to_string()));
+        }
+        let (req, mut payload) = req.to_http_parts();
+        AuthServer::from_request(&req, &mut payload)
+    };
+    assert_eq!(auth(Some(&key.key), "").await.unwrap().name, "lobby");
+    assert_eq!(
+        auth(None, &format!("?api_key={}", key.key))
+            .await
+            .unwrap()
+            .name,
+        "lobby"
+    );
+    assert!(auth(None, "").await.is_err());
+    assert!(auth(Some("mcu_invalid"), "").await.is_err());
+
+    // 更换后旧密钥失效，吊销后新密钥也失效
+    let rotated = ApiKey::generate();
+    server_repo
+        .rotate_server_key("lobby", &rotated.hash, &rotated.prefix, now)
+        .await
+        .unwrap();
+    assert!(auth(Some(&key.key), "").await.is_err());
+    assert!(auth(Some(&rotated.key), "").await.is_ok());
+    server_repo.revoke_server("lobby", now).await.unwrap();
+    assert!(matches!(
+        auth(Some(&rotated.key), "").await,
+        Err(ApiError::InvalidApiKey)
+    ));
+}
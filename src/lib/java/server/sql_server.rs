@@ -0,0 +1,344 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::lib::repository::{
+    memory::{unique_violation, GameServerRow},
+    MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+};
+
+// 游戏服务器，不包含API密钥
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct GameServer {
+    pub id: i64,
+    pub name: String,
+    pub key_prefix: String, // 密钥的前几位，用于辨认
+    pub created_at: i64,
+    pub rotated_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+// 游戏服务器数据仓库，API密钥只保存sha256
+#[async_trait]
+pub trait GameServerRepository: Send + Sync {
+    // 注册游戏服务器，返回新记录的id
+    async fn add_server(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<i64, sqlx::Error>;
+
+    // 按API密钥查找未吊销的服务器
+    async fn find_server_by_key(&self, key_hash: &str) -> Result<GameServer, sqlx::Error>;
+
+    // 更换API密钥，旧密钥立即失效，已吊销的服务器重新启用；返回受影响的行数
+    async fn rotate_server_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error>;
+
+    // 吊销API密钥，返回受影响的行数
+    async fn revoke_server(&self, name: &str, now: i64) -> Result<u64, sqlx::Error>;
+
+    // admin-获取所有游戏服务器
+    async fn get_all_server(&self) -> Result<Vec<GameServer>, sqlx::Error>;
+}
+
+#[async_trait]
+impl GameServerRepository for SqliteRepository {
+    async fn add_server(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO game_servers (name, key_hash, key_prefix, created_at)
+            VALUES (?, ?, ?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(name)
+            .bind(key_hash)
+            .bind(key_prefix)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn find_server_by_key(&self, key_hash: &str) -> Result<GameServer, sqlx::Error> {
+        let sql = r#"SELECT id, name, key_prefix, created_at, rotated_at, revoked_at
+            FROM game_servers WHERE key_hash = ? AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(key_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn rotate_server_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE game_servers SET key_hash = ?, key_prefix = ?, rotated_at = ?,
+            revoked_at = NULL WHERE name = ?"#;
+        let result = sqlx::query(sql)
+            .bind(key_hash)
+            .bind(key_prefix)
+            .bind(now)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_server(&self, name: &str, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE game_servers SET revoked_at = ? WHERE name = ? AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_all_server(&self) -> Result<Vec<GameServer>, sqlx::Error> {
+        let sql = r#"SELECT id, name, key_prefix, created_at, rotated_at, revoked_at
+            FROM game_servers ORDER BY id"#;
+        sqlx::query_as(sql).fetch_all(&self.pool).await
+    }
+}
+
+#[async_trait]
+impl GameServerRepository for MySqlRepository {
+    async fn add_server(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO game_servers (name, key_hash, key_prefix, created_at)
+            VALUES (?, ?, ?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(name)
+            .bind(key_hash)
+            .bind(key_prefix)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_id() as i64)
+    }
+
+    async fn find_server_by_key(&self, key_hash: &str) -> Result<GameServer, sqlx::Error> {
+        let sql = r#"SELECT id, name, key_prefix, created_at, rotated_at, revoked_at
+            FROM game_servers WHERE key_hash = ? AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(key_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn rotate_server_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE game_servers SET key_hash = ?, key_prefix = ?, rotated_at = ?,
+            revoked_at = NULL WHERE name = ?"#;
+        let result = sqlx::query(sql)
+            .bind(key_hash)
+            .bind(key_prefix)
+            .bind(now)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_server(&self, name: &str, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE game_servers SET revoked_at = ? WHERE name = ? AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_all_server(&self) -> Result<Vec<GameServer>, sqlx::Error> {
+        let sql = r#"SELECT id, name, key_prefix, created_at, rotated_at, revoked_at
+            FROM game_servers ORDER BY id"#;
+        sqlx::query_as(sql).fetch_all(&self.pool).await
+    }
+}
+
+#[async_trait]
+impl GameServerRepository for PostgresRepository {
+    async fn add_server(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO game_servers (name, key_hash, key_prefix, created_at)
+            VALUES ($1, $2, $3, $4) RETURNING id"#;
+        let row = sqlx::query(sql)
+            .bind(name)
+            .bind(key_hash)
+            .bind(key_prefix)
+            .bind(now)
+            .fetch_one(&self.pool)
+            .await?;
+        row.try_get("id")
+    }
+
+    async fn find_server_by_key(&self, key_hash: &str) -> Result<GameServer, sqlx::Error> {
+        let sql = r#"SELECT id, name, key_prefix, created_at, rotated_at, revoked_at
+            FROM game_servers WHERE key_hash = $1 AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(key_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn rotate_server_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE game_servers SET key_hash = $1, key_prefix = $2, rotated_at = $3,
+            revoked_at = NULL WHERE name = $4"#;
+        let result = sqlx::query(sql)
+            .bind(key_hash)
+            .bind(key_prefix)
+            .bind(now)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_server(&self, name: &str, now: i64) -> Result<u64, sqlx::Error> {
+        let sql =
+            r#"UPDATE game_servers SET revoked_at = $1 WHERE name = $2 AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_all_server(&self) -> Result<Vec<GameServer>, sqlx::Error> {
+        let sql = r#"SELECT id, name, key_prefix, created_at, rotated_at, revoked_at
+            FROM game_servers ORDER BY id"#;
+        sqlx::query_as(sql).fetch_all(&self.pool).await
+    }
+}
+
+impl From<&GameServerRow> for GameServer {
+    fn from(row: &GameServerRow) -> Self {
+        GameServer {
+            id: row.id,
+            name: row.name.clone(),
+            key_prefix: row.key_prefix.clone(),
+            created_at: row.created_at,
+            rotated_at: row.rotated_at,
+            revoked_at: row.revoked_at,
+        }
+    }
+}
+
+#[async_trait]
+impl GameServerRepository for MemoryRepository {
+    async fn add_server(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.game_servers.iter().any(|row| row.name == name) {
+            return Err(unique_violation("game_servers.name"));
+        }
+        if state
+            .game_servers
+            .iter()
+            .any(|row| row.key_hash == key_hash)
+        {
+            return Err(unique_violation("game_servers.key_hash"));
+        }
+        let id = state.next_id();
+        state.game_servers.push(GameServerRow {
+            id,
+            name: name.to_string(),
+            key_hash: key_hash.to_string(),
+            key_prefix: key_prefix.to_string(),
+            created_at: now,
+            rotated_at: None,
+            revoked_at: None,
+        });
+        Ok(id)
+    }
+
+    async fn find_server_by_key(&self, key_hash: &str) -> Result<GameServer, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .game_servers
+            .iter()
+            .find(|row| row.key_hash == key_hash && row.revoked_at.is_none())
+            .map(GameServer::from)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn rotate_server_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        key_prefix: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state.game_servers.iter_mut().filter(|row| row.name == name) {
+            row.key_hash = key_hash.to_string();
+            row.key_prefix = key_prefix.to_string();
+            row.rotated_at = Some(now);
+            row.revoked_at = None;
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn revoke_server(&self, name: &str, now: i64) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state
+            .game_servers
+            .iter_mut()
+            .filter(|row| row.name == name && row.revoked_at.is_none())
+        {
+            row.revoked_at = Some(now);
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn get_all_server(&self) -> Result<Vec<GameServer>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state.game_servers.iter().map(GameServer::from).collect())
+    }
+}
@@ -0,0 +1,99 @@
+// 游戏服务器管理，路由上要求 server 资源的权限
+
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::lib::{
+    config::ResponseMessage,
+    error::{is_unique_violation, ApiError},
+    validate::{ValidJson, Validate, Validator},
+};
+
+use super::{sql_server::GameServerRepository, ApiKey};
+
+// 服务器名
+#[derive(Debug, serde::Deserialize)]
+pub struct ServerNameRequest {
+    pub name: String,
+}
+
+impl Validate for ServerNameRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("name", &self.name, 64);
+    }
+}
+
+// 新的API密钥，只返回这一次
+#[derive(Serialize)]
+struct ServerKey<'a> {
+    code: i32,
+    name: &'a str,
+    api_key: String,
+}
+
+// 获取所有游戏服务器，路由上要求 server 的 Check 权限
+pub async fn server_get_all(
+    server_repo: web::Data<dyn GameServerRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let servers = server_repo.get_all_server().await?;
+    Ok(HttpResponse::Ok().json(servers))
+}
+
+// 注册游戏服务器并生成API密钥，路由上要求 server 的 Add 权限
+pub async fn server_add(
+    server_repo: web::Data<dyn GameServerRepository>,
+    body: ValidJson<ServerNameRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let key = ApiKey::generate();
+    match server_repo
+        .add_server(&body.name, &key.hash, &key.prefix, Utc::now().timestamp())
+        .await
+    {
+        Ok(_) => {}
+        Err(err) if is_unique_violation(&err) => return Err(ApiError::Conflict("服务器已存在")),
+        Err(err) => return Err(err.into()),
+    }
+    Ok(HttpResponse::Ok().json(ServerKey {
+        code: 200,
+        name: &body.name,
+        api_key: key.key,
+    }))
+}
+
+// 更换API密钥，旧密钥立即失效，路由上要求 server 的 Update 权限
+pub async fn server_rotate_key(
+    server_repo: web::Data<dyn GameServerRepository>,
+    body: ValidJson<ServerNameRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let key = ApiKey::generate();
+    let rotated = server_repo
+        .rotate_server_key(&body.name, &key.hash, &key.prefix, Utc::now().timestamp())
+        .await?;
+    if rotated == 0 {
+        return Err(ApiError::NotFound("服务器不存在"));
+    }
+    Ok(HttpResponse::Ok().json(ServerKey {
+        code: 200,
+        name: &body.name,
+        api_key: key.key,
+    }))
+}
+
+// 吊销API密钥，路由上要求 server 的 Remove 权限
+pub async fn server_revoke(
+    server_repo: web::Data<dyn GameServerRepository>,
+    body: ValidJson<ServerNameRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if server_repo
+        .revoke_server(&body.name, Utc::now().timestamp())
+        .await?
+        == 0
+    {
+        return Err(ApiError::NotFound("服务器不存在或已吊销"));
+    }
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "已吊销",
+    }))
+}
@@ -1,43 +1,13 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use super::config::HttpServerConfig;
-
-#[derive(Debug)]
-pub struct DecryptionError;
-
-impl std::fmt::Display for DecryptionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Decryption error")
-    }
-}
-
-impl std::error::Error for DecryptionError {}
-
-type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
-type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
-//私钥
-const KEY: &[u8; 16] = b"1234567890abcdef";
-//初始化向量
-const IV: &[u8; 16] = b"1234567890abcdef";
-
-pub struct Key {
-    key: [u8; 16],
-    iv: [u8; 16],
-}
-
-use base64::{engine::general_purpose, Engine};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{EncodingKey, Header};
-use jsonwebtoken::{Algorithm, DecodingKey, TokenData, Validation};
+use jsonwebtoken::TokenData;
+use md5::{Digest, Md5};
 use serde::{Deserialize, Serialize};
-#[derive(Debug, Serialize, Deserialize)]
-pub struct UserTimeTokenStruct {
-    user: u64,
-    time: u128,
-}
 
-use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
-use md5::{Digest, Md5};
+use super::{keyring::keyring, user::session::is_session_revoked};
 
 // 生成md5
 pub fn generate_md5_key(text: &str) -> [u8; 16] {
@@ -46,194 +16,121 @@ pub fn generate_md5_key(text: &str) -> [u8; 16] {
     let result = hasher.finalize();
     result.into()
 }
-// md5转换为字符串
-pub fn generate_md5_key_tostr(text: &str) -> String {
-    let mut hasher = Md5::new();
-    hasher.update(text);
-    let result = hasher.finalize();
-    format!("{:x}", result)
-}
-
-impl Default for Key {
-    fn default() -> Self {
-        let config = HttpServerConfig::default();
-        let key = generate_md5_key(&config.name);
-        let iv = generate_md5_key(&config.sql_url);
-        Key { key, iv }
-    }
-}
-
-/// 普通加密
-pub fn encrypt(plain: &[u8]) -> Result<Vec<u8>, DecryptionError> {
-    let iv: [u8; 16] = Key::default().iv;
-    let key: &[u8; 16] = &Key::default().key;
-    // 创建明文缓冲区并复制明文到其中
-    let mut plain_buf: Vec<u8> = Vec::with_capacity(plain.len());
-    plain_buf.extend_from_slice(plain);
-
-    // 创建密文缓冲区
-    let mut ct_buf: Vec<u8> = vec![0u8; plain.len() + 16]; // 初始容量为明文长度 + 16（iv长度）
-
-    // 进行加密
-    let ct = match Aes128CbcEnc::new(key.into(), &iv.into())
-        .encrypt_padded_b2b_mut::<Pkcs7>(&plain_buf, &mut ct_buf)
-    {
-        Ok(pt) => pt,
-        Err(_) => return Err(DecryptionError),
-    };
-    Ok(ct.to_vec())
-}
-
-/// 普通解密
-pub fn decrypt(cipher: &[u8]) -> Result<Vec<u8>, DecryptionError> {
-    let iv: [u8; 16] = Key::default().iv;
-    let key: &[u8; 16] = &Key::default().key;
-
-    // 创建明文缓冲区并复制明文到其中
-    let mut plain_buf: Vec<u8> = Vec::with_capacity(cipher.len());
-    plain_buf.extend_from_slice(cipher);
-
-    // 创建密文缓冲区
-    let mut ct_buf: Vec<u8> = vec![0u8; cipher.len() + 16];
-
-    // 进行解密并处理错误
-    let pt = match Aes128CbcDec::new(key.into(), &iv.into())
-        .decrypt_padded_b2b_mut::<Pkcs7>(cipher, &mut ct_buf)
-    {
-        Ok(pt) => pt,
-        Err(_) => return Err(DecryptionError),
-    };
-    Ok(pt.to_vec())
-}
-
-//生成临时token值
-pub fn generate_token(user: u64) -> Result<String, DecryptionError> {
-    let time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis();
-    let user = UserTimeTokenStruct { user, time };
-    match serde_json::to_string(&user) {
-        Ok(_datestr) => {
-            let encrypt_str = encrypt(_datestr.as_bytes()).unwrap();
-            Ok(general_purpose::STANDARD.encode(&encrypt_str))
-        }
-        Err(_) => Err(DecryptionError),
-    }
-}
-
-//token值验证
-pub fn gettoken_to_user(user_token_text: String) -> Result<u64, DecryptionError> {
-    let bytes = general_purpose::STANDARD.decode(&user_token_text);
-    match bytes {
-        Ok(bytes) => {
-            let decrypt_str = decrypt(&bytes).unwrap();
-            let user_str = String::from_utf8(decrypt_str.to_vec()).unwrap();
-            match serde_json::from_str::<UserTimeTokenStruct>(&user_str) {
-                Ok(user_time_token_struct) => {
-                    let time = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis();
-
-                    let old_time = user_time_token_struct.time;
-                    let _now_time_s = (time - old_time) / 1000; //秒
-                    let _now_time_min = (time - old_time) / (1000 * 60); //分
-                    let now_time_h = (time - old_time) / (1000 * 60 * 60); //小时
-
-                    //token超过十二个小时无效
-                    if now_time_h <= 12 {
-                        Ok(user_time_token_struct.user)
-                    } else {
-                        Err(DecryptionError)
-                    }
-                }
-                Err(_err) => Err(DecryptionError),
-            }
-        }
-        Err(_err) => Err(DecryptionError),
-    }
-}
 
-use jsonwebtoken::decode as json_decode;
-use jsonwebtoken::encode as json_encode;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenUser {
     pub uid: u64,
     pub(crate) exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<i64>, // 会话id，会话被撤销后token失效
 }
 
-pub const TOKEN_KEY_STR: &str = "1234567890abcdef";
 // 解密token值
 pub fn gettoken_to_user_no_time(
     token: &str,
 ) -> Result<TokenData<TokenUser>, jsonwebtoken::errors::Error> {
-    json_decode::<TokenUser>(
-        &token,
-        &DecodingKey::from_secret(TOKEN_KEY_STR.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-}
-
-
-// 为user生成一个token，有效期time秒
-pub fn create_token_time_s(user: u64, time: u128) -> String {
-    json_encode(
-        &Header::default(),
-        &TokenUser {
-            uid: user,
-            exp: (Utc::now() + Duration::seconds(time as i64)).timestamp() as u64,
-        },
-        &EncodingKey::from_secret(TOKEN_KEY_STR.as_ref()),
-    )
-    .unwrap()
+    let token_data = keyring().decode::<TokenUser>(token)?;
+    if token_data.claims.sid.is_some_and(is_session_revoked) {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+    Ok(token_data)
 }
 
-// 为user生成一个token，有效期time分
+// 为user生成一个不绑定会话的token，有效期time分，无法撤销，只用于测试
+#[cfg(test)]
 pub fn create_token_time_min(user: u64, time: u128) -> String {
-    json_encode(
-        &Header::default(),
-        &TokenUser {
+    keyring()
+        .encode(&TokenUser {
             uid: user,
             exp: (Utc::now() + Duration::minutes(time as i64)).timestamp() as u64,
-        },
-        &EncodingKey::from_secret(TOKEN_KEY_STR.as_ref()),
-    )
-    .unwrap()
+            sid: None,
+        })
+        .unwrap()
 }
 
-// 为user生成一个token，有效期time小时
-pub fn create_token_time_h(user: u64, time: u128) -> String {
-    json_encode(
-        &Header::default(),
-        &TokenUser {
+// 为会话生成访问token
+pub fn create_session_token(user: u64, sid: i64, duration: Duration) -> String {
+    keyring()
+        .encode(&TokenUser {
             uid: user,
-            exp: (Utc::now() + Duration::hours(time as i64)).timestamp() as u64,
-        },
-        &EncodingKey::from_secret(TOKEN_KEY_STR.as_ref()),
-    )
-    .unwrap()
+            exp: (Utc::now() + duration).timestamp() as u64,
+            sid: Some(sid),
+        })
+        .unwrap()
 }
 
-// 为user生成一个token，有效期time天
-pub fn create_token_time_day(user: u64, time: u128) -> String {
-    json_encode(
-        &Header::default(),
-        &TokenUser {
-            uid: user,
-            exp: (Utc::now() + Duration::days(time as i64)).timestamp() as u64,
+// 密码校验结果
+#[derive(Debug, PartialEq)]
+pub enum PasswordMatch {
+    Valid,     // 与哈希匹配
+    Plaintext, // 与旧的明文密码匹配，需要升级为哈希
+    Invalid,
+}
+
+// 生成加盐的 argon2id 哈希
+pub fn hash_password_sync(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+// 校验密码，兼容尚未升级的明文密码
+pub fn verify_password_sync(password: &str, stored: &str) -> PasswordMatch {
+    match PasswordHash::new(stored) {
+        Ok(hash) => match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(_) => PasswordMatch::Valid,
+            Err(_) => PasswordMatch::Invalid,
         },
-        &EncodingKey::from_secret(TOKEN_KEY_STR.as_ref()),
-    )
-    .unwrap()
+        Err(_) if !stored.starts_with("$argon2") && stored == password => PasswordMatch::Plaintext,
+        Err(_) => PasswordMatch::Invalid,
+    }
 }
 
+// 哈希计算较慢，放到阻塞线程中执行
+pub async fn hash_password(password: &str) -> String {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || hash_password_sync(&password))
+        .await
+        .unwrap()
+}
+
+pub async fn verify_password(password: &str, stored: &str) -> PasswordMatch {
+    let password = password.to_string();
+    let stored = stored.to_string();
+    tokio::task::spawn_blocking(move || verify_password_sync(&password, &stored))
+        .await
+        .unwrap()
+}
 
 #[tokio::test]
 async fn test_key() {
     let user = 1;
-    let token = create_token_time_h(user, 12);
+    let token = create_token_time_min(user, 5);
     println!("token: {}", token);
     let user = gettoken_to_user_no_time(&token).unwrap();
     assert_eq!(user.claims.uid, 1);
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn test_password_hash() {
+    let hash = hash_password("password").await;
+    assert!(hash.starts_with("$argon2id$"));
+    assert_ne!(hash, hash_password("password").await);
+    assert_eq!(
+        verify_password("password", &hash).await,
+        PasswordMatch::Valid
+    );
+    assert_eq!(
+        verify_password("wrong", &hash).await,
+        PasswordMatch::Invalid
+    );
+    assert_eq!(
+        verify_password("password", "password").await,
+        PasswordMatch::Plaintext
+    );
+    assert_eq!(
+        verify_password("wrong", "password").await,
+        PasswordMatch::Invalid
+    );
+}
@@ -0,0 +1,415 @@
+// token签名密钥
+//
+// 密钥保存在 jwt_config.key_file 中，每个密钥有一个 kid，签发token时写入 header。
+// 轮换时生成新密钥作为签名密钥，旧密钥保留用于验证，直到超过 keep_keys 被移除。
+// 设置环境变量 MCU_JWT_SECRET 时不读取密钥文件，使用该值作为 HS256 密钥，
+// 轮换时可以把旧值放到 MCU_JWT_PREVIOUS_SECRET 中继续验证。
+
+use std::{fs, io::Write, sync::OnceLock};
+
+use actix_web::HttpResponse;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use log::{info, warn};
+use rand::RngCore;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::config::{JwtAlgorithm, JwtConfig};
+
+const SECRET_ENV: &str = "MCU_JWT_SECRET";
+const PREVIOUS_SECRET_ENV: &str = "MCU_JWT_PREVIOUS_SECRET";
+
+// 密钥文件
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    active: String, // 当前用于签名的kid
+    keys: Vec<StoredKey>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct StoredKey {
+    kid: String,
+    created_at: i64,
+    #[serde(flatten)]
+    material: KeyMaterial,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "algorithm")]
+enum KeyMaterial {
+    HS256 { secret: String },      // base64
+    RS256 { private_pem: String }, // pkcs8 pem
+    EdDSA { pkcs8: String },       // pkcs8 der 的 base64
+}
+
+impl StoredKey {
+    // 生成新密钥
+    fn generate(algorithm: &JwtAlgorithm) -> Result<Self, Box<dyn std::error::Error>> {
+        let material = match algorithm {
+            JwtAlgorithm::HS256 => {
+                let mut secret = [0u8; 32];
+                rand::rngs::OsRng.fill_bytes(&mut secret);
+                KeyMaterial::HS256 {
+                    secret: STANDARD.encode(secret),
+                }
+            }
+            JwtAlgorithm::RS256 => {
+                let private = RsaPrivateKey::new(&mut rand::rngs::OsRng, 2048)?;
+                KeyMaterial::RS256 {
+                    private_pem: private.to_pkcs8_pem(LineEnding::LF)?.to_string(),
+                }
+            }
+            JwtAlgorithm::EdDSA => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                    .map_err(|_| "生成 Ed25519 密钥失败")?;
+                KeyMaterial::EdDSA {
+                    pkcs8: STANDARD.encode(pkcs8.as_ref()),
+                }
+            }
+        };
+        let mut kid = [0u8; 8];
+        rand::rngs::OsRng.fill_bytes(&mut kid);
+        Ok(StoredKey {
+            kid: kid.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            created_at: Utc::now().timestamp(),
+            material,
+        })
+    }
+}
+
+// 已加载的密钥
+struct JwtKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    jwk: Option<Value>, // 非对称密钥的公钥
+}
+
+impl JwtKey {
+    fn hs256(kid: String, secret: &[u8]) -> Self {
+        JwtKey {
+            kid,
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            jwk: None,
+        }
+    }
+
+    fn load(stored: &StoredKey) -> Result<Self, Box<dyn std::error::Error>> {
+        let kid = stored.kid.clone();
+        match &stored.material {
+            KeyMaterial::HS256 { secret } => Ok(Self::hs256(kid, &STANDARD.decode(secret)?)),
+            KeyMaterial::RS256 { private_pem } => {
+                let public = RsaPublicKey::from(RsaPrivateKey::from_pkcs8_pem(private_pem)?);
+                let n = URL_SAFE_NO_PAD.encode(public.n().to_bytes_be());
+                let e = URL_SAFE_NO_PAD.encode(public.e().to_bytes_be());
+                Ok(JwtKey {
+                    algorithm: Algorithm::RS256,
+                    encoding: EncodingKey::from_rsa_pem(private_pem.as_bytes())?,
+                    decoding: DecodingKey::from_rsa_components(&n, &e)?,
+                    jwk: Some(json!({
+                        "kty": "RSA",
+                        "use": "sig",
+                        "alg": "RS256",
+                        "kid": kid,
+                        "n": n,
+                        "e": e,
+                    })),
+                    kid,
+                })
+            }
+            KeyMaterial::EdDSA { pkcs8 } => {
+                let pkcs8 = STANDARD.decode(pkcs8)?;
+                let key_pair =
+                    Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| "Ed25519 密钥格式错误")?;
+                let x = URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref());
+                Ok(JwtKey {
+                    algorithm: Algorithm::EdDSA,
+                    encoding: EncodingKey::from_ed_der(&pkcs8),
+                    decoding: DecodingKey::from_ed_components(&x)?,
+                    jwk: Some(json!({
+                        "kty": "OKP",
+                        "crv": "Ed25519",
+                        "use": "sig",
+                        "alg": "EdDSA",
+                        "kid": kid,
+                        "x": x,
+                    })),
+                    kid,
+                })
+            }
+        }
+    }
+}
+
+/// token签名密钥集合
+pub struct KeyRing {
+    active: String,
+    keys: Vec<JwtKey>,
+}
+
+impl KeyRing {
+    fn from_stored(file: &KeyFile) -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = file
+            .keys
+            .iter()
+            .map(JwtKey::load)
+            .collect::<Result<Vec<_>, _>>()?;
+        if !keys.iter().any(|key| key.kid == file.active) {
+            return Err(format!("签名密钥 {} 不存在", file.active).into());
+        }
+        Ok(KeyRing {
+            active: file.active.clone(),
+            keys,
+        })
+    }
+
+    // 由环境变量提供的密钥，kid 取密钥 sha256 的前8位
+    fn from_secrets(secret: &str, previous: Option<&str>) -> Self {
+        let kid =
+            |secret: &str| format!("{:x}", Sha256::digest(secret.as_bytes()))[..8].to_string();
+        let mut keys = vec![JwtKey::hs256(kid(secret), secret.as_bytes())];
+        if let Some(previous) = previous.filter(|previous| !previous.is_empty()) {
+            keys.push(JwtKey::hs256(kid(previous), previous.as_bytes()));
+        }
+        KeyRing {
+            active: kid(secret),
+            keys,
+        }
+    }
+
+    // 进程内的临时密钥，重启后签发的token全部失效
+    fn ephemeral() -> Self {
+        let stored = StoredKey::generate(&JwtAlgorithm::HS256).unwrap();
+        KeyRing::from_stored(&KeyFile {
+            active: stored.kid.clone(),
+            keys: vec![stored],
+        })
+        .unwrap()
+    }
+
+    /// 加载密钥，密钥文件不存在时生成
+    pub fn load(config: &JwtConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Ok(secret) = std::env::var(SECRET_ENV) {
+            if secret.is_empty() {
+                return Err(format!("环境变量 {} 不能为空", SECRET_ENV).into());
+            }
+            info!("使用环境变量 {} 提供的token签名密钥", SECRET_ENV);
+            let previous = std::env::var(PREVIOUS_SECRET_ENV).ok();
+            return Ok(KeyRing::from_secrets(&secret, previous.as_deref()));
+        }
+
+        let file = match fs::read_to_string(&config.key_file) {
+            Ok(contents) => serde_json::from_str::<KeyFile>(&contents)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let stored = StoredKey::generate(&config.algorithm)?;
+                let file = KeyFile {
+                    active: stored.kid.clone(),
+                    keys: vec![stored],
+                };
+                write_key_file(&config.key_file, &file)?;
+                info!("已生成token签名密钥: {}", config.key_file);
+                file
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let keyring = KeyRing::from_stored(&file)?;
+        if keyring.active_key().algorithm != algorithm(&config.algorithm) {
+            warn!(
+                "当前签名密钥的算法与配置的 {:?} 不一致，轮换密钥后生效",
+                config.algorithm
+            );
+        }
+        Ok(keyring)
+    }
+
+    /// 轮换密钥：生成新的签名密钥，保留最近的 keep_keys 个密钥，返回新的kid
+    pub fn rotate(config: &JwtConfig) -> Result<String, Box<dyn std::error::Error>> {
+        let mut keys = match fs::read_to_string(&config.key_file) {
+            Ok(contents) => serde_json::from_str::<KeyFile>(&contents)?.keys,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let stored = StoredKey::generate(&config.algorithm)?;
+        let kid = stored.kid.clone();
+        keys.push(stored);
+        let keep = config.keep_keys.max(1);
+        if keys.len() > keep {
+            keys.drain(..keys.len() - keep);
+        }
+        write_key_file(
+            &config.key_file,
+            &KeyFile {
+                active: kid.clone(),
+                keys,
+            },
+        )?;
+        Ok(kid)
+    }
+
+    fn active_key(&self) -> &JwtKey {
+        self.keys.iter().find(|key| key.kid == self.active).unwrap()
+    }
+
+    /// 使用当前密钥签发token
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let key = self.active_key();
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+        jsonwebtoken::encode(&header, claims, &key.encoding)
+    }
+
+    /// 按 header 中的kid选择密钥验证token
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+    ) -> Result<TokenData<T>, jsonwebtoken::errors::Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = header
+            .kid
+            .and_then(|kid| self.keys.iter().find(|key| key.kid == kid))
+            .ok_or(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat)?;
+        jsonwebtoken::decode(token, &key.decoding, &Validation::new(key.algorithm))
+    }
+
+    /// 非对称密钥的公钥，JWKS 格式
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<&Value> = self
+            .keys
+            .iter()
+            .filter_map(|key| key.jwk.as_ref())
+            .collect();
+        json!({ "keys": keys })
+    }
+}
+
+fn algorithm(algorithm: &JwtAlgorithm) -> Algorithm {
+    match algorithm {
+        JwtAlgorithm::HS256 => Algorithm::HS256,
+        JwtAlgorithm::RS256 => Algorithm::RS256,
+        JwtAlgorithm::EdDSA => Algorithm::EdDSA,
+    }
+}
+
+// 写入密钥文件，仅当前用户可读写
+// 先以 0600 权限创建临时文件写入，再重命名替换，密钥不会以其他权限落盘
+pub(crate) fn write_key_file<T: Serialize>(
+    path: &str,
+    file: &T,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data = serde_json::to_string_pretty(file)?;
+    let tmp_path = format!("{}.tmp", path);
+    // 上次写入中断时留下的临时文件
+    match fs::remove_file(&tmp_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut tmp = options.open(&tmp_path)?;
+    tmp.write_all(data.as_bytes())?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+static KEYRING: OnceLock<KeyRing> = OnceLock::new();
+
+/// 启动时加载密钥
+pub fn init_keyring(config: &JwtConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let keyring = KeyRing::load(config)?;
+    KEYRING
+        .set(keyring)
+        .map_err(|_| "token签名密钥已初始化".into())
+}
+
+/// 获取密钥，未初始化时(如测试中)使用进程内的临时密钥
+pub fn keyring() -> &'static KeyRing {
+    KEYRING.get_or_init(KeyRing::ephemeral)
+}
+
+// 公开的验证公钥，游戏服务器插件可以用它离线验证token
+pub async fn jwks() -> HttpResponse {
+    HttpResponse::Ok().json(keyring().jwks())
+}
+
+#[tokio::test]
+async fn test_keyring() {
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Claims {
+        uid: u64,
+        exp: u64,
+    }
+    let claims = Claims {
+        uid: 1,
+        exp: (Utc::now().timestamp() + 60) as u64,
+    };
+
+    let key_file = std::env::temp_dir().join(format!("mcu_jwt_keys_{}.json", std::process::id()));
+    let mut config = JwtConfig {
+        key_file: key_file.to_string_lossy().to_string(),
+        keep_keys: 2,
+        ..Default::default()
+    };
+
+    // 每种算法都能签发并验证，非对称密钥公开公钥
+    let mut tokens = Vec::new();
+    for jwt_algorithm in [
+        JwtAlgorithm::HS256,
+        JwtAlgorithm::EdDSA,
+        JwtAlgorithm::RS256,
+    ] {
+        config.algorithm = jwt_algorithm;
+        KeyRing::rotate(&config).unwrap();
+        let keyring = KeyRing::load(&config).unwrap();
+        let token = keyring.encode(&claims).unwrap();
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        assert_eq!(header.alg, algorithm(&config.algorithm));
+        assert_eq!(header.kid.unwrap(), keyring.active);
+        assert_eq!(keyring.decode::<Claims>(&token).unwrap().claims, claims);
+        tokens.push(token);
+    }
+    let keyring = KeyRing::load(&config).unwrap();
+    assert_eq!(keyring.keys.len(), 2);
+    assert_eq!(keyring.jwks()["keys"].as_array().unwrap().len(), 2);
+    // 轮换后旧密钥签发的token仍然有效，超过keep_keys的密钥被移除
+    assert!(keyring.decode::<Claims>(&tokens[1]).is_ok());
+    assert!(keyring.decode::<Claims>(&tokens[0]).is_err());
+    // 密钥文件仅当前用户可读写
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&key_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    fs::remove_file(&key_file).unwrap();
+
+    // 环境变量提供的密钥
+    let old = KeyRing::from_secrets("old", None);
+    let new = KeyRing::from_secrets("new", Some("old"));
+    let token = old.encode(&claims).unwrap();
+    assert!(new.decode::<Claims>(&token).is_ok());
+    assert!(KeyRing::from_secrets("new", None)
+        .decode::<Claims>(&token)
+        .is_err());
+    assert!(KeyRing::ephemeral().decode::<Claims>(&token).is_err());
+}
@@ -0,0 +1,270 @@
+// 邮件发送
+//
+// 通过配置 email_config.transport 选择发送方式：
+// smtp 通过SMTP服务器发送；file 每封邮件写入 mail_dir 下的一个 .eml 文件；
+// log 只输出到日志；memory 保存在内存中，供测试读取。
+// 选择 smtp 但未填写SMTP配置时改为输出到日志，开发时无需真实的邮箱账号。
+// 处理函数不直接发送，而是写入发件箱，见 outbox；邮件内容由 template 中的模板生成。
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentType, Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::config::{EmailConfig, MailTransportKind};
+
+pub mod outbox;
+pub mod sql_outbox;
+pub mod template;
+pub mod web_outbox;
+pub mod web_template;
+
+/// 待发送的邮件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub html: String,
+    #[serde(default)]
+    pub text: Option<String>, // 纯文本内容，不支持html的客户端显示
+}
+
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl std::fmt::Display for MailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for MailError {}
+
+/// 邮件发送方式
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError>;
+}
+
+// 按配置创建邮件发送方式
+pub fn create_mail_transport(config: &EmailConfig) -> Result<Arc<dyn MailTransport>, MailError> {
+    let transport: Arc<dyn MailTransport> = match config.transport {
+        MailTransportKind::smtp
+            if config.smtp_server.is_empty()
+                || config.mine_email.is_empty()
+                || config.email_password.is_empty() =>
+        {
+            warn!("未填写SMTP配置，邮件只输出到日志");
+            Arc::new(LogMailTransport)
+        }
+        MailTransportKind::smtp => Arc::new(SmtpMailTransport::new(config)?),
+        MailTransportKind::file => Arc::new(FileMailTransport::new(
+            &config.mail_dir,
+            &config.mine_email,
+        )?),
+        MailTransportKind::log => Arc::new(LogMailTransport),
+        MailTransportKind::memory => Arc::new(MemoryMailTransport::new()),
+    };
+    Ok(transport)
+}
+
+// 转换为 lettre 的邮件
+fn build_message(from: &Mailbox, mail: &Mail) -> Result<Message, MailError> {
+    let to: Mailbox = mail
+        .to
+        .parse()
+        .map_err(|err| MailError(format!("收件人 {} 格式错误: {}", mail.to, err)))?;
+    let builder = Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(mail.subject.as_str());
+    match &mail.text {
+        Some(text) => builder.multipart(MultiPart::alternative_plain_html(
+            text.clone(),
+            mail.html.clone(),
+        )),
+        None => builder
+            .header(ContentType::TEXT_HTML)
+            .body(mail.html.clone()),
+    }
+    .map_err(|err| MailError(err.to_string()))
+}
+
+// 发件人
+fn sender(email: &str) -> Result<Mailbox, MailError> {
+    email
+        .parse()
+        .map_err(|err| MailError(format!("发件人 {} 格式错误: {}", email, err)))
+}
+
+/// 通过SMTP服务器发送
+pub struct SmtpMailTransport {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailTransport {
+    pub fn new(config: &EmailConfig) -> Result<Self, MailError> {
+        let credentials =
+            Credentials::new(config.mine_email.clone(), config.email_password.clone());
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_server)
+            .map_err(|err| MailError(err.to_string()))?
+            .credentials(credentials)
+            .build();
+        Ok(SmtpMailTransport {
+            from: sender(&config.mine_email)?,
+            transport,
+        })
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpMailTransport {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let message = build_message(&self.from, mail)?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|err| MailError(err.to_string()))?;
+        Ok(())
+    }
+}
+
+/// 每封邮件写入目录下的一个 .eml 文件
+pub struct FileMailTransport {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl FileMailTransport {
+    pub fn new(dir: &str, from: &str) -> Result<Self, MailError> {
+        std::fs::create_dir_all(dir)
+            .map_err(|err| MailError(format!("创建邮件目录 {} 失败: {}", dir, err)))?;
+        // 未填写发件人时使用本地地址
+        let from = if from.is_empty() {
+            "noreply@localhost"
+        } else {
+            from
+        };
+        Ok(FileMailTransport {
+            from: sender(from)?,
+            dir: PathBuf::from(dir),
+        })
+    }
+}
+
+#[async_trait]
+impl MailTransport for FileMailTransport {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        let message = build_message(&self.from, mail)?;
+        let name = format!(
+            "{}-{:08x}.eml",
+            chrono::Utc::now().format("%Y%m%d%H%M%S%3f"),
+            rand::random::<u32>()
+        );
+        let path = self.dir.join(name);
+        tokio::fs::write(&path, message.formatted())
+            .await
+            .map_err(|err| MailError(format!("写入 {} 失败: {}", path.display(), err)))?;
+        info!("邮件已写入 {}", path.display());
+        Ok(())
+    }
+}
+
+/// 只输出到日志
+pub struct LogMailTransport;
+
+#[async_trait]
+impl MailTransport for LogMailTransport {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        info!(
+            "邮件 收件人: {} 标题: {}\n{}",
+            mail.to,
+            mail.subject,
+            mail.text.as_deref().unwrap_or(&mail.html)
+        );
+        Ok(())
+    }
+}
+
+/// 保存在内存中，供测试读取
+#[derive(Default)]
+pub struct MemoryMailTransport {
+    mails: Mutex<Vec<Mail>>,
+}
+
+impl MemoryMailTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 已发送的邮件
+    #[cfg(test)]
+    pub fn mails(&self) -> Vec<Mail> {
+        self.mails.lock().unwrap().clone()
+    }
+
+    // 发给该邮箱的最后一封邮件
+    #[cfg(test)]
+    pub fn last_mail(&self, to: &str) -> Option<Mail> {
+        self.mails
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|mail| mail.to == to)
+            .cloned()
+    }
+}
+
+#[async_trait]
+impl MailTransport for MemoryMailTransport {
+    async fn send(&self, mail: &Mail) -> Result<(), MailError> {
+        self.mails.lock().unwrap().push(mail.clone());
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_mail_transport() {
+    let mail = Mail {
+        to: "steve@example.com".to_string(),
+        subject: "测试".to_string(),
+        html: "<p>你好</p>".to_string(),
+        text: Some("你好".to_string()),
+    };
+
+    let memory = MemoryMailTransport::new();
+    memory.send(&mail).await.unwrap();
+    assert_eq!(memory.mails().len(), 1);
+    assert_eq!(
+        memory.last_mail("steve@example.com").unwrap().html,
+        mail.html
+    );
+    assert!(memory.last_mail("alex@example.com").is_none());
+
+    let dir = std::env::temp_dir().join(format!("mcu_mail_{}", rand::random::<u32>()));
+    let file = FileMailTransport::new(dir.to_str().unwrap(), "").unwrap();
+    file.send(&mail).await.unwrap();
+    // 收件人格式错误
+    let invalid = Mail {
+        to: "invalid".to_string(),
+        ..mail.clone()
+    };
+    assert!(file.send(&invalid).await.is_err());
+    let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(files.len(), 1);
+    let eml = std::fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+    assert!(eml.contains("To: steve@example.com"));
+    assert!(eml.contains("multipart/alternative"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,201 @@
+// 邮件发件箱
+//
+// 处理函数只把邮件写入 email_outbox 表，由后台任务发送，不等待SMTP服务器。
+// 发送失败时按 retry_delay * 2^(n-1) 秒后重试，最长 max_retry_delay；
+// 发送 max_attempts 次仍失败时标记为 failed，管理员可以查看并重新发送。
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use log::{error, warn};
+use tokio::sync::Notify;
+
+use crate::lib::config::OutboxConfig;
+
+use super::{sql_outbox::OutboxRepository, Mail, MailTransport};
+
+/// 发件箱，写入后唤醒发送任务
+#[derive(Clone)]
+pub struct Outbox {
+    repo: Arc<dyn OutboxRepository>,
+    notify: Arc<Notify>,
+}
+
+impl Outbox {
+    pub fn new(repo: Arc<dyn OutboxRepository>) -> Self {
+        Outbox {
+            repo,
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    // 写入发件箱，返回邮件id
+    pub async fn send(&self, mail: &Mail) -> Result<i64, sqlx::Error> {
+        let id = self.repo.enqueue_mail(mail, Utc::now().timestamp()).await?;
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    // 唤醒发送任务，例如重新发送失败的邮件后
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    // 启动后台发送任务
+    pub fn start_worker(&self, transport: Arc<dyn MailTransport>, config: OutboxConfig) {
+        let repo = self.repo.clone();
+        let notify = self.notify.clone();
+        let poll_interval = Duration::from_secs(config.poll_interval.max(1));
+        tokio::spawn(async move {
+            loop {
+                let now = Utc::now().timestamp();
+                match deliver_due(repo.as_ref(), transport.as_ref(), &config, now).await {
+                    // 取满一批时可能还有待发送的邮件
+                    Ok(count) if count as i64 >= config.batch_size => continue,
+                    Ok(_) => {}
+                    Err(err) => error!("读取发件箱失败: {}", err),
+                }
+                tokio::select! {
+                    _ = notify.notified() => {}
+                    _ = tokio::time::sleep(poll_interval) => {}
+                }
+            }
+        });
+    }
+}
+
+// 第 attempts 次失败后的等待时间(秒)
+fn retry_delay(config: &OutboxConfig, attempts: i64) -> u64 {
+    let times = (attempts - 1).clamp(0, 32) as u32;
+    config
+        .retry_delay
+        .saturating_mul(1 << times)
+        .min(config.max_retry_delay)
+}
+
+/// 发送已到时间的邮件，返回处理的邮件数
+pub async fn deliver_due(
+    repo: &dyn OutboxRepository,
+    transport: &dyn MailTransport,
+    config: &OutboxConfig,
+    now: i64,
+) -> Result<usize, sqlx::Error> {
+    let mails = repo.get_due_mails(now, config.batch_size).await?;
+    for mail in &mails {
+        match transport.send(&mail.mail()).await {
+            Ok(()) => {
+                repo.mark_mail_sent(mail.id, now).await?;
+            }
+            Err(err) => {
+                let attempts = mail.attempts + 1;
+                let next_attempt_at = (attempts < config.max_attempts)
+                    .then(|| now + retry_delay(config, attempts) as i64);
+                match next_attempt_at {
+                    Some(_) => warn!("邮件 {} 第{}次发送失败: {}", mail.id, attempts, err),
+                    None => error!("邮件 {} 发送失败，不再重试: {}", mail.id, err),
+                }
+                repo.mark_mail_failed(mail.id, attempts, &err.0, next_attempt_at)
+                    .await?;
+            }
+        }
+    }
+    Ok(mails.len())
+}
+
+#[tokio::test]
+async fn test_outbox() {
+    use async_trait::async_trait;
+
+    use super::{sql_outbox::MAIL_FAILED, MailError, MemoryMailTransport};
+    use crate::lib::repository::Repositories;
+
+    // 总是发送失败
+    struct BrokenTransport;
+
+    #[async_trait]
+    impl MailTransport for BrokenTransport {
+        async fn send(&self, _: &Mail) -> Result<(), MailError> {
+            Err(MailError("connection refused".to_string()))
+        }
+    }
+
+    let config = OutboxConfig {
+        max_attempts: 3,
+        retry_delay: 10,
+        max_retry_delay: 15,
+        ..Default::default()
+    };
+    assert_eq!(retry_delay(&config, 1), 10);
+    assert_eq!(retry_delay(&config, 2), 15);
+
+    let repo = Repositories::memory().outbox;
+    let outbox = Outbox::new(repo.clone());
+    let mail = Mail {
+        to: "steve@example.com".to_string(),
+        subject: "测试".to_string(),
+        html: "<p>123456</p>".to_string(),
+        text: None,
+    };
+    let id = outbox.send(&mail).await.unwrap();
+    let now = Utc::now().timestamp();
+
+    // 失败后按退避时间重试，超过次数后进入失败列表
+    let broken = BrokenTransport;
+    assert_eq!(
+        deliver_due(repo.as_ref(), &broken, &config, now)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        deliver_due(repo.as_ref(), &broken, &config, now + 9)
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        deliver_due(repo.as_ref(), &broken, &config, now + 10)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        deliver_due(repo.as_ref(), &broken, &config, now + 25)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        deliver_due(repo.as_ref(), &broken, &config, now + 1000)
+            .await
+            .unwrap(),
+        0
+    );
+    let failed = repo.get_failed_mails().await.unwrap();
+    assert_eq!(failed.len(), 1);
+    assert_eq!((failed[0].id, failed[0].status.as_str()), (id, MAIL_FAILED));
+    assert_eq!(failed[0].attempts, 3);
+    assert_eq!(failed[0].last_error.as_deref(), Some("connection refused"));
+
+    // 重新发送
+    let memory = MemoryMailTransport::new();
+    assert_eq!(repo.retry_mail(id, now).await.unwrap(), 1);
+    assert_eq!(repo.retry_mail(id, now).await.unwrap(), 0);
+    assert_eq!(
+        deliver_due(repo.as_ref(), &memory, &config, now)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        memory.last_mail("steve@example.com").unwrap().html,
+        mail.html
+    );
+    assert!(repo.get_failed_mails().await.unwrap().is_empty());
+    assert_eq!(
+        deliver_due(repo.as_ref(), &memory, &config, now)
+            .await
+            .unwrap(),
+        0
+    );
+}
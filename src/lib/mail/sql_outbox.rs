@@ -0,0 +1,445 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::lib::repository::{
+    memory::OutboxRow, MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+};
+
+use super::Mail;
+
+// 邮件状态
+pub const MAIL_PENDING: &str = "pending"; // 等待发送或重试
+pub const MAIL_SENT: &str = "sent";
+pub const MAIL_FAILED: &str = "failed"; // 超过最多发送次数，不再重试
+
+// 发件箱中的邮件
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct OutboxMail {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    #[serde(skip)]
+    pub html: String, // 可能包含验证码，不返回给接口
+    #[serde(skip)]
+    pub plain_text: Option<String>,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub sent_at: Option<i64>,
+}
+
+impl OutboxMail {
+    pub fn mail(&self) -> Mail {
+        Mail {
+            to: self.recipient.clone(),
+            subject: self.subject.clone(),
+            html: self.html.clone(),
+            text: self.plain_text.clone(),
+        }
+    }
+}
+
+// 邮件发件箱数据仓库
+#[async_trait]
+pub trait OutboxRepository: Send + Sync {
+    // 写入待发送的邮件，返回新记录的id
+    async fn enqueue_mail(&self, mail: &Mail, now: i64) -> Result<i64, sqlx::Error>;
+
+    // 已到发送时间的邮件，按写入顺序
+    async fn get_due_mails(&self, now: i64, limit: i64) -> Result<Vec<OutboxMail>, sqlx::Error>;
+
+    // 发送成功，同时清空邮件内容
+    async fn mark_mail_sent(&self, id: i64, now: i64) -> Result<u64, sqlx::Error>;
+
+    // 发送失败；next_attempt_at 为None时不再重试，标记为 failed
+    async fn mark_mail_failed(
+        &self,
+        id: i64,
+        attempts: i64,
+        error: &str,
+        next_attempt_at: Option<i64>,
+    ) -> Result<u64, sqlx::Error>;
+
+    // admin-获取发送失败的邮件
+    async fn get_failed_mails(&self) -> Result<Vec<OutboxMail>, sqlx::Error>;
+
+    // 重新发送失败的邮件，返回受影响的行数
+    async fn retry_mail(&self, id: i64, now: i64) -> Result<u64, sqlx::Error>;
+}
+
+// 失败后的状态
+fn failed_status(next_attempt_at: Option<i64>) -> &'static str {
+    match next_attempt_at {
+        Some(_) => MAIL_PENDING,
+        None => MAIL_FAILED,
+    }
+}
+
+#[async_trait]
+impl OutboxRepository for SqliteRepository {
+    async fn enqueue_mail(&self, mail: &Mail, now: i64) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO email_outbox (recipient, subject, html, plain_text, status,
+            next_attempt_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(&mail.to)
+            .bind(&mail.subject)
+            .bind(&mail.html)
+            .bind(&mail.text)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn get_due_mails(&self, now: i64, limit: i64) -> Result<Vec<OutboxMail>, sqlx::Error> {
+        let sql = r#"SELECT id, recipient, subject, html, plain_text, status, attempts, next_attempt_at,
+            last_error, created_at, sent_at FROM email_outbox
+            WHERE status = ? AND next_attempt_at <= ? ORDER BY id LIMIT ?"#;
+        sqlx::query_as(sql)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn mark_mail_sent(&self, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = ?, html = '', plain_text = NULL,
+            attempts = attempts + 1, sent_at = ?, last_error = NULL WHERE id = ?"#;
+        let result = sqlx::query(sql)
+            .bind(MAIL_SENT)
+            .bind(now)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn mark_mail_failed(
+        &self,
+        id: i64,
+        attempts: i64,
+        error: &str,
+        next_attempt_at: Option<i64>,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = ?, attempts = ?, last_error = ?,
+            next_attempt_at = COALESCE(?, next_attempt_at) WHERE id = ?"#;
+        let result = sqlx::query(sql)
+            .bind(failed_status(next_attempt_at))
+            .bind(attempts)
+            .bind(error)
+            .bind(next_attempt_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_failed_mails(&self) -> Result<Vec<OutboxMail>, sqlx::Error> {
+        let sql = r#"SELECT id, recipient, subject, html, plain_text, status, attempts, next_attempt_at,
+            last_error, created_at, sent_at FROM email_outbox WHERE status = ? ORDER BY id"#;
+        sqlx::query_as(sql)
+            .bind(MAIL_FAILED)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn retry_mail(&self, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = ?, attempts = 0, next_attempt_at = ?
+            WHERE id = ? AND status = ?"#;
+        let result = sqlx::query(sql)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(id)
+            .bind(MAIL_FAILED)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl OutboxRepository for MySqlRepository {
+    async fn enqueue_mail(&self, mail: &Mail, now: i64) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO email_outbox (recipient, subject, html, plain_text, status,
+            next_attempt_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(&mail.to)
+            .bind(&mail.subject)
+            .bind(&mail.html)
+            .bind(&mail.text)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_id() as i64)
+    }
+
+    async fn get_due_mails(&self, now: i64, limit: i64) -> Result<Vec<OutboxMail>, sqlx::Error> {
+        let sql = r#"SELECT id, recipient, subject, html, plain_text, status, attempts, next_attempt_at,
+            last_error, created_at, sent_at FROM email_outbox
+            WHERE status = ? AND next_attempt_at <= ? ORDER BY id LIMIT ?"#;
+        sqlx::query_as(sql)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn mark_mail_sent(&self, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = ?, html = '', plain_text = NULL,
+            attempts = attempts + 1, sent_at = ?, last_error = NULL WHERE id = ?"#;
+        let result = sqlx::query(sql)
+            .bind(MAIL_SENT)
+            .bind(now)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn mark_mail_failed(
+        &self,
+        id: i64,
+        attempts: i64,
+        error: &str,
+        next_attempt_at: Option<i64>,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = ?, attempts = ?, last_error = ?,
+            next_attempt_at = COALESCE(?, next_attempt_at) WHERE id = ?"#;
+        let result = sqlx::query(sql)
+            .bind(failed_status(next_attempt_at))
+            .bind(attempts)
+            .bind(error)
+            .bind(next_attempt_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_failed_mails(&self) -> Result<Vec<OutboxMail>, sqlx::Error> {
+        let sql = r#"SELECT id, recipient, subject, html, plain_text, status, attempts, next_attempt_at,
+            last_error, created_at, sent_at FROM email_outbox WHERE status = ? ORDER BY id"#;
+        sqlx::query_as(sql)
+            .bind(MAIL_FAILED)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn retry_mail(&self, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = ?, attempts = 0, next_attempt_at = ?
+            WHERE id = ? AND status = ?"#;
+        let result = sqlx::query(sql)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(id)
+            .bind(MAIL_FAILED)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl OutboxRepository for PostgresRepository {
+    async fn enqueue_mail(&self, mail: &Mail, now: i64) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO email_outbox (recipient, subject, html, plain_text, status,
+            next_attempt_at, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id"#;
+        let row = sqlx::query(sql)
+            .bind(&mail.to)
+            .bind(&mail.subject)
+            .bind(&mail.html)
+            .bind(&mail.text)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(now)
+            .fetch_one(&self.pool)
+            .await?;
+        row.try_get("id")
+    }
+
+    async fn get_due_mails(&self, now: i64, limit: i64) -> Result<Vec<OutboxMail>, sqlx::Error> {
+        let sql = r#"SELECT id, recipient, subject, html, plain_text, status, attempts, next_attempt_at,
+            last_error, created_at, sent_at FROM email_outbox
+            WHERE status = $1 AND next_attempt_at <= $2 ORDER BY id LIMIT $3"#;
+        sqlx::query_as(sql)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn mark_mail_sent(&self, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = $1, html = '', plain_text = NULL,
+            attempts = attempts + 1, sent_at = $2, last_error = NULL WHERE id = $3"#;
+        let result = sqlx::query(sql)
+            .bind(MAIL_SENT)
+            .bind(now)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn mark_mail_failed(
+        &self,
+        id: i64,
+        attempts: i64,
+        error: &str,
+        next_attempt_at: Option<i64>,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = $1, attempts = $2, last_error = $3,
+            next_attempt_at = COALESCE($4, next_attempt_at) WHERE id = $5"#;
+        let result = sqlx::query(sql)
+            .bind(failed_status(next_attempt_at))
+            .bind(attempts)
+            .bind(error)
+            .bind(next_attempt_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_failed_mails(&self) -> Result<Vec<OutboxMail>, sqlx::Error> {
+        let sql = r#"SELECT id, recipient, subject, html, plain_text, status, attempts, next_attempt_at,
+            last_error, created_at, sent_at FROM email_outbox WHERE status = $1 ORDER BY id"#;
+        sqlx::query_as(sql)
+            .bind(MAIL_FAILED)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn retry_mail(&self, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE email_outbox SET status = $1, attempts = 0, next_attempt_at = $2
+            WHERE id = $3 AND status = $4"#;
+        let result = sqlx::query(sql)
+            .bind(MAIL_PENDING)
+            .bind(now)
+            .bind(id)
+            .bind(MAIL_FAILED)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+impl From<&OutboxRow> for OutboxMail {
+    fn from(row: &OutboxRow) -> Self {
+        OutboxMail {
+            id: row.id,
+            recipient: row.recipient.clone(),
+            subject: row.subject.clone(),
+            html: row.html.clone(),
+            plain_text: row.plain_text.clone(),
+            status: row.status.clone(),
+            attempts: row.attempts,
+            next_attempt_at: row.next_attempt_at,
+            last_error: row.last_error.clone(),
+            created_at: row.created_at,
+            sent_at: row.sent_at,
+        }
+    }
+}
+
+#[async_trait]
+impl OutboxRepository for MemoryRepository {
+    async fn enqueue_mail(&self, mail: &Mail, now: i64) -> Result<i64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        state.outbox.push(OutboxRow {
+            id,
+            recipient: mail.to.clone(),
+            subject: mail.subject.clone(),
+            html: mail.html.clone(),
+            plain_text: mail.text.clone(),
+            status: MAIL_PENDING.to_string(),
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+            sent_at: None,
+        });
+        Ok(id)
+    }
+
+    async fn get_due_mails(&self, now: i64, limit: i64) -> Result<Vec<OutboxMail>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .outbox
+            .iter()
+            .filter(|row| row.status == MAIL_PENDING && row.next_attempt_at <= now)
+            .take(limit.max(0) as usize)
+            .map(OutboxMail::from)
+            .collect())
+    }
+
+    async fn mark_mail_sent(&self, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state.outbox.iter_mut().filter(|row| row.id == id) {
+            row.status = MAIL_SENT.to_string();
+            row.html.clear();
+            row.plain_text = None;
+            row.attempts += 1;
+            row.sent_at = Some(now);
+            row.last_error = None;
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn mark_mail_failed(
+        &self,
+        id: i64,
+        attempts: i64,
+        error: &str,
+        next_attempt_at: Option<i64>,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state.outbox.iter_mut().filter(|row| row.id == id) {
+            row.status = failed_status(next_attempt_at).to_string();
+            row.attempts = attempts;
+            row.last_error = Some(error.to_string());
+            row.next_attempt_at = next_attempt_at.unwrap_or(row.next_attempt_at);
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn get_failed_mails(&self) -> Result<Vec<OutboxMail>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .outbox
+            .iter()
+            .filter(|row| row.status == MAIL_FAILED)
+            .map(OutboxMail::from)
+            .collect())
+    }
+
+    async fn retry_mail(&self, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state
+            .outbox
+            .iter_mut()
+            .filter(|row| row.id == id && row.status == MAIL_FAILED)
+        {
+            row.status = MAIL_PENDING.to_string();
+            row.attempts = 0;
+            row.next_attempt_at = now;
+            affected += 1;
+        }
+        Ok(affected)
+    }
+}
@@ -0,0 +1,478 @@
+// 邮件模板
+//
+// 模板目录 email_config.template_dir 下按语言分目录，每种邮件一个文件，例如
+// templates/mail/zh-CN/register_code.yml、templates/mail/en/security_alert.yml，
+// 文件包含 subject、html、text 三项，未填写的项使用内置模板。
+// 模板中的 {{变量}} 替换为对应的值，html 中的值会转义；未知的变量原样保留。
+// 依次查找 请求的语言(zh-CN)、语言前缀(zh)、默认语言 的模板文件，都没有时使用内置模板，
+// 内置模板有中文和英文两种。每次发送时读取文件，修改模板无需重启。
+
+use std::path::PathBuf;
+
+use actix_web::{http::header::ACCEPT_LANGUAGE, HttpRequest};
+use build_html::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::lib::config::HttpServerConfig;
+
+use super::Mail;
+
+/// 邮件用途，同时是模板文件名
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum MailTemplateKind {
+    register_code,  // 注册验证码
+    reset_password, // 重置密码验证码
+    change_email,   // 更换邮箱验证码
+    delete_account, // 注销账号验证码
+    email_changed,  // 邮箱已更换，发送到旧邮箱，包含撤销链接
+    security_alert, // 账号安全提醒
+}
+
+impl MailTemplateKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MailTemplateKind::register_code => "register_code",
+            MailTemplateKind::reset_password => "reset_password",
+            MailTemplateKind::change_email => "change_email",
+            MailTemplateKind::delete_account => "delete_account",
+            MailTemplateKind::email_changed => "email_changed",
+            MailTemplateKind::security_alert => "security_alert",
+        }
+    }
+
+    // 预览时使用的示例变量
+    pub fn sample_vars(&self) -> Vec<(&'static str, String)> {
+        let code = vec![
+            ("code", "123456".to_string()),
+            ("expires_minutes", "5".to_string()),
+        ];
+        match self {
+            MailTemplateKind::register_code
+            | MailTemplateKind::reset_password
+            | MailTemplateKind::delete_account => code,
+            MailTemplateKind::change_email => {
+                let mut vars = code;
+                vars.push(("new_email", "steve@example.com".to_string()));
+                vars
+            }
+            MailTemplateKind::email_changed => vec![
+                ("new_email", "steve@example.com".to_string()),
+                (
+                    "cancel_url",
+                    "http://localhost:2024/api/user/change_email/cancel?token=example".to_string(),
+                ),
+                ("expires_hours", "72".to_string()),
+            ],
+            MailTemplateKind::security_alert => vec![
+                ("event", "登录".to_string()),
+                ("ip", "127.0.0.1".to_string()),
+                (
+                    "time",
+                    chrono::Utc::now()
+                        .format("%Y-%m-%d %H:%M:%S UTC")
+                        .to_string(),
+                ),
+            ],
+        }
+    }
+}
+
+/// 模板文件的内容
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MailTemplate {
+    pub subject: String,
+    pub html: String,
+    pub text: String,
+}
+
+impl MailTemplate {
+    // 替换变量
+    pub fn render(&self, to: &str, vars: &[(&str, &str)]) -> Mail {
+        let text = substitute(&self.text, vars, false);
+        Mail {
+            to: to.to_string(),
+            subject: substitute(&self.subject, vars, false),
+            html: substitute(&self.html, vars, true),
+            text: (!text.is_empty()).then_some(text),
+        }
+    }
+}
+
+/// 按用途和语言查找模板
+#[derive(Debug, Clone)]
+pub struct MailTemplates {
+    dir: PathBuf,
+    default_locale: String,
+    server_name: String, // 所有模板都可以使用 {{server_name}}
+}
+
+impl MailTemplates {
+    pub fn new(config: &HttpServerConfig) -> Self {
+        MailTemplates {
+            dir: PathBuf::from(&config.email_config.template_dir),
+            default_locale: config.email_config.default_locale.clone(),
+            server_name: config.name.clone(),
+        }
+    }
+
+    // 依次尝试的语言
+    fn locales(&self, locale: Option<&str>) -> Vec<String> {
+        let mut locales: Vec<String> = Vec::new();
+        for locale in [locale, Some(self.default_locale.as_str())]
+            .into_iter()
+            .flatten()
+            .filter(|locale| is_valid_locale(locale))
+        {
+            let language = locale.split(['-', '_']).next().unwrap_or(locale);
+            for candidate in [locale, language] {
+                if !locales.iter().any(|l| l.eq_ignore_ascii_case(candidate)) {
+                    locales.push(candidate.to_string());
+                }
+            }
+        }
+        locales
+    }
+
+    /// 查找模板，返回模板和来源(文件路径或 builtin:语言)
+    pub async fn load(
+        &self,
+        kind: MailTemplateKind,
+        locale: Option<&str>,
+    ) -> (MailTemplate, String) {
+        let locales = self.locales(locale);
+        for locale in &locales {
+            let path = self.dir.join(locale).join(format!("{}.yml", kind.name()));
+            let Ok(content) = tokio::fs::read_to_string(&path).await else {
+                continue;
+            };
+            match serde_yaml::from_str::<MailTemplate>(&content) {
+                Ok(template) => {
+                    let builtin = builtin(kind, locale);
+                    let or_builtin = |value: String, default: String| {
+                        if value.trim().is_empty() {
+                            default
+                        } else {
+                            value
+                        }
+                    };
+                    let template = MailTemplate {
+                        subject: or_builtin(template.subject, builtin.subject),
+                        html: or_builtin(template.html, builtin.html),
+                        text: or_builtin(template.text, builtin.text),
+                    };
+                    return (template, path.display().to_string());
+                }
+                Err(err) => warn!("邮件模板 {} 格式错误: {}", path.display(), err),
+            }
+        }
+        // 没有模板文件时使用第一个有内置模板的语言
+        let language = locales
+            .iter()
+            .map(|locale| builtin_language(locale))
+            .find(|language| language.is_some())
+            .flatten()
+            .unwrap_or("zh");
+        (builtin(kind, language), format!("builtin:{}", language))
+    }
+
+    /// 生成邮件，vars 之外自动加入 server_name
+    pub async fn render(
+        &self,
+        kind: MailTemplateKind,
+        locale: Option<&str>,
+        to: &str,
+        vars: &[(&str, &str)],
+    ) -> Mail {
+        let (template, _) = self.load(kind, locale).await;
+        let mut vars = vars.to_vec();
+        vars.push(("server_name", &self.server_name));
+        template.render(to, &vars)
+    }
+
+    // 使用示例变量生成邮件，返回邮件和模板来源
+    pub async fn preview(&self, kind: MailTemplateKind, locale: Option<&str>) -> (Mail, String) {
+        let (template, source) = self.load(kind, locale).await;
+        let sample = kind.sample_vars();
+        let mut vars: Vec<(&str, &str)> = sample.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        vars.push(("server_name", &self.server_name));
+        (template.render("steve@example.com", &vars), source)
+    }
+}
+
+// 语言只允许字母、数字、- 和 _，避免读取模板目录以外的文件
+pub fn is_valid_locale(locale: &str) -> bool {
+    (1..=16).contains(&locale.len())
+        && locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// 请求头 Accept-Language 中的首选语言
+pub fn request_locale(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get(ACCEPT_LANGUAGE)?.to_str().ok()?;
+    let locale = header.split(',').next()?.split(';').next()?.trim();
+    is_valid_locale(locale).then(|| locale.to_string())
+}
+
+// 替换 {{变量}}，escape 为 true 时转义html
+fn substitute(template: &str, vars: &[(&str, &str)], escape: bool) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let placeholder = &rest[start..start + len + 2];
+        let name = placeholder[2..placeholder.len() - 2].trim();
+        match vars.iter().rev().find(|(key, _)| *key == name) {
+            Some((_, value)) if escape => result.push_str(&escape_html(value)),
+            Some((_, value)) => result.push_str(value),
+            None => result.push_str(placeholder),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// 内置模板的语言
+fn builtin_language(locale: &str) -> Option<&'static str> {
+    match locale.split(['-', '_']).next() {
+        Some(language) if language.eq_ignore_ascii_case("zh") => Some("zh"),
+        Some(language) if language.eq_ignore_ascii_case("en") => Some("en"),
+        _ => None,
+    }
+}
+
+// 内置模板，没有对应语言时使用中文
+fn builtin(kind: MailTemplateKind, locale: &str) -> MailTemplate {
+    let english = builtin_language(locale) == Some("en");
+    let greeting = if english {
+        "Hello,"
+    } else {
+        "亲爱的玩家你好！"
+    };
+    let (subject, title, body) = match (kind, english) {
+        (MailTemplateKind::register_code, false) => (
+            "{{server_name}}邮箱验证码",
+            "邮箱验证码",
+            "你的验证码是：{{code}},请在 {{expires_minutes}} 分钟内进行验证。如果该验证码不为您本人申请，请无视。",
+        ),
+        (MailTemplateKind::register_code, true) => (
+            "{{server_name}} verification code",
+            "Verification code",
+            "Your verification code is {{code}}. It expires in {{expires_minutes}} minutes. If you did not request it, please ignore this email.",
+        ),
+        (MailTemplateKind::reset_password, false) => (
+            "{{server_name}}重置密码",
+            "重置密码",
+            "你正在重置密码，验证码是：{{code}},请在 {{expires_minutes}} 分钟内进行验证。如果不是您本人操作，请无视，您的密码不会被修改。",
+        ),
+        (MailTemplateKind::reset_password, true) => (
+            "{{server_name}} password reset",
+            "Password reset",
+            "Your password reset code is {{code}}. It expires in {{expires_minutes}} minutes. If you did not request it, please ignore this email and your password will stay unchanged.",
+        ),
+        (MailTemplateKind::change_email, false) => (
+            "{{server_name}}更换邮箱",
+            "更换邮箱",
+            "你正在将账号邮箱更换为 {{new_email}}，验证码是：{{code}},请在 {{expires_minutes}} 分钟内进行验证。如果不是您本人操作，请尽快修改密码。",
+        ),
+        (MailTemplateKind::change_email, true) => (
+            "{{server_name}} email change",
+            "Email change",
+            "You are changing your account email to {{new_email}}. Your verification code is {{code}}. It expires in {{expires_minutes}} minutes. If this was not you, please change your password immediately.",
+        ),
+        (MailTemplateKind::delete_account, false) => (
+            "{{server_name}}注销账号",
+            "注销账号",
+            "你正在注销账号，验证码是：{{code}},请在 {{expires_minutes}} 分钟内进行验证。注销后账号将被删除且无法恢复。如果不是您本人操作，请尽快修改密码。",
+        ),
+        (MailTemplateKind::delete_account, true) => (
+            "{{server_name}} account deletion",
+            "Account deletion",
+            "You are deleting your account. Your verification code is {{code}}. It expires in {{expires_minutes}} minutes. A deleted account cannot be restored. If this was not you, please change your password immediately.",
+        ),
+        (MailTemplateKind::email_changed, false) => (
+            "{{server_name}}账号邮箱已更换",
+            "账号邮箱已更换",
+            "你的账号邮箱已更换为 {{new_email}}，之后请使用新邮箱登录。如果不是您本人操作，请在 {{expires_hours}} 小时内打开以下链接撤销更换：{{cancel_url}}",
+        ),
+        (MailTemplateKind::email_changed, true) => (
+            "{{server_name}} email changed",
+            "Email changed",
+            "Your account email has been changed to {{new_email}}. Please use the new email to log in. If this was not you, open the following link within {{expires_hours}} hours to undo the change: {{cancel_url}}",
+        ),
+        (MailTemplateKind::security_alert, false) => (
+            "{{server_name}}账号安全提醒",
+            "账号安全提醒",
+            "你的账号于 {{time}} 进行了以下操作：{{event}}，ip：{{ip}}。如果不是您本人操作，请尽快修改密码。",
+        ),
+        (MailTemplateKind::security_alert, true) => (
+            "{{server_name}} security alert",
+            "Security alert",
+            "The following action was performed on your account at {{time}}: {{event}}, ip: {{ip}}. If this was not you, please change your password immediately.",
+        ),
+    };
+    MailTemplate {
+        subject: subject.to_string(),
+        html: default_html(title, greeting, body),
+        text: format!("{}\n\n{}\n\n{{{{server_name}}}}\n", greeting, body),
+    }
+}
+
+// 内置模板的html
+fn default_html(title: &str, greeting: &str, body: &str) -> String {
+    let msg = format!(
+        r#"<table width="500" border="0" align="center" cellpadding="0" cellspacing="0">
+    <div class="inner-div">
+        <table width="100%" border="0" cellspacing="0" cellpadding="0">
+            <td class="dynamic-machine-td" valign="middle">
+            {{{{server_name}}}}
+            </td>
+            <body>
+                <tr class="spacer-tr">
+                    <td class="email-verification-code-td">
+                        {}
+                    </td>
+                </tr>
+                <tr>
+                    <td class="user-greeting-td">
+
+                    <br>
+                        {}
+</br>
+                        {}
+                    </td>
+                </tr>
+                <tr class="spacer-tr"></tr>
+            </body>
+        </table>
+    </div>
+</table>"#,
+        title, greeting, body
+    );
+
+    HtmlPage::new()
+        .with_style(
+            r#".outer-div {
+        background: #eee;
+    }
+    .inner-div {
+        background: #fff;
+    }
+    .dynamic-machine-td {
+        padding-left: 30px;
+        background-color: #415a94;
+        color: #fff;
+        padding: 20px 40px;
+        font-size: 21px;
+    }
+    .email-verification-code-td {
+        font-size: 24px;
+        line-height: 1.5;
+        color: #000;
+        margin-top: 40px;
+    }
+    .user-greeting-td {
+        font-size: 14px;
+        color: #333;
+        padding: 24px 40px 0 40px;
+    }
+    .spacer-tr {
+        padding: 40px;
+        display: table-cell;
+    }"#,
+        )
+        .with_container(
+            Container::new(ContainerType::Div)
+                .with_attributes([("class", "outer-div")])
+                .with_raw(msg),
+        )
+        .to_html_string()
+}
+
+#[tokio::test]
+async fn test_mail_templates() {
+    let dir = std::env::temp_dir().join(format!("mcu_templates_{}", rand::random::<u32>()));
+    let config = HttpServerConfig {
+        name: "MCU".to_string(),
+        ..Default::default()
+    };
+    let mut templates = MailTemplates::new(&config);
+    templates.dir = dir.clone();
+    let vars = [("code", "123456"), ("expires_minutes", "5")];
+
+    // 没有模板文件时使用内置模板
+    let mail = templates
+        .render(
+            MailTemplateKind::register_code,
+            None,
+            "steve@example.com",
+            &vars,
+        )
+        .await;
+    assert_eq!(mail.subject, "MCU邮箱验证码");
+    assert!(mail.html.contains("123456") && !mail.html.contains("{{"));
+    assert!(mail.text.unwrap().contains("123456"));
+    let (_, source) = templates
+        .load(MailTemplateKind::register_code, Some("en-US"))
+        .await;
+    assert_eq!(source, "builtin:en");
+    let (_, source) = templates
+        .load(MailTemplateKind::register_code, Some("fr"))
+        .await;
+    assert_eq!(source, "builtin:zh");
+
+    // 模板文件覆盖内置模板，未填写的项使用内置模板
+    std::fs::create_dir_all(dir.join("en")).unwrap();
+    std::fs::write(
+        dir.join("en").join("change_email.yml"),
+        "subject: \"{{server_name}}: change to {{new_email}}\"\nhtml: \"<p>{{new_email}} {{unknown}}</p>\"\n",
+    )
+    .unwrap();
+    let vars = [("new_email", "<a@x.com>"), ("code", "654321")];
+    let mail = templates
+        .render(
+            MailTemplateKind::change_email,
+            Some("en-GB"),
+            "a@x.com",
+            &vars,
+        )
+        .await;
+    assert_eq!(mail.subject, "MCU: change to <a@x.com>");
+    assert_eq!(mail.html, "<p>&lt;a@x.com&gt; {{unknown}}</p>");
+    assert!(mail.text.unwrap().contains("654321"));
+
+    assert_eq!(
+        substitute("{{ code }} {{code", &vars, false),
+        "654321 {{code"
+    );
+
+    // 语言不能用于访问模板目录以外的文件
+    assert!(!is_valid_locale("../en"));
+    let (_, source) = templates
+        .load(MailTemplateKind::change_email, Some("../en"))
+        .await;
+    assert_eq!(source, "builtin:zh");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
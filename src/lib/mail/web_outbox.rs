@@ -0,0 +1,52 @@
+// 发件箱管理，路由上要求 mail 资源的权限
+
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+
+use crate::lib::{
+    config::ResponseMessage,
+    error::ApiError,
+    validate::{ValidJson, Validate, Validator},
+};
+
+use super::{outbox::Outbox, sql_outbox::OutboxRepository};
+
+// 邮件id
+#[derive(Debug, serde::Deserialize)]
+pub struct MailIdRequest {
+    pub id: i64,
+}
+
+impl Validate for MailIdRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.check("id", self.id > 0, "邮件id无效");
+    }
+}
+
+// 获取发送失败的邮件，路由上要求 mail 的 Check 权限
+pub async fn outbox_failed(
+    outbox_repo: web::Data<dyn OutboxRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let mails = outbox_repo.get_failed_mails().await?;
+    Ok(HttpResponse::Ok().json(mails))
+}
+
+// 重新发送失败的邮件，路由上要求 mail 的 Update 权限
+pub async fn outbox_retry(
+    outbox_repo: web::Data<dyn OutboxRepository>,
+    outbox: web::Data<Outbox>,
+    body: ValidJson<MailIdRequest>,
+) -> Result<HttpResponse, ApiError> {
+    if outbox_repo
+        .retry_mail(body.id, Utc::now().timestamp())
+        .await?
+        == 0
+    {
+        return Err(ApiError::NotFound("邮件不存在或未发送失败"));
+    }
+    outbox.wake();
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "已重新加入发送队列",
+    }))
+}
@@ -0,0 +1,52 @@
+// 邮件模板预览，路由上要求 mail 资源的权限
+
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+use crate::lib::{
+    error::ApiError,
+    validate::{ValidQuery, Validate, Validator},
+};
+
+use super::template::{is_valid_locale, MailTemplateKind, MailTemplates};
+
+// 预览的模板和语言
+#[derive(Debug, serde::Deserialize)]
+pub struct PreviewQuery {
+    pub name: MailTemplateKind,
+    pub locale: Option<String>,
+}
+
+impl Validate for PreviewQuery {
+    fn validate(&self, v: &mut Validator) {
+        v.check(
+            "locale",
+            self.locale.as_deref().is_none_or(is_valid_locale),
+            "语言格式错误",
+        );
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplatePreview {
+    pub name: &'static str,
+    pub source: String, // 模板文件路径，或 builtin:语言
+    pub subject: String,
+    pub html: String,
+    pub text: Option<String>,
+}
+
+// 使用示例数据生成邮件，路由上要求 mail 的 Check 权限
+pub async fn template_preview(
+    templates: web::Data<MailTemplates>,
+    query: ValidQuery<PreviewQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (mail, source) = templates.preview(query.name, query.locale.as_deref()).await;
+    Ok(HttpResponse::Ok().json(TemplatePreview {
+        name: query.name.name(),
+        source,
+        subject: mail.subject,
+        html: mail.html,
+        text: mail.text,
+    }))
+}
@@ -0,0 +1,666 @@
+// 数据库迁移
+//
+// 每个迁移拥有递增的版本号，并分别为 sqlite、mysql、postgres 提供建表/改表语句。
+// 已执行的版本记录在 schema_version 表中，启动时自动执行尚未执行的迁移。
+// 新增字段或表时只能追加新的迁移，不能修改已发布的迁移。
+
+use chrono::Utc;
+use log::info;
+use sqlx::Row;
+
+use super::config::DbPool;
+
+/// 单个迁移
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sqlite: &'static [&'static str],
+    pub mysql: &'static [&'static str],
+    pub postgres: &'static [&'static str],
+}
+
+impl Migration {
+    fn statements(&self, pool: &DbPool) -> &'static [&'static str] {
+        match pool {
+            DbPool::Sqlite(_) => self.sqlite,
+            DbPool::Mysql(_) => self.mysql,
+            DbPool::Postgres(_) => self.postgres,
+        }
+    }
+}
+
+/// 所有迁移，按版本号升序排列
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create user, java_player, acl and resource tables",
+        sqlite: &[
+            r#"CREATE TABLE IF NOT EXISTS user (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email TEXT NOT NULL UNIQUE,
+                password TEXT NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS java_player (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                uid INTEGER NOT NULL,
+                name TEXT NOT NULL UNIQUE,
+                password TEXT NOT NULL UNIQUE,
+                player_id TEXT NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS acl (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                uid INTEGER NOT NULL,
+                resource_id INTEGER NOT NULL,
+                operation TEXT NOT NULL,
+                UNIQUE(uid, resource_id, operation)
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS resource (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE
+            )"#,
+        ],
+        mysql: &[
+            r#"CREATE TABLE IF NOT EXISTS user (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                email VARCHAR(255) NOT NULL UNIQUE,
+                password VARCHAR(255) NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS java_player (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                uid BIGINT NOT NULL,
+                name VARCHAR(64) NOT NULL UNIQUE,
+                password VARCHAR(255) NOT NULL UNIQUE,
+                player_id VARCHAR(64) NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS acl (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                uid BIGINT NOT NULL,
+                resource_id BIGINT NOT NULL,
+                operation VARCHAR(64) NOT NULL,
+                UNIQUE(uid, resource_id, operation)
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS resource (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                name VARCHAR(255) NOT NULL UNIQUE
+            )"#,
+        ],
+        postgres: &[
+            r#"CREATE TABLE IF NOT EXISTS "user" (
+                id BIGSERIAL PRIMARY KEY,
+                email TEXT NOT NULL UNIQUE,
+                password TEXT NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS java_player (
+                id BIGSERIAL PRIMARY KEY,
+                uid BIGINT NOT NULL,
+                name TEXT NOT NULL UNIQUE,
+                password TEXT NOT NULL UNIQUE,
+                player_id TEXT NOT NULL
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS acl (
+                id BIGSERIAL PRIMARY KEY,
+                uid BIGINT NOT NULL,
+                resource_id BIGINT NOT NULL,
+                operation TEXT NOT NULL,
+                UNIQUE(uid, resource_id, operation)
+            )"#,
+            r#"CREATE TABLE IF NOT EXISTS resource (
+                id BIGSERIAL PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE
+            )"#,
+        ],
+    },
+    // user 是 postgres 的保留字，统一改名为 users
+    Migration {
+        version: 2,
+        description: "rename user table to users",
+        sqlite: &[r#"ALTER TABLE user RENAME TO users"#],
+        mysql: &[r#"RENAME TABLE user TO users"#],
+        postgres: &[r#"ALTER TABLE "user" RENAME TO users"#],
+    },
+    // 快捷密码改为加盐哈希保存，不再具有唯一性
+    Migration {
+        version: 3,
+        description: "drop unique constraint on java_player.password",
+        sqlite: &[
+            r#"CREATE TABLE java_player_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                uid INTEGER NOT NULL,
+                name TEXT NOT NULL UNIQUE,
+                password TEXT NOT NULL,
+                player_id TEXT NOT NULL
+            )"#,
+            r#"INSERT INTO java_player_new (id, uid, name, password, player_id)
+                SELECT id, uid, name, password, player_id FROM java_player"#,
+            r#"DROP TABLE java_player"#,
+            r#"ALTER TABLE java_player_new RENAME TO java_player"#,
+        ],
+        mysql: &[r#"ALTER TABLE java_player DROP INDEX password"#],
+        postgres: &[r#"ALTER TABLE java_player DROP CONSTRAINT java_player_password_key"#],
+    },
+    // 登录会话，保存刷新token的sha256
+    Migration {
+        version: 4,
+        description: "create sessions table",
+        sqlite: &[
+            r#"CREATE TABLE sessions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                uid INTEGER NOT NULL,
+                refresh_hash TEXT NOT NULL UNIQUE,
+                previous_hash TEXT,
+                ip TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                last_used_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                revoked_at INTEGER
+            )"#,
+            r#"CREATE INDEX sessions_uid ON sessions (uid)"#,
+        ],
+        mysql: &[
+            r#"CREATE TABLE sessions (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                uid BIGINT NOT NULL,
+                refresh_hash VARCHAR(64) NOT NULL UNIQUE,
+                previous_hash VARCHAR(64),
+                ip VARCHAR(64) NOT NULL,
+                created_at BIGINT NOT NULL,
+                last_used_at BIGINT NOT NULL,
+                expires_at BIGINT NOT NULL,
+                revoked_at BIGINT
+            )"#,
+            r#"CREATE INDEX sessions_uid ON sessions (uid)"#,
+        ],
+        postgres: &[
+            r#"CREATE TABLE sessions (
+                id BIGSERIAL PRIMARY KEY,
+                uid BIGINT NOT NULL,
+                refresh_hash TEXT NOT NULL UNIQUE,
+                previous_hash TEXT,
+                ip TEXT NOT NULL,
+                created_at BIGINT NOT NULL,
+                last_used_at BIGINT NOT NULL,
+                expires_at BIGINT NOT NULL,
+                revoked_at BIGINT
+            )"#,
+            r#"CREATE INDEX sessions_uid ON sessions (uid)"#,
+        ],
+    },
+    // 两步验证，密钥加密保存，恢复码只保存sha256
+    Migration {
+        version: 5,
+        description: "create totp and recovery_codes tables",
+        sqlite: &[
+            r#"CREATE TABLE totp (
+                uid INTEGER PRIMARY KEY,
+                secret TEXT NOT NULL,
+                last_step INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                enabled_at INTEGER
+            )"#,
+            r#"CREATE TABLE recovery_codes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                uid INTEGER NOT NULL,
+                code_hash TEXT NOT NULL,
+                used_at INTEGER
+            )"#,
+            r#"CREATE INDEX recovery_codes_uid ON recovery_codes (uid)"#,
+        ],
+        mysql: &[
+            r#"CREATE TABLE totp (
+                uid BIGINT PRIMARY KEY,
+                secret VARCHAR(255) NOT NULL,
+                last_step BIGINT NOT NULL,
+                created_at BIGINT NOT NULL,
+                enabled_at BIGINT
+            )"#,
+            r#"CREATE TABLE recovery_codes (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                uid BIGINT NOT NULL,
+                code_hash VARCHAR(64) NOT NULL,
+                used_at BIGINT
+            )"#,
+            r#"CREATE INDEX recovery_codes_uid ON recovery_codes (uid)"#,
+        ],
+        postgres: &[
+            r#"CREATE TABLE totp (
+                uid BIGINT PRIMARY KEY,
+                secret TEXT NOT NULL,
+                last_step BIGINT NOT NULL,
+                created_at BIGINT NOT NULL,
+                enabled_at BIGINT
+            )"#,
+            r#"CREATE TABLE recovery_codes (
+                id BIGSERIAL PRIMARY KEY,
+                uid BIGINT NOT NULL,
+                code_hash TEXT NOT NULL,
+                used_at BIGINT
+            )"#,
+            r#"CREATE INDEX recovery_codes_uid ON recovery_codes (uid)"#,
+        ],
+    },
+    // 游戏服务器及其API密钥的sha256
+    Migration {
+        version: 6,
+        description: "create game_servers table",
+        sqlite: &[r#"CREATE TABLE game_servers (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE,
+                key_hash TEXT NOT NULL UNIQUE,
+                key_prefix TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                rotated_at INTEGER,
+                revoked_at INTEGER
+            )"#],
+        mysql: &[r#"CREATE TABLE game_servers (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                name VARCHAR(64) NOT NULL UNIQUE,
+                key_hash VARCHAR(64) NOT NULL UNIQUE,
+                key_prefix VARCHAR(16) NOT NULL,
+                created_at BIGINT NOT NULL,
+                rotated_at BIGINT,
+                revoked_at BIGINT
+            )"#],
+        postgres: &[r#"CREATE TABLE game_servers (
+                id BIGSERIAL PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                key_hash TEXT NOT NULL UNIQUE,
+                key_prefix TEXT NOT NULL,
+                created_at BIGINT NOT NULL,
+                rotated_at BIGINT,
+                revoked_at BIGINT
+            )"#],
+    },
+    // 邮件发件箱，后台任务发送并在失败后重试
+    Migration {
+        version: 7,
+        description: "create email_outbox table",
+        sqlite: &[
+            r#"CREATE TABLE email_outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                recipient TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                html TEXT NOT NULL,
+                status TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                created_at INTEGER NOT NULL,
+                sent_at INTEGER
+            )"#,
+            r#"CREATE INDEX email_outbox_status ON email_outbox (status, next_attempt_at)"#,
+        ],
+        mysql: &[
+            r#"CREATE TABLE email_outbox (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                recipient VARCHAR(254) NOT NULL,
+                subject VARCHAR(255) NOT NULL,
+                html MEDIUMTEXT NOT NULL,
+                status VARCHAR(16) NOT NULL,
+                attempts BIGINT NOT NULL DEFAULT 0,
+                next_attempt_at BIGINT NOT NULL,
+                last_error TEXT,
+                created_at BIGINT NOT NULL,
+                sent_at BIGINT
+            )"#,
+            r#"CREATE INDEX email_outbox_status ON email_outbox (status, next_attempt_at)"#,
+        ],
+        postgres: &[
+            r#"CREATE TABLE email_outbox (
+                id BIGSERIAL PRIMARY KEY,
+                recipient TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                html TEXT NOT NULL,
+                status TEXT NOT NULL,
+                attempts BIGINT NOT NULL DEFAULT 0,
+                next_attempt_at BIGINT NOT NULL,
+                last_error TEXT,
+                created_at BIGINT NOT NULL,
+                sent_at BIGINT
+            )"#,
+            r#"CREATE INDEX email_outbox_status ON email_outbox (status, next_attempt_at)"#,
+        ],
+    },
+    // 邮件的纯文本内容
+    Migration {
+        version: 8,
+        description: "add plain_text to email_outbox",
+        sqlite: &[r#"ALTER TABLE email_outbox ADD COLUMN plain_text TEXT"#],
+        mysql: &[r#"ALTER TABLE email_outbox ADD COLUMN plain_text MEDIUMTEXT"#],
+        postgres: &[r#"ALTER TABLE email_outbox ADD COLUMN plain_text TEXT"#],
+    },
+    // 按用途保存的邮箱验证码，只保存sha256
+    Migration {
+        version: 9,
+        description: "create verification_codes table",
+        sqlite: &[
+            r#"CREATE TABLE verification_codes (
+                email TEXT NOT NULL,
+                purpose TEXT NOT NULL,
+                code_hash TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                expires_at INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (email, purpose)
+            )"#,
+            r#"CREATE INDEX verification_codes_expires_at ON verification_codes (expires_at)"#,
+        ],
+        mysql: &[
+            r#"CREATE TABLE verification_codes (
+                email VARCHAR(254) NOT NULL,
+                purpose VARCHAR(32) NOT NULL,
+                code_hash VARCHAR(64) NOT NULL,
+                attempts BIGINT NOT NULL DEFAULT 0,
+                expires_at BIGINT NOT NULL,
+                created_at BIGINT NOT NULL,
+                PRIMARY KEY (email, purpose)
+            )"#,
+            r#"CREATE INDEX verification_codes_expires_at ON verification_codes (expires_at)"#,
+        ],
+        postgres: &[
+            r#"CREATE TABLE verification_codes (
+                email TEXT NOT NULL,
+                purpose TEXT NOT NULL,
+                code_hash TEXT NOT NULL,
+                attempts BIGINT NOT NULL DEFAULT 0,
+                expires_at BIGINT NOT NULL,
+                created_at BIGINT NOT NULL,
+                PRIMARY KEY (email, purpose)
+            )"#,
+            r#"CREATE INDEX verification_codes_expires_at ON verification_codes (expires_at)"#,
+        ],
+    },
+    // 邮箱更换记录，保存旧邮箱撤销链接的sha256
+    Migration {
+        version: 10,
+        description: "create email_changes table",
+        sqlite: &[
+            r#"CREATE TABLE email_changes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                uid INTEGER NOT NULL,
+                old_email TEXT NOT NULL,
+                new_email TEXT NOT NULL,
+                cancel_hash TEXT NOT NULL UNIQUE,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                cancelled_at INTEGER
+            )"#,
+            r#"CREATE INDEX email_changes_uid ON email_changes (uid)"#,
+        ],
+        mysql: &[
+            r#"CREATE TABLE email_changes (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                uid BIGINT NOT NULL,
+                old_email VARCHAR(254) NOT NULL,
+                new_email VARCHAR(254) NOT NULL,
+                cancel_hash VARCHAR(64) NOT NULL UNIQUE,
+                created_at BIGINT NOT NULL,
+                expires_at BIGINT NOT NULL,
+                cancelled_at BIGINT
+            )"#,
+            r#"CREATE INDEX email_changes_uid ON email_changes (uid)"#,
+        ],
+        postgres: &[
+            r#"CREATE TABLE email_changes (
+                id BIGSERIAL PRIMARY KEY,
+                uid BIGINT NOT NULL,
+                old_email TEXT NOT NULL,
+                new_email TEXT NOT NULL,
+                cancel_hash TEXT NOT NULL UNIQUE,
+                created_at BIGINT NOT NULL,
+                expires_at BIGINT NOT NULL,
+                cancelled_at BIGINT
+            )"#,
+            r#"CREATE INDEX email_changes_uid ON email_changes (uid)"#,
+        ],
+    },
+    // 角色，用户的权限为直接授予的权限与所属角色权限的并集
+    Migration {
+        version: 11,
+        description: "create roles, role_acl and user_roles tables",
+        sqlite: &[
+            r#"CREATE TABLE roles (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE
+            )"#,
+            r#"CREATE TABLE role_acl (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                role_id INTEGER NOT NULL,
+                resource_id INTEGER NOT NULL,
+                operation TEXT NOT NULL,
+                UNIQUE(role_id, resource_id, operation)
+            )"#,
+            r#"CREATE TABLE user_roles (
+                uid INTEGER NOT NULL,
+                role_id INTEGER NOT NULL,
+                PRIMARY KEY (uid, role_id)
+            )"#,
+        ],
+        mysql: &[
+            r#"CREATE TABLE roles (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                name VARCHAR(64) NOT NULL UNIQUE
+            )"#,
+            r#"CREATE TABLE role_acl (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                role_id BIGINT NOT NULL,
+                resource_id BIGINT NOT NULL,
+                operation VARCHAR(64) NOT NULL,
+                UNIQUE(role_id, resource_id, operation)
+            )"#,
+            r#"CREATE TABLE user_roles (
+                uid BIGINT NOT NULL,
+                role_id BIGINT NOT NULL,
+                PRIMARY KEY (uid, role_id)
+            )"#,
+        ],
+        postgres: &[
+            r#"CREATE TABLE roles (
+                id BIGSERIAL PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE
+            )"#,
+            r#"CREATE TABLE role_acl (
+                id BIGSERIAL PRIMARY KEY,
+                role_id BIGINT NOT NULL,
+                resource_id BIGINT NOT NULL,
+                operation TEXT NOT NULL,
+                UNIQUE(role_id, resource_id, operation)
+            )"#,
+            r#"CREATE TABLE user_roles (
+                uid BIGINT NOT NULL,
+                role_id BIGINT NOT NULL,
+                PRIMARY KEY (uid, role_id)
+            )"#,
+        ],
+    },
+    // 拒绝规则，优先于同一层级的允许
+    Migration {
+        version: 12,
+        description: "add deny to acl and role_acl",
+        sqlite: &[
+            r#"ALTER TABLE acl ADD COLUMN deny INTEGER NOT NULL DEFAULT 0"#,
+            r#"ALTER TABLE role_acl ADD COLUMN deny INTEGER NOT NULL DEFAULT 0"#,
+        ],
+        mysql: &[
+            r#"ALTER TABLE acl ADD COLUMN deny BOOLEAN NOT NULL DEFAULT FALSE"#,
+            r#"ALTER TABLE role_acl ADD COLUMN deny BOOLEAN NOT NULL DEFAULT FALSE"#,
+        ],
+        postgres: &[
+            r#"ALTER TABLE acl ADD COLUMN deny BOOLEAN NOT NULL DEFAULT FALSE"#,
+            r#"ALTER TABLE role_acl ADD COLUMN deny BOOLEAN NOT NULL DEFAULT FALSE"#,
+        ],
+    },
+    // 资源上注册的操作，内置的 Add、Remove、Update、Check 不需要注册
+    Migration {
+        version: 13,
+        description: "create resource_operations table",
+        sqlite: &[r#"CREATE TABLE resource_operations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                resource_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                UNIQUE(resource_id, name)
+            )"#],
+        mysql: &[r#"CREATE TABLE resource_operations (
+                id BIGINT PRIMARY KEY AUTO_INCREMENT,
+                resource_id BIGINT NOT NULL,
+                name VARCHAR(64) NOT NULL,
+                UNIQUE(resource_id, name)
+            )"#],
+        postgres: &[r#"CREATE TABLE resource_operations (
+                id BIGSERIAL PRIMARY KEY,
+                resource_id BIGINT NOT NULL,
+                name TEXT NOT NULL,
+                UNIQUE(resource_id, name)
+            )"#],
+    },
+];
+
+// 创建版本记录表
+async fn create_version_table(pool: &DbPool) -> Result<(), sqlx::Error> {
+    let sql = r#"
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version BIGINT PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at BIGINT NOT NULL
+        )
+    "#;
+
+    match pool {
+        DbPool::Sqlite(pool) => {
+            sqlx::query(sql).execute(pool).await?;
+        }
+        DbPool::Mysql(pool) => {
+            sqlx::query(sql).execute(pool).await?;
+        }
+        DbPool::Postgres(pool) => {
+            sqlx::query(sql).execute(pool).await?;
+        }
+    }
+    Ok(())
+}
+
+// 获取已执行的版本号
+pub async fn applied_versions(pool: &DbPool) -> Result<Vec<i64>, sqlx::Error> {
+    create_version_table(pool).await?;
+    let sql = r#"SELECT version FROM schema_version ORDER BY version"#;
+
+    let versions = match pool {
+        DbPool::Sqlite(pool) => sqlx::query(sql)
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<Vec<i64>, _>>()?,
+        DbPool::Mysql(pool) => sqlx::query(sql)
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<Vec<i64>, _>>()?,
+        DbPool::Postgres(pool) => sqlx::query(sql)
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<Result<Vec<i64>, _>>()?,
+    };
+    Ok(versions)
+}
+
+/// 获取尚未执行的迁移
+pub async fn pending_migrations(pool: &DbPool) -> Result<Vec<&'static Migration>, sqlx::Error> {
+    let applied = applied_versions(pool).await?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect())
+}
+
+// 在一个事务中执行迁移并记录版本
+async fn apply_migration(pool: &DbPool, migration: &Migration) -> Result<(), sqlx::Error> {
+    let statements = migration.statements(pool);
+    let applied_at = Utc::now().timestamp();
+
+    match pool {
+        DbPool::Sqlite(pool) => {
+            let mut tx = pool.begin().await?;
+            for sql in statements {
+                sqlx::query(sql).execute(&mut *tx).await?;
+            }
+            sqlx::query(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)",
+            )
+            .bind(migration.version)
+            .bind(migration.description)
+            .bind(applied_at)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        DbPool::Mysql(pool) => {
+            // mysql 的 DDL 会隐式提交，事务只保证版本记录与最后的语句一致
+            let mut tx = pool.begin().await?;
+            for sql in statements {
+                sqlx::query(sql).execute(&mut *tx).await?;
+            }
+            sqlx::query(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES (?, ?, ?)",
+            )
+            .bind(migration.version)
+            .bind(migration.description)
+            .bind(applied_at)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        DbPool::Postgres(pool) => {
+            let mut tx = pool.begin().await?;
+            for sql in statements {
+                sqlx::query(sql).execute(&mut *tx).await?;
+            }
+            sqlx::query(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, $3)",
+            )
+            .bind(migration.version)
+            .bind(migration.description)
+            .bind(applied_at)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+    }
+    Ok(())
+}
+
+/// 执行所有尚未执行的迁移，返回本次执行的版本号
+pub async fn run_migrations(pool: &DbPool) -> Result<Vec<i64>, sqlx::Error> {
+    let mut applied = Vec::new();
+    for migration in pending_migrations(pool).await? {
+        info!(
+            "执行数据库迁移 v{}: {}",
+            migration.version, migration.description
+        );
+        apply_migration(pool, migration).await?;
+        applied.push(migration.version);
+    }
+    Ok(applied)
+}
+
+#[tokio::test]
+async fn test_migrations() {
+    // 版本号必须严格递增
+    assert!(MIGRATIONS.windows(2).all(|w| w[0].version < w[1].version));
+
+    let pool = crate::lib::config::memory_pool().await;
+    assert_eq!(
+        pending_migrations(&pool).await.unwrap().len(),
+        MIGRATIONS.len()
+    );
+
+    let applied = run_migrations(&pool).await.unwrap();
+    assert_eq!(applied.len(), MIGRATIONS.len());
+    assert!(pending_migrations(&pool).await.unwrap().is_empty());
+
+    // 再次执行不会重复迁移
+    assert!(run_migrations(&pool).await.unwrap().is_empty());
+}
@@ -1,15 +1,38 @@
 // 配置文件
 pub mod config;
 
+// 接口错误
+pub mod error;
+
+// 请求参数校验
+pub mod validate;
+
+// 数据库迁移
+pub mod migrations;
+
 // 用户
 pub mod user;
 
-// 权限组 
+// 权限组
 pub mod acl;
 
-
 // java端
 pub mod java;
 
 // 加密工具
 pub mod key;
+
+// token签名密钥
+pub mod keyring;
+
+// 数据加密
+pub mod cipher;
+
+// 数据仓库
+pub mod repository;
+
+// 频率限制与登录失败锁定
+pub mod ratelimit;
+
+// 邮件发送
+pub mod mail;
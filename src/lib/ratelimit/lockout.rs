@@ -0,0 +1,195 @@
+// 登录失败锁定
+//
+// 账号登录、两步验证和玩家快捷登录共用，键分别为 "user:邮箱"、"totp:uid"、"player:玩家名"。
+// 连续失败 max_failures 次后锁定 lock_seconds 秒，之后每再失败 max_failures 次锁定时间翻倍，
+// 最长 max_lock_seconds；登录成功后清零，锁定结束后超过 max_lock_seconds 没有失败也会清零。
+// 每次尝试在验证之前先计为一次失败，检查锁定与计数在同一个消息中完成，
+// 同时提交的请求也不会超过 max_failures 次；验证出错时同样计入。
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use actix::{Actor, AsyncContext, Context, Handler, Message};
+
+use crate::lib::config::LockoutConfig;
+
+use super::ceil_secs;
+
+// 清理过期记录的间隔
+const PURGE_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,                       // 连续失败次数
+    locked_until: Option<SystemTime>, // 锁定截止时间
+    forget_at: SystemTime,            // 之后没有失败时清零
+}
+
+#[derive(Debug, Default)]
+pub struct LoginGuard {
+    config: LockoutConfig,
+    failures: HashMap<String, Failures>,
+}
+
+pub fn user_key(email: &str) -> String {
+    format!("user:{}", email.trim().to_lowercase())
+}
+
+pub fn totp_key(uid: i64) -> String {
+    format!("totp:{}", uid)
+}
+
+pub fn player_key(name: &str) -> String {
+    format!("player:{}", name.to_lowercase())
+}
+
+impl LoginGuard {
+    pub fn new(config: LockoutConfig) -> Self {
+        LoginGuard {
+            config,
+            failures: HashMap::new(),
+        }
+    }
+
+    // 剩余锁定时间(秒)，未锁定时返回None
+    pub fn locked(&mut self, key: &str) -> Option<u64> {
+        let until = self.failures.get(key)?.locked_until?;
+        until.duration_since(SystemTime::now()).ok().map(ceil_secs)
+    }
+
+    // 记录一次失败
+    pub fn failure(&mut self, key: &str) {
+        let now = SystemTime::now();
+        let max_failures = self.config.max_failures.max(1);
+        let max_lock = Duration::from_secs(self.config.max_lock_seconds);
+        let failures = self.failures.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            locked_until: None,
+            forget_at: now,
+        });
+        if failures.forget_at < now && failures.count > 0 {
+            failures.count = 0;
+            failures.locked_until = None;
+        }
+        failures.count += 1;
+        if failures.count.is_multiple_of(max_failures) {
+            // 第n次锁定为 lock_seconds * 2^(n-1)
+            let times = (failures.count / max_failures - 1).min(32);
+            let seconds = self
+                .config
+                .lock_seconds
+                .saturating_mul(1 << times)
+                .min(self.config.max_lock_seconds);
+            failures.locked_until = Some(now + Duration::from_secs(seconds));
+        }
+        failures.forget_at = failures.locked_until.unwrap_or(now).max(now) + max_lock;
+    }
+
+    // 未锁定时预先记为一次失败，锁定时返回剩余锁定时间(秒)
+    pub fn reserve(&mut self, key: &str) -> Option<u64> {
+        if let Some(seconds) = self.locked(key) {
+            return Some(seconds);
+        }
+        self.failure(key);
+        None
+    }
+
+    // 登录成功，清除失败记录
+    pub fn success(&mut self, key: &str) {
+        self.failures.remove(key);
+    }
+
+    // 清理已经清零的记录
+    fn purge(&mut self) {
+        let now = SystemTime::now();
+        self.failures.retain(|_, failures| failures.forget_at > now);
+    }
+}
+
+impl Actor for LoginGuard {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(PURGE_INTERVAL, |guard, _| guard.purge());
+    }
+}
+
+// 验证之前占用一次尝试，锁定时返回剩余锁定时间(秒)
+#[derive(Message)]
+#[rtype(result = "Option<u64>")]
+pub struct ReserveAttempt {
+    pub key: String,
+}
+
+impl Handler<ReserveAttempt> for LoginGuard {
+    type Result = Option<u64>;
+
+    fn handle(&mut self, msg: ReserveAttempt, _: &mut Context<Self>) -> Option<u64> {
+        self.reserve(&msg.key)
+    }
+}
+
+// 验证通过，清除失败记录
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct LoginSucceeded {
+    pub key: String,
+}
+
+impl Handler<LoginSucceeded> for LoginGuard {
+    type Result = ();
+
+    fn handle(&mut self, msg: LoginSucceeded, _: &mut Context<Self>) {
+        self.success(&msg.key);
+    }
+}
+
+#[tokio::test]
+async fn test_login_guard() {
+    let config = LockoutConfig::default();
+    let max_failures = config.max_failures;
+    let lock_seconds = config.lock_seconds;
+    let mut guard = LoginGuard::new(config);
+    let steve = player_key("steve");
+    for _ in 0..max_failures - 1 {
+        guard.failure(&steve);
+    }
+    assert_eq!(guard.locked(&steve), None);
+    guard.failure(&steve);
+    assert!(guard.locked(&steve).unwrap() <= lock_seconds);
+    // 其他玩家不受影响
+    assert_eq!(guard.locked(&player_key("alex")), None);
+    // 再次达到失败次数时锁定时间翻倍
+    for _ in 0..max_failures {
+        guard.failure(&steve);
+    }
+    let locked = guard.locked(&steve).unwrap();
+    assert!(locked > lock_seconds && locked <= lock_seconds * 2);
+    guard.success(&steve);
+    assert_eq!(guard.locked(&steve), None);
+
+    // 锁定时间不超过 max_lock_seconds
+    let mut guard = LoginGuard::new(LockoutConfig {
+        max_failures: 1,
+        lock_seconds: 60,
+        max_lock_seconds: 100,
+    });
+    let admin = user_key(" Admin@example.com");
+    assert_eq!(admin, user_key("admin@example.com"));
+    for _ in 0..5 {
+        guard.failure(&admin);
+    }
+    assert!(guard.locked(&admin).unwrap() <= 100);
+
+    // 预先计数，验证结果返回之前的请求也计入
+    let mut guard = LoginGuard::new(LockoutConfig::default());
+    let alex = player_key("alex");
+    for _ in 0..max_failures {
+        assert_eq!(guard.reserve(&alex), None);
+    }
+    assert!(guard.reserve(&alex).is_some());
+    guard.success(&alex);
+    assert_eq!(guard.reserve(&alex), None);
+}
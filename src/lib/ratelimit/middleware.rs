@@ -0,0 +1,279 @@
+// 频率限制中间件
+//
+// identifier 指定按哪个字段计数，从处理函数读取该字段的位置读取：默认为JSON请求体，
+// 查询参数需要用 query_identifier 声明，否则可以附加另一个查询参数换用新的计数。
+// 读取请求体后原样放回，处理函数不受影响。
+// 标识的计数在窗口结束前一直保留，按ip的限制先于按标识的限制，单个ip能产生的计数有限。
+
+use std::{collections::HashMap, rc::Rc};
+
+use actix::Addr;
+use actix_web::{
+    body::BoxBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error::PayloadError,
+    web, Error,
+};
+use futures_util::{future::LocalBoxFuture, stream};
+
+use crate::lib::{
+    config::{HttpServerConfig, RateLimitRule},
+    error::ApiError,
+};
+
+use super::{client_ip, CheckRate, RateLimiter};
+
+// 标识的长度上限，与邮箱的长度限制一致
+const MAX_IDENTIFIER_LEN: usize = 254;
+
+// 计数用的标识所在的位置
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentifierSource {
+    Query, // 查询参数
+    Body,  // JSON请求体
+}
+
+/// 按规则限制请求频率
+#[derive(Clone)]
+pub struct RateLimit {
+    name: Rc<str>,
+    rule: RateLimitRule,
+    identifier: Option<(IdentifierSource, Rc<str>)>,
+}
+
+impl RateLimit {
+    pub fn new(name: &str, rule: &RateLimitRule) -> Self {
+        RateLimit {
+            name: name.into(),
+            rule: rule.clone(),
+            identifier: None,
+        }
+    }
+
+    /// 同时按JSON请求体中的该字段计数，例如邮箱、玩家名
+    pub fn identifier(mut self, field: &str) -> Self {
+        self.identifier = Some((IdentifierSource::Body, field.into()));
+        self
+    }
+
+    /// 同时按查询参数中的该字段计数
+    pub fn query_identifier(mut self, field: &str) -> Self {
+        self.identifier = Some((IdentifierSource::Query, field.into()));
+        self
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = std::future::Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        std::future::ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limit: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limit: RateLimit,
+}
+
+impl<S> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limit = self.limit.clone();
+        Box::pin(async move {
+            let limiter = req
+                .app_data::<web::Data<Addr<RateLimiter>>>()
+                .expect("RateLimiter 未注册")
+                .clone();
+            let trust_proxy_headers = req
+                .app_data::<web::Data<HttpServerConfig>>()
+                .is_some_and(|config| config.rate_limit_config.trust_proxy_headers);
+            let ip = client_ip(req.request(), trust_proxy_headers);
+            let identifier = match &limit.identifier {
+                Some((source, field)) => read_identifier(&mut req, *source, field).await,
+                None => None,
+            };
+            let wait = limiter
+                .send(CheckRate {
+                    name: limit.name.to_string(),
+                    rule: limit.rule,
+                    ip,
+                    identifier,
+                })
+                .await
+                .map_err(ApiError::from)?;
+            if let Some(seconds) = wait {
+                return Err(ApiError::RateLimited(seconds).into());
+            }
+            service.call(req).await
+        })
+    }
+}
+
+// 读取计数用的标识，统一为小写
+async fn read_identifier(
+    req: &mut ServiceRequest,
+    source: IdentifierSource,
+    field: &str,
+) -> Option<String> {
+    let value = match source {
+        IdentifierSource::Query => {
+            web::Query::<HashMap<String, String>>::from_query(req.query_string())
+                .ok()
+                .and_then(|query| query.get(field).cloned())
+        }
+        IdentifierSource::Body => {
+            let body = req.extract::<web::Bytes>().await.ok()?;
+            let value = serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|json| json.get(field)?.as_str().map(str::to_string));
+            // 放回请求体
+            req.set_payload(Payload::Stream {
+                payload: Box::pin(stream::once(async move { Ok::<_, PayloadError>(body) })),
+            });
+            value
+        }
+    }?;
+    Some(
+        value
+            .trim()
+            .chars()
+            .take(MAX_IDENTIFIER_LEN)
+            .collect::<String>()
+            .to_lowercase(),
+    )
+}
+
+#[actix_web::test]
+async fn test_rate_limit() {
+    use actix::Actor;
+    use actix_web::{http::StatusCode, test, App, HttpResponse};
+
+    #[derive(serde::Deserialize)]
+    struct Login {
+        email: String,
+    }
+
+    let rule = RateLimitRule {
+        window: 60,
+        per_ip: 3,
+        per_identifier: 2,
+    };
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(RateLimiter::new().start()))
+            .service(
+                web::resource("/login")
+                    .wrap(RateLimit::new("login", &rule).identifier("email"))
+                    // 处理函数仍能读取请求体
+                    .to(|body: web::Json<Login>| async move {
+                        HttpResponse::Ok().body(body.into_inner().email)
+                    }),
+            )
+            .service(
+                web::resource("/code")
+                    .wrap(RateLimit::new("code", &rule).query_identifier("email"))
+                    .to(HttpResponse::Ok),
+            ),
+    )
+    .await;
+
+    // 查询参数与请求体不同时按处理函数读取的请求体计数
+    let login = |email: &str, uri: &str, ip: &str| {
+        let req = test::TestRequest::post()
+            .uri(uri)
+            .peer_addr(format!("{}:25565", ip).parse().unwrap())
+            .set_json(serde_json::json!({ "email": email }))
+            .to_request();
+        let res = test::try_call_service(&app, req);
+        async move {
+            match res.await {
+                Ok(res) => (res.status(), test::read_body(res).await),
+                Err(err) => {
+                    let res = err.error_response();
+                    assert!(res.headers().contains_key("retry-after"));
+                    (res.status(), Default::default())
+                }
+            }
+        }
+    };
+    let (status, body) = login("steve@example.com", "/login", "1.1.1.1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "steve@example.com");
+    // 大小写不同也按同一邮箱计数
+    assert_eq!(
+        login("Steve@example.com", "/login", "1.1.1.1").await.0,
+        StatusCode::OK
+    );
+    assert_eq!(
+        login("steve@example.com", "/login", "1.1.1.1").await.0,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    // 按ip计数
+    assert_eq!(
+        login("alex@example.com", "/login", "1.1.1.1").await.0,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    // 查询参数中的标识不影响按请求体的计数
+    assert_eq!(
+        login(
+            "steve@example.com",
+            "/login?email=other@example.com",
+            "2.2.2.2"
+        )
+        .await
+        .0,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(
+        login(
+            "alex@example.com",
+            "/login?email=steve@example.com",
+            "2.2.2.2"
+        )
+        .await
+        .0,
+        StatusCode::OK
+    );
+
+    // 声明为查询参数时按查询参数计数
+    let code = |email: &str| {
+        let req = test::TestRequest::get()
+            .uri(&format!("/code?email={}", email))
+            .peer_addr("3.3.3.3:25565".parse().unwrap())
+            .to_request();
+        let res = test::try_call_service(&app, req);
+        async move {
+            match res.await {
+                Ok(res) => res.status(),
+                Err(err) => err.error_response().status(),
+            }
+        }
+    };
+    assert_eq!(code("steve@example.com").await, StatusCode::OK);
+    assert_eq!(code("alex@example.com").await, StatusCode::OK);
+    assert_eq!(code("steve@example.com").await, StatusCode::OK);
+    assert_eq!(
+        code("steve@example.com").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+}
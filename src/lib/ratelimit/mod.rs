@@ -0,0 +1,159 @@
+// 请求频率限制
+//
+// 在路由上声明规则，例如
+// `web::resource("/login").wrap(RateLimit::new("login", &config.login).identifier("email"))`，
+// 同一时间窗口内按ip、按请求中的邮箱/玩家名分别计数，超过限制返回429与 Retry-After。
+// 连续登录失败的锁定见 lockout。
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use actix::{Actor, AsyncContext, Context, Handler, Message};
+use actix_web::HttpRequest;
+
+use super::config::RateLimitRule;
+
+pub mod lockout;
+pub mod middleware;
+
+// 清理过期计数的间隔
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    // 规则名:ip/标识、(窗口内的请求数, 窗口结束时间)
+    windows: HashMap<String, (u32, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 记录一次请求，超过限制时返回需要等待的秒数
+    fn hit(&mut self, key: String, limit: u32, window: u64) -> Option<u64> {
+        if limit == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let (count, end) = self.windows.entry(key).or_insert((0, now));
+        if *end <= now {
+            *count = 0;
+            *end = now + Duration::from_secs(window);
+        }
+        if *count >= limit {
+            return Some(ceil_secs(*end - now));
+        }
+        *count += 1;
+        None
+    }
+
+    // 先按ip再按标识计数，任一超过限制时返回需要等待的秒数
+    pub fn check(
+        &mut self,
+        name: &str,
+        rule: &RateLimitRule,
+        ip: &str,
+        identifier: Option<&str>,
+    ) -> Option<u64> {
+        let ip_key = format!("{}:ip:{}", name, ip);
+        if let Some(wait) = self.hit(ip_key, rule.per_ip, rule.window) {
+            return Some(wait);
+        }
+        let identifier = identifier?;
+        self.hit(
+            format!("{}:id:{}", name, identifier),
+            rule.per_identifier,
+            rule.window,
+        )
+    }
+
+    // 清理已结束的窗口
+    fn purge(&mut self) {
+        let now = Instant::now();
+        self.windows.retain(|_, (_, end)| *end > now);
+    }
+}
+
+impl Actor for RateLimiter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(PURGE_INTERVAL, |limiter, _| limiter.purge());
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<u64>")]
+pub struct CheckRate {
+    pub name: String,
+    pub rule: RateLimitRule,
+    pub ip: String,
+    pub identifier: Option<String>,
+}
+
+impl Handler<CheckRate> for RateLimiter {
+    type Result = Option<u64>;
+
+    fn handle(&mut self, msg: CheckRate, _: &mut Context<Self>) -> Option<u64> {
+        self.check(&msg.name, &msg.rule, &msg.ip, msg.identifier.as_deref())
+    }
+}
+
+// 需要等待的秒数，不足一秒按一秒
+pub fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// 客户端ip，只在反向代理之后信任代理请求头
+pub fn client_ip(req: &HttpRequest, trust_proxy_headers: bool) -> String {
+    let info = req.connection_info();
+    let ip = if trust_proxy_headers {
+        info.realip_remote_addr()
+    } else {
+        info.peer_addr()
+    };
+    ip.unwrap_or("unknown").to_string()
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    let rule = RateLimitRule {
+        window: 60,
+        per_ip: 3,
+        per_identifier: 1,
+    };
+    let mut limiter = RateLimiter::new();
+    assert_eq!(
+        limiter.check("code", &rule, "1.1.1.1", Some("a@x.com")),
+        None
+    );
+    // 同一邮箱超过限制
+    let wait = limiter.check("code", &rule, "2.2.2.2", Some("a@x.com"));
+    assert!(matches!(wait, Some(1..=60)));
+    assert_eq!(
+        limiter.check("code", &rule, "1.1.1.1", Some("b@x.com")),
+        None
+    );
+    assert_eq!(limiter.check("code", &rule, "1.1.1.1", None), None);
+    // 同一ip超过限制
+    assert!(limiter
+        .check("code", &rule, "1.1.1.1", Some("c@x.com"))
+        .is_some());
+    // 不同规则分别计数
+    assert_eq!(
+        limiter.check("login", &rule, "1.1.1.1", Some("a@x.com")),
+        None
+    );
+
+    // 0为不限制
+    let unlimited = RateLimitRule::default();
+    for _ in 0..10 {
+        assert_eq!(
+            limiter.check("other", &unlimited, "1.1.1.1", Some("a@x.com")),
+            None
+        );
+    }
+}
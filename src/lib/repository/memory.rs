@@ -0,0 +1,162 @@
+// 内存仓库的数据表，修改权限时复制一份用于回滚
+
+/// 模拟数据库的唯一约束错误
+pub(crate) fn unique_violation(constraint: &str) -> sqlx::Error {
+    sqlx::Error::Protocol(format!("UNIQUE constraint failed: {}", constraint))
+}
+
+#[derive(Clone)]
+pub(crate) struct UserRow {
+    pub id: i64,
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct PlayerRow {
+    pub uid: i64,
+    pub name: String,
+    pub password: String,
+    pub player_id: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct ResourceRow {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct ResourceOperationRow {
+    pub resource_id: i64,
+    pub name: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct AclRow {
+    pub uid: i64,
+    pub resource_id: i64,
+    pub operation: String,
+    pub deny: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct RoleRow {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Clone)]
+pub(crate) struct RoleAclRow {
+    pub role_id: i64,
+    pub resource_id: i64,
+    pub operation: String,
+    pub deny: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct UserRoleRow {
+    pub uid: i64,
+    pub role_id: i64,
+}
+
+#[derive(Clone)]
+pub(crate) struct SessionRow {
+    pub id: i64,
+    pub uid: i64,
+    pub refresh_hash: String,
+    pub previous_hash: Option<String>,
+    pub ip: String,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+    pub revoked_at: Option<i64>,
+}
+
+#[derive(Clone)]
+pub(crate) struct TotpRow {
+    pub uid: i64,
+    pub secret: String,
+    pub last_step: i64,
+    pub enabled_at: Option<i64>,
+}
+
+#[derive(Clone)]
+pub(crate) struct RecoveryCodeRow {
+    pub uid: i64,
+    pub code_hash: String,
+    pub used_at: Option<i64>,
+}
+
+#[derive(Clone)]
+pub(crate) struct GameServerRow {
+    pub id: i64,
+    pub name: String,
+    pub key_hash: String,
+    pub key_prefix: String,
+    pub created_at: i64,
+    pub rotated_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+#[derive(Clone)]
+pub(crate) struct CodeRow {
+    pub email: String,
+    pub purpose: String,
+    pub code_hash: String,
+    pub attempts: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Clone)]
+pub(crate) struct EmailChangeRow {
+    pub id: i64,
+    pub uid: i64,
+    pub old_email: String,
+    pub cancel_hash: String,
+    pub expires_at: i64,
+    pub cancelled_at: Option<i64>,
+}
+
+#[derive(Clone)]
+pub(crate) struct OutboxRow {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub html: String,
+    pub plain_text: Option<String>,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub sent_at: Option<i64>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct MemoryState {
+    last_id: i64,
+    pub users: Vec<UserRow>,
+    pub players: Vec<PlayerRow>,
+    pub resources: Vec<ResourceRow>,
+    pub resource_operations: Vec<ResourceOperationRow>,
+    pub acl: Vec<AclRow>,
+    pub roles: Vec<RoleRow>,
+    pub role_acl: Vec<RoleAclRow>,
+    pub user_roles: Vec<UserRoleRow>,
+    pub sessions: Vec<SessionRow>,
+    pub totp: Vec<TotpRow>,
+    pub recovery_codes: Vec<RecoveryCodeRow>,
+    pub game_servers: Vec<GameServerRow>,
+    pub outbox: Vec<OutboxRow>,
+    pub codes: Vec<CodeRow>,
+    pub email_changes: Vec<EmailChangeRow>,
+}
+
+impl MemoryState {
+    // 自增id，所有表共用一个序列
+    pub fn next_id(&mut self) -> i64 {
+        self.last_id += 1;
+        self.last_id
+    }
+}
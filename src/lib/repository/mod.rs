@@ -0,0 +1,170 @@
+// 数据仓库
+//
+// 每种数据库各有一个实现（sqlite/mysql/postgres），另有一个内存实现供测试使用。
+// 仓库 trait 定义在各模块的 sql_*.rs 中，处理函数只依赖 trait。
+
+use std::sync::{Arc, Mutex};
+
+use actix::Addr;
+use actix_web::web;
+use sqlx::{MySqlPool, PgPool, SqlitePool};
+
+use super::{
+    acl::{
+        cache::{AclCache, CachedAclRepository, CachedRoleRepository},
+        sql_acl::AclRepository,
+        sql_role::RoleRepository,
+    },
+    config::DbPool,
+    java::{player::sql_player::PlayerRepository, server::sql_server::GameServerRepository},
+    mail::sql_outbox::OutboxRepository,
+    user::{
+        sql_code::CodeRepository, sql_email_change::EmailChangeRepository,
+        sql_session::SessionRepository, sql_totp::TotpRepository, sql_user::UserRepository,
+    },
+};
+
+pub mod memory;
+#[cfg(test)]
+mod suite;
+
+use memory::MemoryState;
+
+pub struct SqliteRepository {
+    pub(crate) pool: SqlitePool,
+}
+
+pub struct MySqlRepository {
+    pub(crate) pool: MySqlPool,
+}
+
+pub struct PostgresRepository {
+    pub(crate) pool: PgPool,
+}
+
+/// 内存仓库，进程退出后数据丢失，用于测试和保存在内存中的验证码
+#[derive(Default)]
+pub struct MemoryRepository {
+    pub(crate) state: Mutex<MemoryState>,
+}
+
+/// 所有仓库的集合
+#[derive(Clone)]
+pub struct Repositories {
+    pub user: Arc<dyn UserRepository>,
+    pub player: Arc<dyn PlayerRepository>,
+    pub acl: Arc<dyn AclRepository>,
+    pub role: Arc<dyn RoleRepository>,
+    pub session: Arc<dyn SessionRepository>,
+    pub totp: Arc<dyn TotpRepository>,
+    pub server: Arc<dyn GameServerRepository>,
+    pub outbox: Arc<dyn OutboxRepository>,
+    pub code: Arc<dyn CodeRepository>,
+    pub email_change: Arc<dyn EmailChangeRepository>,
+}
+
+impl Repositories {
+    pub fn new(pool: &DbPool) -> Self {
+        match pool {
+            DbPool::Sqlite(pool) => Self::from_backend(Arc::new(SqliteRepository {
+                pool: pool.clone(),
+            })),
+            DbPool::Mysql(pool) => Self::from_backend(Arc::new(MySqlRepository {
+                pool: pool.clone(),
+            })),
+            DbPool::Postgres(pool) => Self::from_backend(Arc::new(PostgresRepository {
+                pool: pool.clone(),
+            })),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn memory() -> Self {
+        Self::from_backend(Arc::new(MemoryRepository::default()))
+    }
+
+    fn from_backend<R>(backend: Arc<R>) -> Self
+    where
+        R: UserRepository
+            + PlayerRepository
+            + AclRepository
+            + RoleRepository
+            + SessionRepository
+            + TotpRepository
+            + GameServerRepository
+            + OutboxRepository
+            + CodeRepository
+            + EmailChangeRepository
+            + 'static,
+    {
+        Repositories {
+            user: backend.clone(),
+            player: backend.clone(),
+            acl: backend.clone(),
+            role: backend.clone(),
+            session: backend.clone(),
+            totp: backend.clone(),
+            server: backend.clone(),
+            outbox: backend.clone(),
+            code: backend.clone(),
+            email_change: backend,
+        }
+    }
+
+    // 按用户缓存权限规则，包装后的仓库需要在 actix 运行时中使用
+    pub fn with_acl_cache(mut self, cache: Addr<AclCache>) -> Self {
+        self.acl = Arc::new(CachedAclRepository {
+            inner: self.acl,
+            cache: cache.clone(),
+        });
+        self.role = Arc::new(CachedRoleRepository {
+            inner: self.role,
+            cache,
+        });
+        self
+    }
+
+    // 注册到 actix，处理函数通过 web::Data<dyn XxxRepository> 获取
+    pub fn app_data(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::from(self.user.clone()))
+            .app_data(web::Data::from(self.player.clone()))
+            .app_data(web::Data::from(self.acl.clone()))
+            .app_data(web::Data::from(self.role.clone()))
+            .app_data(web::Data::from(self.session.clone()))
+            .app_data(web::Data::from(self.totp.clone()))
+            .app_data(web::Data::from(self.server.clone()))
+            .app_data(web::Data::from(self.outbox.clone()))
+            .app_data(web::Data::from(self.code.clone()))
+            .app_data(web::Data::from(self.email_change.clone()));
+    }
+}
+
+// 内存实现与 sqlite 实现的结果应当一致
+#[tokio::test]
+async fn test_memory_repository() {
+    use super::{
+        acl::query_effective_acl,
+        config::{init_base_data_acl, init_db, memory_pool, HttpServerConfig},
+    };
+
+    let config = HttpServerConfig::default();
+    let sqlite = init_db(&memory_pool().await, &config).await;
+    let memory = Repositories::memory();
+    init_base_data_acl(&memory, &config).await;
+
+    let uid = memory
+        .user
+        .get_user_id(&config.register_user.email)
+        .await
+        .unwrap();
+    let mut expected = query_effective_acl(sqlite.acl.as_ref(), sqlite.role.as_ref(), uid as u64)
+        .await
+        .unwrap();
+    let mut actual = query_effective_acl(memory.acl.as_ref(), memory.role.as_ref(), uid as u64)
+        .await
+        .unwrap();
+    expected.values_mut().for_each(|operations| operations.sort());
+    actual.values_mut().for_each(|operations| operations.sort());
+    assert_eq!(expected, actual);
+    assert!(memory.user.register_user(&config.register_user).await.is_err());
+}
@@ -0,0 +1,97 @@
+// 请求认证
+//
+// 处理函数通过参数 `user: AuthUser` 获取当前用户，缺少或无效的token直接返回401。
+
+use std::future::{ready, Ready};
+
+use actix_web::{dev::Payload, http::header::AUTHORIZATION, FromRequest, HttpMessage, HttpRequest};
+
+use crate::lib::{error::ApiError, key::gettoken_to_user_no_time};
+
+/// 已通过token验证的用户
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub uid: u64,
+    pub sid: Option<i64>, // 会话id，旧版token没有
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Missing, // 缺少Authorization头
+    Invalid, // token无效、过期或会话已撤销
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "缺少token"),
+            AuthError::Invalid => write!(f, "token已过期"),
+        }
+    }
+}
+
+impl AuthUser {
+    /// 从请求头解析用户，结果缓存在请求中，权限守卫和处理函数只解析一次
+    pub fn from_http_request(req: &HttpRequest) -> Result<AuthUser, AuthError> {
+        if let Some(user) = req.extensions().get::<AuthUser>() {
+            return Ok(user.clone());
+        }
+        let header = req
+            .headers()
+            .get(AUTHORIZATION)
+            .ok_or(AuthError::Missing)?
+            .to_str()
+            .map_err(|_| AuthError::Invalid)?;
+        // 兼容旧客户端直接传token
+        let token = header.strip_prefix("Bearer ").unwrap_or(header).trim();
+        if token.is_empty() {
+            return Err(AuthError::Missing);
+        }
+        let claims = gettoken_to_user_no_time(token)
+            .map_err(|_| AuthError::Invalid)?
+            .claims;
+        let user = AuthUser {
+            uid: claims.uid,
+            sid: claims.sid,
+        };
+        req.extensions_mut().insert(user.clone());
+        Ok(user)
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = ApiError;
+    type Future = Ready<Result<AuthUser, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(AuthUser::from_http_request(req).map_err(ApiError::from))
+    }
+}
+
+#[tokio::test]
+async fn test_auth_user() {
+    use actix_web::test::TestRequest;
+
+    use crate::lib::key::create_token_time_min;
+
+    let token = create_token_time_min(7, 5);
+    for header in [format!("Bearer {}", token), token] {
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, header))
+            .to_http_request();
+        assert_eq!(AuthUser::from_http_request(&req).unwrap().uid, 7);
+    }
+
+    let req = TestRequest::default().to_http_request();
+    assert!(matches!(
+        AuthUser::from_http_request(&req),
+        Err(AuthError::Missing)
+    ));
+    let req = TestRequest::default()
+        .insert_header((AUTHORIZATION, "Bearer invalid"))
+        .to_http_request();
+    assert!(matches!(
+        AuthUser::from_http_request(&req),
+        Err(AuthError::Invalid)
+    ));
+}
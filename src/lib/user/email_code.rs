@@ -1,255 +1,282 @@
 // 邮箱验证码管理
+//
+// 验证码按 邮箱+用途 保存，注册的验证码不能用于重置密码等其他用途。
+// 同一邮箱同一用途只保留最新的验证码，重新获取后旧验证码失效。
+// code_config.store 选择保存在内存或数据库中，后台任务定期清理过期的验证码。
 
-use std::{collections::HashMap, time::SystemTime};
+use std::{sync::Arc, time::Duration};
 
-use actix::{Actor, Context, Handler, Message};
-use lettre::message::Mailbox;
-use lettre::Message as LettreMessage;
-use lettre::{
-    message::header::ContentType, transport::smtp::authentication::Credentials, SmtpTransport,
-    Transport,
+use chrono::Utc;
+use log::error;
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::lib::{
+    config::{CodeStore, HttpServerConfig},
+    mail::template::MailTemplateKind,
+    repository::{MemoryRepository, Repositories},
 };
 
-use build_html::*;
+use super::sql_code::CodeRepository;
 
-#[derive(Debug, Clone)]
-pub struct EmaiCodeManager {
-    //验证码、邮箱、过期时间
-    codelist: HashMap<String, (String, SystemTime)>,
+/// 验证码用途
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Default, Debug)]
+pub enum CodePurpose {
+    #[default]
+    register,
+    reset_password,
+    change_email,
+    delete_account,
 }
 
-impl EmaiCodeManager {
-    pub fn new() -> Self {
-        Self {
-            codelist: HashMap::new(),
+impl CodePurpose {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CodePurpose::register => "register",
+            CodePurpose::reset_password => "reset_password",
+            CodePurpose::change_email => "change_email",
+            CodePurpose::delete_account => "delete_account",
         }
     }
-    // 生成验证码
-    pub fn generate_code(&mut self, email: String) -> String {
-        let code = format!("{:06}", rand::random::<u32>() % 1000000);
-        self.codelist
-            .insert(email.clone(), (code.clone(), SystemTime::now()));
-        code
-    }
 
-    // 验证验证码
-    pub fn verify_code(&mut self, email: String, code: String) -> bool {
-        match self.codelist.get(&email) {
-            Some((c, _)) => {
-                let now = SystemTime::now();
-                let duration = now
-                    .duration_since(self.codelist.get(&email).unwrap().1)
-                    .unwrap();
-                // 验证时间是否超过5分钟
-                if c == &code && duration.as_secs() < 300 {
-                    self.codelist.remove(&email);
-                    return true;
-                } else {
-                    return false;
-                }
-            }
-            None => false,
+    // 发送验证码使用的邮件模板
+    pub fn template(&self) -> MailTemplateKind {
+        match self {
+            CodePurpose::register => MailTemplateKind::register_code,
+            CodePurpose::reset_password => MailTemplateKind::reset_password,
+            CodePurpose::change_email => MailTemplateKind::change_email,
+            CodePurpose::delete_account => MailTemplateKind::delete_account,
         }
     }
 }
-impl Actor for EmaiCodeManager {
-    type Context = Context<Self>;
-}
 
-#[derive(Message)]
-#[rtype(result = "String")]
-pub struct GenerateCode {
-    pub email: String,
+#[derive(Clone)]
+pub struct EmaiCodeManager {
+    repo: Arc<dyn CodeRepository>,
+    // 有效期(秒)
+    ttl: u64,
+    // 允许的错误次数，超过后验证码作废
+    max_attempts: u32,
 }
 
-impl Handler<GenerateCode> for EmaiCodeManager {
-    type Result = String;
+impl EmaiCodeManager {
+    pub fn new(repositories: &Repositories, config: &HttpServerConfig) -> Self {
+        let repo: Arc<dyn CodeRepository> = match config.code_config.store {
+            CodeStore::memory => Arc::new(MemoryRepository::default()),
+            CodeStore::database => repositories.code.clone(),
+        };
+        Self {
+            repo,
+            ttl: config.code_config.ttl.max(1),
+            max_attempts: config.rate_limit_config.code_max_attempts.max(1),
+        }
+    }
 
-    fn handle(&mut self, generatecode: GenerateCode, _: &mut Context<Self>) -> String {
-        self.generate_code(generatecode.email)
+    // 有效期(秒)
+    pub fn ttl(&self) -> u64 {
+        self.ttl
     }
-}
 
-#[derive(Message)]
-#[rtype(result = "bool")]
-pub struct VerifyCode {
-    pub email: String,
-    pub code: String,
-}
+    // 生成验证码，替换该邮箱同一用途的旧验证码
+    pub async fn generate_code(
+        &self,
+        email: &str,
+        purpose: CodePurpose,
+        now: i64,
+    ) -> Result<String, sqlx::Error> {
+        let code = format!("{:06}", OsRng.gen_range(0..1_000_000));
+        self.repo
+            .save_code(
+                email,
+                purpose.name(),
+                &hash_code(&code),
+                now + self.ttl as i64,
+                now,
+            )
+            .await?;
+        Ok(code)
+    }
 
-impl Handler<VerifyCode> for EmaiCodeManager {
-    type Result = bool;
+    // 验证验证码，通过后验证码作废
+    pub async fn verify_code(
+        &self,
+        email: &str,
+        purpose: CodePurpose,
+        code: &str,
+        now: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let saved = match self.repo.get_code(email, purpose.name()).await {
+            Ok(saved) => saved,
+            Err(sqlx::Error::RowNotFound) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if saved.expires_at <= now {
+            self.repo
+                .delete_code(email, purpose.name(), &saved.code_hash)
+                .await?;
+            return Ok(false);
+        }
+        // 比较之前先占用一次尝试，同时提交的请求不会超过允许的次数
+        let reserved = self
+            .repo
+            .reserve_code_attempt(
+                email,
+                purpose.name(),
+                &saved.code_hash,
+                self.max_attempts as i64,
+            )
+            .await?;
+        if reserved == 0 {
+            // 错误次数过多时作废，需要重新获取
+            self.repo
+                .delete_code(email, purpose.name(), &saved.code_hash)
+                .await?;
+            return Ok(false);
+        }
+        if saved.code_hash == hash_code(code) {
+            // 同时提交的请求只有一个能删除成功
+            let deleted = self
+                .repo
+                .delete_code(email, purpose.name(), &saved.code_hash)
+                .await?;
+            return Ok(deleted == 1);
+        }
+        Ok(false)
+    }
 
-    fn handle(&mut self, verifycode: VerifyCode, _: &mut Context<Self>) -> bool {
-        self.verify_code(verifycode.email, verifycode.code)
+    // 启动定期清理过期验证码的任务
+    pub fn start_sweeper(&self, interval: u64) {
+        let repo = self.repo.clone();
+        let interval = Duration::from_secs(interval.max(1));
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(err) = repo.delete_expired_codes(Utc::now().timestamp()).await {
+                    error!("清理过期验证码失败: {}", err);
+                }
+            }
+        });
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct EmailManager {
-    smtp_ip: String,
-    server_name: String,
-    credentials: Credentials,
-    from_email: String,
+// 验证码的sha256
+fn hash_code(code: &str) -> String {
+    format!("{:x}", Sha256::digest(code.trim().as_bytes()))
 }
 
-impl EmailManager {
-    pub fn new(server_name: String, smtp_ip: String, username: String, password: String) -> Self {
-        // 检查server_name、smtp_ip、username、password某个是否为空，如果为空，中断程序
-        if server_name.is_empty() || smtp_ip.is_empty() || username.is_empty() || password.is_empty() {
-            panic!("server_name、smtp_ip、username、password均不能为空");
-        }
-        let credentials = Credentials::new(username.clone(), password);
-        Self {
-            smtp_ip,
-            server_name,
-            credentials,
-            from_email: username,
+#[tokio::test]
+async fn test_verify_code() {
+    let repositories = Repositories::memory();
+    let mut config = HttpServerConfig::default();
+    config.code_config.store = CodeStore::database;
+    config.rate_limit_config.code_max_attempts = 3;
+    let manager = EmaiCodeManager::new(&repositories, &config);
+    let ttl = manager.ttl() as i64;
+    let email = "steve@example.com";
+    let now = Utc::now().timestamp();
+    let wrong = |code: &str| format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1000000);
+    let verify = |purpose, code: String, now| {
+        let manager = manager.clone();
+        async move {
+            manager
+                .verify_code(email, purpose, &code, now)
+                .await
+                .unwrap()
         }
-    }
+    };
 
-    pub fn send(
-        self,
-        msg: LettreMessage,
-    ) -> Result<lettre::transport::smtp::response::Response, lettre::transport::smtp::Error> {
-        let mailer = SmtpTransport::relay(&self.smtp_ip)
-            .unwrap()
-            .credentials(self.credentials)
-            .build();
-        mailer.send(&msg)
-    }
+    let code = manager
+        .generate_code(email, CodePurpose::register, now)
+        .await
+        .unwrap();
+    assert!(!verify(CodePurpose::register, wrong(&code), now).await);
+    // 不能用于其他用途
+    assert!(!verify(CodePurpose::reset_password, code.clone(), now).await);
+    assert!(verify(CodePurpose::register, code.clone(), now).await);
+    // 验证码只能使用一次
+    assert!(!verify(CodePurpose::register, code, now).await);
 
-    // 生成验证码的html
-    fn generate_code_html(self, code: String) -> String {
-        let msg = format!(
-            r#"<table width="500" border="0" align="center" cellpadding="0" cellspacing="0">
-    <div class="inner-div">
-        <table width="100%" border="0" cellspacing="0" cellpadding="0">
-            <td class="dynamic-machine-td" valign="middle">
-            {}
-            </td>
-            <body>
-                <tr class="spacer-tr">
-                    <td class="email-verification-code-td">
-                        邮箱验证码
-                    </td>
-                </tr>
-                <tr>
-                    <td class="user-greeting-td">
-
-                    <br>
-                        亲爱的玩家你好！
-</br>
-                        你的验证码是：{},请在 5 分钟内进行验证。如果该验证码不为您本人申请，请无视。
-                    </td>
-                </tr>
-                <tr class="spacer-tr"></tr>
-            </body>
-        </table>
-    </div>
-</table>"#,
-            self.server_name, code
-        );
-
-        HtmlPage::new()
-            .with_style(
-                r#".outer-div {
-        background: #eee;
-    }
-    .inner-div {
-        background: #fff;
-    }
-    .dynamic-machine-td {
-        padding-left: 30px;
-        background-color: #415a94;
-        color: #fff;
-        padding: 20px 40px;
-        font-size: 21px;
-    }
-    .email-verification-code-td {
-        font-size: 24px;
-        line-height: 1.5;
-        color: #000;
-        margin-top: 40px;
-    }
-    .user-greeting-td {
-        font-size: 14px;
-        color: #333;
-        padding: 24px 40px 0 40px;
-    }
-    .spacer-tr {
-        padding: 40px;
-        display: table-cell;
-    }"#,
-            )
-            .with_container(
-                Container::new(ContainerType::Div)
-                    .with_attributes([("class", "outer-div")])
-                    .with_raw(msg),
-            )
-            .to_html_string()
+    // 错误次数过多后正确的验证码也失效
+    let code = manager
+        .generate_code(email, CodePurpose::register, now)
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        assert!(!verify(CodePurpose::register, wrong(&code), now).await);
     }
+    assert!(!verify(CodePurpose::register, code, now).await);
 
-    pub fn send_email_code(
-        self,
-        code: String,
-        to_email: Mailbox,
-    ) -> Result<lettre::transport::smtp::response::Response, lettre::transport::smtp::Error> {
-        let msg = self.clone().generate_code_html(code.clone());
-        let email = LettreMessage::builder()
-            .from(self.from_email.parse().unwrap()) //发送者
-            .to(to_email) //接收者
-            .subject(self.server_name + "邮箱验证码")
-            .header(ContentType::TEXT_HTML)
-            .body(String::from(msg)) //邮箱内容
-            .unwrap();
-
-        let mailer = SmtpTransport::relay(&self.smtp_ip)
-            .unwrap()
-            .credentials(self.credentials)
-            .build();
-        mailer.send(&email)
+    // 重新获取后旧验证码失效
+    let old = manager
+        .generate_code(email, CodePurpose::reset_password, now)
+        .await
+        .unwrap();
+    let code = manager
+        .generate_code(email, CodePurpose::reset_password, now)
+        .await
+        .unwrap();
+    if old != code {
+        assert!(!verify(CodePurpose::reset_password, old, now).await);
     }
-}
+    // 过期
+    assert!(!verify(CodePurpose::reset_password, code, now + ttl).await);
 
-impl Actor for EmailManager {
-    type Context = Context<Self>;
+    // 清理过期的验证码
+    manager
+        .generate_code(email, CodePurpose::delete_account, now)
+        .await
+        .unwrap();
+    let code_repo = &repositories.code;
+    assert_eq!(code_repo.delete_expired_codes(now).await.unwrap(), 0);
+    assert_eq!(code_repo.delete_expired_codes(now + ttl).await.unwrap(), 1);
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct EmailSend {
-    pub msg: LettreMessage,
-}
+#[tokio::test(flavor = "multi_thread")]
+async fn test_verify_code_concurrent() {
+    use crate::lib::config::{init_db, memory_pool};
 
-impl Handler<EmailSend> for EmailManager {
-    type Result = ();
+    let mut config = HttpServerConfig::default();
+    config.code_config.store = CodeStore::database;
+    config.rate_limit_config.code_max_attempts = 3;
+    let repositories = init_db(&memory_pool().await, &config).await;
+    let manager = EmaiCodeManager::new(&repositories, &config);
+    let email = "steve@example.com";
+    let now = Utc::now().timestamp();
+    let code = manager
+        .generate_code(email, CodePurpose::reset_password, now)
+        .await
+        .unwrap();
+    let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1000000);
 
-    fn handle(&mut self, emailsend: EmailSend, _: &mut Context<Self>) {
-        let _ = self.clone().send(emailsend.msg);
+    // 同时提交的错误验证码不超过允许的次数
+    let guesses: Vec<_> = (0..10)
+        .map(|_| {
+            let (manager, wrong) = (manager.clone(), wrong.clone());
+            tokio::spawn(async move {
+                manager
+                    .verify_code(email, CodePurpose::reset_password, &wrong, now)
+                    .await
+                    .unwrap()
+            })
+        })
+        .collect();
+    for guess in guesses {
+        assert!(!guess.await.unwrap());
     }
-}
-
-#[derive(Message)]
-#[rtype(
-    result = "Result<lettre::transport::smtp::response::Response, lettre::transport::smtp::Error>"
-)]
-pub struct EmailCodeSend {
-    pub code: String,
-    pub to_email: Mailbox,
-}
-
-impl Handler<EmailCodeSend> for EmailManager {
-    type Result =
-        Result<lettre::transport::smtp::response::Response, lettre::transport::smtp::Error>;
-
-    fn handle(
-        &mut self,
-        emailcodesend: EmailCodeSend,
-        _: &mut Context<Self>,
-    ) -> Result<lettre::transport::smtp::response::Response, lettre::transport::smtp::Error> {
-        self.clone()
-            .send_email_code(emailcodesend.code, emailcodesend.to_email)
+    if let Ok(saved) = repositories.code.get_code(email, "reset_password").await {
+        assert_eq!(
+            repositories
+                .code
+                .reserve_code_attempt(email, "reset_password", &saved.code_hash, 4)
+                .await
+                .unwrap(),
+            1
+        );
     }
+    assert!(!manager
+        .verify_code(email, CodePurpose::reset_password, &code, now)
+        .await
+        .unwrap());
 }
-
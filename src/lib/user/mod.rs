@@ -1,4 +1,13 @@
+pub mod auth;
 pub mod web_user;
 pub mod sql_user;
+pub mod sql_code;
+pub mod sql_email_change;
+pub mod sql_session;
+pub mod session;
+pub mod sql_totp;
+pub mod totp;
+pub mod web_totp;
+pub mod web_email_change;
 
 pub mod email_code;
\ No newline at end of file
@@ -0,0 +1,252 @@
+// 登录会话
+//
+// 登录后签发短期的访问token和长期的刷新token。访问token中带有会话id(sid)，
+// 刷新token只在数据库中保存sha256，每次刷新都会更换。
+// 撤销会话时把sid记入内存中的撤销列表，列表只需保留一个访问token的有效期，
+// 启动时从数据库加载最近被撤销的会话。
+// 撤销列表只在当前进程内有效：多实例部署时，其他实例要到重启后才能看到撤销，
+// 在此之前已签发的访问token在其他实例上最多仍可使用一个有效期。
+// 不带sid的token无法撤销，只在测试中签发。
+// 删除用户时会话记录一并删除，其会话只记入当前进程的撤销列表，重启后不再加载。
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use log::warn;
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::lib::key::create_session_token;
+
+use super::sql_session::SessionRepository;
+
+// 访问token有效期(分)
+pub const ACCESS_TOKEN_MINUTES: i64 = 15;
+// 刷新token有效期(天)
+pub const REFRESH_TOKEN_DAYS: i64 = 30;
+
+// 登录/刷新后返回的token
+#[derive(Debug, Serialize)]
+pub struct SessionTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: i64, // 访问token的有效期(秒)
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Invalid, // 刷新token无效、已撤销或已过期
+    Reused,  // 刷新token被重复使用，会话已撤销
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Invalid => write!(f, "Invalid Refresh Token"),
+            SessionError::Reused => write!(f, "Refresh Token Reused"),
+            SessionError::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<sqlx::Error> for SessionError {
+    fn from(err: sqlx::Error) -> Self {
+        SessionError::Database(err)
+    }
+}
+
+// 已撤销的会话id、可以从列表中移除的时间，只记录本进程撤销的和启动时加载的会话
+static REVOKED_SESSIONS: OnceLock<Mutex<HashMap<i64, i64>>> = OnceLock::new();
+
+fn revoked_sessions() -> &'static Mutex<HashMap<i64, i64>> {
+    REVOKED_SESSIONS.get_or_init(Default::default)
+}
+
+// 记入撤销列表
+pub(crate) fn mark_revoked(ids: &[i64]) {
+    let now = Utc::now().timestamp();
+    let forget_at = now + Duration::minutes(ACCESS_TOKEN_MINUTES).num_seconds();
+    let mut revoked = revoked_sessions().lock().unwrap();
+    // 访问token都已过期的记录不再需要
+    revoked.retain(|_, at| *at > now);
+    for id in ids {
+        revoked.insert(*id, forget_at);
+    }
+}
+
+/// 会话是否已被撤销
+pub fn is_session_revoked(sid: i64) -> bool {
+    revoked_sessions().lock().unwrap().contains_key(&sid)
+}
+
+/// 启动时加载访问token尚未过期的已撤销会话
+pub async fn load_revoked_sessions(
+    session_repo: &dyn SessionRepository,
+) -> Result<(), sqlx::Error> {
+    let since = (Utc::now() - Duration::minutes(ACCESS_TOKEN_MINUTES)).timestamp();
+    mark_revoked(&session_repo.revoked_since(since).await?);
+    Ok(())
+}
+
+fn hash_refresh_token(refresh_token: &str) -> String {
+    format!("{:x}", Sha256::digest(refresh_token.as_bytes()))
+}
+
+fn generate_refresh_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn session_tokens(uid: i64, sid: i64, refresh_token: String) -> SessionTokens {
+    let duration = Duration::minutes(ACCESS_TOKEN_MINUTES);
+    SessionTokens {
+        access_token: create_session_token(uid as u64, sid, duration),
+        refresh_token,
+        expires_in: duration.num_seconds(),
+    }
+}
+
+/// 登录成功后创建会话
+pub async fn create_session(
+    session_repo: &dyn SessionRepository,
+    uid: i64,
+    ip: &str,
+) -> Result<SessionTokens, sqlx::Error> {
+    let refresh_token = generate_refresh_token();
+    let now = Utc::now();
+    let sid = session_repo
+        .create_session(
+            uid,
+            &hash_refresh_token(&refresh_token),
+            ip,
+            now.timestamp(),
+            (now + Duration::days(REFRESH_TOKEN_DAYS)).timestamp(),
+        )
+        .await?;
+    Ok(session_tokens(uid, sid, refresh_token))
+}
+
+/// 使用刷新token换取新的token，旧的刷新token随即失效
+pub async fn refresh_session(
+    session_repo: &dyn SessionRepository,
+    refresh_token: &str,
+    ip: &str,
+) -> Result<SessionTokens, SessionError> {
+    let refresh_hash = hash_refresh_token(refresh_token);
+    let now = Utc::now().timestamp();
+    let session = match session_repo.find_session(&refresh_hash).await {
+        Ok(session) => session,
+        Err(sqlx::Error::RowNotFound) => {
+            // 已经被轮换掉的刷新token再次出现，说明token可能泄露，撤销整个会话
+            if let Ok(session) = session_repo.find_session_by_previous(&refresh_hash).await {
+                warn!("会话 {} 的刷新token被重复使用，已撤销", session.id);
+                session_repo
+                    .revoke_session(session.uid, session.id, now)
+                    .await?;
+                mark_revoked(&[session.id]);
+                return Err(SessionError::Reused);
+            }
+            return Err(SessionError::Invalid);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if session.expires_at <= now {
+        return Err(SessionError::Invalid);
+    }
+
+    let new_token = generate_refresh_token();
+    let rotated = session_repo
+        .rotate_session(
+            session.id,
+            &refresh_hash,
+            &hash_refresh_token(&new_token),
+            ip,
+            now,
+        )
+        .await?;
+    if rotated == 0 {
+        // 并发刷新，另一个请求已经轮换
+        return Err(SessionError::Invalid);
+    }
+    Ok(session_tokens(session.uid, session.id, new_token))
+}
+
+/// 注销指定会话
+pub async fn revoke_session(
+    session_repo: &dyn SessionRepository,
+    uid: i64,
+    sid: i64,
+) -> Result<bool, sqlx::Error> {
+    let revoked = session_repo
+        .revoke_session(uid, sid, Utc::now().timestamp())
+        .await?;
+    mark_revoked(&[sid]);
+    Ok(revoked > 0)
+}
+
+/// 注销用户的所有会话，修改密码、删除账号时调用
+pub async fn revoke_user_sessions(
+    session_repo: &dyn SessionRepository,
+    uid: i64,
+) -> Result<usize, sqlx::Error> {
+    let revoked = session_repo
+        .revoke_user_sessions(uid, Utc::now().timestamp())
+        .await?;
+    mark_revoked(&revoked);
+    Ok(revoked.len())
+}
+
+#[tokio::test]
+async fn test_session() {
+    use crate::lib::{key::gettoken_to_user_no_time, repository::Repositories};
+
+    let repositories = Repositories::memory();
+    let session_repo = repositories.session.as_ref();
+
+    let tokens = create_session(session_repo, 1, "127.0.0.1").await.unwrap();
+    let claims = gettoken_to_user_no_time(&tokens.access_token)
+        .unwrap()
+        .claims;
+    assert_eq!(claims.uid, 1);
+    let sid = claims.sid.unwrap();
+
+    // 刷新后旧的刷新token失效，重复使用会撤销整个会话
+    let refreshed = refresh_session(session_repo, &tokens.refresh_token, "127.0.0.2")
+        .await
+        .unwrap();
+    let sessions = session_repo
+        .list_sessions(1, Utc::now().timestamp())
+        .await
+        .unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].ip, "127.0.0.2");
+    assert!(matches!(
+        refresh_session(session_repo, &tokens.refresh_token, "127.0.0.1").await,
+        Err(SessionError::Reused)
+    ));
+    assert!(matches!(
+        refresh_session(session_repo, &refreshed.refresh_token, "127.0.0.1").await,
+        Err(SessionError::Invalid)
+    ));
+    assert!(gettoken_to_user_no_time(&refreshed.access_token).is_err());
+    assert!(is_session_revoked(sid));
+
+    // 注销全部会话
+    let first = create_session(session_repo, 2, "127.0.0.1").await.unwrap();
+    let second = create_session(session_repo, 2, "127.0.0.1").await.unwrap();
+    assert_eq!(revoke_user_sessions(session_repo, 2).await.unwrap(), 2);
+    assert!(gettoken_to_user_no_time(&first.access_token).is_err());
+    assert!(gettoken_to_user_no_time(&second.access_token).is_err());
+    assert!(session_repo
+        .list_sessions(2, Utc::now().timestamp())
+        .await
+        .unwrap()
+        .is_empty());
+}
@@ -0,0 +1,378 @@
+use async_trait::async_trait;
+
+use crate::lib::repository::{
+    memory::CodeRow, MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+};
+
+// 邮箱验证码，每个邮箱的每种用途只保留最新的一个
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct VerificationCode {
+    pub code_hash: String,
+    pub expires_at: i64,
+}
+
+// 验证码数据仓库，验证码只保存sha256
+#[async_trait]
+pub trait CodeRepository: Send + Sync {
+    // 保存验证码，替换该邮箱同一用途的旧验证码
+    async fn save_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<(), sqlx::Error>;
+
+    // 获取验证码，不存在时返回 RowNotFound
+    async fn get_code(&self, email: &str, purpose: &str) -> Result<VerificationCode, sqlx::Error>;
+
+    // 占用一次尝试，尝试次数已达到 max_attempts 时不修改，返回受影响的行数
+    async fn reserve_code_attempt(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        max_attempts: i64,
+    ) -> Result<u64, sqlx::Error>;
+
+    // 删除验证码，返回受影响的行数；已被替换时返回0
+    async fn delete_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+    ) -> Result<u64, sqlx::Error>;
+
+    // 清理过期的验证码，返回删除的数量
+    async fn delete_expired_codes(&self, now: i64) -> Result<u64, sqlx::Error>;
+}
+
+#[async_trait]
+impl CodeRepository for SqliteRepository {
+    async fn save_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM verification_codes WHERE email = ? AND purpose = ?"#)
+            .bind(email)
+            .bind(purpose)
+            .execute(&mut *tx)
+            .await?;
+        let sql = r#"INSERT INTO verification_codes (email, purpose, code_hash, attempts,
+            expires_at, created_at) VALUES (?, ?, ?, 0, ?, ?)"#;
+        sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .bind(expires_at)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    async fn get_code(&self, email: &str, purpose: &str) -> Result<VerificationCode, sqlx::Error> {
+        let sql = r#"SELECT code_hash, expires_at FROM verification_codes WHERE email = ? AND purpose = ?"#;
+        sqlx::query_as(sql)
+            .bind(email)
+            .bind(purpose)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn reserve_code_attempt(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        max_attempts: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE verification_codes SET attempts = attempts + 1
+            WHERE email = ? AND purpose = ? AND code_hash = ? AND attempts < ?"#;
+        let result = sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .bind(max_attempts)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql =
+            r#"DELETE FROM verification_codes WHERE email = ? AND purpose = ? AND code_hash = ?"#;
+        let result = sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_expired_codes(&self, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"DELETE FROM verification_codes WHERE expires_at <= ?"#;
+        let result = sqlx::query(sql).bind(now).execute(&self.pool).await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl CodeRepository for MySqlRepository {
+    async fn save_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM verification_codes WHERE email = ? AND purpose = ?"#)
+            .bind(email)
+            .bind(purpose)
+            .execute(&mut *tx)
+            .await?;
+        let sql = r#"INSERT INTO verification_codes (email, purpose, code_hash, attempts,
+            expires_at, created_at) VALUES (?, ?, ?, 0, ?, ?)"#;
+        sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .bind(expires_at)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    async fn get_code(&self, email: &str, purpose: &str) -> Result<VerificationCode, sqlx::Error> {
+        let sql = r#"SELECT code_hash, expires_at FROM verification_codes WHERE email = ? AND purpose = ?"#;
+        sqlx::query_as(sql)
+            .bind(email)
+            .bind(purpose)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn reserve_code_attempt(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        max_attempts: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE verification_codes SET attempts = attempts + 1
+            WHERE email = ? AND purpose = ? AND code_hash = ? AND attempts < ?"#;
+        let result = sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .bind(max_attempts)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql =
+            r#"DELETE FROM verification_codes WHERE email = ? AND purpose = ? AND code_hash = ?"#;
+        let result = sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_expired_codes(&self, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"DELETE FROM verification_codes WHERE expires_at <= ?"#;
+        let result = sqlx::query(sql).bind(now).execute(&self.pool).await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl CodeRepository for PostgresRepository {
+    async fn save_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM verification_codes WHERE email = $1 AND purpose = $2"#)
+            .bind(email)
+            .bind(purpose)
+            .execute(&mut *tx)
+            .await?;
+        let sql = r#"INSERT INTO verification_codes (email, purpose, code_hash, attempts,
+            expires_at, created_at) VALUES ($1, $2, $3, 0, $4, $5)"#;
+        sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .bind(expires_at)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    async fn get_code(&self, email: &str, purpose: &str) -> Result<VerificationCode, sqlx::Error> {
+        let sql = r#"SELECT code_hash, expires_at FROM verification_codes WHERE email = $1 AND purpose = $2"#;
+        sqlx::query_as(sql)
+            .bind(email)
+            .bind(purpose)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn reserve_code_attempt(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        max_attempts: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE verification_codes SET attempts = attempts + 1
+            WHERE email = $1 AND purpose = $2 AND code_hash = $3 AND attempts < $4"#;
+        let result = sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .bind(max_attempts)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"DELETE FROM verification_codes
+            WHERE email = $1 AND purpose = $2 AND code_hash = $3"#;
+        let result = sqlx::query(sql)
+            .bind(email)
+            .bind(purpose)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_expired_codes(&self, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"DELETE FROM verification_codes WHERE expires_at <= $1"#;
+        let result = sqlx::query(sql).bind(now).execute(&self.pool).await?;
+        Ok(result.rows_affected())
+    }
+}
+
+impl From<&CodeRow> for VerificationCode {
+    fn from(row: &CodeRow) -> Self {
+        VerificationCode {
+            code_hash: row.code_hash.clone(),
+            expires_at: row.expires_at,
+        }
+    }
+}
+
+#[async_trait]
+impl CodeRepository for MemoryRepository {
+    async fn save_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        expires_at: i64,
+        _now: i64,
+    ) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .codes
+            .retain(|row| row.email != email || row.purpose != purpose);
+        state.codes.push(CodeRow {
+            email: email.to_string(),
+            purpose: purpose.to_string(),
+            code_hash: code_hash.to_string(),
+            attempts: 0,
+            expires_at,
+        });
+        Ok(())
+    }
+
+    async fn get_code(&self, email: &str, purpose: &str) -> Result<VerificationCode, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .codes
+            .iter()
+            .find(|row| row.email == email && row.purpose == purpose)
+            .map(VerificationCode::from)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn reserve_code_attempt(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+        max_attempts: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state.codes.iter_mut().filter(|row| {
+            row.email == email
+                && row.purpose == purpose
+                && row.code_hash == code_hash
+                && row.attempts < max_attempts
+        }) {
+            row.attempts += 1;
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn delete_code(
+        &self,
+        email: &str,
+        purpose: &str,
+        code_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.codes.len();
+        state.codes.retain(|row| {
+            row.email != email || row.purpose != purpose || row.code_hash != code_hash
+        });
+        Ok((before - state.codes.len()) as u64)
+    }
+
+    async fn delete_expired_codes(&self, now: i64) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.codes.len();
+        state.codes.retain(|row| row.expires_at > now);
+        Ok((before - state.codes.len()) as u64)
+    }
+}
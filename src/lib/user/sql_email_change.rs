@@ -0,0 +1,364 @@
+use async_trait::async_trait;
+
+use crate::lib::repository::{
+    memory::{unique_violation, EmailChangeRow},
+    MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+};
+
+// 邮箱更换记录，旧邮箱可以在 expires_at 之前撤销
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct EmailChange {
+    pub id: i64,
+    pub uid: i64,
+    pub old_email: String,
+}
+
+// 邮箱更换数据仓库，撤销链接只保存sha256
+#[async_trait]
+pub trait EmailChangeRepository: Send + Sync {
+    // 更换邮箱并记录，用户邮箱仍为 old_email 时才更换，返回受影响的行数；
+    // 新邮箱已被注册时返回唯一约束错误
+    async fn change_email(
+        &self,
+        uid: i64,
+        old_email: &str,
+        new_email: &str,
+        cancel_hash: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<u64, sqlx::Error>;
+
+    // 按撤销链接的sha256获取
+    async fn get_email_change(&self, cancel_hash: &str) -> Result<EmailChange, sqlx::Error>;
+
+    // 撤销更换，恢复为旧邮箱，并作废该用户之后的更换；返回受影响的行数
+    async fn cancel_email_change(&self, change: &EmailChange, now: i64)
+        -> Result<u64, sqlx::Error>;
+}
+
+#[async_trait]
+impl EmailChangeRepository for SqliteRepository {
+    async fn change_email(
+        &self,
+        uid: i64,
+        old_email: &str,
+        new_email: &str,
+        cancel_hash: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(r#"UPDATE users SET email = ? WHERE id = ? AND email = ?"#)
+            .bind(new_email)
+            .bind(uid)
+            .bind(old_email)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(0);
+        }
+        let sql = r#"INSERT INTO email_changes (uid, old_email, new_email, cancel_hash,
+            created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(old_email)
+            .bind(new_email)
+            .bind(cancel_hash)
+            .bind(now)
+            .bind(expires_at)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_email_change(&self, cancel_hash: &str) -> Result<EmailChange, sqlx::Error> {
+        let sql = r#"SELECT id, uid, old_email FROM email_changes WHERE cancel_hash = ?"#;
+        sqlx::query_as(sql)
+            .bind(cancel_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn cancel_email_change(
+        &self,
+        change: &EmailChange,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let sql = r#"UPDATE email_changes SET cancelled_at = ?
+            WHERE id = ? AND cancelled_at IS NULL AND expires_at > ?"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(change.id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(0);
+        }
+        let sql = r#"UPDATE email_changes SET cancelled_at = ?
+            WHERE uid = ? AND id > ? AND cancelled_at IS NULL"#;
+        sqlx::query(sql)
+            .bind(now)
+            .bind(change.uid)
+            .bind(change.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(r#"UPDATE users SET email = ? WHERE id = ?"#)
+            .bind(&change.old_email)
+            .bind(change.uid)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl EmailChangeRepository for MySqlRepository {
+    async fn change_email(
+        &self,
+        uid: i64,
+        old_email: &str,
+        new_email: &str,
+        cancel_hash: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(r#"UPDATE users SET email = ? WHERE id = ? AND email = ?"#)
+            .bind(new_email)
+            .bind(uid)
+            .bind(old_email)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(0);
+        }
+        let sql = r#"INSERT INTO email_changes (uid, old_email, new_email, cancel_hash,
+            created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(old_email)
+            .bind(new_email)
+            .bind(cancel_hash)
+            .bind(now)
+            .bind(expires_at)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_email_change(&self, cancel_hash: &str) -> Result<EmailChange, sqlx::Error> {
+        let sql = r#"SELECT id, uid, old_email FROM email_changes WHERE cancel_hash = ?"#;
+        sqlx::query_as(sql)
+            .bind(cancel_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn cancel_email_change(
+        &self,
+        change: &EmailChange,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let sql = r#"UPDATE email_changes SET cancelled_at = ?
+            WHERE id = ? AND cancelled_at IS NULL AND expires_at > ?"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(change.id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(0);
+        }
+        let sql = r#"UPDATE email_changes SET cancelled_at = ?
+            WHERE uid = ? AND id > ? AND cancelled_at IS NULL"#;
+        sqlx::query(sql)
+            .bind(now)
+            .bind(change.uid)
+            .bind(change.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(r#"UPDATE users SET email = ? WHERE id = ?"#)
+            .bind(&change.old_email)
+            .bind(change.uid)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl EmailChangeRepository for PostgresRepository {
+    async fn change_email(
+        &self,
+        uid: i64,
+        old_email: &str,
+        new_email: &str,
+        cancel_hash: &str,
+        expires_at: i64,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let result = sqlx::query(r#"UPDATE users SET email = $1 WHERE id = $2 AND email = $3"#)
+            .bind(new_email)
+            .bind(uid)
+            .bind(old_email)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(0);
+        }
+        let sql = r#"INSERT INTO email_changes (uid, old_email, new_email, cancel_hash,
+            created_at, expires_at) VALUES ($1, $2, $3, $4, $5, $6)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(old_email)
+            .bind(new_email)
+            .bind(cancel_hash)
+            .bind(now)
+            .bind(expires_at)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_email_change(&self, cancel_hash: &str) -> Result<EmailChange, sqlx::Error> {
+        let sql = r#"SELECT id, uid, old_email FROM email_changes WHERE cancel_hash = $1"#;
+        sqlx::query_as(sql)
+            .bind(cancel_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn cancel_email_change(
+        &self,
+        change: &EmailChange,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let sql = r#"UPDATE email_changes SET cancelled_at = $1
+            WHERE id = $2 AND cancelled_at IS NULL AND expires_at > $3"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(change.id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(0);
+        }
+        let sql = r#"UPDATE email_changes SET cancelled_at = $1
+            WHERE uid = $2 AND id > $3 AND cancelled_at IS NULL"#;
+        sqlx::query(sql)
+            .bind(now)
+            .bind(change.uid)
+            .bind(change.id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(r#"UPDATE users SET email = $1 WHERE id = $2"#)
+            .bind(&change.old_email)
+            .bind(change.uid)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+}
+
+impl From<&EmailChangeRow> for EmailChange {
+    fn from(row: &EmailChangeRow) -> Self {
+        EmailChange {
+            id: row.id,
+            uid: row.uid,
+            old_email: row.old_email.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmailChangeRepository for MemoryRepository {
+    async fn change_email(
+        &self,
+        uid: i64,
+        old_email: &str,
+        new_email: &str,
+        cancel_hash: &str,
+        expires_at: i64,
+        _now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.users.iter().any(|row| row.email == new_email) {
+            return Err(unique_violation("users.email"));
+        }
+        let Some(user) = state
+            .users
+            .iter_mut()
+            .find(|row| row.id == uid && row.email == old_email)
+        else {
+            return Ok(0);
+        };
+        user.email = new_email.to_string();
+        let id = state.next_id();
+        state.email_changes.push(EmailChangeRow {
+            id,
+            uid,
+            old_email: old_email.to_string(),
+            cancel_hash: cancel_hash.to_string(),
+            expires_at,
+            cancelled_at: None,
+        });
+        Ok(1)
+    }
+
+    async fn get_email_change(&self, cancel_hash: &str) -> Result<EmailChange, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .email_changes
+            .iter()
+            .find(|row| row.cancel_hash == cancel_hash)
+            .map(EmailChange::from)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn cancel_email_change(
+        &self,
+        change: &EmailChange,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let pending = |row: &EmailChangeRow| row.cancelled_at.is_none();
+        if !state
+            .email_changes
+            .iter()
+            .any(|row| row.id == change.id && pending(row) && row.expires_at > now)
+        {
+            return Ok(0);
+        }
+        if state
+            .users
+            .iter()
+            .any(|row| row.email == change.old_email && row.id != change.uid)
+        {
+            return Err(unique_violation("users.email"));
+        }
+        for row in state
+            .email_changes
+            .iter_mut()
+            .filter(|row| row.uid == change.uid && row.id >= change.id && pending(row))
+        {
+            row.cancelled_at = Some(now);
+        }
+        for row in state.users.iter_mut().filter(|row| row.id == change.uid) {
+            row.email = change.old_email.clone();
+        }
+        Ok(1)
+    }
+}
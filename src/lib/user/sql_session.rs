@@ -0,0 +1,519 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::lib::repository::{
+    memory::{unique_violation, SessionRow},
+    MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+};
+
+// 登录会话，不包含刷新token
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Session {
+    pub id: i64,
+    pub uid: i64,
+    pub ip: String,
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+}
+
+// 会话数据仓库，刷新token只保存sha256
+#[async_trait]
+pub trait SessionRepository: Send + Sync {
+    // 创建会话，返回会话id
+    async fn create_session(
+        &self,
+        uid: i64,
+        refresh_hash: &str,
+        ip: &str,
+        created_at: i64,
+        expires_at: i64,
+    ) -> Result<i64, sqlx::Error>;
+
+    // 按当前的刷新token查找未撤销的会话
+    async fn find_session(&self, refresh_hash: &str) -> Result<Session, sqlx::Error>;
+
+    // 按上一个刷新token查找未撤销的会话，用于发现刷新token被重复使用
+    async fn find_session_by_previous(&self, refresh_hash: &str) -> Result<Session, sqlx::Error>;
+
+    // 轮换刷新token，返回受影响的行数；并发刷新时只有一个请求成功
+    async fn rotate_session(
+        &self,
+        id: i64,
+        refresh_hash: &str,
+        new_hash: &str,
+        ip: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error>;
+
+    // 撤销指定会话，返回受影响的行数
+    async fn revoke_session(&self, uid: i64, id: i64, now: i64) -> Result<u64, sqlx::Error>;
+
+    // 撤销用户的所有会话，返回被撤销的会话id
+    async fn revoke_user_sessions(&self, uid: i64, now: i64) -> Result<Vec<i64>, sqlx::Error>;
+
+    // 用户未撤销且未过期的会话
+    async fn list_sessions(&self, uid: i64, now: i64) -> Result<Vec<Session>, sqlx::Error>;
+
+    // since之后被撤销的会话id
+    async fn revoked_since(&self, since: i64) -> Result<Vec<i64>, sqlx::Error>;
+}
+
+#[async_trait]
+impl SessionRepository for SqliteRepository {
+    async fn create_session(
+        &self,
+        uid: i64,
+        refresh_hash: &str,
+        ip: &str,
+        created_at: i64,
+        expires_at: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO sessions (uid, refresh_hash, ip, created_at, last_used_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(uid)
+            .bind(refresh_hash)
+            .bind(ip)
+            .bind(created_at)
+            .bind(created_at)
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_rowid())
+    }
+
+    async fn find_session(&self, refresh_hash: &str) -> Result<Session, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE refresh_hash = ? AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(refresh_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn find_session_by_previous(&self, refresh_hash: &str) -> Result<Session, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE previous_hash = ? AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(refresh_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn rotate_session(
+        &self,
+        id: i64,
+        refresh_hash: &str,
+        new_hash: &str,
+        ip: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE sessions SET refresh_hash = ?, previous_hash = ?, ip = ?, last_used_at = ?
+            WHERE id = ? AND refresh_hash = ? AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(new_hash)
+            .bind(refresh_hash)
+            .bind(ip)
+            .bind(now)
+            .bind(id)
+            .bind(refresh_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_session(&self, uid: i64, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql =
+            r#"UPDATE sessions SET revoked_at = ? WHERE id = ? AND uid = ? AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(id)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_user_sessions(&self, uid: i64, now: i64) -> Result<Vec<i64>, sqlx::Error> {
+        let sql = r#"UPDATE sessions SET revoked_at = ? WHERE uid = ? AND revoked_at IS NULL"#;
+        sqlx::query(sql)
+            .bind(now)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        let sql = r#"SELECT id FROM sessions WHERE uid = ? AND revoked_at = ?"#;
+        let rows = sqlx::query(sql)
+            .bind(uid)
+            .bind(now)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|row| row.try_get(0)).collect()
+    }
+
+    async fn list_sessions(&self, uid: i64, now: i64) -> Result<Vec<Session>, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE uid = ? AND revoked_at IS NULL AND expires_at > ? ORDER BY id"#;
+        sqlx::query_as(sql)
+            .bind(uid)
+            .bind(now)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn revoked_since(&self, since: i64) -> Result<Vec<i64>, sqlx::Error> {
+        let sql = r#"SELECT id FROM sessions WHERE revoked_at >= ?"#;
+        let rows = sqlx::query(sql).bind(since).fetch_all(&self.pool).await?;
+        rows.iter().map(|row| row.try_get(0)).collect()
+    }
+}
+
+#[async_trait]
+impl SessionRepository for MySqlRepository {
+    async fn create_session(
+        &self,
+        uid: i64,
+        refresh_hash: &str,
+        ip: &str,
+        created_at: i64,
+        expires_at: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO sessions (uid, refresh_hash, ip, created_at, last_used_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(uid)
+            .bind(refresh_hash)
+            .bind(ip)
+            .bind(created_at)
+            .bind(created_at)
+            .bind(expires_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_id() as i64)
+    }
+
+    async fn find_session(&self, refresh_hash: &str) -> Result<Session, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE refresh_hash = ? AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(refresh_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn find_session_by_previous(&self, refresh_hash: &str) -> Result<Session, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE previous_hash = ? AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(refresh_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn rotate_session(
+        &self,
+        id: i64,
+        refresh_hash: &str,
+        new_hash: &str,
+        ip: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE sessions SET refresh_hash = ?, previous_hash = ?, ip = ?, last_used_at = ?
+            WHERE id = ? AND refresh_hash = ? AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(new_hash)
+            .bind(refresh_hash)
+            .bind(ip)
+            .bind(now)
+            .bind(id)
+            .bind(refresh_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_session(&self, uid: i64, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql =
+            r#"UPDATE sessions SET revoked_at = ? WHERE id = ? AND uid = ? AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(id)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_user_sessions(&self, uid: i64, now: i64) -> Result<Vec<i64>, sqlx::Error> {
+        let sql = r#"UPDATE sessions SET revoked_at = ? WHERE uid = ? AND revoked_at IS NULL"#;
+        sqlx::query(sql)
+            .bind(now)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        let sql = r#"SELECT id FROM sessions WHERE uid = ? AND revoked_at = ?"#;
+        let rows = sqlx::query(sql)
+            .bind(uid)
+            .bind(now)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|row| row.try_get(0)).collect()
+    }
+
+    async fn list_sessions(&self, uid: i64, now: i64) -> Result<Vec<Session>, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE uid = ? AND revoked_at IS NULL AND expires_at > ? ORDER BY id"#;
+        sqlx::query_as(sql)
+            .bind(uid)
+            .bind(now)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn revoked_since(&self, since: i64) -> Result<Vec<i64>, sqlx::Error> {
+        let sql = r#"SELECT id FROM sessions WHERE revoked_at >= ?"#;
+        let rows = sqlx::query(sql).bind(since).fetch_all(&self.pool).await?;
+        rows.iter().map(|row| row.try_get(0)).collect()
+    }
+}
+
+#[async_trait]
+impl SessionRepository for PostgresRepository {
+    async fn create_session(
+        &self,
+        uid: i64,
+        refresh_hash: &str,
+        ip: &str,
+        created_at: i64,
+        expires_at: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO sessions (uid, refresh_hash, ip, created_at, last_used_at, expires_at)
+            VALUES ($1, $2, $3, $4, $4, $5) RETURNING id"#;
+        let row = sqlx::query(sql)
+            .bind(uid)
+            .bind(refresh_hash)
+            .bind(ip)
+            .bind(created_at)
+            .bind(expires_at)
+            .fetch_one(&self.pool)
+            .await?;
+        row.try_get("id")
+    }
+
+    async fn find_session(&self, refresh_hash: &str) -> Result<Session, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE refresh_hash = $1 AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(refresh_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn find_session_by_previous(&self, refresh_hash: &str) -> Result<Session, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE previous_hash = $1 AND revoked_at IS NULL"#;
+        sqlx::query_as(sql)
+            .bind(refresh_hash)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn rotate_session(
+        &self,
+        id: i64,
+        refresh_hash: &str,
+        new_hash: &str,
+        ip: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE sessions SET refresh_hash = $1, previous_hash = $2, ip = $3, last_used_at = $4
+            WHERE id = $5 AND refresh_hash = $2 AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(new_hash)
+            .bind(refresh_hash)
+            .bind(ip)
+            .bind(now)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_session(&self, uid: i64, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE sessions SET revoked_at = $1 WHERE id = $2 AND uid = $3 AND revoked_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(id)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn revoke_user_sessions(&self, uid: i64, now: i64) -> Result<Vec<i64>, sqlx::Error> {
+        let sql = r#"UPDATE sessions SET revoked_at = $1 WHERE uid = $2 AND revoked_at IS NULL
+            RETURNING id"#;
+        let rows = sqlx::query(sql)
+            .bind(now)
+            .bind(uid)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|row| row.try_get(0)).collect()
+    }
+
+    async fn list_sessions(&self, uid: i64, now: i64) -> Result<Vec<Session>, sqlx::Error> {
+        let sql = r#"SELECT id, uid, ip, created_at, last_used_at, expires_at FROM sessions
+            WHERE uid = $1 AND revoked_at IS NULL AND expires_at > $2 ORDER BY id"#;
+        sqlx::query_as(sql)
+            .bind(uid)
+            .bind(now)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    async fn revoked_since(&self, since: i64) -> Result<Vec<i64>, sqlx::Error> {
+        let sql = r#"SELECT id FROM sessions WHERE revoked_at >= $1"#;
+        let rows = sqlx::query(sql).bind(since).fetch_all(&self.pool).await?;
+        rows.iter().map(|row| row.try_get(0)).collect()
+    }
+}
+
+impl From<&SessionRow> for Session {
+    fn from(row: &SessionRow) -> Self {
+        Session {
+            id: row.id,
+            uid: row.uid,
+            ip: row.ip.clone(),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+            expires_at: row.expires_at,
+        }
+    }
+}
+
+#[async_trait]
+impl SessionRepository for MemoryRepository {
+    async fn create_session(
+        &self,
+        uid: i64,
+        refresh_hash: &str,
+        ip: &str,
+        created_at: i64,
+        expires_at: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .sessions
+            .iter()
+            .any(|row| row.refresh_hash == refresh_hash)
+        {
+            return Err(unique_violation("sessions.refresh_hash"));
+        }
+        let id = state.next_id();
+        state.sessions.push(SessionRow {
+            id,
+            uid,
+            refresh_hash: refresh_hash.to_string(),
+            previous_hash: None,
+            ip: ip.to_string(),
+            created_at,
+            last_used_at: created_at,
+            expires_at,
+            revoked_at: None,
+        });
+        Ok(id)
+    }
+
+    async fn find_session(&self, refresh_hash: &str) -> Result<Session, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .sessions
+            .iter()
+            .find(|row| row.refresh_hash == refresh_hash && row.revoked_at.is_none())
+            .map(Session::from)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn find_session_by_previous(&self, refresh_hash: &str) -> Result<Session, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .sessions
+            .iter()
+            .find(|row| {
+                row.previous_hash.as_deref() == Some(refresh_hash) && row.revoked_at.is_none()
+            })
+            .map(Session::from)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn rotate_session(
+        &self,
+        id: i64,
+        refresh_hash: &str,
+        new_hash: &str,
+        ip: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state.sessions.iter_mut().filter(|row| {
+            row.id == id && row.refresh_hash == refresh_hash && row.revoked_at.is_none()
+        }) {
+            row.previous_hash = Some(row.refresh_hash.clone());
+            row.refresh_hash = new_hash.to_string();
+            row.ip = ip.to_string();
+            row.last_used_at = now;
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn revoke_session(&self, uid: i64, id: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state
+            .sessions
+            .iter_mut()
+            .filter(|row| row.id == id && row.uid == uid && row.revoked_at.is_none())
+        {
+            row.revoked_at = Some(now);
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn revoke_user_sessions(&self, uid: i64, now: i64) -> Result<Vec<i64>, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut revoked = Vec::new();
+        for row in state
+            .sessions
+            .iter_mut()
+            .filter(|row| row.uid == uid && row.revoked_at.is_none())
+        {
+            row.revoked_at = Some(now);
+            revoked.push(row.id);
+        }
+        Ok(revoked)
+    }
+
+    async fn list_sessions(&self, uid: i64, now: i64) -> Result<Vec<Session>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .sessions
+            .iter()
+            .filter(|row| row.uid == uid && row.revoked_at.is_none() && row.expires_at > now)
+            .map(Session::from)
+            .collect())
+    }
+
+    async fn revoked_since(&self, since: i64) -> Result<Vec<i64>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .sessions
+            .iter()
+            .filter(|row| row.revoked_at.is_some_and(|revoked_at| revoked_at >= since))
+            .map(|row| row.id)
+            .collect())
+    }
+}
@@ -0,0 +1,551 @@
+use async_trait::async_trait;
+use sqlx::Row;
+
+use crate::lib::repository::{
+    memory::{unique_violation, RecoveryCodeRow, TotpRow},
+    MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+};
+
+// 两步验证密钥
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct TotpSecret {
+    pub secret: String,          // 加密后的密钥
+    pub last_step: i64,          // 最后一次通过验证的时间步，同一个验证码不能重复使用
+    pub enabled_at: Option<i64>, // 确认开启的时间，扫码后尚未确认时为空
+}
+
+// 两步验证数据仓库，恢复码只保存sha256
+#[async_trait]
+pub trait TotpRepository: Send + Sync {
+    // 获取用户的两步验证密钥
+    async fn get_totp(&self, uid: i64) -> Result<TotpSecret, sqlx::Error>;
+
+    // 保存新密钥，替换尚未开启的密钥；已开启时返回唯一约束错误
+    async fn save_totp_secret(&self, uid: i64, secret: &str, now: i64) -> Result<(), sqlx::Error>;
+
+    // 确认开启，返回受影响的行数
+    async fn enable_totp(&self, uid: i64, step: i64, now: i64) -> Result<u64, sqlx::Error>;
+
+    // 记录通过验证的时间步，只能增大；返回0表示该验证码已被使用
+    async fn update_last_step(&self, uid: i64, step: i64) -> Result<u64, sqlx::Error>;
+
+    // 替换加密后的密钥，只有当前密文仍为 old_secret 时才更新，返回受影响的行数
+    async fn update_totp_secret(
+        &self,
+        uid: i64,
+        old_secret: &str,
+        new_secret: &str,
+    ) -> Result<u64, sqlx::Error>;
+
+    // 关闭两步验证并删除恢复码，返回受影响的行数
+    async fn delete_totp(&self, uid: i64) -> Result<u64, sqlx::Error>;
+
+    // 替换用户的全部恢复码
+    async fn replace_recovery_codes(
+        &self,
+        uid: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error>;
+
+    // 使用恢复码，返回受影响的行数；每个恢复码只能使用一次
+    async fn use_recovery_code(
+        &self,
+        uid: i64,
+        code_hash: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error>;
+
+    // 剩余可用的恢复码数量
+    async fn count_recovery_codes(&self, uid: i64) -> Result<i64, sqlx::Error>;
+}
+
+#[async_trait]
+impl TotpRepository for SqliteRepository {
+    async fn get_totp(&self, uid: i64) -> Result<TotpSecret, sqlx::Error> {
+        let sql = r#"SELECT secret, last_step, enabled_at FROM totp WHERE uid = ?"#;
+        sqlx::query_as(sql).bind(uid).fetch_one(&self.pool).await
+    }
+
+    async fn save_totp_secret(&self, uid: i64, secret: &str, now: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"DELETE FROM totp WHERE uid = ? AND enabled_at IS NULL"#;
+        sqlx::query(sql).bind(uid).execute(&self.pool).await?;
+        let sql = r#"INSERT INTO totp (uid, secret, last_step, created_at) VALUES (?, ?, 0, ?)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(secret)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn enable_totp(&self, uid: i64, step: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql =
+            r#"UPDATE totp SET enabled_at = ?, last_step = ? WHERE uid = ? AND enabled_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(step)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn update_last_step(&self, uid: i64, step: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE totp SET last_step = ? WHERE uid = ? AND last_step < ?"#;
+        let result = sqlx::query(sql)
+            .bind(step)
+            .bind(uid)
+            .bind(step)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn update_totp_secret(
+        &self,
+        uid: i64,
+        old_secret: &str,
+        new_secret: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE totp SET secret = ? WHERE uid = ? AND secret = ?"#;
+        let result = sqlx::query(sql)
+            .bind(new_secret)
+            .bind(uid)
+            .bind(old_secret)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_totp(&self, uid: i64) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM recovery_codes WHERE uid = ?"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query(r#"DELETE FROM totp WHERE uid = ?"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        uid: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM recovery_codes WHERE uid = ?"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        for code_hash in code_hashes {
+            sqlx::query(r#"INSERT INTO recovery_codes (uid, code_hash) VALUES (?, ?)"#)
+                .bind(uid)
+                .bind(code_hash)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn use_recovery_code(
+        &self,
+        uid: i64,
+        code_hash: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE recovery_codes SET used_at = ?
+            WHERE uid = ? AND code_hash = ? AND used_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(uid)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn count_recovery_codes(&self, uid: i64) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT COUNT(*) FROM recovery_codes WHERE uid = ? AND used_at IS NULL"#;
+        let row = sqlx::query(sql).bind(uid).fetch_one(&self.pool).await?;
+        row.try_get(0)
+    }
+}
+
+#[async_trait]
+impl TotpRepository for MySqlRepository {
+    async fn get_totp(&self, uid: i64) -> Result<TotpSecret, sqlx::Error> {
+        let sql = r#"SELECT secret, last_step, enabled_at FROM totp WHERE uid = ?"#;
+        sqlx::query_as(sql).bind(uid).fetch_one(&self.pool).await
+    }
+
+    async fn save_totp_secret(&self, uid: i64, secret: &str, now: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"DELETE FROM totp WHERE uid = ? AND enabled_at IS NULL"#;
+        sqlx::query(sql).bind(uid).execute(&self.pool).await?;
+        let sql = r#"INSERT INTO totp (uid, secret, last_step, created_at) VALUES (?, ?, 0, ?)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(secret)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn enable_totp(&self, uid: i64, step: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql =
+            r#"UPDATE totp SET enabled_at = ?, last_step = ? WHERE uid = ? AND enabled_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(step)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn update_last_step(&self, uid: i64, step: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE totp SET last_step = ? WHERE uid = ? AND last_step < ?"#;
+        let result = sqlx::query(sql)
+            .bind(step)
+            .bind(uid)
+            .bind(step)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn update_totp_secret(
+        &self,
+        uid: i64,
+        old_secret: &str,
+        new_secret: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE totp SET secret = ? WHERE uid = ? AND secret = ?"#;
+        let result = sqlx::query(sql)
+            .bind(new_secret)
+            .bind(uid)
+            .bind(old_secret)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_totp(&self, uid: i64) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM recovery_codes WHERE uid = ?"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query(r#"DELETE FROM totp WHERE uid = ?"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        uid: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM recovery_codes WHERE uid = ?"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        for code_hash in code_hashes {
+            sqlx::query(r#"INSERT INTO recovery_codes (uid, code_hash) VALUES (?, ?)"#)
+                .bind(uid)
+                .bind(code_hash)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn use_recovery_code(
+        &self,
+        uid: i64,
+        code_hash: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE recovery_codes SET used_at = ?
+            WHERE uid = ? AND code_hash = ? AND used_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(uid)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn count_recovery_codes(&self, uid: i64) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT COUNT(*) FROM recovery_codes WHERE uid = ? AND used_at IS NULL"#;
+        let row = sqlx::query(sql).bind(uid).fetch_one(&self.pool).await?;
+        row.try_get(0)
+    }
+}
+
+#[async_trait]
+impl TotpRepository for PostgresRepository {
+    async fn get_totp(&self, uid: i64) -> Result<TotpSecret, sqlx::Error> {
+        let sql = r#"SELECT secret, last_step, enabled_at FROM totp WHERE uid = $1"#;
+        sqlx::query_as(sql).bind(uid).fetch_one(&self.pool).await
+    }
+
+    async fn save_totp_secret(&self, uid: i64, secret: &str, now: i64) -> Result<(), sqlx::Error> {
+        let sql = r#"DELETE FROM totp WHERE uid = $1 AND enabled_at IS NULL"#;
+        sqlx::query(sql).bind(uid).execute(&self.pool).await?;
+        let sql = r#"INSERT INTO totp (uid, secret, last_step, created_at) VALUES ($1, $2, 0, $3)"#;
+        sqlx::query(sql)
+            .bind(uid)
+            .bind(secret)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn enable_totp(&self, uid: i64, step: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE totp SET enabled_at = $1, last_step = $2
+            WHERE uid = $3 AND enabled_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(step)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn update_last_step(&self, uid: i64, step: i64) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE totp SET last_step = $1 WHERE uid = $2 AND last_step < $1"#;
+        let result = sqlx::query(sql)
+            .bind(step)
+            .bind(uid)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn update_totp_secret(
+        &self,
+        uid: i64,
+        old_secret: &str,
+        new_secret: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE totp SET secret = $1 WHERE uid = $2 AND secret = $3"#;
+        let result = sqlx::query(sql)
+            .bind(new_secret)
+            .bind(uid)
+            .bind(old_secret)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_totp(&self, uid: i64) -> Result<u64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM recovery_codes WHERE uid = $1"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query(r#"DELETE FROM totp WHERE uid = $1"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        uid: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(r#"DELETE FROM recovery_codes WHERE uid = $1"#)
+            .bind(uid)
+            .execute(&mut *tx)
+            .await?;
+        for code_hash in code_hashes {
+            sqlx::query(r#"INSERT INTO recovery_codes (uid, code_hash) VALUES ($1, $2)"#)
+                .bind(uid)
+                .bind(code_hash)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn use_recovery_code(
+        &self,
+        uid: i64,
+        code_hash: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE recovery_codes SET used_at = $1
+            WHERE uid = $2 AND code_hash = $3 AND used_at IS NULL"#;
+        let result = sqlx::query(sql)
+            .bind(now)
+            .bind(uid)
+            .bind(code_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn count_recovery_codes(&self, uid: i64) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT COUNT(*) FROM recovery_codes WHERE uid = $1 AND used_at IS NULL"#;
+        let row = sqlx::query(sql).bind(uid).fetch_one(&self.pool).await?;
+        row.try_get(0)
+    }
+}
+
+impl From<&TotpRow> for TotpSecret {
+    fn from(row: &TotpRow) -> Self {
+        TotpSecret {
+            secret: row.secret.clone(),
+            last_step: row.last_step,
+            enabled_at: row.enabled_at,
+        }
+    }
+}
+
+#[async_trait]
+impl TotpRepository for MemoryRepository {
+    async fn get_totp(&self, uid: i64) -> Result<TotpSecret, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .totp
+            .iter()
+            .find(|row| row.uid == uid)
+            .map(TotpSecret::from)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn save_totp_secret(&self, uid: i64, secret: &str, _now: i64) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .totp
+            .retain(|row| row.uid != uid || row.enabled_at.is_some());
+        if state.totp.iter().any(|row| row.uid == uid) {
+            return Err(unique_violation("totp.uid"));
+        }
+        state.totp.push(TotpRow {
+            uid,
+            secret: secret.to_string(),
+            last_step: 0,
+            enabled_at: None,
+        });
+        Ok(())
+    }
+
+    async fn enable_totp(&self, uid: i64, step: i64, now: i64) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state
+            .totp
+            .iter_mut()
+            .filter(|row| row.uid == uid && row.enabled_at.is_none())
+        {
+            row.enabled_at = Some(now);
+            row.last_step = step;
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn update_last_step(&self, uid: i64, step: i64) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state
+            .totp
+            .iter_mut()
+            .filter(|row| row.uid == uid && row.last_step < step)
+        {
+            row.last_step = step;
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn update_totp_secret(
+        &self,
+        uid: i64,
+        old_secret: &str,
+        new_secret: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state
+            .totp
+            .iter_mut()
+            .filter(|row| row.uid == uid && row.secret == old_secret)
+        {
+            row.secret = new_secret.to_string();
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn delete_totp(&self, uid: i64) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.recovery_codes.retain(|row| row.uid != uid);
+        let before = state.totp.len();
+        state.totp.retain(|row| row.uid != uid);
+        Ok((before - state.totp.len()) as u64)
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        uid: i64,
+        code_hashes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.recovery_codes.retain(|row| row.uid != uid);
+        for code_hash in code_hashes {
+            state.recovery_codes.push(RecoveryCodeRow {
+                uid,
+                code_hash: code_hash.clone(),
+                used_at: None,
+            });
+        }
+        Ok(())
+    }
+
+    async fn use_recovery_code(
+        &self,
+        uid: i64,
+        code_hash: &str,
+        now: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        if let Some(row) = state
+            .recovery_codes
+            .iter_mut()
+            .find(|row| row.uid == uid && row.code_hash == code_hash && row.used_at.is_none())
+        {
+            row.used_at = Some(now);
+            affected += 1;
+        }
+        Ok(affected)
+    }
+
+    async fn count_recovery_codes(&self, uid: i64) -> Result<i64, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .recovery_codes
+            .iter()
+            .filter(|row| row.uid == uid && row.used_at.is_none())
+            .count() as i64)
+    }
+}
@@ -1,246 +1,351 @@
-use futures_util::TryStreamExt;
+use async_trait::async_trait;
+use serde::Serialize;
 use sqlx::Row;
 
-use crate::lib::config::ConnectionType;
+use crate::lib::{
+    key::{hash_password, verify_password, PasswordMatch},
+    repository::{
+        memory::{unique_violation, UserRow},
+        MemoryRepository, MySqlRepository, PostgresRepository, SqliteRepository,
+    },
+};
 
 use super::web_user::RegisterUser;
 
-// 创建用户表
-pub async fn create_user_table(conn: ConnectionType) -> Result<ConnectionType, sqlx::Error> {
-    let sql = r#"
-        CREATE TABLE IF NOT EXISTS user (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            email TEXT NOT NULL UNIQUE,
-            password TEXT NOT NULL
-        );
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            sqlx::query(sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Sqlite(conn))
-        }
+// 用户信息，不包含密码
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    pub id: i64,
+    pub email: String,
+}
 
-        ConnectionType::Mysql(mut conn) => {
-            sqlx::query(sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Mysql(conn))
-        }
-        ConnectionType::Postgres(mut conn) => {
-            sqlx::query(sql).execute(&mut conn).await?;
-            Ok(ConnectionType::Postgres(conn))
-        }
+// 用户数据仓库
+//
+// 数据库中只保存 argon2id 哈希，哈希与校验在 register_user/login_user/change_password 中完成
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    // 写入新用户，返回新用户的uid
+    async fn insert_user(&self, email: &str, password_hash: &str) -> Result<i64, sqlx::Error>;
+
+    // 获取uid和保存的密码
+    async fn get_password_hash(&self, email: &str) -> Result<(i64, String), sqlx::Error>;
+
+    // 更新保存的密码，返回受影响的行数
+    async fn update_password_hash(
+        &self,
+        email: &str,
+        password_hash: &str,
+    ) -> Result<u64, sqlx::Error>;
+
+    // admin-获取所有用户
+    async fn get_all_user(&self) -> Result<Vec<UserInfo>, sqlx::Error>;
+
+    // 获取用户uid
+    async fn get_user_id(&self, email: &str) -> Result<i64, sqlx::Error>;
+
+    // 获取用户邮箱
+    async fn get_user_email(&self, uid: i64) -> Result<String, sqlx::Error>;
+
+    // 注册账号，返回新用户的uid
+    async fn register_user(&self, user: &RegisterUser) -> Result<i64, sqlx::Error> {
+        let password_hash = hash_password(&user.password).await;
+        self.insert_user(&user.email, &password_hash).await
     }
-}
 
-// 注册账号
-pub async fn register_user(conn: ConnectionType, user: &RegisterUser) -> Result<u64, sqlx::Error> {
-    let sql = r#"
-        INSERT INTO user (email, password) VALUES (?, ?);
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&user.email)
-                .bind(&user.password)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.last_insert_rowid().try_into().unwrap())
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&user.email)
-                .bind(&user.password)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.last_insert_id())
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&user.email)
-                .bind(&user.password)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.rows_affected())
+    // 登录账号，返回uid；旧的明文密码在登录成功后升级为哈希
+    async fn login_user(&self, user: &RegisterUser) -> Result<i64, sqlx::Error> {
+        let (uid, stored) = self.get_password_hash(&user.email).await?;
+        match verify_password(&user.password, &stored).await {
+            PasswordMatch::Valid => Ok(uid),
+            PasswordMatch::Plaintext => {
+                let password_hash = hash_password(&user.password).await;
+                self.update_password_hash(&user.email, &password_hash)
+                    .await?;
+                Ok(uid)
+            }
+            PasswordMatch::Invalid => Err(sqlx::Error::RowNotFound),
         }
     }
-}
 
-// 登录账号
-pub async fn login_user(conn: ConnectionType, user: &RegisterUser) -> Result<i64, sqlx::Error> {
-    let sql = r#"
-        SELECT id FROM user WHERE email = ? AND password = ?;
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let row = sqlx::query(sql)
-                .bind(&user.email)
-                .bind(&user.password)
-                .fetch_one(&mut conn)
-                .await?;
-
-            Ok(row.try_get("id")?)
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let row = sqlx::query(sql)
-                .bind(&user.email)
-                .bind(&user.password)
-                .fetch_one(&mut conn)
-                .await?;
-            Ok(row.try_get("id")?)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let row = sqlx::query(sql)
-                .bind(&user.email)
-                .bind(&user.password)
-                .fetch_one(&mut conn)
-                .await?;
-            Ok(row.try_get("id")?)
-        }
+    // 修改密码，返回受影响的行数
+    async fn change_password(&self, user: &RegisterUser) -> Result<u64, sqlx::Error> {
+        let password_hash = hash_password(&user.password).await;
+        self.update_password_hash(&user.email, &password_hash).await
     }
 }
 
-// 修改密码
-pub async fn change_password(
-    conn: ConnectionType,
-    user: &RegisterUser,
-) -> Result<u64, sqlx::Error> {
-    let sql = r#"
-        UPDATE user SET password = ? WHERE email = ?;
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&user.password)
-                .bind(&user.email)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.last_insert_rowid().try_into().unwrap())
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&user.password)
-                .bind(&user.email)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.last_insert_id())
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(&user.password)
-                .bind(&user.email)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.rows_affected())
-        }
+#[async_trait]
+impl UserRepository for SqliteRepository {
+    async fn insert_user(&self, email: &str, password_hash: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO users (email, password) VALUES (?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(email)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_rowid())
     }
-}
 
-// admin-获取所有用户
-pub async fn get_all_user(conn: ConnectionType) -> Result<Vec<RegisterUser>, sqlx::Error> {
-    let sql = r#"
-        SELECT email, password FROM user;
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let mut vec = Vec::new();
-            let mut quer = sqlx::query(sql).fetch(&mut conn);
-            while let Some(row) = quer.try_next().await? {
-                vec.push(RegisterUser {
-                    email: row.try_get("email")?,
-                    password: "********".to_string(),
-                });
-            }
-            Ok(vec)
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let mut vec = Vec::new();
-            let mut quer = sqlx::query(sql).fetch(&mut conn);
-            while let Some(row) = quer.try_next().await? {
-                vec.push(RegisterUser {
+    async fn get_password_hash(&self, email: &str) -> Result<(i64, String), sqlx::Error> {
+        let sql = r#"SELECT id, password FROM users WHERE email = ?"#;
+        let row = sqlx::query(sql).bind(email).fetch_one(&self.pool).await?;
+        Ok((row.try_get("id")?, row.try_get("password")?))
+    }
+
+    async fn update_password_hash(
+        &self,
+        email: &str,
+        password_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE users SET password = ? WHERE email = ?"#;
+        let result = sqlx::query(sql)
+            .bind(password_hash)
+            .bind(email)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_all_user(&self) -> Result<Vec<UserInfo>, sqlx::Error> {
+        let sql = r#"SELECT id, email FROM users"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(UserInfo {
+                    id: row.try_get("id")?,
                     email: row.try_get("email")?,
-                    password: row.try_get("password")?,
-                });
-            }
-            Ok(vec)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let mut vec = Vec::new();
-            let mut quer = sqlx::query(sql).fetch(&mut conn);
-            while let Some(row) = quer.try_next().await? {
-                vec.push(RegisterUser {
+                })
+            })
+            .collect()
+    }
+
+    async fn get_user_id(&self, email: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM users WHERE email = ?"#;
+        let row = sqlx::query(sql).bind(email).fetch_one(&self.pool).await?;
+        row.try_get("id")
+    }
+
+    async fn get_user_email(&self, uid: i64) -> Result<String, sqlx::Error> {
+        let sql = r#"SELECT email FROM users WHERE id = ?"#;
+        let row = sqlx::query(sql).bind(uid).fetch_one(&self.pool).await?;
+        row.try_get("email")
+    }
+}
+
+#[async_trait]
+impl UserRepository for MySqlRepository {
+    async fn insert_user(&self, email: &str, password_hash: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO users (email, password) VALUES (?, ?)"#;
+        let result = sqlx::query(sql)
+            .bind(email)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.last_insert_id() as i64)
+    }
+
+    async fn get_password_hash(&self, email: &str) -> Result<(i64, String), sqlx::Error> {
+        let sql = r#"SELECT id, password FROM users WHERE email = ?"#;
+        let row = sqlx::query(sql).bind(email).fetch_one(&self.pool).await?;
+        Ok((row.try_get("id")?, row.try_get("password")?))
+    }
+
+    async fn update_password_hash(
+        &self,
+        email: &str,
+        password_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE users SET password = ? WHERE email = ?"#;
+        let result = sqlx::query(sql)
+            .bind(password_hash)
+            .bind(email)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_all_user(&self) -> Result<Vec<UserInfo>, sqlx::Error> {
+        let sql = r#"SELECT id, email FROM users"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(UserInfo {
+                    id: row.try_get("id")?,
                     email: row.try_get("email")?,
-                    password: row.try_get("password")?,
-                });
-            }
-            Ok(vec)
-        }
+                })
+            })
+            .collect()
+    }
+
+    async fn get_user_id(&self, email: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM users WHERE email = ?"#;
+        let row = sqlx::query(sql).bind(email).fetch_one(&self.pool).await?;
+        row.try_get("id")
+    }
+
+    async fn get_user_email(&self, uid: i64) -> Result<String, sqlx::Error> {
+        let sql = r#"SELECT email FROM users WHERE id = ?"#;
+        let row = sqlx::query(sql).bind(uid).fetch_one(&self.pool).await?;
+        row.try_get("email")
     }
 }
 
-// 获取用户uid
-pub async fn get_user_id(conn: ConnectionType, email: &str) -> Result<i64, sqlx::Error> {
-    let sql = r#"
-        SELECT id FROM user WHERE email = ?;
-    "#;
+#[async_trait]
+impl UserRepository for PostgresRepository {
+    async fn insert_user(&self, email: &str, password_hash: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id"#;
+        let row = sqlx::query(sql)
+            .bind(email)
+            .bind(password_hash)
+            .fetch_one(&self.pool)
+            .await?;
+        row.try_get("id")
+    }
 
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let row = sqlx::query(sql).bind(email).fetch_one(&mut conn).await?;
-            Ok(row.try_get("id")?)
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let row = sqlx::query(sql).bind(email).fetch_one(&mut conn).await?;
-            Ok(row.try_get("id")?)
-        }
-        ConnectionType::Postgres(mut conn) => {
-            let row = sqlx::query(sql).bind(email).fetch_one(&mut conn).await?;
-            Ok(row.try_get("id")?)
-        }
+    async fn get_password_hash(&self, email: &str) -> Result<(i64, String), sqlx::Error> {
+        let sql = r#"SELECT id, password FROM users WHERE email = $1"#;
+        let row = sqlx::query(sql).bind(email).fetch_one(&self.pool).await?;
+        Ok((row.try_get("id")?, row.try_get("password")?))
+    }
+
+    async fn update_password_hash(
+        &self,
+        email: &str,
+        password_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = r#"UPDATE users SET password = $1 WHERE email = $2"#;
+        let result = sqlx::query(sql)
+            .bind(password_hash)
+            .bind(email)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_all_user(&self) -> Result<Vec<UserInfo>, sqlx::Error> {
+        let sql = r#"SELECT id, email FROM users"#;
+        let rows = sqlx::query(sql).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                Ok(UserInfo {
+                    id: row.try_get("id")?,
+                    email: row.try_get("email")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_user_id(&self, email: &str) -> Result<i64, sqlx::Error> {
+        let sql = r#"SELECT id FROM users WHERE email = $1"#;
+        let row = sqlx::query(sql).bind(email).fetch_one(&self.pool).await?;
+        row.try_get("id")
+    }
+
+    async fn get_user_email(&self, uid: i64) -> Result<String, sqlx::Error> {
+        let sql = r#"SELECT email FROM users WHERE id = $1"#;
+        let row = sqlx::query(sql).bind(uid).fetch_one(&self.pool).await?;
+        row.try_get("email")
     }
 }
 
-pub async fn delete_user(
-    conn: ConnectionType,
-    email: &str,
-) -> Result<u64, sqlx::Error> {
-    let sql = r#"
-        DELETE FROM user WHERE email = ?;
-    "#;
-
-    match conn {
-        ConnectionType::Sqlite(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(email)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.last_insert_rowid().try_into().unwrap())
-        }
-        ConnectionType::Mysql(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(email)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.last_insert_id())
+#[async_trait]
+impl UserRepository for MemoryRepository {
+    async fn insert_user(&self, email: &str, password_hash: &str) -> Result<i64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.users.iter().any(|row| row.email == email) {
+            return Err(unique_violation("users.email"));
         }
-        ConnectionType::Postgres(mut conn) => {
-            let quer_id = sqlx::query(sql)
-                .bind(email)
-                .execute(&mut conn)
-                .await?;
-            Ok(quer_id.rows_affected())
+        let id = state.next_id();
+        state.users.push(UserRow {
+            id,
+            email: email.to_string(),
+            password: password_hash.to_string(),
+        });
+        Ok(id)
+    }
+
+    async fn get_password_hash(&self, email: &str) -> Result<(i64, String), sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .users
+            .iter()
+            .find(|row| row.email == email)
+            .map(|row| (row.id, row.password.clone()))
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn update_password_hash(
+        &self,
+        email: &str,
+        password_hash: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let mut state = self.state.lock().unwrap();
+        let mut affected = 0;
+        for row in state.users.iter_mut().filter(|row| row.email == email) {
+            row.password = password_hash.to_string();
+            affected += 1;
         }
+        Ok(affected)
+    }
+
+    async fn get_all_user(&self) -> Result<Vec<UserInfo>, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .users
+            .iter()
+            .map(|row| UserInfo {
+                id: row.id,
+                email: row.email.clone(),
+            })
+            .collect())
+    }
+
+    async fn get_user_id(&self, email: &str) -> Result<i64, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .users
+            .iter()
+            .find(|row| row.email == email)
+            .map(|row| row.id)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    async fn get_user_email(&self, uid: i64) -> Result<String, sqlx::Error> {
+        let state = self.state.lock().unwrap();
+        state
+            .users
+            .iter()
+            .find(|row| row.id == uid)
+            .map(|row| row.email.clone())
+            .ok_or(sqlx::Error::RowNotFound)
     }
 }
 
 // 测试
 #[tokio::test]
 async fn test_register_user() {
-    use crate::lib::config::HttpServerConfig;
-    let conn = crate::lib::config::init_db(&HttpServerConfig::default()).await;
-    let users = get_all_user(conn).await.unwrap();
+    use crate::lib::config::{memory_pool, HttpServerConfig};
+    let pool = memory_pool().await;
+    let repositories = crate::lib::config::init_db(&pool, &HttpServerConfig::default()).await;
+    let users = repositories.user.get_all_user().await.unwrap();
     println!("{:?}", users);
+
+    // 旧的明文密码在登录后升级为哈希
+    let user = RegisterUser {
+        email: "plain@example.com".to_string(),
+        password: "password".to_string(),
+    };
+    repositories
+        .user
+        .insert_user(&user.email, &user.password)
+        .await
+        .unwrap();
+    let uid = repositories.user.login_user(&user).await.unwrap();
+    let (_, stored) = repositories
+        .user
+        .get_password_hash(&user.email)
+        .await
+        .unwrap();
+    assert!(stored.starts_with("$argon2id$"));
+    assert_eq!(repositories.user.login_user(&user).await.unwrap(), uid);
 }
@@ -0,0 +1,400 @@
+// 两步验证(TOTP, RFC 6238)
+//
+// setup 生成密钥并返回 otpauth:// 地址供验证器扫码，enable 提交一次验证码确认后才真正开启，
+// 同时生成一次性恢复码。开启后登录分两步：密码正确时只返回5分钟有效的 totp_token，
+// 提交验证码或恢复码后才签发会话token。
+// 密钥使用 cipher 加密保存，同一个时间步的验证码只能使用一次。
+
+use std::collections::HashMap;
+
+use chrono::{Duration, Utc};
+use rand::{Rng, RngCore};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::lib::{
+    acl::{resource_matches, sql_acl::Operation},
+    cipher::cipher,
+    config::TotpConfig,
+    error::ApiError,
+};
+
+use super::{auth::AuthError, sql_totp::TotpRepository};
+
+// 验证码位数
+pub const DIGITS: u32 = 6;
+// 时间步长(秒)
+pub const PERIOD: i64 = 30;
+// 允许前后各一个时间步的时钟误差
+const SKEW: i64 = 1;
+// 密钥长度，与 HMAC-SHA1 的输出长度相同
+const SECRET_LEN: usize = 20;
+// 每次生成的恢复码数量
+pub const RECOVERY_CODES: usize = 10;
+// 登录第二步的有效期(分)
+const CHALLENGE_MINUTES: i64 = 5;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+// 恢复码字符，去掉了容易混淆的 0/o、1/l/i
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+// RFC 4648 base32，不带填充
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            out.push(BASE32[((buffer >> (bits - 5)) & 31) as usize] as char);
+            bits -= 5;
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+// RFC 4226 HOTP
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let hash = tag.as_ref();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset],
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]) & 0x7fff_ffff;
+    binary % 10u32.pow(DIGITS)
+}
+
+/// 当前时间所在的时间步
+pub fn current_step(now: i64) -> i64 {
+    now.div_euclid(PERIOD)
+}
+
+/// 指定时间步的验证码
+pub fn totp_code(secret: &[u8], step: i64) -> String {
+    format!(
+        "{:0width$}",
+        hotp(secret, step as u64),
+        width = DIGITS as usize
+    )
+}
+
+/// 校验验证码，返回匹配的时间步；只接受大于 last_step 的时间步，防止验证码被重放
+pub fn verify_code(secret: &[u8], code: &str, now: i64, last_step: i64) -> Option<i64> {
+    let step = current_step(now);
+    (step - SKEW..=step + SKEW)
+        .filter(|step| *step > last_step)
+        .find(|step| {
+            ring::constant_time::verify_slices_are_equal(
+                totp_code(secret, *step).as_bytes(),
+                code.as_bytes(),
+            )
+            .is_ok()
+        })
+}
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+// 只保留 RFC 3986 的非保留字符
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// 验证器扫码使用的 otpauth:// 地址
+pub fn provisioning_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(issuer),
+        account = percent_encode(account),
+        secret = base32_encode(secret),
+        digits = DIGITS,
+        period = PERIOD,
+    )
+}
+
+/// 加密保存的密钥
+pub fn encrypt_secret(secret: &[u8]) -> String {
+    cipher().encrypt_to_string(secret)
+}
+
+pub fn decrypt_secret(secret: &str) -> Result<Vec<u8>, ApiError> {
+    cipher()
+        .decrypt_str(secret)
+        .map_err(|err| ApiError::Internal(format!("两步验证密钥解密失败: {}", err)))
+}
+
+// 密钥轮换后由旧密钥加密的密钥换成当前密钥加密，之后旧密钥才可以删除
+async fn reencrypt_secret(
+    totp_repo: &dyn TotpRepository,
+    uid: i64,
+    secret: &str,
+) -> Result<(), ApiError> {
+    let reencrypted = cipher()
+        .reencrypt_str(secret)
+        .map_err(|err| ApiError::Internal(format!("两步验证密钥解密失败: {}", err)))?;
+    if let Some(reencrypted) = reencrypted {
+        // 同时重新开启过两步验证时密文已变化，不覆盖新密钥
+        totp_repo
+            .update_totp_secret(uid, secret, &reencrypted)
+            .await?;
+    }
+    Ok(())
+}
+
+/// 生成一组恢复码，格式为 xxxxx-xxxxx
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::rngs::OsRng;
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut code: String = (0..10)
+                .map(|_| RECOVERY_ALPHABET[rng.gen_range(0..RECOVERY_ALPHABET.len())] as char)
+                .collect();
+            code.insert(5, '-');
+            code
+        })
+        .collect()
+}
+
+/// 恢复码的sha256，忽略大小写、空格和连字符
+pub fn hash_recovery_code(code: &str) -> String {
+    let code: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(code.as_bytes()))
+}
+
+// 登录第二步的凭证，加密后交给客户端
+#[derive(Serialize, Deserialize)]
+struct LoginChallenge {
+    purpose: String,
+    uid: i64,
+    exp: i64,
+}
+
+const CHALLENGE_PURPOSE: &str = "totp_login";
+
+/// 密码验证通过后签发，提交验证码时换取会话token
+pub fn create_login_challenge(uid: i64) -> String {
+    let challenge = LoginChallenge {
+        purpose: CHALLENGE_PURPOSE.to_string(),
+        uid,
+        exp: (Utc::now() + Duration::minutes(CHALLENGE_MINUTES)).timestamp(),
+    };
+    cipher().encrypt_to_string(&serde_json::to_vec(&challenge).unwrap())
+}
+
+/// 校验登录第二步的凭证，返回uid
+pub fn verify_login_challenge(token: &str) -> Result<i64, AuthError> {
+    let data = cipher()
+        .decrypt_str(token)
+        .map_err(|_| AuthError::Invalid)?;
+    let challenge: LoginChallenge =
+        serde_json::from_slice(&data).map_err(|_| AuthError::Invalid)?;
+    if challenge.purpose != CHALLENGE_PURPOSE || challenge.exp <= Utc::now().timestamp() {
+        return Err(AuthError::Invalid);
+    }
+    Ok(challenge.uid)
+}
+
+/// 用户是否已开启两步验证
+pub async fn is_totp_enabled(
+    totp_repo: &dyn TotpRepository,
+    uid: i64,
+) -> Result<bool, sqlx::Error> {
+    match totp_repo.get_totp(uid).await {
+        Ok(totp) => Ok(totp.enabled_at.is_some()),
+        Err(sqlx::Error::RowNotFound) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// 校验验证码或恢复码，两者都只能使用一次
+pub async fn verify_second_factor(
+    totp_repo: &dyn TotpRepository,
+    uid: i64,
+    code: &str,
+) -> Result<(), ApiError> {
+    let totp = match totp_repo.get_totp(uid).await {
+        Ok(totp) if totp.enabled_at.is_some() => totp,
+        Ok(_) | Err(sqlx::Error::RowNotFound) => return Err(ApiError::InvalidTotpCode),
+        Err(err) => return Err(err.into()),
+    };
+    let now = Utc::now().timestamp();
+    let code = code.trim();
+    let verified = if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+        let secret = decrypt_secret(&totp.secret)?;
+        match verify_code(&secret, code, now, totp.last_step) {
+            // 并发提交同一个验证码时只有一个请求成功
+            Some(step) => {
+                let verified = totp_repo.update_last_step(uid, step).await? > 0;
+                if verified {
+                    reencrypt_secret(totp_repo, uid, &totp.secret).await?;
+                }
+                verified
+            }
+            None => false,
+        }
+    } else {
+        totp_repo
+            .use_recovery_code(uid, &hash_recovery_code(code), now)
+            .await?
+            > 0
+    };
+    match verified {
+        true => Ok(()),
+        false => Err(ApiError::InvalidTotpCode),
+    }
+}
+
+/// 该资源操作是否要求开启两步验证，配置中的资源名可以是通配符如 server.*
+pub fn requires_totp(config: &TotpConfig, resource: &str, operation: &Operation) -> bool {
+    config.required_acl.iter().any(|(pattern, operations)| {
+        resource_matches(pattern, resource)
+            && operations
+                .iter()
+                .any(|name| Operation::from_string(name) == *operation)
+    })
+}
+
+/// 用户拥有的权限中是否有要求两步验证的，relo 为 query_user_acl 的结果
+pub fn holds_required_acl(config: &TotpConfig, relo: &HashMap<String, Vec<String>>) -> bool {
+    relo.iter().any(|(resource, operations)| {
+        operations
+            .iter()
+            .any(|name| requires_totp(config, resource, &Operation::from_string(name)))
+    })
+}
+
+/// 使用要求两步验证的资源操作前检查用户是否已开启
+pub async fn check_totp_required(
+    totp_repo: &dyn TotpRepository,
+    config: &TotpConfig,
+    uid: u64,
+    resource: &str,
+    operation: &Operation,
+) -> Result<(), ApiError> {
+    if !requires_totp(config, resource, operation) || is_totp_enabled(totp_repo, uid as i64).await?
+    {
+        return Ok(());
+    }
+    Err(ApiError::TotpRequired)
+}
+
+#[tokio::test]
+async fn test_totp() {
+    use crate::lib::repository::Repositories;
+
+    // RFC 6238 附录B的测试向量(取后6位)
+    let secret = b"12345678901234567890";
+    assert_eq!(totp_code(secret, current_step(59)), "287082");
+    assert_eq!(totp_code(secret, current_step(1111111109)), "081804");
+    assert_eq!(totp_code(secret, current_step(2000000000)), "279037");
+    assert_eq!(base32_encode(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    assert_eq!(
+        provisioning_uri("MCU", "a b@example.com", b"12345"),
+        "otpauth://totp/MCU:a%20b%40example.com?secret=GEZDGNBV&issuer=MCU&algorithm=SHA1&digits=6&period=30"
+    );
+
+    // 允许一个时间步的误差，已使用的时间步不能再次通过
+    let code = totp_code(secret, current_step(1111111109));
+    assert_eq!(
+        verify_code(secret, &code, 1111111109 + 30, 0),
+        Some(37037036)
+    );
+    assert_eq!(verify_code(secret, &code, 1111111109 + 60, 0), None);
+    assert_eq!(verify_code(secret, &code, 1111111109, 37037036), None);
+
+    assert_eq!(
+        hash_recovery_code("ABCDE-fghjk"),
+        hash_recovery_code("abcde fghjk")
+    );
+    let codes = generate_recovery_codes();
+    assert_eq!(codes.len(), RECOVERY_CODES);
+    assert!(codes.iter().all(|code| code.len() == 11));
+
+    let uid = verify_login_challenge(&create_login_challenge(7)).unwrap();
+    assert_eq!(uid, 7);
+    assert!(verify_login_challenge("invalid").is_err());
+
+    // 开启后验证码与恢复码都只能使用一次
+    let repositories = Repositories::memory();
+    let totp_repo = repositories.totp.as_ref();
+    let secret = generate_secret();
+    let now = Utc::now().timestamp();
+    totp_repo
+        .save_totp_secret(1, &encrypt_secret(&secret), now)
+        .await
+        .unwrap();
+    assert!(!is_totp_enabled(totp_repo, 1).await.unwrap());
+    assert!(matches!(
+        verify_second_factor(totp_repo, 1, &totp_code(&secret, current_step(now))).await,
+        Err(ApiError::InvalidTotpCode)
+    ));
+    totp_repo
+        .enable_totp(1, current_step(now) - 2, now)
+        .await
+        .unwrap();
+    totp_repo
+        .replace_recovery_codes(
+            1,
+            &codes
+                .iter()
+                .map(|code| hash_recovery_code(code))
+                .collect::<Vec<_>>(),
+        )
+        .await
+        .unwrap();
+
+    let code = totp_code(&secret, current_step(now));
+    verify_second_factor(totp_repo, 1, &code).await.unwrap();
+    assert!(verify_second_factor(totp_repo, 1, &code).await.is_err());
+    verify_second_factor(totp_repo, 1, &codes[0]).await.unwrap();
+    assert!(verify_second_factor(totp_repo, 1, &codes[0]).await.is_err());
+    assert_eq!(
+        totp_repo.count_recovery_codes(1).await.unwrap(),
+        RECOVERY_CODES as i64 - 1
+    );
+
+    let config = TotpConfig {
+        required_acl: HashMap::from([("acl".to_string(), vec!["Add".to_string()])]),
+    };
+    assert!(
+        check_totp_required(totp_repo, &config, 1, "acl", &Operation::Add)
+            .await
+            .is_ok()
+    );
+    assert!(matches!(
+        check_totp_required(totp_repo, &config, 2, "acl", &Operation::Add).await,
+        Err(ApiError::TotpRequired)
+    ));
+    assert!(
+        check_totp_required(totp_repo, &config, 2, "acl", &Operation::Check)
+            .await
+            .is_ok()
+    );
+    assert!(holds_required_acl(
+        &config,
+        &HashMap::from([("acl".to_string(), vec!["Add".to_string()])])
+    ));
+}
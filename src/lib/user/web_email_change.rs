@@ -0,0 +1,374 @@
+// 更换邮箱
+//
+// 1. 已登录用户提交新邮箱，验证码发送到新邮箱；
+// 2. 提交验证码后在同一事务中更换 users.email 并写入更换记录。uid 不变，
+//    acl、java_player 等按uid关联的数据不受影响；
+// 3. 旧邮箱收到通知和撤销链接，有效期内撤销后恢复为旧邮箱，并注销该用户的所有会话。
+// 撤销链接先打开确认页面，提交后才撤销，避免邮件客户端预览链接时误撤销。
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use build_html::*;
+use chrono::Utc;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::lib::{
+    config::{HttpServerConfig, ResponseMessage},
+    error::{is_unique_violation, ApiError},
+    mail::{
+        outbox::Outbox,
+        template::{request_locale, MailTemplateKind, MailTemplates},
+    },
+    validate::{ValidJson, ValidQuery, Validate, Validator},
+};
+
+use super::{
+    auth::AuthUser,
+    email_code::{CodePurpose, EmaiCodeManager},
+    session::revoke_user_sessions,
+    sql_email_change::EmailChangeRepository,
+    sql_session::SessionRepository,
+    sql_user::UserRepository,
+    web_user::verify_code,
+};
+
+// 撤销链接中token的sha256
+fn hash_cancel_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// 申请更换邮箱
+#[derive(Debug, serde::Deserialize)]
+pub struct ChangeEmailRequest {
+    pub new_email: String,
+}
+
+impl Validate for ChangeEmailRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.email("new_email", &self.new_email);
+    }
+}
+
+// 当前邮箱，admin 不能更换
+async fn current_email(
+    user_repo: &dyn UserRepository,
+    user: &AuthUser,
+) -> Result<String, ApiError> {
+    let email = user_repo.get_user_email(user.uid as i64).await?;
+    if email == "admin" {
+        return Err(ApiError::Forbidden("不能修改admin邮箱"));
+    }
+    Ok(email)
+}
+
+// 发送验证码到新邮箱
+pub async fn request_change(
+    req: HttpRequest,
+    body: ValidJson<ChangeEmailRequest>,
+    user: AuthUser,
+    user_repo: web::Data<dyn UserRepository>,
+    outbox: web::Data<Outbox>,
+    templates: web::Data<MailTemplates>,
+    email_code_manager: web::Data<EmaiCodeManager>,
+) -> Result<HttpResponse, ApiError> {
+    let old_email = current_email(user_repo.get_ref(), &user).await?;
+    if body.new_email == old_email {
+        return Err(ApiError::BadRequest("新邮箱与当前邮箱相同".to_string()));
+    }
+    if user_repo.get_user_id(&body.new_email).await.is_ok() {
+        return Err(ApiError::Conflict("已被注册"));
+    }
+
+    let code = email_code_manager
+        .generate_code(
+            &body.new_email,
+            CodePurpose::change_email,
+            Utc::now().timestamp(),
+        )
+        .await?;
+    let expires_minutes = email_code_manager.ttl().div_ceil(60).to_string();
+    let mail = templates
+        .render(
+            MailTemplateKind::change_email,
+            request_locale(&req).as_deref(),
+            &body.new_email,
+            &[
+                ("code", &code),
+                ("expires_minutes", &expires_minutes),
+                ("new_email", &body.new_email),
+            ],
+        )
+        .await;
+    outbox.send(&mail).await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "已发送验证码",
+    }))
+}
+
+// 确认更换邮箱
+#[derive(Debug, serde::Deserialize)]
+pub struct ConfirmChangeRequest {
+    pub new_email: String,
+    pub code: String,
+}
+
+impl Validate for ConfirmChangeRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.email("new_email", &self.new_email)
+            .code("code", &self.code);
+    }
+}
+
+// 校验新邮箱的验证码后更换，并通知旧邮箱
+#[allow(clippy::too_many_arguments)]
+pub async fn confirm_change(
+    req: HttpRequest,
+    body: ValidJson<ConfirmChangeRequest>,
+    user: AuthUser,
+    user_repo: web::Data<dyn UserRepository>,
+    change_repo: web::Data<dyn EmailChangeRepository>,
+    outbox: web::Data<Outbox>,
+    templates: web::Data<MailTemplates>,
+    email_code_manager: web::Data<EmaiCodeManager>,
+    config: web::Data<HttpServerConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let old_email = current_email(user_repo.get_ref(), &user).await?;
+    verify_code(
+        &email_code_manager,
+        &body.new_email,
+        CodePurpose::change_email,
+        &body.code,
+    )
+    .await?;
+
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let now = Utc::now().timestamp();
+    let cancel_ttl = config.email_config.change_cancel_ttl;
+    let changed = change_repo
+        .change_email(
+            user.uid as i64,
+            &old_email,
+            &body.new_email,
+            &hash_cancel_token(&token),
+            now + cancel_ttl as i64,
+            now,
+        )
+        .await;
+    match changed {
+        Ok(0) => return Err(ApiError::Conflict("邮箱已被修改，请重试")),
+        Ok(_) => {}
+        Err(err) if is_unique_violation(&err) => return Err(ApiError::Conflict("已被注册")),
+        Err(err) => return Err(err.into()),
+    }
+
+    let cancel_url = format!(
+        "{}/api/user/change_email/cancel?token={}",
+        config.public_url(),
+        token
+    );
+    let expires_hours = cancel_ttl.div_ceil(3600).to_string();
+    let mail = templates
+        .render(
+            MailTemplateKind::email_changed,
+            request_locale(&req).as_deref(),
+            &old_email,
+            &[
+                ("new_email", &body.new_email),
+                ("cancel_url", &cancel_url),
+                ("expires_hours", &expires_hours),
+            ],
+        )
+        .await;
+    // 邮箱已更换，通知写入失败只记录日志
+    if let Err(err) = outbox.send(&mail).await {
+        log::error!("写入邮箱更换通知失败: {}", err);
+    }
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "邮箱已更换",
+    }))
+}
+
+// 撤销链接
+#[derive(Debug, serde::Deserialize)]
+pub struct CancelQuery {
+    pub token: String,
+}
+
+impl Validate for CancelQuery {
+    fn validate(&self, v: &mut Validator) {
+        v.check(
+            "token",
+            !self.token.is_empty()
+                && self.token.len() <= 64
+                && self
+                    .token
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "链接无效",
+        );
+    }
+}
+
+// 撤销确认页面，提交后才撤销
+pub async fn cancel_page(query: ValidQuery<CancelQuery>) -> HttpResponse {
+    let form = format!(
+        r#"<form method="post" action="?token={}">
+    <p>如果邮箱更换不是您本人操作，请点击下方按钮撤销，撤销后需要重新登录。</p>
+    <button type="submit">撤销邮箱更换</button>
+</form>"#,
+        query.token
+    );
+    let html = HtmlPage::new()
+        .with_title("撤销邮箱更换")
+        .with_raw(form)
+        .to_html_string();
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html)
+}
+
+// 撤销更换，恢复为旧邮箱并注销该用户的所有会话
+pub async fn cancel_change(
+    query: ValidQuery<CancelQuery>,
+    change_repo: web::Data<dyn EmailChangeRepository>,
+    session_repo: web::Data<dyn SessionRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let change = match change_repo
+        .get_email_change(&hash_cancel_token(&query.token))
+        .await
+    {
+        Ok(change) => change,
+        Err(sqlx::Error::RowNotFound) => return Err(ApiError::NotFound("链接无效或已过期")),
+        Err(err) => return Err(err.into()),
+    };
+    match change_repo
+        .cancel_email_change(&change, Utc::now().timestamp())
+        .await
+    {
+        Ok(0) => return Err(ApiError::NotFound("链接无效或已过期")),
+        Ok(_) => {}
+        Err(err) if is_unique_violation(&err) => {
+            return Err(ApiError::Conflict("旧邮箱已被其他账号使用"))
+        }
+        Err(err) => return Err(err.into()),
+    }
+    revoke_user_sessions(session_repo.get_ref(), change.uid).await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "已撤销邮箱更换，请重新登录",
+    }))
+}
+
+#[actix_web::test]
+async fn test_change_email() {
+    use actix_web::{http::StatusCode, test, App};
+
+    use crate::lib::{
+        config::OutboxConfig,
+        key::create_token_time_min,
+        mail::{outbox::deliver_due, MemoryMailTransport},
+        repository::Repositories,
+        user::{session::create_session, web_user::RegisterUser},
+    };
+
+    let repositories = Repositories::memory();
+    let config = HttpServerConfig::default();
+    let old_email = "steve@example.com";
+    let new_email = "alex@example.com";
+    let uid = repositories
+        .user
+        .register_user(&RegisterUser {
+            email: old_email.to_string(),
+            password: "Password123".to_string(),
+        })
+        .await
+        .unwrap();
+    create_session(repositories.session.as_ref(), uid, "127.0.0.1")
+        .await
+        .unwrap();
+    let app = test::init_service(
+        App::new()
+            .configure(|cfg| repositories.app_data(cfg))
+            .app_data(web::Data::new(Outbox::new(repositories.outbox.clone())))
+            .app_data(web::Data::new(MailTemplates::new(&config)))
+            .app_data(web::Data::new(EmaiCodeManager::new(&repositories, &config)))
+            .app_data(web::Data::new(config.clone()))
+            .route("/change_email", web::post().to(request_change))
+            .route("/change_email/confirm", web::post().to(confirm_change))
+            .route("/change_email/cancel", web::get().to(cancel_page))
+            .route("/change_email/cancel", web::post().to(cancel_change)),
+    )
+    .await;
+    let mails = MemoryMailTransport::new();
+    let deliver = || async {
+        deliver_due(
+            repositories.outbox.as_ref(),
+            &mails,
+            &OutboxConfig::default(),
+            Utc::now().timestamp(),
+        )
+        .await
+        .unwrap();
+    };
+    let bearer = format!("Bearer {}", create_token_time_min(uid as u64, 5));
+
+    let req = test::TestRequest::post()
+        .uri("/change_email")
+        .insert_header(("Authorization", bearer.as_str()))
+        .set_json(serde_json::json!({ "new_email": new_email }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    deliver().await;
+    let text = mails.last_mail(new_email).unwrap().text.unwrap();
+    let code: String = text
+        .split("验证码是：")
+        .nth(1)
+        .unwrap()
+        .chars()
+        .take(6)
+        .collect();
+
+    let req = test::TestRequest::post()
+        .uri("/change_email/confirm")
+        .insert_header(("Authorization", bearer.as_str()))
+        .set_json(serde_json::json!({ "new_email": new_email, "code": code }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    // uid 不变
+    assert_eq!(repositories.user.get_user_id(new_email).await.unwrap(), uid);
+    assert!(repositories.user.get_user_id(old_email).await.is_err());
+
+    // 旧邮箱收到撤销链接
+    deliver().await;
+    let text = mails.last_mail(old_email).unwrap().text.unwrap();
+    let token = text
+        .split("token=")
+        .nth(1)
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .unwrap()
+        .to_string();
+    let uri = format!("/change_email/cancel?token={}", token);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert_eq!(repositories.user.get_user_id(new_email).await.unwrap(), uid);
+
+    let req = test::TestRequest::post().uri(&uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert_eq!(repositories.user.get_user_id(old_email).await.unwrap(), uid);
+    // 撤销后会话失效，撤销链接只能使用一次
+    let now = Utc::now().timestamp();
+    let sessions = repositories.session.list_sessions(uid, now).await.unwrap();
+    assert!(sessions.is_empty());
+    let req = test::TestRequest::post().uri(&uri).to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
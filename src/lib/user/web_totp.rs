@@ -0,0 +1,158 @@
+// 两步验证的开启与关闭，均需登录
+
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::lib::{
+    config::{HttpServerConfig, ResponseMessage},
+    error::ApiError,
+    validate::{ValidJson, Validate, Validator},
+};
+
+use super::{
+    auth::AuthUser,
+    sql_totp::TotpRepository,
+    sql_user::UserRepository,
+    totp::{
+        base32_encode, decrypt_secret, encrypt_secret, generate_recovery_codes, generate_secret,
+        hash_recovery_code, is_totp_enabled, provisioning_uri, verify_code, verify_second_factor,
+    },
+};
+
+// 验证码或恢复码
+#[derive(Debug, serde::Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+impl Validate for TotpCodeRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("code", &self.code, 32);
+    }
+}
+
+// 新生成的恢复码，只返回这一次
+#[derive(Serialize)]
+struct RecoveryCodes {
+    code: i32,
+    message: &'static str,
+    recovery_codes: Vec<String>,
+}
+
+// 生成并保存一组新的恢复码
+async fn renew_recovery_codes(
+    totp_repo: &dyn TotpRepository,
+    uid: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    totp_repo.replace_recovery_codes(uid, &hashes).await?;
+    Ok(codes)
+}
+
+// 查询是否已开启及剩余的恢复码数量
+pub async fn status(
+    totp_repo: web::Data<dyn TotpRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let uid = user.uid as i64;
+    #[derive(Serialize)]
+    struct TotpStatus {
+        enabled: bool,
+        recovery_codes: i64,
+    }
+    Ok(HttpResponse::Ok().json(TotpStatus {
+        enabled: is_totp_enabled(totp_repo.get_ref(), uid).await?,
+        recovery_codes: totp_repo.count_recovery_codes(uid).await?,
+    }))
+}
+
+// 生成密钥，返回供验证器扫码的地址；调用 enable 确认前不会生效
+pub async fn setup(
+    totp_repo: web::Data<dyn TotpRepository>,
+    user_repo: web::Data<dyn UserRepository>,
+    config: web::Data<HttpServerConfig>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let uid = user.uid as i64;
+    if is_totp_enabled(totp_repo.get_ref(), uid).await? {
+        return Err(ApiError::Conflict("已开启两步验证"));
+    }
+    let email = user_repo.get_user_email(uid).await?;
+    let secret = generate_secret();
+    totp_repo
+        .save_totp_secret(uid, &encrypt_secret(&secret), Utc::now().timestamp())
+        .await?;
+    #[derive(Serialize)]
+    struct TotpSetup {
+        code: i32,
+        message: &'static str,
+        secret: String, // 无法扫码时手动输入
+        uri: String,
+    }
+    Ok(HttpResponse::Ok().json(TotpSetup {
+        code: 200,
+        message: "请使用验证器扫码后提交验证码",
+        secret: base32_encode(&secret),
+        uri: provisioning_uri(&config.name, &email, &secret),
+    }))
+}
+
+// 提交验证器上的验证码，确认开启并返回恢复码
+pub async fn enable(
+    body: ValidJson<TotpCodeRequest>,
+    totp_repo: web::Data<dyn TotpRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let uid = user.uid as i64;
+    let totp = match totp_repo.get_totp(uid).await {
+        Ok(totp) => totp,
+        Err(sqlx::Error::RowNotFound) => return Err(ApiError::NotFound("请先获取两步验证密钥")),
+        Err(err) => return Err(err.into()),
+    };
+    if totp.enabled_at.is_some() {
+        return Err(ApiError::Conflict("已开启两步验证"));
+    }
+    let now = Utc::now().timestamp();
+    let secret = decrypt_secret(&totp.secret)?;
+    let step = verify_code(&secret, body.code.trim(), now, 0).ok_or(ApiError::InvalidTotpCode)?;
+    if totp_repo.enable_totp(uid, step, now).await? == 0 {
+        return Err(ApiError::Conflict("已开启两步验证"));
+    }
+    Ok(HttpResponse::Ok().json(RecoveryCodes {
+        code: 200,
+        message: "已开启两步验证，请妥善保存恢复码",
+        recovery_codes: renew_recovery_codes(totp_repo.get_ref(), uid).await?,
+    }))
+}
+
+// 关闭两步验证，需要验证码或恢复码
+pub async fn disable(
+    body: ValidJson<TotpCodeRequest>,
+    totp_repo: web::Data<dyn TotpRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let uid = user.uid as i64;
+    verify_second_factor(totp_repo.get_ref(), uid, &body.code).await?;
+    totp_repo.delete_totp(uid).await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "已关闭两步验证",
+    }))
+}
+
+// 重新生成恢复码，旧的恢复码全部失效
+pub async fn recovery_codes(
+    body: ValidJson<TotpCodeRequest>,
+    totp_repo: web::Data<dyn TotpRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let uid = user.uid as i64;
+    verify_second_factor(totp_repo.get_ref(), uid, &body.code).await?;
+    Ok(HttpResponse::Ok().json(RecoveryCodes {
+        code: 200,
+        message: "已重新生成恢复码",
+        recovery_codes: renew_recovery_codes(totp_repo.get_ref(), uid).await?,
+    }))
+}
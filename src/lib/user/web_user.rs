@@ -1,18 +1,37 @@
 use std::collections::HashMap;
 
 use actix::Addr;
-use actix_web::http::header::AUTHORIZATION;
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
 use serde::Serialize;
 
-use crate::lib::acl::check_acl;
-use crate::lib::acl::sql_acl::get_resource_id;
-use crate::lib::config::{get_conn, write_config_to_yml, HttpServerConfig, ResponseMessage};
-use crate::lib::key::{create_token_time_h, gettoken_to_user_no_time};
-use crate::lib::user::email_code::GenerateCode;
-use crate::lib::user::sql_user;
+use crate::lib::acl::{
+    change::AclChange, delegation::apply_protected, query_effective_acl, sql_acl::AclRepository,
+    sql_role::RoleRepository,
+};
+use crate::lib::config::{AclConfig, HttpServerConfig, ResponseMessage};
+use crate::lib::error::{is_unique_violation, ApiError};
+use crate::lib::key::gettoken_to_user_no_time;
+use crate::lib::mail::outbox::Outbox;
+use crate::lib::mail::template::{request_locale, MailTemplates};
+use crate::lib::ratelimit::lockout::{
+    totp_key, user_key, LoginGuard, LoginSucceeded, ReserveAttempt,
+};
+use crate::lib::user::auth::AuthUser;
+use crate::lib::user::session::{
+    create_session, mark_revoked, refresh_session, revoke_session, revoke_user_sessions,
+    SessionTokens,
+};
+use crate::lib::user::sql_session::{Session, SessionRepository};
+use crate::lib::user::sql_totp::TotpRepository;
+use crate::lib::user::sql_user::UserRepository;
+use crate::lib::user::totp::{
+    create_login_challenge, holds_required_acl, is_totp_enabled, verify_login_challenge,
+    verify_second_factor,
+};
+use crate::lib::validate::{ValidJson, ValidQuery, Validate, Validator};
 
-use super::email_code::{EmaiCodeManager, EmailCodeSend, EmailManager, VerifyCode};
+use super::email_code::{CodePurpose, EmaiCodeManager};
 // 注册用户
 #[derive(Clone, serde::Deserialize, Debug, Serialize)]
 pub struct RegisterUser {
@@ -20,282 +39,642 @@ pub struct RegisterUser {
     pub password: String,
 }
 
-// 获取验证码
-pub async fn get_code(
-    emailmanager: web::Data<Addr<EmailManager>>,
-    email_code_manager: web::Data<Addr<EmaiCodeManager>>,
-    path_email: web::Query<HashMap<String, String>>,
-) -> HttpResponse {
-    let to_email_str = match path_email.get("email") {
-        Some(email) => email,
-        None => {
-            return HttpResponse::Ok().json(ResponseMessage {
-                code: 500,
-                message: "null",
-            });
-        }
-    };
+// 获取验证码，purpose 为验证码用途，默认为注册
+#[derive(Debug, serde::Deserialize)]
+pub struct CodeQuery {
+    pub email: String,
+    #[serde(default)]
+    pub purpose: CodePurpose,
+}
 
-    let to_email = match to_email_str.parse() {
-        Ok(email) => email,
-        Err(_) => {
-            return HttpResponse::Ok().json(ResponseMessage {
-                code: 500,
-                message: "邮箱格式错误",
-            })
-        }
-    };
+impl Validate for CodeQuery {
+    fn validate(&self, v: &mut Validator) {
+        v.email("email", &self.email);
+    }
+}
+
+pub async fn get_code(
+    req: HttpRequest,
+    outbox: web::Data<Outbox>,
+    templates: web::Data<MailTemplates>,
+    email_code_manager: web::Data<EmaiCodeManager>,
+    path_email: ValidQuery<CodeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let to_email_str = &path_email.email;
+    let purpose = path_email.purpose;
 
     let code = email_code_manager
-        .send(GenerateCode {
-            email: to_email_str.to_string(),
-        })
-        .await
-        .unwrap();
+        .generate_code(to_email_str, purpose, Utc::now().timestamp())
+        .await?;
 
-    let email_code_send = EmailCodeSend {
-        code: code.clone(),
-        to_email,
-    };
+    let expires_minutes = email_code_manager.ttl().div_ceil(60).to_string();
+    let mail = templates
+        .render(
+            purpose.template(),
+            request_locale(&req).as_deref(),
+            to_email_str,
+            &[("code", &code), ("expires_minutes", &expires_minutes)],
+        )
+        .await;
+    outbox.send(&mail).await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "已发送验证码",
+    }))
+}
 
-    match emailmanager.send(email_code_send).await {
-        Ok(_result) => HttpResponse::Ok().json(ResponseMessage {
-            code: 200,
-            message: "已发送验证码",
-        }),
-        Err(_) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: "发送验证码失败",
-        }),
+// 注册/忘记密码，需要邮箱验证码
+#[derive(Debug, serde::Deserialize)]
+pub struct RegisterRequest {
+    pub email: String,
+    pub password: String,
+    pub code: String,
+}
+
+impl Validate for RegisterRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.email("email", &self.email)
+            .password("password", &self.password)
+            .code("code", &self.code);
+    }
+}
+
+impl RegisterRequest {
+    fn user(&self) -> RegisterUser {
+        RegisterUser {
+            email: self.email.clone(),
+            password: self.password.clone(),
+        }
+    }
+}
+
+// 校验邮箱验证码
+pub(crate) async fn verify_code(
+    email_code_manager: &EmaiCodeManager,
+    email: &str,
+    purpose: CodePurpose,
+    code: &str,
+) -> Result<(), ApiError> {
+    let result = email_code_manager
+        .verify_code(email, purpose, code, Utc::now().timestamp())
+        .await?;
+    match result {
+        true => Ok(()),
+        false => Err(ApiError::InvalidCode),
     }
 }
 
 // 注册账号
 pub async fn register(
-    user: web::Json<RegisterUser>,
-    config: web::Data<HttpServerConfig>,
-    query_data: web::Query<HashMap<String, String>>,
-    email_code_manager: web::Data<Addr<EmaiCodeManager>>,
-) -> HttpResponse {
-    let result = email_code_manager
-        .send(VerifyCode {
-            email: user.email.clone(),
-            code: query_data.get("code").unwrap().to_string(),
-        })
-        .await
-        .unwrap();
+    body: ValidJson<RegisterRequest>,
+    user_repo: web::Data<dyn UserRepository>,
+    session_repo: web::Data<dyn SessionRepository>,
+    email_code_manager: web::Data<EmaiCodeManager>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    verify_code(
+        &email_code_manager,
+        &body.email,
+        CodePurpose::register,
+        &body.code,
+    )
+    .await?;
 
-    match result {
-        true => {
-            let conn = get_conn(&config).await.unwrap();
-            match sql_user::register_user(conn, &user).await {
-                Ok(uid) => {
-                    let token = create_token_time_h(uid, 12);
-                    HttpResponse::Ok().json(ResponseMessage {
-                        code: 200,
-                        message: &token,
-                    })
-                }
-                Err(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "已被注册",
-                }),
-            }
-        }
-        false => HttpResponse::Ok().json(ResponseMessage {
-            code: 404,
-            message: "验证码错误",
-        }),
+    let uid = match user_repo.register_user(&body.user()).await {
+        Ok(uid) => uid,
+        Err(err) if is_unique_violation(&err) => return Err(ApiError::Conflict("已被注册")),
+        Err(err) => return Err(err.into()),
+    };
+    let tokens = create_session(session_repo.get_ref(), uid, &client_ip(&req)).await?;
+    Ok(HttpResponse::Ok().json(TokenResponse {
+        code: 200,
+        message: tokens.access_token.clone(),
+        tokens,
+    }))
+}
+
+// 登录/刷新成功后返回的token，message 为访问token
+#[derive(Serialize)]
+struct TokenResponse {
+    code: i32,
+    message: String,
+    #[serde(flatten)]
+    tokens: SessionTokens,
+}
+
+// 客户端ip，用于会话列表展示
+fn client_ip(req: &HttpRequest) -> String {
+    req.connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+// 登录，不检查密码强度，兼容规则加入前设置的密码
+impl Validate for RegisterUser {
+    fn validate(&self, v: &mut Validator) {
+        v.required("email", &self.email, 254)
+            .required("password", &self.password, 128);
     }
 }
 
-// 登录账号
+// 登录成功，签发会话token并返回用户权限
+async fn login_success(
+    uid: i64,
+    relo: HashMap<String, Vec<String>>,
+    session_repo: &dyn SessionRepository,
+    totp_setup_required: bool,
+    req: &HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let tokens = create_session(session_repo, uid, &client_ip(req)).await?;
+    #[derive(Serialize)]
+    struct User {
+        code: i32,
+        message: String,
+        relo: HashMap<String, Vec<String>>,
+        // 拥有要求两步验证的权限但尚未开启，开启前无法使用这些权限
+        totp_setup_required: bool,
+        #[serde(flatten)]
+        tokens: SessionTokens,
+    }
+    Ok(HttpResponse::Ok().json(User {
+        relo,
+        code: 200,
+        message: tokens.access_token.clone(),
+        totp_setup_required,
+        tokens,
+    }))
+}
+
+// 登录账号，开启了两步验证时只返回 totp_token，需要再调用 login_totp
+#[allow(clippy::too_many_arguments)]
 pub async fn login(
-    user: web::Json<RegisterUser>,
+    user: ValidJson<RegisterUser>,
+    user_repo: web::Data<dyn UserRepository>,
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    session_repo: web::Data<dyn SessionRepository>,
+    totp_repo: web::Data<dyn TotpRepository>,
     config: web::Data<HttpServerConfig>,
-) -> HttpResponse {
-    let conn = get_conn(&config).await.unwrap();
-
-    match sql_user::login_user(conn, &user).await {
-        Ok(uid) => {
-            let token = create_token_time_h(uid.try_into().unwrap(), 12);
-            let conn = get_conn(&config).await.unwrap();
-            #[derive(Serialize)]
-            struct User {
-                code: i32,
-                message: String,
-                relo: HashMap<String, Vec<String>>,
-            }
-            HttpResponse::Ok().json(User {
-                relo: crate::lib::acl::sql_acl::query_user_acl(conn, uid.try_into().unwrap())
-                    .await
-                    .unwrap(),
-                code: 200,
-                message: token,
-            })
+    login_guard: web::Data<Addr<LoginGuard>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    // 连续失败过多时锁定
+    let key = user_key(&user.email);
+    let locked = login_guard
+        .send(ReserveAttempt { key: key.clone() })
+        .await?;
+    if let Some(seconds) = locked {
+        return Err(ApiError::TooManyAttempts(seconds));
+    }
+    let login = user_repo.login_user(&user).await;
+    if login.is_ok() {
+        login_guard.do_send(LoginSucceeded { key });
+    }
+    let uid = match login {
+        Ok(uid) => uid,
+        Err(sqlx::Error::RowNotFound) => return Err(ApiError::InvalidCredentials),
+        Err(err) => return Err(err.into()),
+    };
+    if is_totp_enabled(totp_repo.get_ref(), uid).await? {
+        #[derive(Serialize)]
+        struct TotpChallenge {
+            code: i32,
+            message: &'static str,
+            totp_required: bool,
+            totp_token: String,
         }
-        Err(_) => HttpResponse::InternalServerError().json(ResponseMessage {
-            code: 500,
-            message: "账号或密码错误",
-        }),
+        return Ok(HttpResponse::Ok().json(TotpChallenge {
+            code: 200,
+            message: "需要两步验证",
+            totp_required: true,
+            totp_token: create_login_challenge(uid),
+        }));
+    }
+    let relo = query_effective_acl(acl_repo.get_ref(), role_repo.get_ref(), uid as u64).await?;
+    let totp_setup_required = holds_required_acl(&config.totp_config, &relo);
+    login_success(uid, relo, session_repo.get_ref(), totp_setup_required, &req).await
+}
+
+// 登录第二步
+#[derive(Debug, serde::Deserialize)]
+pub struct TotpLoginRequest {
+    pub totp_token: String,
+    pub code: String, // 验证码或恢复码
+}
+
+impl Validate for TotpLoginRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("totp_token", &self.totp_token, 512)
+            .required("code", &self.code, 32);
+    }
+}
+
+// 提交两步验证码或恢复码，通过后签发token
+pub async fn login_totp(
+    body: ValidJson<TotpLoginRequest>,
+    acl_repo: web::Data<dyn AclRepository>,
+    role_repo: web::Data<dyn RoleRepository>,
+    session_repo: web::Data<dyn SessionRepository>,
+    totp_repo: web::Data<dyn TotpRepository>,
+    login_guard: web::Data<Addr<LoginGuard>>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let uid = verify_login_challenge(&body.totp_token)?;
+    // 两步验证码只有6位，连续失败过多时锁定
+    let key = totp_key(uid);
+    let locked = login_guard
+        .send(ReserveAttempt { key: key.clone() })
+        .await?;
+    if let Some(seconds) = locked {
+        return Err(ApiError::TooManyAttempts(seconds));
     }
+    verify_second_factor(totp_repo.get_ref(), uid, &body.code).await?;
+    login_guard.do_send(LoginSucceeded { key });
+    let relo = query_effective_acl(acl_repo.get_ref(), role_repo.get_ref(), uid as u64).await?;
+    login_success(uid, relo, session_repo.get_ref(), false, &req).await
+}
+
+// 刷新token
+#[derive(Debug, serde::Deserialize)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
+
+impl Validate for RefreshToken {
+    fn validate(&self, v: &mut Validator) {
+        v.required("refresh_token", &self.refresh_token, 128);
+    }
+}
+
+// 使用刷新token换取新的访问token和刷新token
+pub async fn refresh(
+    body: ValidJson<RefreshToken>,
+    session_repo: web::Data<dyn SessionRepository>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let tokens = refresh_session(
+        session_repo.get_ref(),
+        &body.refresh_token,
+        &client_ip(&req),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(TokenResponse {
+        code: 200,
+        message: tokens.access_token.clone(),
+        tokens,
+    }))
+}
+
+// 注销当前会话
+pub async fn logout(
+    session_repo: web::Data<dyn SessionRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let sid = user
+        .sid
+        .ok_or_else(|| ApiError::BadRequest("该token不属于任何会话".to_string()))?;
+    revoke_session(session_repo.get_ref(), user.uid as i64, sid).await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "已注销",
+    }))
+}
+
+// 注销所有会话
+pub async fn logout_all(
+    session_repo: web::Data<dyn SessionRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    revoke_user_sessions(session_repo.get_ref(), user.uid as i64).await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "已注销所有会话",
+    }))
+}
+
+// 查询当前用户的会话
+pub async fn sessions(
+    session_repo: web::Data<dyn SessionRepository>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    #[derive(Serialize)]
+    struct SessionInfo {
+        #[serde(flatten)]
+        session: Session,
+        current: bool, // 是否为当前请求使用的会话
+    }
+    let sessions = session_repo
+        .list_sessions(user.uid as i64, Utc::now().timestamp())
+        .await?;
+    Ok(HttpResponse::Ok().json(
+        sessions
+            .into_iter()
+            .map(|session| SessionInfo {
+                current: user.sid == Some(session.id),
+                session,
+            })
+            .collect::<Vec<_>>(),
+    ))
 }
 
 // token验证
-pub async fn token_verify(query_data: web::Query<HashMap<String, String>>) -> impl Responder {
-    let token = query_data.get("token").unwrap();
+#[derive(Debug, serde::Deserialize)]
+pub struct TokenQuery {
+    pub token: String,
+}
+
+impl Validate for TokenQuery {
+    fn validate(&self, v: &mut Validator) {
+        v.required("token", &self.token, 4096);
+    }
+}
+
+pub async fn token_verify(query_data: ValidQuery<TokenQuery>) -> Result<HttpResponse, ApiError> {
+    let token = &query_data.token;
     match gettoken_to_user_no_time(token) {
-        Ok(_) => HttpResponse::Ok().json(ResponseMessage {
+        Ok(_) => Ok(HttpResponse::Ok().json(ResponseMessage {
             code: 200,
             message: token,
-        }),
-        Err(_) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: token,
-        }),
+        })),
+        Err(_) => Err(ApiError::Auth(super::auth::AuthError::Invalid)),
     }
 }
 
 // 忘记密码
 pub async fn forget_password(
-    user: web::Json<RegisterUser>,
-    config: web::Data<HttpServerConfig>,
-    query_data: web::Query<HashMap<String, String>>,
-    email_code_manager: web::Data<Addr<EmaiCodeManager>>,
-) -> HttpResponse {
-    let result = email_code_manager
-        .send(VerifyCode {
-            email: user.email.clone(),
-            code: query_data.get("code").unwrap().to_string(),
-        })
-        .await
-        .unwrap();
-    if result {
-        let conn = get_conn(&config).await.unwrap();
-        match sql_user::change_password(conn, &user).await {
-            Ok(_) => HttpResponse::Ok().json(ResponseMessage {
-                code: 200,
-                message: "修改成功",
-            }),
-            Err(_) => HttpResponse::Ok().json(ResponseMessage {
-                code: 500,
-                message: "修改失败",
-            }),
-        }
-    } else {
-        return HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: "验证码错误",
-        });
+    body: ValidJson<RegisterRequest>,
+    user_repo: web::Data<dyn UserRepository>,
+    session_repo: web::Data<dyn SessionRepository>,
+    email_code_manager: web::Data<EmaiCodeManager>,
+) -> Result<HttpResponse, ApiError> {
+    verify_code(
+        &email_code_manager,
+        &body.email,
+        CodePurpose::reset_password,
+        &body.code,
+    )
+    .await?;
+    if user_repo.change_password(&body.user()).await? == 0 {
+        return Err(ApiError::NotFound("用户不存在"));
     }
+    revoke_email_sessions(user_repo.get_ref(), session_repo.get_ref(), &body.email).await;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "修改成功",
+    }))
 }
 
-// 获取所有用户
-pub async fn get_all(config: web::Data<HttpServerConfig>, req: HttpRequest) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    match check_acl(
-        conn,
-        token,
-        "user",
-        &crate::lib::acl::sql_acl::Operation::Remove.to_string(),
-    )
-    .await
-    {
-        Ok(_) => {
-            let conn = get_conn(&config).await.unwrap();
-            let users = sql_user::get_all_user(conn).await.unwrap();
-            return HttpResponse::Ok().json(users);
-        }
-        Err(_) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: "权限不足",
-        }),
-    }
+// 获取所有用户，路由上要求 user 的 Remove 权限
+pub async fn get_all(user_repo: web::Data<dyn UserRepository>) -> Result<HttpResponse, ApiError> {
+    let users = user_repo.get_all_user().await?;
+    Ok(HttpResponse::Ok().json(users))
 }
 
 // 修改指定用户密码
+#[derive(Debug, serde::Deserialize)]
+pub struct ChangePasswordRequest {
+    pub email: String,
+    pub password: String,
+}
+
+impl Validate for ChangePasswordRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("email", &self.email, 254)
+            .password("password", &self.password);
+    }
+}
+
+// 修改指定用户密码，路由上要求 user 的 Update 权限
 pub async fn change_password(
-    user: web::Json<RegisterUser>,
-    config: web::Data<HttpServerConfig>,
-    req: HttpRequest,
-) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let conn = get_conn(&config).await.unwrap();
-    match check_acl(
-        conn,
-        token,
-        "user",
-        &crate::lib::acl::sql_acl::Operation::Update.to_string(),
-    )
-    .await
-    {
-        Ok(_) => {
-            let conn = get_conn(&config).await.unwrap();
-            if user.email == "admin" {
-                return HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "不能修改admin密码",
-                });
-            }
-            match sql_user::change_password(conn, &user).await {
-                Ok(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 200,
-                    message: "修改成功",
-                }),
-                Err(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "修改失败",
-                }),
-            }
-        }
-        Err(_) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: "权限不足",
-        }),
+    body: ValidJson<ChangePasswordRequest>,
+    user_repo: web::Data<dyn UserRepository>,
+    session_repo: web::Data<dyn SessionRepository>,
+) -> Result<HttpResponse, ApiError> {
+    let user = RegisterUser {
+        email: body.email.clone(),
+        password: body.password.clone(),
+    };
+    if user.email == "admin" {
+        return Err(ApiError::Forbidden("不能修改admin密码"));
     }
+    if user_repo.change_password(&user).await? == 0 {
+        return Err(ApiError::NotFound("用户不存在"));
+    }
+    revoke_email_sessions(user_repo.get_ref(), session_repo.get_ref(), &user.email).await;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "修改成功",
+    }))
 }
 
 // 删除指定用户
+#[derive(Debug, serde::Deserialize)]
+pub struct EmailRequest {
+    pub email: String,
+}
+
+impl Validate for EmailRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.required("email", &self.email, 254);
+    }
+}
+
+// 删除指定用户，路由上要求 user 的 Remove 权限
 pub async fn delete_user(
-    email_query: web::Query<HashMap<String, String>>,
+    body: ValidJson<EmailRequest>,
+    user_repo: web::Data<dyn UserRepository>,
+    session_repo: web::Data<dyn SessionRepository>,
+    acl_repo: web::Data<dyn AclRepository>,
     config: web::Data<HttpServerConfig>,
-    req: HttpRequest,
-) -> HttpResponse {
-    let token = req.headers().get(AUTHORIZATION).unwrap().to_str().unwrap();
-    let email = email_query.get("email").unwrap();
-    let conn = get_conn(&config).await.unwrap();
-
-    match check_acl(
-        conn,
-        token,
-        "user",
-        &crate::lib::acl::sql_acl::Operation::Remove.to_string(),
+) -> Result<HttpResponse, ApiError> {
+    let email = body.email.as_str();
+    if email == "admin" {
+        return Err(ApiError::Forbidden("不能删除admin"));
+    }
+    let uid = user_repo
+        .get_user_id(email)
+        .await
+        .map_err(|_| ApiError::NotFound("用户不存在"))?;
+    remove_user(
+        acl_repo.get_ref(),
+        session_repo.get_ref(),
+        &config.acl_config,
+        uid,
     )
-    .await
-    {
-        Ok(_) => {
-            let conn = get_conn(&config).await.unwrap();
-            if email == "admin" {
-                return HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "不能删除admin",
-                });
-            }
-            match sql_user::delete_user(conn, email).await {
-                Ok(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 200,
-                    message: "删除成功",
-                }),
-                Err(_) => HttpResponse::Ok().json(ResponseMessage {
-                    code: 500,
-                    message: "删除失败",
-                }),
-            }
+    .await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "删除成功",
+    }))
+}
+
+// 注销账号，需要发送到账号邮箱的验证码
+#[derive(Debug, serde::Deserialize)]
+pub struct DeleteAccountRequest {
+    pub code: String,
+}
+
+impl Validate for DeleteAccountRequest {
+    fn validate(&self, v: &mut Validator) {
+        v.code("code", &self.code);
+    }
+}
+
+// 注销当前用户的账号
+#[allow(clippy::too_many_arguments)]
+pub async fn delete_account(
+    body: ValidJson<DeleteAccountRequest>,
+    user_repo: web::Data<dyn UserRepository>,
+    session_repo: web::Data<dyn SessionRepository>,
+    acl_repo: web::Data<dyn AclRepository>,
+    config: web::Data<HttpServerConfig>,
+    email_code_manager: web::Data<EmaiCodeManager>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let email = user_repo.get_user_email(user.uid as i64).await?;
+    if email == "admin" {
+        return Err(ApiError::Forbidden("不能删除admin"));
+    }
+    verify_code(
+        &email_code_manager,
+        &email,
+        CodePurpose::delete_account,
+        &body.code,
+    )
+    .await?;
+    remove_user(
+        acl_repo.get_ref(),
+        session_repo.get_ref(),
+        &config.acl_config,
+        user.uid as i64,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(ResponseMessage {
+        code: 200,
+        message: "账号已注销",
+    }))
+}
+
+// 删除用户及其规则、角色、玩家、两步验证、会话、邮箱更换记录与验证码，
+// 会话记录删除后其访问token记入撤销列表
+async fn remove_user(
+    acl_repo: &dyn AclRepository,
+    session_repo: &dyn SessionRepository,
+    config: &AclConfig,
+    uid: i64,
+) -> Result<(), ApiError> {
+    let sessions = session_repo
+        .list_sessions(uid, Utc::now().timestamp())
+        .await?;
+    if apply_protected(acl_repo, config, AclChange::DeleteUser { uid }).await? == 0 {
+        return Err(ApiError::NotFound("用户不存在"));
+    }
+    let ids: Vec<i64> = sessions.iter().map(|session| session.id).collect();
+    mark_revoked(&ids);
+    Ok(())
+}
+
+// 修改密码、重置密码后注销该用户的所有会话
+async fn revoke_email_sessions(
+    user_repo: &dyn UserRepository,
+    session_repo: &dyn SessionRepository,
+    email: &str,
+) {
+    if let Ok(uid) = user_repo.get_user_id(email).await {
+        if let Err(err) = revoke_user_sessions(session_repo, uid).await {
+            log::error!("注销用户 {} 的会话失败: {}", uid, err);
         }
-        Err(_) => HttpResponse::Ok().json(ResponseMessage {
-            code: 500,
-            message: "权限不足",
-        }),
     }
 }
+
+#[actix_web::test]
+async fn test_register_with_email_code() {
+    use actix::Actor;
+    use actix_web::{http::StatusCode, test, App};
+
+    use crate::lib::{
+        config::OutboxConfig,
+        mail::{outbox::deliver_due, MemoryMailTransport},
+        ratelimit::lockout::LoginGuard,
+        repository::Repositories,
+    };
+
+    let repositories = Repositories::memory();
+    let mails = MemoryMailTransport::new();
+    let app = test::init_service(
+        App::new()
+            .configure(|cfg| repositories.app_data(cfg))
+            .app_data(web::Data::new(Outbox::new(repositories.outbox.clone())))
+            .app_data(web::Data::new(MailTemplates::new(
+                &HttpServerConfig::default(),
+            )))
+            .app_data(web::Data::new(EmaiCodeManager::new(
+                &repositories,
+                &HttpServerConfig::default(),
+            )))
+            .app_data(web::Data::new(LoginGuard::default().start()))
+            .app_data(web::Data::new(HttpServerConfig::default()))
+            .route("/get_code", web::get().to(get_code))
+            .route("/register", web::post().to(register))
+            .route("/login", web::post().to(login)),
+    )
+    .await;
+
+    let email = "steve@example.com";
+    for purpose in ["reset_password", "register"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/get_code?email={}&purpose={}", email, purpose))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+    let outbox_repo = repositories.outbox.as_ref();
+    let now = Utc::now().timestamp();
+    deliver_due(outbox_repo, &mails, &OutboxConfig::default(), now)
+        .await
+        .unwrap();
+
+    // 从邮件中读取验证码
+    let codes: Vec<String> = mails
+        .mails()
+        .iter()
+        .map(|mail| {
+            let text = mail.text.as_ref().unwrap();
+            text.split("验证码是：")
+                .nth(1)
+                .unwrap()
+                .chars()
+                .take(6)
+                .collect()
+        })
+        .collect();
+    let (reset_code, code) = (codes[0].clone(), codes[1].clone());
+
+    let register = |code: String| {
+        test::TestRequest::post()
+            .uri("/register")
+            .set_json(serde_json::json!({
+                "email": email,
+                "password": "Password123",
+                "code": code,
+            }))
+            .to_request()
+    };
+    let wrong = format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1000000);
+    let res = test::call_service(&app, register(wrong)).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    // 重置密码的验证码不能用于注册
+    if reset_code != code {
+        let res = test::call_service(&app, register(reset_code)).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+    let res = test::call_service(&app, register(code)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(serde_json::json!({ "email": email, "password": "Password123" }))
+        .to_request();
+    let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(body["refresh_token"].is_string());
+}
@@ -0,0 +1,231 @@
+// 请求参数校验
+//
+// 请求结构体实现 Validate，处理函数使用 ValidJson<T>/ValidQuery<T> 代替
+// web::Json<T>/web::Query<T>，解析失败或校验未通过时返回400，并列出每个无效字段。
+
+use std::{future::Future, ops::Deref, pin::Pin, str::FromStr};
+
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::error::ApiError;
+
+// 无效的字段
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+/// 收集字段错误
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn check(&mut self, field: &'static str, valid: bool, message: &'static str) -> &mut Self {
+        if !valid {
+            self.errors.push(FieldError { field, message });
+        }
+        self
+    }
+
+    // 非空且不超过最大长度
+    pub fn required(&mut self, field: &'static str, value: &str, max_len: usize) -> &mut Self {
+        if value.trim().is_empty() {
+            self.check(field, false, "不能为空")
+        } else {
+            self.check(field, value.chars().count() <= max_len, "长度超出限制")
+        }
+    }
+
+    pub fn email(&mut self, field: &'static str, value: &str) -> &mut Self {
+        self.check(
+            field,
+            value.len() <= 254 && lettre::Address::from_str(value).is_ok(),
+            "邮箱格式错误",
+        )
+    }
+
+    // 账号密码：8-128位，同时包含字母和数字
+    pub fn password(&mut self, field: &'static str, value: &str) -> &mut Self {
+        let len = value.chars().count();
+        self.check(
+            field,
+            (8..=128).contains(&len)
+                && value.chars().any(|c| c.is_alphabetic())
+                && value.chars().any(|c| c.is_ascii_digit()),
+            "密码需为8-128位，且同时包含字母和数字",
+        )
+    }
+
+    // Minecraft 玩家名：3-16位字母、数字或下划线
+    pub fn player_name(&mut self, field: &'static str, value: &str) -> &mut Self {
+        self.check(
+            field,
+            (3..=16).contains(&value.len())
+                && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "玩家名需为3-16位字母、数字或下划线",
+        )
+    }
+
+    // 玩家快捷密码：6-32位
+    pub fn player_password(&mut self, field: &'static str, value: &str) -> &mut Self {
+        self.check(
+            field,
+            (6..=32).contains(&value.chars().count()),
+            "快捷密码需为6-32位",
+        )
+    }
+
+    // 6位数字验证码
+    pub fn code(&mut self, field: &'static str, value: &str) -> &mut Self {
+        self.check(
+            field,
+            value.len() == 6 && value.chars().all(|c| c.is_ascii_digit()),
+            "验证码为6位数字",
+        )
+    }
+
+    // 操作名，内置操作或资源上注册的操作，是否已注册由处理函数检查
+    pub fn operation(&mut self, field: &'static str, value: &str) -> &mut Self {
+        let valid = (1..=32).contains(&value.len())
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        self.check(field, valid, "操作名为1-32位字母、数字、_ 或 -")
+    }
+
+    // 资源名：用 . 分隔的路径，* 只能作为最后一段，例如 server.survival-1.chat、server.*
+    pub fn resource(&mut self, field: &'static str, value: &str) -> &mut Self {
+        if value.trim().is_empty() || value.chars().count() > 64 {
+            return self.required(field, value, 64);
+        }
+        let segments: Vec<&str> = value.split('.').collect();
+        let valid = segments.iter().enumerate().all(|(i, segment)| {
+            !segment.is_empty()
+                && !segment.chars().any(char::is_whitespace)
+                && (*segment == "*" && i == segments.len() - 1 || !segment.contains('*'))
+        });
+        self.check(field, valid, "资源名格式错误")
+    }
+
+    pub fn finish(self) -> Result<(), ApiError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ApiError::Validation(self.errors))
+        }
+    }
+}
+
+/// 请求参数的校验规则
+pub trait Validate {
+    fn validate(&self, v: &mut Validator);
+}
+
+/// 校验后的 JSON 请求体
+#[derive(Debug)]
+pub struct ValidJson<T>(pub T);
+
+/// 校验后的查询参数
+#[derive(Debug)]
+pub struct ValidQuery<T>(pub T);
+
+impl<T> Deref for ValidJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> Deref for ValidQuery<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+fn validated<T: Validate>(value: T) -> Result<T, ApiError> {
+    let mut validator = Validator::default();
+    value.validate(&mut validator);
+    validator.finish().map(|_| value)
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ValidJson<T> {
+    type Error = ApiError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, ApiError>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let json = json
+                .await
+                .map_err(|err| ApiError::BadRequest(format!("请求体格式错误: {}", err)))?;
+            validated(json.into_inner()).map(ValidJson)
+        })
+    }
+}
+
+impl<T: DeserializeOwned + Validate> FromRequest for ValidQuery<T> {
+    type Error = ApiError;
+    type Future = std::future::Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        std::future::ready(
+            web::Query::<T>::from_query(req.query_string())
+                .map_err(|err| ApiError::BadRequest(format!("查询参数格式错误: {}", err)))
+                .and_then(|query| validated(query.into_inner()))
+                .map(ValidQuery),
+        )
+    }
+}
+
+#[tokio::test]
+async fn test_validator() {
+    let mut v = Validator::default();
+    v.email("email", "player@example.com")
+        .password("password", "secret123")
+        .player_name("player_name", "Notch_1")
+        .player_password("quick", "123456")
+        .code("code", "012345")
+        .operation("operation", "Check")
+        .required("resource", "user", 64)
+        .resource("path", "server.survival-1.chat")
+        .resource("pattern", "server.*");
+    assert!(v.finish().is_ok());
+
+    let mut v = Validator::default();
+    v.email("email", "not-an-email")
+        .password("password", "short")
+        .player_name("player_name", "no spaces!")
+        .player_password("quick", "123")
+        .code("code", "12ab")
+        .operation("operation", "kick player")
+        .required("resource", " ", 64)
+        .resource("path", "server..chat")
+        .resource("pattern", "server.*.chat");
+    match v.finish() {
+        Err(ApiError::Validation(errors)) => {
+            let fields: Vec<_> = errors.iter().map(|err| err.field).collect();
+            assert_eq!(
+                fields,
+                [
+                    "email",
+                    "password",
+                    "player_name",
+                    "quick",
+                    "code",
+                    "operation",
+                    "resource",
+                    "path",
+                    "pattern"
+                ]
+            );
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
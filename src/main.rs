@@ -1,15 +1,36 @@
+#![allow(special_module_name)]
+
 use actix::Actor;
 use actix_cors::Cors;
 use actix_web::{web, App, HttpServer};
 use lib::{
-    acl::web_acl,
-    config::{get_conn, HttpServerConfig},
-    java::player::{
-        self, chatserver::{chatserver, session}, onlineplayer::PlayerManager, web_player
+    acl::{
+        cache::{self, AclCache},
+        guard::RequireAcl,
+        sql_acl::Operation,
+        web_acl, web_role,
+    },
+    config::{create_pool, HttpServerConfig},
+    java::{
+        player::{
+            self,
+            chatserver::{chatserver, session},
+            onlineplayer::PlayerManager,
+            web_player,
+        },
+        server::web_server,
     },
+    cipher::{init_cipher, Cipher},
+    keyring::{self, init_keyring, KeyRing},
+    mail::{
+        create_mail_transport, outbox::Outbox, template::MailTemplates, web_outbox, web_template,
+    },
+    migrations::pending_migrations,
+    ratelimit::{lockout::LoginGuard, middleware::RateLimit, RateLimiter},
     user::{
-        email_code::{EmaiCodeManager, EmailManager},
-        web_user,
+        email_code::EmaiCodeManager,
+        session::load_revoked_sessions,
+        web_email_change, web_totp, web_user,
     },
 };
 use log::info;
@@ -50,31 +71,89 @@ async fn main() -> Result<(), std::io::Error> {
     let server = chatserver::ChatServer::new().start();
     // 玩家管理
     let players = PlayerManager::new().start();
+    // 账号、两步验证与玩家快捷登录失败锁定
+    let login_guard = LoginGuard::new(config.rate_limit_config.lockout.clone()).start();
+    // 请求频率限制
+    let rate_limiter = RateLimiter::new().start();
 
-    // 邮箱验证码管理
-    let email_code_manager = EmaiCodeManager::new().start();
-
-    // 邮箱管理发送
-    let emailmanager = EmailManager::new(
-        config.name.clone(),
-        config.email_config.smtp_server.clone(),
-        config.email_config.mine_email.clone(),
-        config.email_config.email_password.clone(),
-    )
-    .start();
+    // 邮件发送
+    let mail_transport = create_mail_transport(&config.email_config)
+        .unwrap_or_else(|err| panic!("初始化邮件发送失败: {}", err));
 
     let v4port = config.v4port;
-    let v6port = config.v6port;
+    let _v6port = config.v6port;
+
+    // 数据库连接池
+    let pool = create_pool(&config)
+        .await
+        .unwrap_or_else(|err| panic!("数据库连接失败: {}", err));
+
+    // 只查看尚未执行的数据库迁移，不启动服务
+    if std::env::args().any(|arg| arg == "--pending-migrations") {
+        let pending = pending_migrations(&pool)
+            .await
+            .unwrap_or_else(|err| panic!("读取迁移版本失败: {}", err));
+        if pending.is_empty() {
+            println!("数据库已是最新版本");
+        }
+        for migration in pending {
+            println!("v{}: {}", migration.version, migration.description);
+        }
+        return Ok(());
+    }
+
+    // 轮换token签名密钥，旧密钥继续用于验证，不启动服务
+    if std::env::args().any(|arg| arg == "--rotate-jwt-key") {
+        let kid = KeyRing::rotate(&config.jwt_config)
+            .unwrap_or_else(|err| panic!("轮换token签名密钥失败: {}", err));
+        println!("新的签名密钥: {}", kid);
+        return Ok(());
+    }
+
+    // 轮换数据加密密钥，旧密钥继续用于解密
+    if std::env::args().any(|arg| arg == "--rotate-encryption-key") {
+        let id = Cipher::rotate(&config.encryption_config)
+            .unwrap_or_else(|err| panic!("轮换数据加密密钥失败: {}", err));
+        println!("新的加密密钥: {}", id);
+        return Ok(());
+    }
+
+    // token签名密钥
+    init_keyring(&config.jwt_config)
+        .unwrap_or_else(|err| panic!("加载token签名密钥失败: {}", err));
+    // 数据加密密钥
+    init_cipher(&config.encryption_config)
+        .unwrap_or_else(|err| panic!("加载数据加密密钥失败: {}", err));
 
     // 初始化数据库
-    lib::config::init_db(&config).await;
+    let repositories = lib::config::init_db(&pool, &config).await;
+    // 权限缓存，acl 与 role 仓库写入时失效
+    let acl_cache = AclCache::default().start();
+    let repositories = repositories.with_acl_cache(acl_cache.clone());
+    // 已撤销但访问token尚未过期的会话
+    load_revoked_sessions(repositories.session.as_ref())
+        .await
+        .unwrap_or_else(|err| panic!("加载已撤销的会话失败: {}", err));
+    // 邮件发件箱与后台发送任务
+    let outbox = Outbox::new(repositories.outbox.clone());
+    outbox.start_worker(mail_transport, config.email_config.outbox.clone());
+    let mail_templates = MailTemplates::new(&config);
+    // 邮箱验证码管理与过期验证码的清理
+    let email_code_manager = EmaiCodeManager::new(&repositories, &config);
+    email_code_manager.start_sweeper(config.code_config.sweep_interval);
     HttpServer::new(move || {
+        let rate_limit = config.rate_limit_config.clone();
         App::new()
+            .configure(|cfg| repositories.app_data(cfg))
             .app_data(web::Data::new(server.clone()))
             .app_data(web::Data::new(players.clone()))
+            .app_data(web::Data::new(login_guard.clone()))
+            .app_data(web::Data::new(rate_limiter.clone()))
+            .app_data(web::Data::new(acl_cache.clone()))
             .app_data(web::Data::new(config.clone()))
             .app_data(web::Data::new(email_code_manager.clone()))
-            .app_data(web::Data::new(emailmanager.clone()))
+            .app_data(web::Data::new(outbox.clone()))
+            .app_data(web::Data::new(mail_templates.clone()))
             .wrap(
                 Cors::default()
                     .allow_any_origin()
@@ -86,64 +165,246 @@ async fn main() -> Result<(), std::io::Error> {
                     cfg.service(
                         web::scope("/user")
                             // 获取验证码
-                            .route("/get_code", web::get().to(web_user::get_code))
+                            .service(
+                                web::resource("/get_code")
+                                    .wrap(
+                                        RateLimit::new("get_code", &rate_limit.get_code)
+                                            .query_identifier("email"),
+                                    )
+                                    .route(web::get().to(web_user::get_code)),
+                            )
                             // token验证
                             .route("/token_verify", web::get().to(web_user::token_verify))
-                            .route("/register", web::post().to(web_user::register))
-                            .route("/login", web::post().to(web_user::login))
+                            .service(
+                                web::resource("/register")
+                                    .wrap(
+                                        RateLimit::new("register", &rate_limit.verify_code)
+                                            .identifier("email"),
+                                    )
+                                    .route(web::post().to(web_user::register)),
+                            )
+                            .service(
+                                web::resource("/login")
+                                    .wrap(
+                                        RateLimit::new("login", &rate_limit.login)
+                                            .identifier("email"),
+                                    )
+                                    .route(web::post().to(web_user::login)),
+                            )
+                            // 登录第二步，提交两步验证码
+                            .service(
+                                web::resource("/login/totp")
+                                    .wrap(RateLimit::new("login_totp", &rate_limit.login_totp))
+                                    .route(web::post().to(web_user::login_totp)),
+                            )
+                            // 刷新token
+                            .route("/refresh", web::post().to(web_user::refresh))
+                            // 注销当前会话
+                            .route("/logout", web::post().to(web_user::logout))
+                            // 注销所有会话
+                            .route("/logout_all", web::post().to(web_user::logout_all))
+                            // 查询当前用户的会话
+                            .route("/sessions", web::get().to(web_user::sessions))
                             // 忘记密码
-                            .route(
-                                "/forget_password",
-                                web::post().to(web_user::forget_password),
+                            .service(
+                                web::resource("/forget_password")
+                                    .wrap(
+                                        RateLimit::new("forget_password", &rate_limit.verify_code)
+                                            .identifier("email"),
+                                    )
+                                    .route(web::post().to(web_user::forget_password)),
+                            )
+                            // 注销当前账号，邮箱来自登录的账号，只按ip计数
+                            .service(
+                                web::resource("/delete_account")
+                                    .wrap(RateLimit::new("delete_account", &rate_limit.verify_code))
+                                    .route(web::post().to(web_user::delete_account)),
+                            )
+                            // 更换邮箱，验证码发送到新邮箱
+                            .service(
+                                web::resource("/change_email")
+                                    .wrap(
+                                        RateLimit::new("change_email", &rate_limit.change_email)
+                                            .identifier("new_email"),
+                                    )
+                                    .route(web::post().to(web_email_change::request_change)),
+                            )
+                            .service(
+                                web::resource("/change_email/confirm")
+                                    .wrap(
+                                        RateLimit::new(
+                                            "change_email_confirm",
+                                            &rate_limit.verify_code,
+                                        )
+                                        .identifier("new_email"),
+                                    )
+                                    .route(web::post().to(web_email_change::confirm_change)),
+                            )
+                            // 旧邮箱中的撤销链接
+                            .service(
+                                web::resource("/change_email/cancel")
+                                    .route(web::get().to(web_email_change::cancel_page))
+                                    .route(web::post().to(web_email_change::cancel_change)),
+                            )
+                            // 两步验证
+                            .service(
+                                web::scope("/totp")
+                                    .route("/status", web::get().to(web_totp::status))
+                                    .route("/setup", web::post().to(web_totp::setup))
+                                    .route("/enable", web::post().to(web_totp::enable))
+                                    .route("/disable", web::post().to(web_totp::disable))
+                                    .route(
+                                        "/recovery_codes",
+                                        web::post().to(web_totp::recovery_codes),
+                                    ),
                             )
                             // *******************admin********************
                             // 获取所有用户
-                            .route("/get_all", web::get().to(web_user::get_all))
+                            .service(
+                                web::resource("/get_all")
+                                    .wrap(RequireAcl::new("user", Operation::Remove))
+                                    .route(web::get().to(web_user::get_all)),
+                            )
                             // 修改用户密码
-                            .route(
-                                "/update_password",
-                                web::post().to(web_user::change_password),
+                            .service(
+                                web::resource("/update_password")
+                                    .wrap(RequireAcl::new("user", Operation::Update))
+                                    .route(web::post().to(web_user::change_password)),
                             )
                             // 删除用户
-                            .route("/delete", web::post().to(web_user::delete_user)),
+                            .service(
+                                web::resource("/delete")
+                                    .wrap(RequireAcl::new("user", Operation::Remove))
+                                    .route(web::post().to(web_user::delete_user)),
+                            ),
+                    );
+                    cfg.service(
+                        web::scope("/java")
+                            .service(
+                                web::scope("/player")
+                                    .route("/bind", web::post().to(web_player::add_bind_player))
+                                    // 游戏服务器通过API密钥验证玩家名与快捷密码，返回uid、是否正版与uuid
+                                    .service(
+                                        web::resource("/login")
+                                            .wrap(
+                                                RateLimit::new(
+                                                    "player_login",
+                                                    &rate_limit.player_login,
+                                                )
+                                                .identifier("player_name"),
+                                            )
+                                            .route(web::post().to(web_player::login)),
+                                    )
+                                    // 检查玩家是否为正版玩家
+                                    .route("/check_player", web::get().to(web_player::check_player))
+                                    // 查询拥有的玩家
+                                    .route("/query_player", web::get().to(web_player::query_player))
+                                    // 修改玩家密码
+                                    .route(
+                                        "/update_password",
+                                        web::post().to(web_player::update_player),
+                                    )
+                                    // 删除玩家
+                                    .route("/delete_player", web::post().to(web_player::delete_player))
+                                    // 在线玩家-加入
+                                    .route("/player_join", web::post().to(player::player_join))
+                                    // 在线玩家-查询在线玩家
+                                    .route("/players", web::get().to(player::get_players))
+                                    // 在线玩家-离开
+                                    .route("/player_leave", web::post().to(player::player_leave)),
+                            )
+                            // 游戏服务器管理
+                            .service(
+                                web::scope("/server")
+                                    .service(
+                                        web::resource("/get_all")
+                                            .wrap(RequireAcl::new("server", Operation::Check))
+                                            .route(web::get().to(web_server::server_get_all)),
+                                    )
+                                    // 注册服务器并生成API密钥
+                                    .service(
+                                        web::resource("/add")
+                                            .wrap(RequireAcl::new("server", Operation::Add))
+                                            .route(web::post().to(web_server::server_add)),
+                                    )
+                                    // 更换API密钥
+                                    .service(
+                                        web::resource("/rotate_key")
+                                            .wrap(RequireAcl::new("server", Operation::Update))
+                                            .route(web::post().to(web_server::server_rotate_key)),
+                                    )
+                                    // 吊销API密钥
+                                    .service(
+                                        web::resource("/revoke")
+                                            .wrap(RequireAcl::new("server", Operation::Remove))
+                                            .route(web::post().to(web_server::server_revoke)),
+                                    ),
+                            ),
                     );
+                    // 发件箱管理
                     cfg.service(
-                        web::scope("/java").service(
-                            web::scope("/player")
-                                .route("/bind", web::post().to(web_player::add_bind_player))
-                                // 验证账绑定了指定的玩家
-                                .route("/login", web::get().to(web_player::login))
-                                // 检查玩家是否为正版玩家
-                                .route("/check_player", web::get().to(web_player::check_player))
-                                // 查询拥有的玩家
-                                .route("/query_player", web::get().to(web_player::query_player))
-                                // 修改玩家密码
-                                .route(
-                                    "/update_password",
-                                    web::post().to(web_player::update_player),
-                                )
-                                // 删除玩家
-                                .route("/delete_player", web::post().to(web_player::delete_player))
-                                // 在线玩家-加入
-                                .route("/player_join", web::post().to(player::player_join))
-                                // 在线玩家-查询在线玩家
-                                .route("/players", web::get().to(player::get_players))
-                                // 在线玩家-离开
-                                .route("/player_leave", web::post().to(player::player_leave),
-                                ),
-                        ),
+                        web::scope("/mail/outbox")
+                            // 发送失败的邮件
+                            .service(
+                                web::resource("/failed")
+                                    .wrap(RequireAcl::new("mail", Operation::Check))
+                                    .route(web::get().to(web_outbox::outbox_failed)),
+                            )
+                            // 重新发送
+                            .service(
+                                web::resource("/retry")
+                                    .wrap(RequireAcl::new("mail", Operation::Update))
+                                    .route(web::post().to(web_outbox::outbox_retry)),
+                            ),
                     );
+                    // 邮件模板预览
+                    cfg.service(
+                        web::resource("/mail/templates/preview")
+                            .wrap(RequireAcl::new("mail", Operation::Check))
+                            .route(web::get().to(web_template::template_preview)),
+                    );
+                    // 游戏服务器连接，需要API密钥
                     cfg.service(web::resource("/ws").route(web::get().to(session::ws_route)));
+                    // token验证公钥(RS256/EdDSA)
+                    cfg.service(web::resource("/jwks").route(web::get().to(keyring::jwks)));
 
                     cfg.service(
                         web::scope("/acl")
                             .service(
                                 web::scope("/resource")
-                                    .route("/get_all", web::get().to(web_acl::acl_get_all_resource))
+                                    .service(
+                                        web::resource("/get_all")
+                                            .wrap(RequireAcl::new("resource", Operation::Check))
+                                            .route(web::get().to(web_acl::acl_get_all_resource)),
+                                    )
                                     // 添加资源
-                                    .route("/add", web::post().to(web_acl::acl_add_resource))
+                                    .service(
+                                        web::resource("/add")
+                                            .wrap(RequireAcl::new("resource", Operation::Add))
+                                            .route(web::post().to(web_acl::acl_add_resource)),
+                                    )
                                     // 删除资源
-                                    .route("/delete", web::post().to(web_acl::acl_delete_resource)),
+                                    .service(
+                                        web::resource("/delete")
+                                            .wrap(RequireAcl::new("resource", Operation::Remove))
+                                            .route(web::post().to(web_acl::acl_delete_resource)),
+                                    )
+                                    // 资源上注册的自定义操作
+                                    .service(
+                                        web::resource("/operation/get_all")
+                                            .wrap(RequireAcl::new("operation", Operation::Check))
+                                            .route(web::get().to(web_acl::acl_get_all_operation)),
+                                    )
+                                    .service(
+                                        web::resource("/operation/add")
+                                            .wrap(RequireAcl::new("operation", Operation::Add))
+                                            .route(web::post().to(web_acl::acl_add_operation)),
+                                    )
+                                    .service(
+                                        web::resource("/operation/delete")
+                                            .wrap(RequireAcl::new("operation", Operation::Remove))
+                                            .route(web::post().to(web_acl::acl_delete_operation)),
+                                    ),
                             )
                             .service(
                                 web::scope("/operation")
@@ -155,14 +416,70 @@ async fn main() -> Result<(), std::io::Error> {
                                         web::post().to(web_acl::acl_remove_user_operation),
                                     ),
                             )
-                            // 查询指定用户对资源的操作
-                            .route("/query", web::get().to(web_acl::acl_get_user_operation)),
+                            // 角色
+                            .service(
+                                web::scope("/role")
+                                    .service(
+                                        web::resource("/get_all")
+                                            .wrap(RequireAcl::new("role", Operation::Check))
+                                            .route(web::get().to(web_role::role_get_all)),
+                                    )
+                                    .service(
+                                        web::resource("/add")
+                                            .wrap(RequireAcl::new("role", Operation::Add))
+                                            .route(web::post().to(web_role::role_add)),
+                                    )
+                                    .service(
+                                        web::resource("/delete")
+                                            .wrap(RequireAcl::new("role", Operation::Remove))
+                                            .route(web::post().to(web_role::role_delete)),
+                                    )
+                                    // 角色对资源的操作
+                                    .service(
+                                        web::resource("/operation/add")
+                                            .wrap(RequireAcl::new("role", Operation::Update))
+                                            .route(web::post().to(web_role::role_add_operation)),
+                                    )
+                                    .service(
+                                        web::resource("/operation/delete")
+                                            .wrap(RequireAcl::new("role", Operation::Update))
+                                            .route(web::post().to(web_role::role_remove_operation)),
+                                    )
+                                    // 用户的角色
+                                    .service(
+                                        web::resource("/user")
+                                            .wrap(RequireAcl::new("role", Operation::Check))
+                                            .route(web::get().to(web_role::role_get_user)),
+                                    )
+                                    .service(
+                                        web::resource("/user/add")
+                                            .wrap(RequireAcl::new("role", Operation::Update))
+                                            .route(web::post().to(web_role::role_assign_user)),
+                                    )
+                                    .service(
+                                        web::resource("/user/delete")
+                                            .wrap(RequireAcl::new("role", Operation::Update))
+                                            .route(web::post().to(web_role::role_unassign_user)),
+                                    ),
+                            )
+                            // 查询当前用户对资源的操作
+                            .service(
+                                web::resource("/query")
+                                    .wrap(RequireAcl::new("resource", Operation::Check))
+                                    .route(web::get().to(web_acl::acl_get_user_operation)),
+                            )
+                            // 权限缓存命中统计
+                            .service(
+                                web::resource("/cache")
+                                    .wrap(RequireAcl::new("resource", Operation::Check))
+                                    .route(web::get().to(cache::acl_cache_stats)),
+                            ),
                     );
                 }),
             )
     })
     .bind(("0.0.0.0", v4port))? //ipv4
-    // .bind(("[::]", v6port))? //ipv6
+    // .bind(("[::]", _v6port))? //ipv6
     .run()
     .await
 }